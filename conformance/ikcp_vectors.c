@@ -0,0 +1,139 @@
+/*
+ * Record the golden vectors of `smol_kcp::conformance` from the reference
+ * ikcp.c (https://github.com/skywind3000/kcp) and print them as Rust source.
+ *
+ *     cc -I path/to/kcp conformance/ikcp_vectors.c path/to/kcp/ikcp.c \
+ *         -o ikcp_vectors && ./ikcp_vectors
+ *
+ * Both sides are ikcp instances driven with a fixed clock, the script is
+ * the one of `smol_kcp::conformance::record_vectors`. Paste the output over
+ * `GOLDEN_VECTORS`.
+ */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "ikcp.h"
+
+#define CONV 0x4b435001
+#define TESTER_MTU 100
+#define T_PUSH 1000
+#define T_FRAGMENTS 1100
+#define T_RECEIVER 5000
+#define FRAGMENTED_LEN 200
+#define MAX_DATAGRAMS 8
+/* Private to ikcp.c */
+#define CMD_WASK 83
+#define WND_RCV 128
+#define OVERHEAD 24
+
+struct datagrams {
+	int count;
+	int len[MAX_DATAGRAMS];
+	char data[MAX_DATAGRAMS][1500];
+};
+
+static int output(const char *buf, int len, ikcpcb *kcp, void *user)
+{
+	struct datagrams *out = user;
+	(void)kcp;
+	memcpy(out->data[out->count], buf, len);
+	out->len[out->count++] = len;
+	return 0;
+}
+
+static void print_datagrams(const char *field, const struct datagrams *d)
+{
+	int i, j;
+	if (d->count == 0) {
+		printf("        %s: &[],\n", field);
+		return;
+	}
+	printf("        %s: &[\n", field);
+	for (i = 0; i < d->count; i++) {
+		printf("            &[\n");
+		for (j = 0; j < d->len[i]; j++) {
+			if (j % 12 == 0)
+				printf("                ");
+			printf("0x%02x,", (unsigned char)d->data[i][j]);
+			printf(j % 12 == 11 || j + 1 == d->len[i] ? "\n" : " ");
+		}
+		printf("            ],\n");
+	}
+	printf("        ],\n");
+}
+
+/* Feed every sent datagram to the receiver, one flush per datagram */
+static void step(const char *name, ikcpcb *receiver, struct datagrams *reply,
+		 const struct datagrams *sent)
+{
+	int i;
+	reply->count = 0;
+	for (i = 0; i < sent->count; i++) {
+		ikcp_input(receiver, sent->data[i], sent->len[i]);
+		ikcp_flush(receiver);
+	}
+	printf("    GoldenVector {\n");
+	printf("        name: \"%s\",\n", name);
+	print_datagrams("sent", sent);
+	print_datagrams("reply", reply);
+	printf("    },\n");
+}
+
+int main(void)
+{
+	static struct datagrams sent, reply, probe, fragments, duplicate;
+	static const char message[] = "smol-kcp conformance";
+	char fragmented[FRAGMENTED_LEN];
+	ikcpcb *tester = ikcp_create(CONV, &sent);
+	ikcpcb *receiver = ikcp_create(CONV, &reply);
+	int i;
+
+	ikcp_setoutput(tester, output);
+	ikcp_setoutput(receiver, output);
+	ikcp_setmtu(tester, TESTER_MTU);
+	/* No congestion window, all three fragments go out in one flush */
+	ikcp_nodelay(tester, 0, 100, 0, 1);
+	ikcp_update(receiver, T_RECEIVER);
+	reply.count = 0;
+
+	printf("pub static GOLDEN_VECTORS: [GoldenVector; 4] = [\n");
+
+	ikcp_send(tester, message, (int)strlen(message));
+	ikcp_update(tester, T_PUSH);
+	step("push", receiver, &reply, &sent);
+
+	/* ikcp_flush sends a probe with the ACK template: sn and ts are zero */
+	memset(probe.data[0], 0, OVERHEAD);
+	for (i = 0; i < 4; i++)
+		probe.data[0][i] = (char)((CONV >> (8 * i)) & 0xff);
+	probe.data[0][4] = CMD_WASK;
+	probe.data[0][6] = WND_RCV;
+	probe.len[0] = OVERHEAD;
+	probe.count = 1;
+	step("window probe", receiver, &reply, &probe);
+
+	for (i = 0; i < FRAGMENTED_LEN; i++)
+		fragmented[i] = (char)(i * 7 + 1);
+	sent.count = 0;
+	ikcp_send(tester, fragmented, FRAGMENTED_LEN);
+	ikcp_update(tester, T_FRAGMENTS);
+	for (i = 0; i < sent.count; i++) {
+		int from = sent.count - 1 - i;
+		memcpy(fragments.data[i], sent.data[from], sent.len[from]);
+		fragments.len[i] = sent.len[from];
+	}
+	fragments.count = sent.count;
+	step("fragments out of order", receiver, &reply, &fragments);
+
+	memcpy(duplicate.data[0], fragments.data[fragments.count - 1],
+	       fragments.len[fragments.count - 1]);
+	duplicate.len[0] = fragments.len[fragments.count - 1];
+	duplicate.count = 1;
+	step("duplicate", receiver, &reply, &duplicate);
+
+	printf("];\n");
+	ikcp_release(tester);
+	ikcp_release(receiver);
+	return 0;
+}
@@ -0,0 +1,1880 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{cmp, fmt, mem};
+
+use crate::{serial, Error, KcpResult, KCP_OVERHEAD};
+
+const KCP_RTO_NDL: u32 = 30; // no delay min rto
+const KCP_RTO_MIN: u32 = 100; // normal min rto
+const KCP_RTO_DEF: u32 = 200;
+const KCP_RTO_MAX: u32 = 60000;
+
+const KCP_CMD_PUSH: u8 = 81; // cmd: push data
+const KCP_CMD_ACK: u8 = 82; // cmd: ack
+const KCP_CMD_WASK: u8 = 83; // cmd: window probe (ask)
+const KCP_CMD_WINS: u8 = 84; // cmd: window size (tell)
+
+const KCP_ASK_SEND: u32 = 1; // need to send KCP_CMD_WASK
+const KCP_ASK_TELL: u32 = 2; // need to send KCP_CMD_WINS
+
+const KCP_WND_SND: u16 = 32;
+const KCP_WND_RCV: u16 = 128; // must >= max fragment size
+
+const KCP_MTU_DEF: usize = 1400;
+const KCP_INTERVAL: u32 = 100;
+const KCP_DEADLINK: u32 = 20;
+
+const KCP_THRESH_INIT: u16 = 2;
+const KCP_THRESH_MIN: u16 = 2;
+
+const KCP_PROBE_INIT: u32 = 7000; // 7 secs to probe window size
+const KCP_PROBE_LIMIT: u32 = 120000; // up to 120 secs to probe window
+const KCP_FASTACK_LIMIT: u32 = 5; // max times to trigger fastack
+
+#[inline]
+fn bound(lower: u32, v: u32, upper: u32) -> u32 {
+    cmp::min(cmp::max(lower, v), upper)
+}
+
+#[derive(Default, Clone, Debug)]
+struct Segment {
+    conv: u32,
+    cmd: u8,
+    frg: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    resendts: u32,
+    rto: u32,
+    fastack: u32,
+    xmit: u32,
+    /// Dropped instead of sent once the clock reaches this, see
+    /// [`Connection::send_with_deadline`]
+    expire: Option<u32>,
+    /// Queued ahead of segments of lower priority, see
+    /// [`Connection::send_message`]
+    priority: u8,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn with_data(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            ..Default::default()
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.conv.to_le_bytes());
+        buf.push(self.cmd);
+        buf.push(self.frg);
+        buf.extend_from_slice(&self.wnd.to_le_bytes());
+        buf.extend_from_slice(&self.ts.to_le_bytes());
+        buf.extend_from_slice(&self.sn.to_le_bytes());
+        buf.extend_from_slice(&self.una.to_le_bytes());
+        buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.data);
+    }
+}
+
+/// Congestion and queue state, for statistics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Internals {
+    pub rx_srtt: u32,
+    pub rx_rttval: u32,
+    pub rx_rto: u32,
+    pub cwnd: u16,
+    pub ssthresh: u16,
+    /// Timeout retransmissions so far
+    pub xmit: u32,
+    /// Messages dropped unsent because their deadline passed
+    pub expired: u32,
+    /// Payload bytes acknowledged by the peer so far
+    pub delivered: u64,
+    /// Congestion control is off
+    pub nocwnd: bool,
+    pub snd_queue_len: usize,
+    pub snd_buf_len: usize,
+    pub rcv_queue_len: usize,
+    pub rcv_buf_len: usize,
+}
+
+/// One KCP conversation
+///
+/// Datagrams to send are queued inside and taken with
+/// [`Connection::poll_transmit`], received datagrams are fed to
+/// [`Connection::handle_input`]. Nothing happens between calls, `now` is
+/// the only clock.
+pub struct Connection {
+    conv: u32,
+    mtu: usize,
+    mss: usize,
+    /// Nonzero once a segment reached the dead link limit
+    state: i32,
+
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+
+    ssthresh: u16,
+
+    rx_rttval: u32,
+    rx_srtt: u32,
+    rx_rto: u32,
+    rx_minrto: u32,
+
+    snd_wnd: u16,
+    rcv_wnd: u16,
+    rmt_wnd: u16,
+    cwnd: u16,
+    /// KCP_ASK_SEND and KCP_ASK_TELL
+    probe: u32,
+
+    /// Time of the last update
+    current: u32,
+    interval: u32,
+    /// Time of the next flush
+    ts_flush: u32,
+    xmit: u32,
+
+    nodelay: bool,
+    updated: bool,
+
+    ts_probe: u32,
+    probe_wait: u32,
+
+    /// Transmissions of a segment after which the link is dead
+    dead_link: u32,
+    incr: usize,
+
+    snd_queue: VecDeque<Segment>,
+    /// Some queued segment has a deadline
+    snd_deadlines: bool,
+    /// The last segment moved to `snd_buf` was not the last fragment of
+    /// its message, the rest must follow
+    snd_mid_message: bool,
+    /// Messages dropped unsent because their deadline passed
+    expired: u32,
+    /// Payload bytes acknowledged by the peer
+    delivered: u64,
+    /// New data stays queued, see [`Connection::set_corked`]
+    corked: bool,
+    rcv_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+
+    /// Pending ACKs, sn and ts
+    acklist: VecDeque<(u32, u32)>,
+    /// How long ACKs may wait for a later flush, see
+    /// [`Connection::set_ack_delay`]
+    ack_delay: Option<u32>,
+    /// Time the oldest pending ACK was queued
+    ack_since: u32,
+    /// A segment arrived out of order, its ACK must not wait
+    ack_now: bool,
+    /// Datagram being assembled by a flush
+    buf: Vec<u8>,
+    /// Datagrams waiting for `poll_transmit`
+    output: VecDeque<Vec<u8>>,
+
+    fastresend: u32,
+    fastlimit: u32,
+    nocwnd: bool,
+    stream: bool,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("conv", &self.conv)
+            .field("mtu", &self.mtu)
+            .field("snd_una", &self.snd_una)
+            .field("snd_nxt", &self.snd_nxt)
+            .field("rcv_nxt", &self.rcv_nxt)
+            .field("rx_rto", &self.rx_rto)
+            .field("cwnd", &self.cwnd)
+            .field("snd_queue.len", &self.snd_queue.len())
+            .field("snd_buf.len", &self.snd_buf.len())
+            .field("rcv_queue.len", &self.rcv_queue.len())
+            .field("rcv_buf.len", &self.rcv_buf.len())
+            .field("output.len", &self.output.len())
+            .field("stream", &self.stream)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Connection {
+    /// Conversation in message mode, `conv` must be equal on both ends
+    pub fn new(conv: u32) -> Self {
+        Self::construct(conv, false)
+    }
+
+    /// Conversation in stream mode, sends are merged into full segments
+    pub fn new_stream(conv: u32) -> Self {
+        Self::construct(conv, true)
+    }
+
+    fn construct(conv: u32, stream: bool) -> Self {
+        Self {
+            conv,
+            mtu: KCP_MTU_DEF,
+            mss: KCP_MTU_DEF - KCP_OVERHEAD,
+            state: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ssthresh: KCP_THRESH_INIT,
+            rx_rttval: 0,
+            rx_srtt: 0,
+            rx_rto: KCP_RTO_DEF,
+            rx_minrto: KCP_RTO_MIN,
+            snd_wnd: KCP_WND_SND,
+            rcv_wnd: KCP_WND_RCV,
+            rmt_wnd: KCP_WND_RCV,
+            cwnd: 0,
+            probe: 0,
+            current: 0,
+            interval: KCP_INTERVAL,
+            ts_flush: KCP_INTERVAL,
+            xmit: 0,
+            nodelay: false,
+            updated: false,
+            ts_probe: 0,
+            probe_wait: 0,
+            dead_link: KCP_DEADLINK,
+            incr: 0,
+            snd_queue: VecDeque::new(),
+            snd_deadlines: false,
+            snd_mid_message: false,
+            expired: 0,
+            delivered: 0,
+            corked: false,
+            rcv_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            acklist: VecDeque::new(),
+            ack_delay: None,
+            ack_since: 0,
+            ack_now: false,
+            buf: Vec::with_capacity((KCP_MTU_DEF + KCP_OVERHEAD) * 3),
+            output: VecDeque::new(),
+            fastresend: 0,
+            fastlimit: KCP_FASTACK_LIMIT,
+            nocwnd: false,
+            stream,
+        }
+    }
+
+    /// Process a datagram received at `now`
+    ///
+    /// Returns the number of bytes consumed. ACKs and window updates it
+    /// causes go out with the next flush, or right away with an ACK delay
+    /// of 0.
+    pub fn handle_input(&mut self, datagram: &[u8], now: u32) -> KcpResult<usize> {
+        self.check_input(datagram)?;
+        self.current = now;
+        let consumed = self.input(datagram)?;
+        if self.ack_delay == Some(0) && self.updated && !self.acklist.is_empty() {
+            self.flush_ack()?;
+        }
+        Ok(consumed)
+    }
+
+    /// Check every segment header of a datagram before any is applied
+    ///
+    /// A datagram is applied whole or not at all. Rejects unknown commands,
+    /// lengths past the end of the datagram and messages of more fragments
+    /// than the receive window holds, which could never be delivered and
+    /// would block everything behind them. Trailing bytes shorter than a
+    /// header are ignored like ikcp does.
+    fn check_input(&self, buf: &[u8]) -> KcpResult<()> {
+        if buf.len() < KCP_OVERHEAD {
+            return Err(Error::InvalidSegmentSize(buf.len()));
+        }
+
+        let mut pos = 0;
+        while buf.len() - pos >= KCP_OVERHEAD {
+            let header = &buf[pos..pos + KCP_OVERHEAD];
+            let u32_at = |at: usize| {
+                u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+            };
+            let conv = u32_at(0);
+            if conv != self.conv {
+                return Err(Error::ConvInconsistent(self.conv, conv));
+            }
+            let cmd = header[4];
+            let frg = header[5];
+            let len = u32_at(20) as usize;
+            pos += KCP_OVERHEAD;
+
+            let remaining = buf.len() - pos;
+            if remaining < len {
+                return Err(Error::InvalidSegmentDataSize(len, remaining));
+            }
+            match cmd {
+                KCP_CMD_PUSH if frg as u16 >= self.rcv_wnd => {
+                    return Err(Error::InvalidSegment("more fragments than the receive window"));
+                }
+                KCP_CMD_PUSH | KCP_CMD_ACK | KCP_CMD_WASK | KCP_CMD_WINS => {}
+                _ => return Err(Error::UnsupportedCmd(cmd)),
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Next datagram to put on the wire
+    ///
+    /// Flushes when the interval elapsed at `now`, call it until it returns
+    /// `None`.
+    pub fn poll_transmit(&mut self, now: u32) -> Option<Vec<u8>> {
+        if self.output.is_empty() {
+            self.update(now);
+        }
+        self.output.pop_front()
+    }
+
+    /// Milliseconds from `now` until [`Connection::poll_transmit`] has
+    /// something to do, if nothing is sent or received meanwhile
+    pub fn poll_timeout(&self, now: u32) -> u32 {
+        if !self.output.is_empty() {
+            return 0;
+        }
+        self.check(now)
+    }
+
+    /// Take every datagram queued by flushes so far
+    pub fn drain_transmit(&mut self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.output.drain(..)
+    }
+
+    /// Conversation id
+    pub fn conv(&self) -> u32 {
+        self.conv
+    }
+
+    // move available data from rcv_buf -> rcv_queue
+    fn move_buf(&mut self) {
+        while let Some(seg) = self.rcv_buf.front() {
+            if seg.sn == self.rcv_nxt && self.rcv_queue.len() < self.rcv_wnd as usize {
+                self.rcv_nxt = serial::add(self.rcv_nxt, 1);
+            } else {
+                break;
+            }
+            let seg = self.rcv_buf.pop_front().unwrap();
+            self.rcv_queue.push_back(seg);
+        }
+    }
+
+    /// Take the next message, or in stream mode the next segment
+    pub fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        if self.rcv_queue.is_empty() {
+            return Err(Error::RecvQueueEmpty);
+        }
+
+        let peeksize = self.peeksize()?;
+        if peeksize > buf.len() {
+            return Err(Error::UserBufTooSmall);
+        }
+
+        let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
+
+        // Merge fragments
+        let mut len = 0;
+        while let Some(seg) = self.rcv_queue.pop_front() {
+            buf[len..len + seg.data.len()].copy_from_slice(&seg.data);
+            len += seg.data.len();
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        debug_assert_eq!(len, peeksize);
+
+        self.move_buf();
+
+        // fast recover
+        if self.rcv_queue.len() < self.rcv_wnd as usize && recover {
+            // ready to send back KCP_CMD_WINS in flush
+            // tell remote my window size
+            self.probe |= KCP_ASK_TELL;
+        }
+
+        Ok(len)
+    }
+
+    /// Size of the next message without taking it
+    pub fn peeksize(&self) -> KcpResult<usize> {
+        let segment = self.rcv_queue.front().ok_or(Error::RecvQueueEmpty)?;
+        if segment.frg == 0 {
+            return Ok(segment.data.len());
+        }
+        if self.rcv_queue.len() < segment.frg as usize + 1 {
+            return Err(Error::ExpectingFragment);
+        }
+
+        let mut len = 0;
+        for segment in &self.rcv_queue {
+            len += segment.data.len();
+            if segment.frg == 0 {
+                break;
+            }
+        }
+        Ok(len)
+    }
+
+    /// Largest buffer a single [`Connection::send`] accepts
+    pub fn max_send(&self) -> usize {
+        (KCP_WND_RCV as usize - 1) * self.mss
+    }
+
+    /// Queue data for sending
+    pub fn send(&mut self, mut buf: &[u8]) -> KcpResult<usize> {
+        let mut sent_size = 0;
+
+        // append to previous segment in streaming mode (if possible)
+        if self.stream {
+            if let Some(old) = self.snd_queue.back_mut() {
+                let l = old.data.len();
+                if l < self.mss {
+                    let extend = cmp::min(buf.len(), self.mss - l);
+                    let (lf, rt) = buf.split_at(extend);
+                    old.data.extend_from_slice(lf);
+                    buf = rt;
+                    old.frg = 0;
+                    sent_size += extend;
+                }
+            }
+            if buf.is_empty() {
+                return Ok(sent_size);
+            }
+        }
+
+        let count = if buf.len() <= self.mss {
+            1
+        } else {
+            buf.len().div_ceil(self.mss)
+        };
+        if count >= KCP_WND_RCV as usize {
+            return Err(Error::UserBufTooBig);
+        }
+
+        for i in 0..count {
+            let size = cmp::min(self.mss, buf.len());
+            let (lf, rt) = buf.split_at(size);
+            let mut segment = Segment::with_data(lf.to_vec());
+            buf = rt;
+            segment.frg = if self.stream { 0 } else { (count - i - 1) as u8 };
+            self.snd_queue.push_back(segment);
+            sent_size += size;
+        }
+
+        Ok(sent_size)
+    }
+
+    /// Queue a message that is dropped instead of sent if it is still
+    /// queued when the clock reaches `deadline`
+    ///
+    /// Only messages no part of which went out yet are dropped, the
+    /// receiver never sees a partial message. In stream mode data merged
+    /// into a segment queued earlier keeps that segment's deadline.
+    /// Deadlines are not part of [`Connection::export_state`].
+    pub fn send_with_deadline(&mut self, buf: &[u8], deadline: u32) -> KcpResult<usize> {
+        self.send_message(buf, 0, Some(deadline))
+    }
+
+    /// Queue a message ahead of the queued messages of lower priority,
+    /// with an optional deadline as in [`Connection::send_with_deadline`]
+    ///
+    /// Higher values go first, messages of equal priority keep their
+    /// order and [`Connection::send`] queues at priority 0. Only messages
+    /// not sent yet are overtaken, segments handed to the send buffer keep
+    /// their sequence numbers. Stream mode ignores the priority, reordering
+    /// would scramble the byte stream.
+    pub fn send_message(
+        &mut self,
+        buf: &[u8],
+        priority: u8,
+        deadline: Option<u32>,
+    ) -> KcpResult<usize> {
+        let queued = self.snd_queue.len();
+        let sent = self.send(buf)?;
+        let mut message = self.snd_queue.split_off(queued);
+        for segment in &mut message {
+            segment.expire = deadline;
+            segment.priority = priority;
+        }
+        self.snd_deadlines |= deadline.is_some() && !message.is_empty();
+
+        let at = if self.stream || priority == 0 {
+            self.snd_queue.len()
+        } else {
+            self.queue_position(priority)
+        };
+        let rest = self.snd_queue.split_off(at);
+        self.snd_queue.extend(message);
+        self.snd_queue.extend(rest);
+        Ok(sent)
+    }
+
+    /// Index in the send queue of the first message of lower priority
+    /// than `priority`
+    fn queue_position(&self, priority: u8) -> usize {
+        let mut rest_of_message = self.snd_mid_message;
+        for (i, segment) in self.snd_queue.iter().enumerate() {
+            // All fragments of a message share its priority, so the first
+            // lower one starts a message
+            if !rest_of_message && segment.priority < priority {
+                return i;
+            }
+            if segment.frg == 0 {
+                rest_of_message = false;
+            }
+        }
+        self.snd_queue.len()
+    }
+
+    /// Drop queued messages whose deadline passed
+    fn drop_expired(&mut self) {
+        if !self.snd_deadlines {
+            return;
+        }
+        let current = self.current;
+        let mut rest_of_message = self.snd_mid_message;
+        let mut expired = 0;
+        let mut deadlines = false;
+        self.snd_queue.retain(|segment| {
+            let stale = segment.expire.is_some_and(|at| serial::diff(current, at) >= 0);
+            let keep = rest_of_message || !stale;
+            if segment.frg == 0 {
+                rest_of_message = false;
+                expired += !keep as u32;
+            }
+            deadlines |= keep && segment.expire.is_some();
+            keep
+        });
+        self.expired = self.expired.wrapping_add(expired);
+        self.snd_deadlines = deadlines;
+    }
+
+    fn update_ack(&mut self, rtt: u32) {
+        // A forged or ancient timestamp must not overflow the filter
+        let rtt = rtt.min(KCP_RTO_MAX);
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt;
+            self.rx_rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = (7 * self.rx_srtt + rtt) / 8;
+            if self.rx_srtt < 1 {
+                self.rx_srtt = 1;
+            }
+        }
+        let rto = self.rx_srtt + cmp::max(self.interval, 4 * self.rx_rttval);
+        self.rx_rto = bound(self.rx_minrto, rto, KCP_RTO_MAX);
+    }
+
+    fn shrink_buf(&mut self) {
+        self.snd_una = match self.snd_buf.front() {
+            Some(seg) => seg.sn,
+            None => self.snd_nxt,
+        };
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if serial::before(sn, self.snd_una) || serial::diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+
+        for i in 0..self.snd_buf.len() {
+            match serial::cmp(sn, self.snd_buf[i].sn) {
+                cmp::Ordering::Equal => {
+                    if let Some(seg) = self.snd_buf.remove(i) {
+                        self.delivered += seg.data.len() as u64;
+                    }
+                    break;
+                }
+                cmp::Ordering::Less => break,
+                cmp::Ordering::Greater => {}
+            }
+        }
+    }
+
+    fn parse_una(&mut self, una: u32) {
+        while let Some(seg) = self.snd_buf.front() {
+            if serial::after(una, seg.sn) {
+                self.delivered += seg.data.len() as u64;
+                self.snd_buf.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_fastack(&mut self, sn: u32, ts: u32) {
+        if serial::before(sn, self.snd_una) || serial::diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+
+        for seg in &mut self.snd_buf {
+            if serial::before(sn, seg.sn) {
+                break;
+            } else if sn != seg.sn && serial::diff(ts, seg.ts) >= 0 {
+                seg.fastack += 1;
+            }
+        }
+    }
+
+    fn parse_data(&mut self, segment: Segment) {
+        let sn = segment.sn;
+        let rcv_end = serial::add(self.rcv_nxt, self.rcv_wnd as u32);
+        if serial::diff(sn, rcv_end) >= 0 || serial::before(sn, self.rcv_nxt) {
+            return;
+        }
+
+        let mut repeat = false;
+        let mut index = self.rcv_buf.len();
+        for seg in self.rcv_buf.iter().rev() {
+            if seg.sn == sn {
+                repeat = true;
+                break;
+            }
+            if serial::after(sn, seg.sn) {
+                break;
+            }
+            index -= 1;
+        }
+
+        if !repeat {
+            self.rcv_buf.insert(index, segment);
+        }
+
+        // move available data from rcv_buf -> rcv_queue
+        self.move_buf();
+    }
+
+    fn input(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        if buf.len() < KCP_OVERHEAD {
+            return Err(Error::InvalidSegmentSize(buf.len()));
+        }
+
+        let mut flag = false;
+        let mut max_ack = 0;
+        let old_una = self.snd_una;
+        let mut latest_ts = 0;
+
+        let mut pos = 0;
+        while buf.len() - pos >= KCP_OVERHEAD {
+            let header = &buf[pos..pos + KCP_OVERHEAD];
+            let u32_at = |at: usize| u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]);
+            let conv = u32_at(0);
+            if conv != self.conv {
+                return Err(Error::ConvInconsistent(self.conv, conv));
+            }
+            let cmd = header[4];
+            let frg = header[5];
+            let wnd = u16::from_le_bytes([header[6], header[7]]);
+            let ts = u32_at(8);
+            let sn = u32_at(12);
+            let una = u32_at(16);
+            let len = u32_at(20) as usize;
+            pos += KCP_OVERHEAD;
+
+            let remaining = buf.len() - pos;
+            if remaining < len {
+                return Err(Error::InvalidSegmentDataSize(len, remaining));
+            }
+
+            match cmd {
+                KCP_CMD_PUSH | KCP_CMD_ACK | KCP_CMD_WASK | KCP_CMD_WINS => {}
+                _ => return Err(Error::UnsupportedCmd(cmd)),
+            }
+
+            self.rmt_wnd = wnd;
+            self.parse_una(una);
+            self.shrink_buf();
+
+            match cmd {
+                KCP_CMD_ACK => {
+                    let rtt = serial::diff(self.current, ts);
+                    if rtt >= 0 {
+                        self.update_ack(rtt as u32);
+                    }
+                    self.parse_ack(sn);
+                    self.shrink_buf();
+
+                    if !flag {
+                        flag = true;
+                        max_ack = sn;
+                        latest_ts = ts;
+                    } else if serial::after(sn, max_ack) && serial::after(ts, latest_ts) {
+                        max_ack = sn;
+                        latest_ts = ts;
+                    }
+                }
+                KCP_CMD_PUSH => {
+                    let rcv_end = serial::add(self.rcv_nxt, self.rcv_wnd as u32);
+                    if serial::before(sn, rcv_end) {
+                        if self.acklist.is_empty() {
+                            self.ack_since = self.current;
+                        }
+                        if serial::after(sn, self.rcv_nxt) {
+                            self.ack_now = true;
+                        }
+                        self.acklist.push_back((sn, ts));
+                        if serial::diff(sn, self.rcv_nxt) >= 0 {
+                            let segment = Segment {
+                                conv,
+                                cmd,
+                                frg,
+                                wnd,
+                                ts,
+                                sn,
+                                una,
+                                ..Segment::with_data(buf[pos..pos + len].to_vec())
+                            };
+                            self.parse_data(segment);
+                        }
+                    }
+                }
+                KCP_CMD_WASK => {
+                    // ready to send back KCP_CMD_WINS in flush
+                    // tell remote my window size
+                    self.probe |= KCP_ASK_TELL;
+                }
+                _ => {}
+            }
+
+            pos += len;
+        }
+
+        if flag {
+            self.parse_fastack(max_ack, latest_ts);
+        }
+
+        if serial::after(self.snd_una, old_una) && self.cwnd < self.rmt_wnd {
+            let mss = self.mss;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr += mss;
+            } else {
+                if self.incr < mss {
+                    self.incr = mss;
+                }
+                self.incr += (mss * mss) / self.incr + (mss / 16);
+                if (self.cwnd as usize + 1) * mss <= self.incr {
+                    self.cwnd = self.incr.div_ceil(mss.max(1)) as u16;
+                }
+            }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd as usize * mss;
+            }
+        }
+
+        Ok(pos)
+    }
+
+    fn wnd_unused(&self) -> u16 {
+        if self.rcv_queue.len() < self.rcv_wnd as usize {
+            self.rcv_wnd - self.rcv_queue.len() as u16
+        } else {
+            0
+        }
+    }
+
+    /// Queue the datagram assembled so far
+    fn output_buf(&mut self) {
+        self.output.push_back(self.buf.to_vec());
+        self.buf.clear();
+    }
+
+    /// Append a segment, starting a new datagram if it would not fit
+    fn push_segment(&mut self, segment: &Segment) {
+        if self.buf.len() + KCP_OVERHEAD + segment.data.len() > self.mtu {
+            self.output_buf();
+        }
+        segment.encode(&mut self.buf);
+    }
+
+    fn flush_acks(&mut self, segment: &mut Segment) {
+        self.ack_now = false;
+        let acklist = mem::take(&mut self.acklist);
+        for (sn, ts) in acklist {
+            segment.sn = sn;
+            segment.ts = ts;
+            self.push_segment(segment);
+        }
+    }
+
+    /// Whether pending ACKs may wait for a later flush: the ACK delay did
+    /// not pass, no segment arrived out of order and they fit a datagram
+    fn hold_acks(&self) -> bool {
+        match self.ack_delay {
+            Some(delay) if delay > 0 && !self.ack_now => {
+                serial::diff(self.current, self.ack_since) < delay as i32
+                    && (self.acklist.len() + 1) * KCP_OVERHEAD <= self.mtu
+            }
+            _ => false,
+        }
+    }
+
+    fn probe_wnd_size(&mut self) {
+        // probe window size (if remote window size equals zero)
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = KCP_PROBE_INIT;
+                self.ts_probe = serial::add(self.current, self.probe_wait);
+            } else if serial::diff(self.current, self.ts_probe) >= 0 {
+                if self.probe_wait < KCP_PROBE_INIT {
+                    self.probe_wait = KCP_PROBE_INIT;
+                }
+                self.probe_wait += self.probe_wait / 2;
+                if self.probe_wait > KCP_PROBE_LIMIT {
+                    self.probe_wait = KCP_PROBE_LIMIT;
+                }
+                self.ts_probe = serial::add(self.current, self.probe_wait);
+                self.probe |= KCP_ASK_SEND;
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+    }
+
+    fn flush_probe_commands(&mut self, segment: &mut Segment) {
+        for (ask, cmd) in [(KCP_ASK_SEND, KCP_CMD_WASK), (KCP_ASK_TELL, KCP_CMD_WINS)] {
+            if self.probe & ask != 0 {
+                segment.cmd = cmd;
+                self.push_segment(segment);
+            }
+        }
+        self.probe = 0;
+    }
+
+    /// Send pending ACKs only
+    pub fn flush_ack(&mut self) -> KcpResult<()> {
+        if !self.updated {
+            return Err(Error::NeedUpdate);
+        }
+        let mut segment = self.ack_template();
+        self.flush_acks(&mut segment);
+        if !self.buf.is_empty() {
+            self.output_buf();
+        }
+        Ok(())
+    }
+
+    fn ack_template(&self) -> Segment {
+        Segment {
+            conv: self.conv,
+            cmd: KCP_CMD_ACK,
+            wnd: self.wnd_unused(),
+            una: self.rcv_nxt,
+            ..Default::default()
+        }
+    }
+
+    /// Send ACKs, probes, new data and due retransmissions now
+    pub fn flush(&mut self) -> KcpResult<()> {
+        if !self.updated {
+            return Err(Error::NeedUpdate);
+        }
+
+        let queued = self.output.len();
+        let mut segment = self.ack_template();
+        let hold_acks = self.hold_acks();
+        if !hold_acks {
+            self.flush_acks(&mut segment);
+        }
+        self.probe_wnd_size();
+        self.flush_probe_commands(&mut segment);
+
+        // calculate window size
+        let mut cwnd = cmp::min(self.snd_wnd, self.rmt_wnd);
+        if !self.nocwnd {
+            cwnd = cmp::min(self.cwnd, cwnd);
+        }
+
+        // move data from snd_queue to snd_buf
+        self.drop_expired();
+        let window = cmp::min(self.snd_wnd, self.rmt_wnd) as usize;
+        let held = self.corked && self.wait_snd() < window;
+        while !held && serial::before(self.snd_nxt, serial::add(self.snd_una, cwnd as u32)) {
+            let Some(mut new_segment) = self.snd_queue.pop_front() else {
+                break;
+            };
+            self.snd_mid_message = new_segment.frg != 0;
+            new_segment.conv = self.conv;
+            new_segment.cmd = KCP_CMD_PUSH;
+            new_segment.wnd = segment.wnd;
+            new_segment.ts = self.current;
+            new_segment.sn = self.snd_nxt;
+            self.snd_nxt = serial::add(self.snd_nxt, 1);
+            new_segment.una = self.rcv_nxt;
+            new_segment.resendts = self.current;
+            new_segment.rto = self.rx_rto;
+            new_segment.fastack = 0;
+            new_segment.xmit = 0;
+            self.snd_buf.push_back(new_segment);
+        }
+
+        // calculate resent
+        let resent = if self.fastresend > 0 {
+            self.fastresend
+        } else {
+            u32::MAX
+        };
+        let rtomin = if !self.nodelay { self.rx_rto >> 3 } else { 0 };
+
+        let mut lost = false;
+        let mut change = 0;
+
+        let mut snd_buf = mem::take(&mut self.snd_buf);
+        for snd_segment in &mut snd_buf {
+            let mut need_send = false;
+
+            if snd_segment.xmit == 0 {
+                need_send = true;
+                snd_segment.xmit += 1;
+                snd_segment.rto = self.rx_rto;
+                snd_segment.resendts = serial::add(self.current, snd_segment.rto + rtomin);
+            } else if serial::diff(self.current, snd_segment.resendts) >= 0 {
+                need_send = true;
+                snd_segment.xmit += 1;
+                self.xmit = self.xmit.wrapping_add(1);
+                if !self.nodelay {
+                    snd_segment.rto += cmp::max(snd_segment.rto, self.rx_rto);
+                } else {
+                    snd_segment.rto += snd_segment.rto / 2;
+                }
+                snd_segment.resendts = serial::add(self.current, snd_segment.rto);
+                lost = true;
+            } else if snd_segment.fastack >= resent
+                && (snd_segment.xmit <= self.fastlimit || self.fastlimit == 0)
+            {
+                need_send = true;
+                snd_segment.xmit += 1;
+                snd_segment.fastack = 0;
+                snd_segment.resendts = serial::add(self.current, snd_segment.rto);
+                change += 1;
+            }
+
+            if need_send {
+                snd_segment.ts = self.current;
+                snd_segment.wnd = segment.wnd;
+                snd_segment.una = self.rcv_nxt;
+                self.push_segment(snd_segment);
+
+                if snd_segment.xmit >= self.dead_link {
+                    self.state = -1;
+                }
+            }
+        }
+        self.snd_buf = snd_buf;
+
+        // Held ACKs ride along when something is sent anyway
+        if hold_acks && (!self.buf.is_empty() || self.output.len() > queued) {
+            segment.cmd = KCP_CMD_ACK;
+            self.flush_acks(&mut segment);
+        }
+
+        // Flush all data in buffer
+        if !self.buf.is_empty() {
+            self.output_buf();
+        }
+
+        // update ssthresh
+        if change > 0 {
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.ssthresh = cmp::max(inflight as u16 / 2, KCP_THRESH_MIN);
+            self.cwnd = self.ssthresh + resent as u16;
+            self.incr = self.cwnd as usize * self.mss;
+        }
+
+        if lost {
+            self.ssthresh = cmp::max(cwnd / 2, KCP_THRESH_MIN);
+            self.cwnd = 1;
+            self.incr = self.mss;
+        }
+
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = self.mss;
+        }
+
+        Ok(())
+    }
+
+    /// Advance the clock to `now` and flush when the interval elapsed
+    pub fn update(&mut self, now: u32) {
+        self.current = now;
+
+        if !self.updated {
+            self.updated = true;
+            self.ts_flush = self.current;
+        }
+
+        let mut slap = serial::diff(self.current, self.ts_flush);
+        if !(-10000..10000).contains(&slap) {
+            self.ts_flush = self.current;
+            slap = 0;
+        }
+
+        if slap >= 0 {
+            self.ts_flush = serial::add(self.ts_flush, self.interval);
+            if serial::diff(self.current, self.ts_flush) >= 0 {
+                self.ts_flush = serial::add(self.current, self.interval);
+            }
+            // Cannot fail, updated is set
+            let _ = self.flush();
+        }
+    }
+
+    /// Milliseconds from `now` until the next [`Connection::update`] is
+    /// due, at most the interval
+    pub fn check(&self, now: u32) -> u32 {
+        if !self.updated {
+            return 0;
+        }
+
+        let mut ts_flush = self.ts_flush;
+        if !(-10000..10000).contains(&serial::diff(now, ts_flush)) {
+            ts_flush = now;
+        }
+        if serial::diff(now, ts_flush) >= 0 {
+            return 0;
+        }
+
+        let tm_flush = serial::diff(ts_flush, now) as u32;
+        let mut tm_packet = u32::MAX;
+        for seg in &self.snd_buf {
+            let diff = serial::diff(seg.resendts, now);
+            if diff <= 0 {
+                return 0;
+            }
+            tm_packet = cmp::min(tm_packet, diff as u32);
+        }
+
+        cmp::min(cmp::min(tm_packet, tm_flush), self.interval)
+    }
+
+    /// Change the MTU, 1400 by default
+    pub fn set_mtu(&mut self, mtu: usize) -> KcpResult<()> {
+        if mtu < 50 {
+            return Err(Error::InvalidMtu(mtu));
+        }
+        self.mtu = mtu;
+        self.mss = mtu - KCP_OVERHEAD;
+        self.buf.reserve((mtu + KCP_OVERHEAD) * 3);
+        Ok(())
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Maximum segment size, the MTU minus the header
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
+
+    /// Clock value of the last [`Connection::update`], in ms
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// Flush interval in ms
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Set the flush interval, clamped to 10..=5000 ms
+    pub fn set_interval(&mut self, interval: u32) {
+        self.interval = interval.clamp(10, 5000);
+    }
+
+    /// Hold ACKs for up to `delay` ms so several go out in one datagram
+    ///
+    /// `Some(0)` sends them right after the input that caused them, `None`
+    /// (the default) with the next flush. Held ACKs still go out with any
+    /// data sent before, and at once when a segment arrives out of order.
+    /// The delay is rounded up to the flush interval.
+    pub fn set_ack_delay(&mut self, delay: Option<u32>) {
+        self.ack_delay = delay;
+    }
+
+    pub fn ack_delay(&self) -> Option<u32> {
+        self.ack_delay
+    }
+
+    /// Keep new data queued instead of sending it, so a message written
+    /// in several parts leaves in full segments
+    ///
+    /// ACKs, probes and retransmissions still go out. Data held back is
+    /// sent once uncorked, or when a full window is waiting.
+    pub fn set_corked(&mut self, corked: bool) {
+        self.corked = corked;
+    }
+
+    pub fn is_corked(&self) -> bool {
+        self.corked
+    }
+
+    /// Set nodelay
+    ///
+    /// `nodelay` lowers the minimum RTO and slows its backoff, `interval`
+    /// is the flush interval in ms, `resend` the number of duplicate ACKs
+    /// that trigger a fast retransmission (0 disables it), `nc` disables
+    /// congestion control. Fastest: `set_nodelay(true, 10, 2, true)`.
+    pub fn set_nodelay(&mut self, nodelay: bool, interval: i32, resend: i32, nc: bool) {
+        self.nodelay = nodelay;
+        self.rx_minrto = if nodelay { KCP_RTO_NDL } else { KCP_RTO_MIN };
+        self.interval = interval.clamp(10, 5000) as u32;
+        if resend >= 0 {
+            self.fastresend = resend as u32;
+        }
+        self.nocwnd = nc;
+    }
+
+    /// Set the send and receive windows in segments, 0 keeps the current
+    /// value; the receive window is at least 128
+    pub fn set_wndsize(&mut self, sndwnd: u16, rcvwnd: u16) {
+        if sndwnd > 0 {
+            self.snd_wnd = sndwnd;
+        }
+        if rcvwnd > 0 {
+            self.rcv_wnd = cmp::max(rcvwnd, KCP_WND_RCV);
+        }
+    }
+
+    pub fn snd_wnd(&self) -> u16 {
+        self.snd_wnd
+    }
+
+    pub fn rcv_wnd(&self) -> u16 {
+        self.rcv_wnd
+    }
+
+    /// Receive window last advertised by the peer
+    pub fn rmt_wnd(&self) -> u16 {
+        self.rmt_wnd
+    }
+
+    /// Segments queued or in flight
+    pub fn wait_snd(&self) -> usize {
+        self.snd_buf.len() + self.snd_queue.len()
+    }
+
+    /// Segments in flight or queued that a message of `priority` would
+    /// not overtake
+    pub fn wait_snd_before(&self, priority: u8) -> usize {
+        if priority == 0 || self.stream {
+            return self.wait_snd();
+        }
+        let overtaken = self.snd_queue.len() - self.queue_position(priority);
+        self.wait_snd() - overtaken
+    }
+
+    /// Payload bytes queued and not sent yet
+    pub fn unsent_bytes(&self) -> usize {
+        self.snd_queue.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    /// Approximate heap memory held by the send queue and buffer and by
+    /// output datagrams not yet taken
+    pub fn snd_bytes(&self) -> usize {
+        let output = self.output.iter().map(Vec::capacity).sum::<usize>();
+        segment_bytes(&self.snd_queue) + segment_bytes(&self.snd_buf) + output
+    }
+
+    /// Approximate heap memory held by the receive queue and buffer and by
+    /// pending ACKs
+    pub fn rcv_bytes(&self) -> usize {
+        segment_bytes(&self.rcv_queue)
+            + segment_bytes(&self.rcv_buf)
+            + self.acklist.len() * mem::size_of::<(u32, u32)>()
+    }
+
+    pub fn set_rx_minrto(&mut self, rto: u32) {
+        self.rx_minrto = rto;
+    }
+
+    pub fn set_fast_resend(&mut self, resend: u32) {
+        self.fastresend = resend;
+    }
+
+    /// Transmissions of one segment after which the link counts as dead
+    pub fn set_maximum_resend_times(&mut self, dead_link: u32) {
+        self.dead_link = dead_link;
+    }
+
+    /// A segment was sent the maximum number of times without an ACK
+    pub fn is_dead_link(&self) -> bool {
+        self.state != 0
+    }
+
+    /// Ask the peer for its window size with the next flush
+    ///
+    /// The peer answers with its window even when it has nothing to send,
+    /// which makes a cheap keepalive any KCP implementation understands.
+    pub fn probe_window(&mut self) {
+        self.probe |= KCP_ASK_SEND;
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.stream
+    }
+
+    /// Congestion and queue state
+    pub fn internals(&self) -> Internals {
+        Internals {
+            rx_srtt: self.rx_srtt,
+            rx_rttval: self.rx_rttval,
+            rx_rto: self.rx_rto,
+            cwnd: self.cwnd,
+            ssthresh: self.ssthresh,
+            xmit: self.xmit,
+            expired: self.expired,
+            delivered: self.delivered,
+            nocwnd: self.nocwnd,
+            snd_queue_len: self.snd_queue.len(),
+            snd_buf_len: self.snd_buf.len(),
+            rcv_queue_len: self.rcv_queue.len(),
+            rcv_buf_len: self.rcv_buf.len(),
+        }
+    }
+}
+
+/// Leading bytes of an exported state, the last one is the format version
+const STATE_MAGIC: &[u8; 5] = b"KCPS\x01";
+
+impl Connection {
+    /// Serialize the whole conversation: sequence numbers, windows, RTT
+    /// and congestion state and every queued, in-flight and received
+    /// segment with its data
+    ///
+    /// Datagrams not yet taken with `poll_transmit` are left out, KCP
+    /// sends them again. Restore with [`Connection::import_state`] and
+    /// keep feeding the same clock.
+    pub fn export_state(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128 + self.snd_bytes() + self.rcv_bytes());
+        buf.extend_from_slice(STATE_MAGIC);
+        for v in [self.conv, self.mtu as u32, self.state as u32] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in [self.snd_una, self.snd_nxt, self.rcv_nxt] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in [self.rx_rttval, self.rx_srtt, self.rx_rto, self.rx_minrto] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in [self.ssthresh, self.snd_wnd, self.rcv_wnd, self.rmt_wnd, self.cwnd] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in [
+            self.probe,
+            self.current,
+            self.interval,
+            self.ts_flush,
+            self.xmit,
+            self.ts_probe,
+            self.probe_wait,
+            self.dead_link,
+            self.incr as u32,
+            self.fastresend,
+            self.fastlimit,
+        ] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&[
+            self.nodelay as u8,
+            self.updated as u8,
+            self.nocwnd as u8,
+            self.stream as u8,
+        ]);
+
+        buf.extend_from_slice(&(self.acklist.len() as u32).to_le_bytes());
+        for (sn, ts) in &self.acklist {
+            buf.extend_from_slice(&sn.to_le_bytes());
+            buf.extend_from_slice(&ts.to_le_bytes());
+        }
+        for queue in [&self.snd_queue, &self.snd_buf, &self.rcv_queue, &self.rcv_buf] {
+            buf.extend_from_slice(&(queue.len() as u32).to_le_bytes());
+            for segment in queue {
+                segment.export(&mut buf);
+            }
+        }
+        buf
+    }
+
+    /// Restore a conversation saved with [`Connection::export_state`]
+    pub fn import_state(state: &[u8]) -> KcpResult<Self> {
+        let mut r = StateReader(state);
+        if r.bytes(STATE_MAGIC.len())? != STATE_MAGIC {
+            return Err(Error::InvalidState);
+        }
+
+        let conv = r.u32()?;
+        let mtu = r.u32()? as usize;
+        let mut conn = Self::construct(conv, false);
+        conn.set_mtu(mtu).map_err(|_| Error::InvalidState)?;
+        conn.state = r.u32()? as i32;
+        conn.snd_una = r.u32()?;
+        conn.snd_nxt = r.u32()?;
+        conn.rcv_nxt = r.u32()?;
+        conn.rx_rttval = r.u32()?;
+        conn.rx_srtt = r.u32()?;
+        conn.rx_rto = r.u32()?;
+        conn.rx_minrto = r.u32()?;
+        conn.ssthresh = r.u16()?;
+        conn.snd_wnd = r.u16()?;
+        conn.rcv_wnd = r.u16()?;
+        conn.rmt_wnd = r.u16()?;
+        conn.cwnd = r.u16()?;
+        conn.probe = r.u32()?;
+        conn.current = r.u32()?;
+        conn.interval = r.u32()?;
+        conn.ts_flush = r.u32()?;
+        conn.xmit = r.u32()?;
+        conn.ts_probe = r.u32()?;
+        conn.probe_wait = r.u32()?;
+        conn.dead_link = r.u32()?;
+        conn.incr = r.u32()? as usize;
+        conn.fastresend = r.u32()?;
+        conn.fastlimit = r.u32()?;
+        conn.nodelay = r.u8()? != 0;
+        conn.updated = r.u8()? != 0;
+        conn.nocwnd = r.u8()? != 0;
+        conn.stream = r.u8()? != 0;
+
+        for _ in 0..r.count(8)? {
+            conn.acklist.push_back((r.u32()?, r.u32()?));
+        }
+        for queue in [
+            &mut conn.snd_queue,
+            &mut conn.snd_buf,
+            &mut conn.rcv_queue,
+            &mut conn.rcv_buf,
+        ] {
+            for _ in 0..r.count(SEGMENT_STATE_LEN)? {
+                queue.push_back(Segment::import(&mut r)?);
+            }
+        }
+        if !r.0.is_empty() {
+            return Err(Error::InvalidState);
+        }
+        Ok(conn)
+    }
+}
+
+/// Exported size of a segment without its data
+const SEGMENT_STATE_LEN: usize = 40;
+
+impl Segment {
+    fn export(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.conv.to_le_bytes());
+        buf.push(self.cmd);
+        buf.push(self.frg);
+        buf.extend_from_slice(&self.wnd.to_le_bytes());
+        for v in [
+            self.ts,
+            self.sn,
+            self.una,
+            self.resendts,
+            self.rto,
+            self.fastack,
+            self.xmit,
+            self.data.len() as u32,
+        ] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.data);
+    }
+
+    fn import(r: &mut StateReader<'_>) -> KcpResult<Self> {
+        let mut segment = Segment {
+            conv: r.u32()?,
+            cmd: r.u8()?,
+            frg: r.u8()?,
+            wnd: r.u16()?,
+            ts: r.u32()?,
+            sn: r.u32()?,
+            una: r.u32()?,
+            resendts: r.u32()?,
+            rto: r.u32()?,
+            fastack: r.u32()?,
+            xmit: r.u32()?,
+            expire: None,
+            priority: 0,
+            data: Vec::new(),
+        };
+        let len = r.u32()? as usize;
+        segment.data = r.bytes(len)?.to_vec();
+        Ok(segment)
+    }
+}
+
+/// Cursor over an exported state, every short read is `InvalidState`
+struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    fn bytes(&mut self, len: usize) -> KcpResult<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Error::InvalidState);
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> KcpResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> KcpResult<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> KcpResult<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Element count, checked against the bytes left so a corrupt count
+    /// cannot make the caller allocate
+    fn count(&mut self, min_len: usize) -> KcpResult<usize> {
+        let count = self.u32()? as usize;
+        if count.saturating_mul(min_len) > self.0.len() {
+            return Err(Error::InvalidState);
+        }
+        Ok(count)
+    }
+}
+
+fn segment_bytes(segments: &VecDeque<Segment>) -> usize {
+    segments
+        .iter()
+        .map(|segment| segment.data.capacity() + mem::size_of::<Segment>())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    const CONV: u32 = 0x4b43_5002;
+
+    /// Header fields and payload of every segment of a datagram
+    fn decode(datagram: &[u8]) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut rest = datagram;
+        while !rest.is_empty() {
+            let u32_at = |at: usize| u32::from_le_bytes(rest[at..at + 4].try_into().unwrap());
+            let len = u32_at(20) as usize;
+            segments.push(Segment {
+                conv: u32_at(0),
+                cmd: rest[4],
+                frg: rest[5],
+                wnd: u16::from_le_bytes([rest[6], rest[7]]),
+                ts: u32_at(8),
+                sn: u32_at(12),
+                una: u32_at(16),
+                ..Segment::with_data(rest[KCP_OVERHEAD..KCP_OVERHEAD + len].to_vec())
+            });
+            rest = &rest[KCP_OVERHEAD + len..];
+        }
+        segments
+    }
+
+    fn encode(segments: &[Segment]) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        for segment in segments {
+            segment.encode(&mut datagram);
+        }
+        datagram
+    }
+
+    fn push(sn: u32, ts: u32, data: &[u8]) -> Segment {
+        Segment {
+            conv: CONV,
+            cmd: KCP_CMD_PUSH,
+            wnd: KCP_WND_RCV,
+            ts,
+            sn,
+            ..Segment::with_data(data.to_vec())
+        }
+    }
+
+    fn ack(sn: u32, ts: u32, una: u32) -> Segment {
+        Segment {
+            conv: CONV,
+            cmd: KCP_CMD_ACK,
+            wnd: KCP_WND_RCV,
+            ts,
+            sn,
+            una,
+            ..Default::default()
+        }
+    }
+
+    /// Two ends of a conversation, both updated once at `now`
+    fn pair(now: u32) -> (Connection, Connection) {
+        let mut a = Connection::new(CONV);
+        let mut b = Connection::new(CONV);
+        a.update(now);
+        b.update(now);
+        (a, b)
+    }
+
+    /// A clock that wraps to 0 at `wrap` ms of the test's timeline
+    fn wrapping_at(wrap: u32) -> impl Fn(u32) -> u32 {
+        move |ms| ms.wrapping_sub(wrap)
+    }
+
+    /// Segments of every datagram `conn` queued
+    fn transmitted(conn: &mut Connection) -> Vec<Segment> {
+        conn.drain_transmit().flat_map(|datagram| decode(&datagram)).collect()
+    }
+
+    /// Input every datagram `from` queued into `to` at `now`, returns
+    /// their segments
+    fn deliver(from: &mut Connection, to: &mut Connection, now: u32) -> Vec<Segment> {
+        let datagrams: Vec<_> = from.drain_transmit().collect();
+        let mut segments = Vec::new();
+        for datagram in datagrams {
+            to.handle_input(&datagram, now).unwrap();
+            segments.extend(decode(&datagram));
+        }
+        segments
+    }
+
+    /// Every complete message `conn` received
+    fn received(conn: &mut Connection) -> Vec<Vec<u8>> {
+        let mut buf = vec![0; 64 * 1024];
+        let mut messages = Vec::new();
+        while let Ok(n) = conn.recv(&mut buf) {
+            messages.push(buf[..n].to_vec());
+        }
+        messages
+    }
+
+    #[test]
+    fn segment_header_is_little_endian() {
+        let segment = Segment {
+            conv: 0x0403_0201,
+            cmd: KCP_CMD_PUSH,
+            frg: 2,
+            wnd: 0x0605,
+            ts: 0x0a09_0807,
+            sn: 0x0e0d_0c0b,
+            una: 0x1211_1009,
+            ..Segment::with_data(vec![0xaa, 0xbb])
+        };
+        let datagram = encode(&[segment]);
+        assert_eq!(
+            datagram,
+            [
+                0x01, 0x02, 0x03, 0x04, 81, 2, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+                0x0d, 0x0e, 0x09, 0x10, 0x11, 0x12, 2, 0, 0, 0, 0xaa, 0xbb,
+            ]
+        );
+
+        let decoded = decode(&datagram);
+        assert_eq!(decoded.len(), 1);
+        let decoded = &decoded[0];
+        assert_eq!(
+            (decoded.conv, decoded.cmd, decoded.frg, decoded.wnd),
+            (0x0403_0201, KCP_CMD_PUSH, 2, 0x0605)
+        );
+        assert_eq!(
+            (decoded.ts, decoded.sn, decoded.una, decoded.data.as_slice()),
+            (0x0a09_0807, 0x0e0d_0c0b, 0x1211_1009, &[0xaa, 0xbb][..])
+        );
+    }
+
+    #[test]
+    fn input_reads_every_segment_of_a_datagram() {
+        let (_, mut receiver) = pair(0);
+        let datagram = encode(&[push(0, 100, b"one"), push(1, 110, b"two")]);
+        assert_eq!(receiver.handle_input(&datagram, 120), Ok(datagram.len()));
+
+        receiver.flush().unwrap();
+        let acks = transmitted(&mut receiver);
+        let fields: Vec<_> = acks.iter().map(|s| (s.cmd, s.sn, s.ts, s.una, s.wnd)).collect();
+        assert_eq!(
+            fields,
+            [(KCP_CMD_ACK, 0, 100, 2, 126), (KCP_CMD_ACK, 1, 110, 2, 126)]
+        );
+        assert_eq!(received(&mut receiver), [b"one".to_vec(), b"two".to_vec()]);
+    }
+
+    #[test]
+    fn malformed_datagrams_change_nothing() {
+        let (_, mut receiver) = pair(0);
+        let valid = encode(&[push(0, 0, b"data")]);
+
+        assert_eq!(
+            receiver.handle_input(&valid[..10], 0),
+            Err(Error::InvalidSegmentSize(10))
+        );
+        assert_eq!(
+            receiver.handle_input(&valid[..valid.len() - 1], 0),
+            Err(Error::InvalidSegmentDataSize(4, 3))
+        );
+        let mut foreign = valid.clone();
+        foreign[0] ^= 1;
+        assert_eq!(
+            receiver.handle_input(&foreign, 0),
+            Err(Error::ConvInconsistent(CONV, CONV ^ 1))
+        );
+        // The valid segment in front is not applied either
+        let mut unknown = valid.clone();
+        Segment { cmd: 99, ..push(1, 0, b"") }.encode(&mut unknown);
+        assert_eq!(receiver.handle_input(&unknown, 0), Err(Error::UnsupportedCmd(99)));
+
+        assert_eq!(receiver.internals().rcv_queue_len, 0);
+        receiver.flush().unwrap();
+        assert!(transmitted(&mut receiver).is_empty());
+    }
+
+    #[test]
+    fn rto_follows_the_measured_rtt() {
+        let (mut sender, mut receiver) = pair(1000);
+        sender.send(b"ping").unwrap();
+        sender.flush().unwrap();
+        deliver(&mut sender, &mut receiver, 1010);
+        receiver.flush().unwrap();
+        let acks = deliver(&mut receiver, &mut sender, 1080);
+        assert_eq!(acks.iter().map(|s| (s.cmd, s.ts)).collect::<Vec<_>>(), [(KCP_CMD_ACK, 1000)]);
+        let internals = sender.internals();
+        assert_eq!((internals.rx_srtt, internals.rx_rttval, internals.rx_rto), (80, 40, 240));
+
+        sender.send(b"pong").unwrap();
+        sender.flush().unwrap();
+        deliver(&mut sender, &mut receiver, 1090);
+        receiver.flush().unwrap();
+        deliver(&mut receiver, &mut sender, 1100);
+        let internals = sender.internals();
+        assert_eq!((internals.rx_srtt, internals.rx_rttval, internals.rx_rto), (72, 45, 252));
+    }
+
+    #[test]
+    fn rtt_is_measured_across_the_clock_wrap() {
+        let t = wrapping_at(1050);
+        let (mut sender, mut receiver) = pair(t(1000));
+        sender.send(b"ping").unwrap();
+        sender.flush().unwrap();
+        deliver(&mut sender, &mut receiver, t(1010));
+        receiver.flush().unwrap();
+        let acks = deliver(&mut receiver, &mut sender, t(1080));
+        assert_eq!(acks.iter().map(|s| (s.cmd, s.ts)).collect::<Vec<_>>(), [(KCP_CMD_ACK, t(1000))]);
+        let internals = sender.internals();
+        assert_eq!((internals.rx_srtt, internals.rx_rttval, internals.rx_rto), (80, 40, 240));
+        assert_eq!(sender.wait_snd(), 0);
+    }
+
+    #[test]
+    fn rto_stays_within_its_bounds() {
+        let mut conn = Connection::new(CONV);
+        conn.update_ack(u32::MAX);
+        assert_eq!(conn.internals().rx_rto, KCP_RTO_MAX);
+
+        let mut conn = Connection::new(CONV);
+        conn.set_rx_minrto(200);
+        for _ in 0..50 {
+            conn.update_ack(1);
+        }
+        assert_eq!(conn.internals().rx_rto, 200);
+    }
+
+    #[test]
+    fn unacknowledged_segments_back_off() {
+        let (mut sender, _) = pair(1000);
+        sender.send(b"lost").unwrap();
+        sender.flush().unwrap();
+        assert_eq!(transmitted(&mut sender).len(), 1);
+
+        // First resend after rto + rto/8, then the rto doubles
+        let mut resent = Vec::new();
+        for now in (1100..=2500).step_by(100) {
+            sender.update(now);
+            resent.extend(transmitted(&mut sender).iter().map(|s| (now, s.sn, s.ts)));
+        }
+        assert_eq!(resent, [(1300, 0, 1300), (1700, 0, 1700), (2500, 0, 2500)]);
+        assert_eq!(sender.internals().xmit, 3);
+    }
+
+    #[test]
+    fn back_off_continues_across_the_clock_wrap() {
+        let t = wrapping_at(1500);
+        let (mut sender, _) = pair(t(1000));
+        sender.send(b"lost").unwrap();
+        sender.flush().unwrap();
+        assert_eq!(transmitted(&mut sender).len(), 1);
+
+        let mut resent = Vec::new();
+        for now in (1100..=2500).step_by(100) {
+            sender.update(t(now));
+            resent.extend(transmitted(&mut sender).iter().map(|s| (now, s.sn, s.ts)));
+        }
+        assert_eq!(resent, [(1300, 0, t(1300)), (1700, 0, t(1700)), (2500, 0, t(2500))]);
+        assert_eq!(sender.internals().xmit, 3);
+    }
+
+    #[test]
+    fn duplicate_acks_trigger_fast_retransmit() {
+        let (mut sender, _) = pair(1000);
+        sender.set_nodelay(true, 10, 2, true);
+        for message in [b"a", b"b", b"c", b"d"] {
+            sender.send(message).unwrap();
+        }
+        sender.flush().unwrap();
+        let sent: Vec<_> = transmitted(&mut sender).iter().map(|s| s.sn).collect();
+        assert_eq!(sent, [0, 1, 2, 3]);
+
+        // sn 0 is lost, the ACKs of 1 and 2 each skip it once
+        sender.handle_input(&encode(&[ack(1, 1000, 0)]), 1005).unwrap();
+        sender.flush().unwrap();
+        assert!(transmitted(&mut sender).is_empty());
+        sender.handle_input(&encode(&[ack(2, 1000, 0)]), 1006).unwrap();
+        sender.flush().unwrap();
+        let resent = transmitted(&mut sender);
+        assert_eq!(resent.len(), 1);
+        assert_eq!((resent[0].sn, resent[0].ts, resent[0].data.as_slice()), (0, 1006, &b"a"[..]));
+        // Not a timeout
+        assert_eq!(sender.internals().xmit, 0);
+    }
+
+    #[test]
+    fn acks_are_ordered_across_the_clock_wrap() {
+        let t = wrapping_at(1100);
+        let (mut sender, _) = pair(t(1000));
+        sender.set_nodelay(true, 10, 2, true);
+        sender.send(b"a").unwrap();
+        sender.send(b"b").unwrap();
+        sender.flush().unwrap();
+        assert_eq!(transmitted(&mut sender).len(), 2);
+
+        // Both time out and are resent after the wrap
+        let mut resent = Vec::new();
+        for now in (1010..=1200).step_by(10) {
+            sender.update(t(now));
+            resent.extend(transmitted(&mut sender).iter().map(|s| (now, s.sn, s.ts)));
+        }
+        assert_eq!(resent, [(1200, 0, t(1200)), (1200, 1, t(1200))]);
+
+        // ACKs of the first transmission of sn 1 predate the resent sn 0
+        for now in [1205, 1206] {
+            sender.handle_input(&encode(&[ack(1, t(1000), 0)]), t(now)).unwrap();
+            sender.flush().unwrap();
+            assert!(transmitted(&mut sender).is_empty(), "resent at {}", now);
+        }
+        assert_eq!(sender.internals().rx_srtt, 205);
+
+        // Those of the resend do not
+        sender.handle_input(&encode(&[ack(1, t(1200), 0)]), t(1210)).unwrap();
+        sender.handle_input(&encode(&[ack(1, t(1200), 0)]), t(1211)).unwrap();
+        sender.flush().unwrap();
+        let fast = transmitted(&mut sender);
+        assert_eq!(fast.iter().map(|s| (s.sn, s.ts)).collect::<Vec<_>>(), [(0, t(1211))]);
+    }
+
+    #[test]
+    fn full_receive_window_is_probed_and_reopened() {
+        let (mut sender, mut receiver) = pair(1000);
+        sender.set_wndsize(256, 0);
+        sender.set_nodelay(false, 100, 0, true);
+        for i in 0..=KCP_WND_RCV {
+            sender.send(&[i as u8]).unwrap();
+        }
+        sender.flush().unwrap();
+        deliver(&mut sender, &mut receiver, 1010);
+        receiver.flush().unwrap();
+        let acks = deliver(&mut receiver, &mut sender, 1020);
+        assert_eq!(acks.len(), KCP_WND_RCV as usize);
+        assert!(acks.iter().all(|s| s.cmd == KCP_CMD_ACK && s.wnd == 0));
+        assert_eq!(sender.rmt_wnd(), 0);
+        assert_eq!(sender.wait_snd(), 1);
+
+        // Nothing goes out until the window probe is due
+        for now in (1100..8100).step_by(100) {
+            sender.update(now);
+            assert!(transmitted(&mut sender).is_empty(), "sent at {}", now);
+        }
+        sender.update(8100);
+        let probe = deliver(&mut sender, &mut receiver, 8110);
+        assert_eq!(probe.iter().map(|s| s.cmd).collect::<Vec<_>>(), [KCP_CMD_WASK]);
+        receiver.flush().unwrap();
+        let tell = deliver(&mut receiver, &mut sender, 8120);
+        assert_eq!(tell.iter().map(|s| (s.cmd, s.wnd)).collect::<Vec<_>>(), [(KCP_CMD_WINS, 0)]);
+
+        // Reading from a full window tells the sender right away
+        let mut buf = [0; 1];
+        receiver.recv(&mut buf).unwrap();
+        receiver.flush().unwrap();
+        let tell = deliver(&mut receiver, &mut sender, 8130);
+        assert_eq!(tell.iter().map(|s| (s.cmd, s.wnd)).collect::<Vec<_>>(), [(KCP_CMD_WINS, 1)]);
+        sender.flush().unwrap();
+        let data = deliver(&mut sender, &mut receiver, 8140);
+        assert_eq!(data.iter().map(|s| (s.cmd, s.sn)).collect::<Vec<_>>(), [(KCP_CMD_PUSH, 128)]);
+    }
+
+    #[test]
+    fn exported_state_round_trips() {
+        let (mut sender, mut receiver) = pair(1000);
+        sender.set_mtu(100).unwrap();
+        sender.set_nodelay(false, 100, 0, true);
+        let messages: Vec<_> = (0..6).map(|i| vec![i as u8; 150]).collect();
+        for message in &messages {
+            sender.send(message).unwrap();
+        }
+        sender.flush().unwrap();
+        // sn 2 is lost: the receiver holds data out of order and ACKs,
+        // the sender segments in flight
+        let datagrams: Vec<_> = sender.drain_transmit().collect();
+        assert_eq!(datagrams.len(), 12);
+        for (sn, datagram) in datagrams.iter().enumerate() {
+            if sn != 2 {
+                receiver.handle_input(datagram, 1010).unwrap();
+            }
+        }
+
+        let mut resumed = Vec::new();
+        for conn in [&sender, &receiver] {
+            let state = conn.export_state();
+            let imported = Connection::import_state(&state).unwrap();
+            assert_eq!(imported.export_state(), state);
+            resumed.push(imported);
+        }
+        let [mut sender, mut receiver]: [Connection; 2] = resumed.try_into().ok().unwrap();
+        assert_eq!(sender.internals().snd_buf_len, 12);
+        assert_eq!(receiver.internals().rcv_buf_len, 9);
+
+        for now in (1100..=2000).step_by(100) {
+            sender.update(now);
+            receiver.update(now);
+            deliver(&mut sender, &mut receiver, now);
+            deliver(&mut receiver, &mut sender, now);
+        }
+        assert_eq!(received(&mut receiver), messages);
+        assert_eq!(sender.wait_snd(), 0);
+    }
+
+    #[test]
+    fn truncated_state_is_rejected() {
+        let (mut sender, _) = pair(1000);
+        sender.send(b"queued").unwrap();
+        let state = sender.export_state();
+        for len in 0..state.len() {
+            assert!(matches!(Connection::import_state(&state[..len]), Err(Error::InvalidState)));
+        }
+        let mut longer = state.clone();
+        longer.push(0);
+        assert!(matches!(Connection::import_state(&longer), Err(Error::InvalidState)));
+    }
+}
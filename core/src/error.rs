@@ -0,0 +1,53 @@
+use core::fmt;
+
+/// KCP protocol errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A segment of another conversation, expected and found conv
+    ConvInconsistent(u32, u32),
+    InvalidMtu(usize),
+    /// Datagram shorter than a segment header
+    InvalidSegmentSize(usize),
+    /// Segment length, remaining bytes of the datagram
+    InvalidSegmentDataSize(usize, usize),
+    /// `update` has not been called yet
+    NeedUpdate,
+    RecvQueueEmpty,
+    /// The next message is still missing fragments
+    ExpectingFragment,
+    UnsupportedCmd(u8),
+    /// Header fields that cannot come from a well-behaved peer
+    InvalidSegment(&'static str),
+    UserBufTooBig,
+    UserBufTooSmall,
+    /// Data given to [`Connection::import_state`](crate::Connection::import_state)
+    /// is not an exported state
+    InvalidState,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ConvInconsistent(expected, found) => {
+                write!(f, "conv inconsistent, expected {}, found {}", expected, found)
+            }
+            Error::InvalidMtu(mtu) => write!(f, "invalid mtu {}", mtu),
+            Error::InvalidSegmentSize(size) => write!(f, "invalid segment size {}", size),
+            Error::InvalidSegmentDataSize(expected, found) => write!(
+                f,
+                "invalid segment data size, expected {}, found {}",
+                expected, found
+            ),
+            Error::NeedUpdate => f.write_str("need to call update() once"),
+            Error::RecvQueueEmpty => f.write_str("recv queue is empty"),
+            Error::ExpectingFragment => f.write_str("expecting fragment"),
+            Error::UnsupportedCmd(cmd) => write!(f, "command {} is not supported", cmd),
+            Error::InvalidSegment(reason) => write!(f, "invalid segment: {}", reason),
+            Error::UserBufTooBig => f.write_str("user's send buffer is too big"),
+            Error::UserBufTooSmall => f.write_str("user's recv buffer is too small"),
+            Error::InvalidState => f.write_str("invalid exported connection state"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
@@ -0,0 +1,43 @@
+//! Sans-io KCP connection state machine
+//!
+//! The protocol code of smol-kcp without sockets, clocks or an executor,
+//! for `no_std` targets with an allocator (RTOS-class devices). The caller
+//! owns the transport and the time, given in milliseconds from any fixed
+//! point:
+//!
+//! ```ignore
+//! let mut conn = Connection::new(conv);
+//! conn.send(b"hello")?;
+//! loop {
+//!     let now = millis();
+//!     while let Some(datagram) = conn.poll_transmit(now) {
+//!         radio.send(&datagram);
+//!     }
+//!     if let Some(datagram) = radio.recv_timeout(conn.poll_timeout(now)) {
+//!         conn.handle_input(&datagram, millis())?;
+//!     }
+//!     while let Ok(n) = conn.recv(&mut buf) {
+//!         handle(&buf[..n]);
+//!     }
+//! }
+//! ```
+//!
+//! The wire format and timing are those of ikcp, the smol wrappers in the
+//! `smol-kcp` crate drive the same [`Connection`].
+
+#![no_std]
+
+extern crate alloc;
+
+mod connection;
+mod error;
+pub mod serial;
+
+pub use connection::{Connection, Internals};
+pub use error::Error;
+
+/// Result of KCP operations
+pub type KcpResult<T> = Result<T, Error>;
+
+/// Size of a segment header
+pub const KCP_OVERHEAD: usize = 24;
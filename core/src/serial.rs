@@ -0,0 +1,75 @@
+//! Serial number arithmetic for sequence numbers and timestamps
+//!
+//! Sequence numbers and the u32 millisecond clock both wrap, the clock
+//! every 49.7 days. Like RFC 1982, two values compare by the sign of their
+//! wrapping distance, valid while they are less than 2^31 apart. Never
+//! compare them with `<` or subtract them directly.
+
+use core::cmp::Ordering;
+
+/// Signed distance from `earlier` to `later`
+///
+/// ```
+/// use smol_kcp_core::serial;
+///
+/// assert_eq!(serial::diff(5, u32::MAX - 4), 10);
+/// assert_eq!(serial::diff(u32::MAX - 4, 5), -10);
+/// ```
+#[inline]
+pub fn diff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+/// Order of `a` relative to `b`
+///
+/// ```
+/// use core::cmp::Ordering;
+/// use smol_kcp_core::serial;
+///
+/// assert_eq!(serial::cmp(1, u32::MAX), Ordering::Greater);
+/// ```
+#[inline]
+pub fn cmp(a: u32, b: u32) -> Ordering {
+    diff(a, b).cmp(&0)
+}
+
+/// `a` comes before `b`
+#[inline]
+pub fn before(a: u32, b: u32) -> bool {
+    diff(a, b) < 0
+}
+
+/// `a` comes after `b`
+#[inline]
+pub fn after(a: u32, b: u32) -> bool {
+    diff(a, b) > 0
+}
+
+/// `a` advanced by `n`, wrapping
+#[inline]
+pub fn add(a: u32, n: u32) -> u32 {
+    a.wrapping_add(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_across_the_wrap() {
+        let before_wrap = u32::MAX - 9;
+        let after_wrap = add(before_wrap, 20);
+        assert_eq!(after_wrap, 10);
+        assert_eq!(diff(after_wrap, before_wrap), 20);
+        assert!(before(before_wrap, after_wrap));
+        assert!(after(after_wrap, before_wrap));
+        assert_eq!(cmp(after_wrap, before_wrap), Ordering::Greater);
+        assert_eq!(cmp(before_wrap, before_wrap), Ordering::Equal);
+    }
+
+    #[test]
+    fn order_flips_at_half_the_range() {
+        assert!(after(0x7fff_ffff, 0));
+        assert!(before(0x8000_0000, 0));
+    }
+}
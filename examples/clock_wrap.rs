@@ -0,0 +1,73 @@
+//! Run a lossy session across the wrap of the u32 millisecond clock
+//!
+//! Two `smol_kcp::core::Connection`s exchange messages on a simulated link
+//! with 20ms delay and every 7th datagram lost, on a virtual clock that
+//! starts 1s before it wraps. Build without `--release` so arithmetic
+//! overflow panics instead of wrapping silently.
+
+use std::collections::VecDeque;
+
+use smol_kcp::core::{serial, Connection};
+
+const DELAY: u32 = 20;
+const MESSAGES: u32 = 5000;
+
+fn main() {
+    let mut now = u32::MAX - 1000;
+    let mut client = Connection::new(0x1234);
+    let mut server = Connection::new(0x1234);
+    for conn in [&mut client, &mut server] {
+        conn.set_nodelay(true, 10, 2, true);
+        conn.set_wndsize(256, 256);
+    }
+
+    // (arrival time, to server, datagram)
+    let mut link: VecDeque<(u32, bool, Vec<u8>)> = VecDeque::new();
+    let mut datagrams = 0u32;
+    let mut sent = 0;
+    let mut received = 0;
+    let mut buf = [0u8; 64];
+    let started = now;
+
+    while received < MESSAGES {
+        if now.wrapping_sub(started) > 60_000 {
+            eprintln!("stalled after {} of {} messages", received, MESSAGES);
+            std::process::exit(1);
+        }
+        if sent < MESSAGES && client.wait_snd() < 128 {
+            client.send(&sent.to_le_bytes()).expect("send");
+            sent += 1;
+        }
+
+        for (conn, to_server) in [(&mut client, true), (&mut server, false)] {
+            while let Some(datagram) = conn.poll_transmit(now) {
+                datagrams += 1;
+                if !datagrams.is_multiple_of(7) {
+                    link.push_back((now.wrapping_add(DELAY), to_server, datagram));
+                }
+            }
+        }
+        while let Some((at, _, _)) = link.front() {
+            if serial::before(now, *at) {
+                break;
+            }
+            let (_, to_server, datagram) = link.pop_front().unwrap();
+            let conn = if to_server { &mut server } else { &mut client };
+            conn.handle_input(&datagram, now).expect("input");
+        }
+        while let Ok(n) = server.recv(&mut buf) {
+            let value = u32::from_le_bytes(buf[..n].try_into().unwrap());
+            assert_eq!(value, received, "messages out of order");
+            received += 1;
+        }
+
+        now = now.wrapping_add(1);
+    }
+
+    println!(
+        "{} messages delivered in {}ms across the clock wrap, now {}",
+        received,
+        now.wrapping_sub(started),
+        now
+    );
+}
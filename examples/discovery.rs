@@ -0,0 +1,48 @@
+//! Zero-config echo over the LAN
+//!
+//!     cargo run --example discovery --features discovery -- server living-room
+//!     cargo run --example discovery --features discovery -- client
+
+use smol_kcp::{KcpConfig, KcpListener, KcpStream};
+use std::net::SocketAddr;
+
+const SERVICE: &str = "_kcpecho._udp";
+
+fn main() {
+    env_logger::init();
+    let mut args = std::env::args().skip(1);
+    let side = args.next().unwrap_or_default();
+
+    futures_lite::future::block_on(async {
+        let config = KcpConfig::default();
+        if side == "server" {
+            let instance = args.next().unwrap_or_else(|| "echo".to_string());
+            let addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+            let mut listener = KcpListener::bind(config, addr).await.unwrap();
+            let announcer = listener.announcer(SERVICE, &instance).unwrap();
+            println!("Announcing {} on {}", announcer.instance_name(), listener.local_addr().unwrap());
+            std::thread::spawn(move || futures_lite::future::block_on(announcer.run()));
+
+            loop {
+                let (mut stream, peer) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 1024];
+                let n = stream.recv(&mut buf).await.unwrap();
+                println!("Echoing {} bytes to {}", n, peer);
+                stream.send(&buf[..n]).await.unwrap();
+            }
+        } else {
+            for instance in smol_kcp::discovery::browse(SERVICE, std::time::Duration::from_secs(2))
+                .await
+                .unwrap()
+            {
+                println!("Found {} on {} at {:?}", instance.name, instance.host, instance.addrs);
+            }
+
+            let mut stream = KcpStream::discover_and_connect(&config, SERVICE).await.unwrap();
+            stream.send(b"Hello, LAN!").await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let n = stream.recv(&mut buf).await.unwrap();
+            println!("Received: {}", String::from_utf8_lossy(&buf[..n]));
+        }
+    });
+}
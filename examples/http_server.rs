@@ -0,0 +1,39 @@
+//! Serve HTTP over KCP with hyper
+//!
+//! Run with `cargo run --example http_server --features http`.
+
+use std::{convert::Infallible, net::SocketAddr, thread};
+
+use futures_lite::future::block_on;
+use hyper::{server::conn::http1, service::service_fn, Request, Response};
+use smol_kcp::{KcpConfig, KcpListener};
+
+async fn handle(req: Request<hyper::body::Incoming>) -> Result<Response<String>, Infallible> {
+    Ok(Response::new(format!("{} {} over KCP\n", req.method(), req.uri())))
+}
+
+fn main() {
+    env_logger::init();
+
+    block_on(async {
+        let addr: SocketAddr = "127.0.0.1:8088".parse().unwrap();
+        let mut listener = KcpListener::bind(KcpConfig::default(), addr).await.unwrap();
+        println!("Serving HTTP over KCP on {}", addr);
+
+        // Keep accepting: the listener also feeds the sessions being served
+        loop {
+            match listener.accept_hyper().await {
+                Ok((io, peer)) => {
+                    println!("Accepted connection from {}", peer);
+                    thread::spawn(move || {
+                        let conn = http1::Builder::new().serve_connection(io, service_fn(handle));
+                        if let Err(e) = block_on(conn) {
+                            eprintln!("Connection {} failed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Accept error: {}", e),
+            }
+        }
+    });
+}
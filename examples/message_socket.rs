@@ -0,0 +1,66 @@
+//! Request/response server without a stream per client
+//!
+//! A [`KcpMessageSocket`] answers every request of 50 clients, each on a
+//! thread of its own, with the request reversed; the clients check their
+//! answers. Exits non-zero on a wrong answer.
+
+use std::{collections::HashSet, net::SocketAddr};
+
+use futures_lite::future;
+use smol_kcp::{KcpConfig, KcpMessageSocket, KcpStream};
+
+const CLIENTS: usize = 50;
+const REQUESTS: usize = 5;
+
+async fn client(config: KcpConfig, addr: SocketAddr, id: usize) {
+    let mut stream = KcpStream::connect(&config, addr).await.unwrap();
+    let mut buf = [0u8; 64];
+    for i in 0..REQUESTS {
+        let request = format!("client {} request {}", id, i);
+        stream.send(request.as_bytes()).await.unwrap();
+        let n = stream.recv(&mut buf).await.unwrap();
+        let expected: Vec<u8> = request.bytes().rev().collect();
+        if buf[..n] != expected[..] {
+            eprintln!("client {} got {:?}", id, String::from_utf8_lossy(&buf[..n]));
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let config = KcpConfig::default();
+
+    future::block_on(async {
+        let mut server = KcpMessageSocket::bind(config, "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let serve = async {
+            let mut answered = 0;
+            let mut peers = HashSet::new();
+            while answered < CLIENTS * REQUESTS {
+                let (_, peer, mut msg) = server.recv_from().await.unwrap();
+                peers.insert(peer);
+                msg.reverse();
+                server.send_to(peer, &msg).await.unwrap();
+                answered += 1;
+            }
+            println!("{} requests of {} clients answered", answered, peers.len());
+            // Keep driving the sessions until the last answers are acknowledged
+            let _ = future::or(server.recv_from(), async {
+                async_io::Timer::after(std::time::Duration::from_millis(500)).await;
+                Ok((0, addr, Vec::new()))
+            })
+            .await;
+        };
+
+        let clients: Vec<_> = (0..CLIENTS)
+            .map(|id| std::thread::spawn(move || future::block_on(client(config, addr, id))))
+            .collect();
+        serve.await;
+        for client in clients {
+            client.join().unwrap();
+        }
+    });
+}
@@ -0,0 +1,99 @@
+//! Bond two local sockets into one connection
+//!
+//! Both client sockets are bound to the loopback interface, standing in
+//! for two uplinks. The client stripes a known byte pattern over them and
+//! the server checks that every byte arrives once and in order, then both
+//! ends print the statistics of each path. Pass `duplicate` to send every
+//! datagram over both paths instead. Exits non-zero on a mismatch.
+
+use std::net::{SocketAddr, UdpSocket};
+
+use futures_lite::future;
+use smol_kcp::{KcpConfig, KcpListener, KcpStream, MultipathMode, PathStats};
+
+const TOTAL: usize = 500_000;
+
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+fn print_paths(side: &str, paths: &[PathStats]) {
+    for path in paths {
+        println!(
+            "{} path {} -> {}: {} sent, {} received, srtt {:?}, active {}",
+            side,
+            path.local,
+            path.peer,
+            path.packets_sent,
+            path.packets_received,
+            path.rtt.srtt,
+            path.active
+        );
+    }
+}
+
+fn main() {
+    let mode = match std::env::args().nth(1).as_deref() {
+        Some("duplicate") => MultipathMode::Duplicate,
+        _ => MultipathMode::Stripe,
+    };
+    let config = KcpConfig {
+        stream: true,
+        ..Default::default()
+    };
+
+    future::block_on(async {
+        let mut listener = KcpListener::bind(config, "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+
+        let client = async {
+            let sockets = vec![
+                UdpSocket::bind("127.0.0.1:0").unwrap(),
+                UdpSocket::bind("127.0.0.1:0").unwrap(),
+            ];
+            let mut stream = KcpStream::connect_multipath(&config, addr, sockets, mode)
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..TOTAL).map(pattern).collect();
+            for chunk in data.chunks(4000) {
+                stream.send(chunk).await.unwrap();
+            }
+            // Stay connected until the server has read everything
+            let mut done = [0u8; 1];
+            stream.recv(&mut done).await.unwrap();
+            print_paths("client", &stream.path_stats().await);
+        };
+
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let read_all = async {
+                let mut buf = [0u8; 9000];
+                let mut received = 0;
+                while received < TOTAL {
+                    let n = stream.recv(&mut buf).await.unwrap();
+                    for (i, &byte) in buf[..n].iter().enumerate() {
+                        if byte != pattern(received + i) {
+                            eprintln!("byte {} is {}", received + i, byte);
+                            std::process::exit(1);
+                        }
+                    }
+                    received += n;
+                }
+                stream.send(b"k").await.unwrap();
+                println!("{} bytes read intact", received);
+                print_paths("server", &stream.path_stats().await);
+            };
+            // Accepted streams are fed by the accept loop
+            future::or(read_all, async {
+                loop {
+                    let _ = listener.accept().await;
+                }
+            })
+            .await;
+        };
+
+        future::zip(client, server).await;
+    });
+}
@@ -0,0 +1,82 @@
+//! Drive a `KcpSocket` from an application's own UDP loop
+//!
+//! The client reads its UDP socket itself and feeds the datagrams to a
+//! `KcpSocket`, flushing on every tick of the KCP interval. A listener
+//! echoes what it receives, the client checks the echo. Exits non-zero on
+//! a mismatch.
+
+use std::{net::UdpSocket, sync::Arc};
+
+use async_io::{Async, Timer};
+use futures_lite::future;
+use smol_kcp::{KcpConfig, KcpListener, KcpSocket};
+
+const MESSAGES: usize = 20;
+
+fn main() {
+    let config = KcpConfig::default();
+
+    future::block_on(async {
+        let mut listener = KcpListener::bind(config, "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = async {
+            let udp = Arc::new(Async::new(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap());
+            let mut socket = KcpSocket::new(&config, 0x1234, udp.clone(), addr, false).unwrap();
+            for i in 0..MESSAGES {
+                socket.send(format!("message {}", i).as_bytes()).unwrap();
+            }
+
+            let mut datagram = [0u8; 2048];
+            let mut msg = [0u8; 64];
+            let mut echoed = 0;
+            while echoed < MESSAGES {
+                let received = future::or(async { Some(udp.recv_from(&mut datagram).await) }, async {
+                    Timer::after(socket.interval()).await;
+                    None
+                })
+                .await;
+                match received {
+                    Some(Ok((n, _))) => {
+                        socket.input(&datagram[..n]).unwrap();
+                    }
+                    Some(Err(e)) => panic!("{}", e),
+                    None => socket.flush().unwrap(),
+                }
+                socket.send_output().await.unwrap();
+
+                while let Ok(n) = socket.recv(&mut msg) {
+                    let expected = format!("message {}", echoed);
+                    if msg[..n] != *expected.as_bytes() {
+                        eprintln!("got {:?}", String::from_utf8_lossy(&msg[..n]));
+                        std::process::exit(1);
+                    }
+                    echoed += 1;
+                }
+            }
+            println!("{} messages echoed, {:?}", echoed, socket.rtt());
+        };
+
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let echo = async {
+                let mut buf = [0u8; 64];
+                loop {
+                    let n = stream.recv(&mut buf).await.unwrap();
+                    stream.send(&buf[..n]).await.unwrap();
+                }
+            };
+            // Accepted streams are fed by the accept loop
+            future::or(echo, async {
+                loop {
+                    let _ = listener.accept().await;
+                }
+            })
+            .await;
+        };
+
+        future::or(client, server).await;
+    });
+}
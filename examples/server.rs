@@ -3,47 +3,47 @@ use std::net::SocketAddr;
 
 fn main() {
     env_logger::init();
-    
-    futures_lite::future::block_on(async {
+
+    smol::block_on(async {
         let config = KcpConfig::default();
         let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
-        
+
         println!("Starting KCP echo server on {}", addr);
-        
+
         let mut listener = KcpListener::bind(config, addr).await.unwrap();
-        
-        loop {
-            match listener.accept().await {
-                Ok((mut stream, peer_addr)) => {
-                    println!("Accepted connection from {}", peer_addr);
-                    
-                    // Handle connection directly without spawning
-                    // For minimal resource usage on embedded systems
-                    let mut buf = vec![0u8; 1024];
-                    loop {
-                        match stream.recv(&mut buf).await {
-                            Ok(0) => break,
-                            Ok(n) => {
-                                println!("Received {} bytes from {}: {}", 
-                                    n, peer_addr, String::from_utf8_lossy(&buf[..n]));
-                                if let Err(e) = stream.send(&buf[..n]).await {
-                                    eprintln!("Send error: {}", e);
-                                    break;
-                                }
-                                println!("Echoed {} bytes back to {}", n, peer_addr);
-                            }
-                            Err(e) => {
-                                eprintln!("Receive error: {}", e);
+
+        // Every connection gets its own task, the loop keeps feeding them
+        let served = listener
+            .serve(|mut stream, peer_addr| async move {
+                println!("Accepted connection from {}", peer_addr);
+                let mut buf = vec![0u8; 1024];
+                loop {
+                    match stream.recv(&mut buf).await {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            println!(
+                                "Received {} bytes from {}: {}",
+                                n,
+                                peer_addr,
+                                String::from_utf8_lossy(&buf[..n])
+                            );
+                            if let Err(e) = stream.send(&buf[..n]).await {
+                                eprintln!("Send error: {}", e);
                                 break;
                             }
+                            println!("Echoed {} bytes back to {}", n, peer_addr);
+                        }
+                        Err(e) => {
+                            eprintln!("Receive error: {}", e);
+                            break;
                         }
                     }
-                    println!("Connection {} closed", peer_addr);
-                }
-                Err(e) => {
-                    eprintln!("Accept error: {}", e);
                 }
-            }
+                println!("Connection {} closed", peer_addr);
+            })
+            .await;
+        if let Err(e) = served {
+            eprintln!("Accept error: {}", e);
         }
     });
-}
\ No newline at end of file
+}
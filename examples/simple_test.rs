@@ -13,7 +13,7 @@ fn main() {
         
         // For embedded systems, we'll run a simpler sequential test
         // Create server and client futures without spawning tasks
-        let server_config = config.clone();
+        let server_config = config;
         
         let server_future = async move {
             let mut listener = KcpListener::bind(server_config, addr).await.unwrap();
@@ -0,0 +1,118 @@
+#ifndef SMOL_KCP_H
+#define SMOL_KCP_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ * A KCP connection
+ */
+typedef struct KcpHandle KcpHandle;
+
+/*
+ * A listening KCP socket
+ */
+typedef struct KcpServer KcpServer;
+
+/*
+ * Called by [`kcp_serve`] for every new connection
+ *
+ * The callback owns `conn` and frees it with [`kcp_close`]. `peer` is only
+ * valid during the call. Returning non-zero stops [`kcp_serve`].
+ */
+typedef int (*KcpAcceptCallback)(void *user_data, KcpHandle *conn, const char *peer);
+
+/*
+ * Message of the last failed call on this thread, `NULL` if none
+ *
+ * The string stays valid until the next failing call on the same thread.
+ */
+const char *kcp_last_error(void);
+
+/*
+ * Connect to a KCP server at `host:port` with the default configuration
+ *
+ * # Safety
+ *
+ * `addr` must be `NULL` or a valid NUL terminated string.
+ */
+KcpHandle *kcp_connect(const char *addr);
+
+/*
+ * Second handle to the same connection
+ *
+ * One thread can send on one handle while another receives on the other.
+ * Each handle is freed with [`kcp_close`].
+ *
+ * # Safety
+ *
+ * `conn` must be a live handle.
+ */
+KcpHandle *kcp_dup(const KcpHandle *conn);
+
+/*
+ * Send `len` bytes, returns the number of bytes queued or -1
+ *
+ * # Safety
+ *
+ * `conn` must be a live handle not used by another thread at the same
+ * time, `buf` must point to `len` readable bytes.
+ */
+ptrdiff_t kcp_send(KcpHandle *conn, const uint8_t *buf, size_t len);
+
+/*
+ * Receive into `buf`, blocks until data arrives
+ *
+ * Returns the number of bytes received or -1.
+ *
+ * # Safety
+ *
+ * `conn` must be a live handle not used by another thread at the same
+ * time, `buf` must point to `len` writable bytes.
+ */
+ptrdiff_t kcp_recv(KcpHandle *conn, uint8_t *buf, size_t len);
+
+/*
+ * Free a connection handle, `NULL` is ignored
+ *
+ * # Safety
+ *
+ * `conn` must be `NULL` or a handle not freed before.
+ */
+void kcp_close(KcpHandle *conn);
+
+/*
+ * Listen on `host:port` with the default configuration
+ *
+ * # Safety
+ *
+ * `addr` must be `NULL` or a valid NUL terminated string.
+ */
+KcpServer *kcp_listen(const char *addr);
+
+/*
+ * Accept connections and pass each to `callback`
+ *
+ * Runs until the callback returns non-zero (returns 0) or the socket
+ * fails (returns -1).
+ *
+ * # Safety
+ *
+ * `server` must be a live server used by no other thread, `callback`
+ * must be safe to call with `user_data`.
+ */
+int kcp_serve(KcpServer *server, KcpAcceptCallback callback, void *user_data);
+
+/*
+ * Close a listening socket, `NULL` is ignored
+ *
+ * # Safety
+ *
+ * `server` must be `NULL` or a server not freed before.
+ */
+void kcp_listener_close(KcpServer *server);
+
+#endif  /* SMOL_KCP_H */
@@ -0,0 +1,256 @@
+//! Minimal command line parsing
+//!
+//! Options are taken out of the argument list by name first, whatever is
+//! left are positional arguments. No dependencies, which keeps the binary
+//! small enough for routers.
+
+use std::{
+    fmt::{self, Display},
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+};
+
+use log::{info, warn};
+use smol_kcp::{KcpConfig, KcpInterop};
+
+use crate::CliError;
+
+pub struct Args {
+    args: Vec<String>,
+}
+
+impl Args {
+    pub fn new(args: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            args: args.into_iter().collect(),
+        }
+    }
+
+    /// Take a boolean `--name` flag
+    pub fn flag(&mut self, name: &str) -> bool {
+        match self.args.iter().position(|arg| arg == name) {
+            Some(index) => {
+                self.args.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take `--name value` or `--name=value`
+    pub fn opt<T>(&mut self, name: &str) -> Result<Option<T>, CliError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let prefix = format!("{}=", name);
+        let Some(index) = self
+            .args
+            .iter()
+            .position(|arg| arg == name || arg.starts_with(&prefix))
+        else {
+            return Ok(None);
+        };
+
+        let arg = self.args.remove(index);
+        let value = match arg.strip_prefix(&prefix) {
+            Some(value) => value.to_string(),
+            None if index < self.args.len() => self.args.remove(index),
+            None => return Err(CliError::Usage(format!("{} requires a value", name))),
+        };
+        value
+            .parse()
+            .map(Some)
+            .map_err(|e| CliError::Usage(format!("invalid value `{}` for {}: {}", value, name, e)))
+    }
+
+    /// Take the next positional argument
+    pub fn positional<T>(&mut self, what: &str) -> Result<T, CliError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self
+            .next_positional()
+            .ok_or_else(|| CliError::Usage(format!("missing {}", what)))?;
+        value
+            .parse()
+            .map_err(|e| CliError::Usage(format!("invalid {} `{}`: {}", what, value, e)))
+    }
+
+    /// Take the next positional argument if there is one
+    pub fn next_positional(&mut self) -> Option<String> {
+        let index = self.args.iter().position(|arg| !arg.starts_with("--"))?;
+        Some(self.args.remove(index))
+    }
+
+    /// KCP configuration from `--preset`, `--interop`, `--mtu` and `--stream`
+    pub fn config(&mut self) -> Result<KcpConfig, CliError> {
+        let mut config = match self.opt::<String>("--preset")?.as_deref() {
+            None | Some("default") => KcpConfig::default(),
+            Some("lan") => KcpConfig::lan(),
+            Some("wan") => KcpConfig::wan(),
+            Some("high-latency") => KcpConfig::high_latency(),
+            Some("low-bandwidth") => KcpConfig::low_bandwidth(),
+            Some("kcp-go") => KcpConfig::kcp_go(),
+            Some(other) => return Err(CliError::Usage(format!("unknown preset `{}`", other))),
+        };
+        match self.opt::<String>("--interop")?.as_deref() {
+            None => {}
+            Some("native") => config.interop = KcpInterop::Native,
+            Some("kcp-go") => config.interop = KcpInterop::KcpGo,
+            Some("ikcp") => config.interop = KcpInterop::Ikcp,
+            Some(other) => {
+                return Err(CliError::Usage(format!("unknown value `{}` for --interop", other)))
+            }
+        }
+        if let Some(mtu) = self.opt::<usize>("--mtu")? {
+            // Smallest MTU the KCP engine accepts, it panics on less
+            if mtu < 50 {
+                return Err(CliError::Usage(format!(
+                    "invalid value `{}` for --mtu: must be at least 50",
+                    mtu
+                )));
+            }
+            config.mtu = mtu;
+        }
+        if self.flag("--stream") {
+            config.stream = true;
+        }
+        Ok(config)
+    }
+
+    /// Take a `--name ADDR` socket address option with a default
+    pub fn addr(&mut self, name: &str, default: &str) -> Result<SocketAddr, CliError> {
+        match self.opt(name)? {
+            Some(addr) => Ok(addr),
+            None => Ok(default.parse().expect("valid default address")),
+        }
+    }
+
+    /// Take a required `--name scheme://host:port` endpoint option
+    ///
+    /// The scheme may be omitted, host names are resolved once.
+    pub fn endpoint(&mut self, name: &str, scheme: &str) -> Result<SocketAddr, CliError> {
+        self.opt_endpoint(name, scheme)?
+            .ok_or_else(|| CliError::Usage(format!("{} is required", name)))
+    }
+
+    /// Take an optional `--name scheme://host:port` endpoint option
+    pub fn opt_endpoint(&mut self, name: &str, scheme: &str) -> Result<Option<SocketAddr>, CliError> {
+        Ok(self.opt_remote(name, scheme)?.map(|remote| remote.addr))
+    }
+
+    /// Take a required endpoint option whose host name is resolved again
+    /// for every new session, see [`Remote`]
+    pub fn remote(&mut self, name: &str, scheme: &str) -> Result<Remote, CliError> {
+        self.opt_remote(name, scheme)?
+            .ok_or_else(|| CliError::Usage(format!("{} is required", name)))
+    }
+
+    fn opt_remote(&mut self, name: &str, scheme: &str) -> Result<Option<Remote>, CliError> {
+        let Some(value) = self.opt::<String>(name)? else {
+            return Ok(None);
+        };
+        let host = match value.split_once("://") {
+            Some((given, host)) if given == scheme => host,
+            Some((given, _)) => {
+                return Err(CliError::Usage(format!(
+                    "{} expects a {}:// address, got {}://",
+                    name, scheme, given
+                )))
+            }
+            None => value.as_str(),
+        };
+        let addr = resolve(host)
+            .map_err(|e| CliError::Usage(format!("invalid address `{}` for {}: {}", value, name, e)))?;
+        Ok(Some(Remote {
+            host: host.to_string(),
+            addr,
+        }))
+    }
+
+    /// Arguments not taken yet
+    pub fn to_vec(&self) -> Vec<String> {
+        self.args.clone()
+    }
+
+    /// Add `--name` unless it was given
+    pub fn default_flag(&mut self, name: &str) {
+        if !self.args.iter().any(|arg| arg == name) {
+            self.args.push(name.to_string());
+        }
+    }
+
+    /// Add `--name value` unless the option was given
+    pub fn default_opt(&mut self, name: &str, value: &str) {
+        let prefix = format!("{}=", name);
+        if !self
+            .args
+            .iter()
+            .any(|arg| arg == name || arg.starts_with(&prefix))
+        {
+            self.args.push(format!("{}{}", prefix, value));
+        }
+    }
+
+    /// Put `value` first unless the arguments start with a positional one
+    ///
+    /// Used for the `client`/`server` argument, which comes first.
+    pub fn default_positional(&mut self, value: &str) {
+        if self.args.first().is_none_or(|arg| arg.starts_with("--")) {
+            self.args.insert(0, value.to_string());
+        }
+    }
+
+    /// Fail on arguments nobody asked for
+    pub fn finish(self) -> Result<(), CliError> {
+        match self.args.first() {
+            Some(arg) => Err(CliError::Usage(format!("unexpected argument `{}`", arg))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Endpoint given by host name, e.g. a DDNS name of a server on a dynamic
+/// IP
+///
+/// Resolved once when parsed, so a typo fails at startup, and again by
+/// [`Remote::resolve`] before each reconnect.
+pub struct Remote {
+    host: String,
+    addr: SocketAddr,
+}
+
+impl Remote {
+    /// Address the host name resolved to last
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Resolve the host name again, keeping the last address if that fails
+    pub fn resolve(&mut self) -> SocketAddr {
+        match resolve(&self.host) {
+            Ok(addr) if addr != self.addr => {
+                info!("{} now resolves to {}", self.host, addr);
+                self.addr = addr;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("resolving {} failed, keeping {}: {}", self.host, self.addr, e),
+        }
+        self.addr
+    }
+}
+
+impl Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.host)
+    }
+}
+
+fn resolve(host: &str) -> io::Result<SocketAddr> {
+    host.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "did not resolve to an address")
+    })
+}
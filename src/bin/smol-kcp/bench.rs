@@ -0,0 +1,271 @@
+//! `smol-kcp bench`: throughput measurement
+
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures_lite::future::block_on;
+use log::{info, warn};
+use smol_kcp::{
+    KcpConfig, KcpListener, KcpMuxStream, KcpMuxer, KcpResult, KcpStats, KcpStream,
+    LatencySummary, MuxConfig,
+};
+
+use crate::{args::Args, config, daemon, help, http, linger, spawn_muxer, CliError};
+
+const USAGE: &str = "\
+Usage: smol-kcp bench server [--listen <addr>] [KCP options]
+       smol-kcp bench client <addr> [options] [KCP options]
+
+The client sends as fast as the connections allow for the given time and
+prints a JSON report of throughput (as counted by the server), loss
+(estimated as the share of retransmitted segments), retransmissions and
+latency percentiles to stdout.
+
+Options:
+  --listen <addr>     Server address to listen on [default: 0.0.0.0:8081]
+  --time <secs>       Test duration [default: 10]
+  --size <bytes>      Size of each write [default: 16384]
+  --parallel <n>      Number of concurrent KCP connections [default: 1]";
+
+/// Time allowed for the last data to be acknowledged after the test
+const LINGER: Duration = Duration::from_secs(5);
+
+pub fn run(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, USAGE) {
+        return Ok(());
+    }
+    let side = args.next_positional();
+    let mut config = args.config()?;
+
+    match side.as_deref() {
+        Some("server") => {
+            let listen = args.addr("--listen", "0.0.0.0:8081")?;
+            args.finish()?;
+            block_on(async {
+                let mut listener = KcpListener::bind(config, listen).await?;
+                info!("bench server listening on {}", listener.local_addr()?);
+                loop {
+                    let (stream, peer) = config::accept(&mut listener).await?;
+                    let tracked = http::track(&stream, true).await;
+                    let muxer = KcpMuxer::new(stream, MuxConfig::default(), false);
+                    spawn_muxer(&muxer);
+                    thread::spawn(move || {
+                        block_on(serve(muxer, peer));
+                        drop(tracked);
+                    });
+                }
+            })
+        }
+        Some("client") => {
+            let duration = Duration::from_secs(args.opt("--time")?.unwrap_or(10));
+            let size: usize = args.opt("--size")?.unwrap_or(16 * 1024);
+            let parallel: usize = args.opt("--parallel")?.unwrap_or(1).max(1);
+            let addr: SocketAddr = args.positional("server address")?;
+            args.finish()?;
+            config.latency_histograms = true;
+
+            let workers: Vec<_> = (0..parallel)
+                .map(|_| thread::spawn(move || block_on(measure(config, addr, duration, size))))
+                .collect();
+            let mut results = Vec::with_capacity(parallel);
+            for worker in workers {
+                let result = worker
+                    .join()
+                    .map_err(|_| CliError::Failed("bench worker panicked".to_string()))??;
+                results.push(result);
+            }
+
+            println!("{}", report(&config, duration, size, &results));
+            Ok(())
+        }
+        _ => Err(CliError::Usage("expected `bench server` or `bench client`".to_string())),
+    }
+}
+
+/// Outcome of one benchmark connection
+struct StreamResult {
+    bytes_sent: u64,
+    bytes_received: u64,
+    elapsed: Duration,
+    stats: KcpStats,
+    rtt: LatencySummary,
+    send_to_ack: LatencySummary,
+}
+
+async fn measure(
+    config: KcpConfig,
+    addr: SocketAddr,
+    duration: Duration,
+    size: usize,
+) -> KcpResult<StreamResult> {
+    let stream = KcpStream::connect(&config, addr).await?;
+    let muxer = KcpMuxer::new(stream, MuxConfig::default(), true);
+    spawn_muxer(&muxer);
+    let stream = muxer.open().await?;
+
+    let (bytes_sent, bytes_received, elapsed) = send_for(stream, duration, size).await?;
+    linger(&muxer, LINGER).await;
+
+    let connection = muxer.connection();
+    let latency = connection.latency_report().await.unwrap_or_default();
+    Ok(StreamResult {
+        bytes_sent,
+        bytes_received,
+        elapsed,
+        stats: connection.stats().await,
+        rtt: latency.rtt,
+        send_to_ack: latency.send_to_ack,
+    })
+}
+
+/// Send for `duration`, returns the bytes sent and what the server reported
+/// receiving in how much time
+async fn send_for(
+    mut stream: KcpMuxStream,
+    duration: Duration,
+    size: usize,
+) -> KcpResult<(u64, u64, Duration)> {
+    let data = vec![0x5a; size];
+    let mut sent = 0u64;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        sent += stream.send(&data).await? as u64;
+    }
+    stream.close().await?;
+
+    let mut report = [0u8; 16];
+    let mut filled = 0;
+    while filled < report.len() {
+        match stream.recv(&mut report[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    let received = u64::from_le_bytes(report[..8].try_into().unwrap());
+    let micros = u64::from_le_bytes(report[8..].try_into().unwrap());
+    Ok((sent, received, Duration::from_micros(micros)))
+}
+
+/// Count the bytes of every stream and report them back when it ends
+async fn serve(muxer: KcpMuxer, peer: SocketAddr) {
+    while let Ok(mut stream) = muxer.accept().await {
+        let _session = daemon::Session::begin();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0u64;
+        let mut start = None;
+        loop {
+            match stream.recv(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    start.get_or_insert_with(Instant::now);
+                    received += n as u64;
+                }
+                Err(e) => {
+                    warn!("bench stream from {} failed: {}", peer, e);
+                    break;
+                }
+            }
+        }
+
+        let elapsed = start.map(|start| start.elapsed()).unwrap_or_default();
+        info!(
+            "{}: {} bytes in {:.2}s, {:.2} Mbit/s",
+            peer,
+            received,
+            elapsed.as_secs_f64(),
+            megabits_per_sec(received, elapsed)
+        );
+        let mut report = received.to_le_bytes().to_vec();
+        report.extend_from_slice(&(elapsed.as_micros() as u64).to_le_bytes());
+        if stream.send(&report).await.is_ok() {
+            let _ = stream.close().await;
+        }
+    }
+}
+
+/// Render the results as a JSON object
+fn report(config: &KcpConfig, duration: Duration, size: usize, results: &[StreamResult]) -> String {
+    let mut json = String::new();
+    let total_received: u64 = results.iter().map(|r| r.bytes_received).sum();
+    let longest = results.iter().map(|r| r.elapsed).max().unwrap_or_default();
+
+    let _ = write!(
+        json,
+        "{{\"config\":{{\"mtu\":{},\"snd_wnd\":{},\"rcv_wnd\":{},\"nodelay\":{},\"interval\":{},\"resend\":{},\"nc\":{}}},",
+        config.mtu,
+        config.wnd_size.0,
+        config.wnd_size.1,
+        config.nodelay.nodelay,
+        config.nodelay.interval,
+        config.nodelay.resend,
+        config.nodelay.nc
+    );
+    let _ = write!(
+        json,
+        "\"duration_secs\":{},\"write_size\":{},\"parallel\":{},",
+        duration.as_secs_f64(),
+        size,
+        results.len()
+    );
+    let _ = write!(
+        json,
+        "\"total\":{{\"bytes_received\":{},\"throughput_mbps\":{:.3}}},\"streams\":[",
+        total_received,
+        megabits_per_sec(total_received, longest)
+    );
+
+    for (i, result) in results.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let stats = &result.stats;
+        let loss = if stats.segments_sent == 0 {
+            0.0
+        } else {
+            stats.retransmissions as f64 / stats.segments_sent as f64
+        };
+        let _ = write!(
+            json,
+            "{{\"bytes_sent\":{},\"bytes_received\":{},\"elapsed_secs\":{:.3},\"throughput_mbps\":{:.3},\
+             \"segments_sent\":{},\"retransmissions\":{},\"fast_retransmissions\":{},\
+             \"timeout_retransmissions\":{},\"loss\":{:.5},\"rtt_ms\":{},\"send_to_ack_ms\":{}}}",
+            result.bytes_sent,
+            result.bytes_received,
+            result.elapsed.as_secs_f64(),
+            megabits_per_sec(result.bytes_received, result.elapsed),
+            stats.segments_sent,
+            stats.retransmissions,
+            stats.fast_retransmissions,
+            stats.timeout_retransmissions,
+            loss,
+            summary_json(&result.rtt),
+            summary_json(&result.send_to_ack)
+        );
+    }
+    json.push_str("]}");
+    json
+}
+
+fn summary_json(summary: &LatencySummary) -> String {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    format!(
+        "{{\"count\":{},\"min\":{:.1},\"p50\":{:.1},\"p95\":{:.1},\"p99\":{:.1},\"max\":{:.1}}}",
+        summary.count,
+        ms(summary.min),
+        ms(summary.p50),
+        ms(summary.p95),
+        ms(summary.p99),
+        ms(summary.max)
+    )
+}
+
+fn megabits_per_sec(bytes: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1_000_000.0
+}
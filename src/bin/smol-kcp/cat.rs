@@ -0,0 +1,120 @@
+//! `smol-kcp cat`: netcat-like stdio pipe
+
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+    thread,
+    time::Duration,
+};
+
+use futures_lite::future::block_on;
+use log::{debug, info};
+use smol_kcp::{KcpListener, KcpMuxStream, KcpMuxer, KcpStream, MuxConfig};
+
+use crate::{args::Args, help, linger, spawn_muxer, CliError};
+
+const USAGE: &str = "\
+Usage: smol-kcp cat <addr> [KCP options]
+       smol-kcp cat --listen <addr> [KCP options]
+
+Copy stdin to the peer and everything the peer sends to stdout. End of
+input is forwarded to the peer, the command exits once both directions
+are finished.
+
+  sender$   smol-kcp cat --listen 0.0.0.0:9000 < firmware.bin
+  receiver$ smol-kcp cat 192.168.1.1:9000 < /dev/null > firmware.bin";
+
+const BUFFER_SIZE: usize = 32 * 1024;
+/// How long to wait for outstanding acknowledgements before exiting
+const LINGER: Duration = Duration::from_secs(5);
+
+pub fn run(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, USAGE) {
+        return Ok(());
+    }
+    let config = args.config()?;
+    let listen: Option<SocketAddr> = args.opt("--listen")?;
+
+    let (muxer, stream) = match listen {
+        Some(listen) => {
+            args.finish()?;
+            block_on(async {
+                let mut listener = KcpListener::bind(config, listen).await?;
+                info!("waiting for a connection on {}", listener.local_addr()?);
+                let (stream, peer) = listener.accept().await?;
+                info!("connection from {}", peer);
+
+                // The listener feeds its sessions while accepting
+                thread::spawn(move || {
+                    block_on(async {
+                        while let Ok((_, peer)) = listener.accept().await {
+                            debug!("ignoring connection from {}", peer);
+                        }
+                    })
+                });
+
+                let muxer = KcpMuxer::new(stream, MuxConfig::default(), false);
+                spawn_muxer(&muxer);
+                let stream = muxer.accept().await?;
+                Ok::<_, CliError>((muxer, stream))
+            })?
+        }
+        None => {
+            let addr: SocketAddr = args.positional("address")?;
+            args.finish()?;
+            block_on(async {
+                let stream = KcpStream::connect(&config, addr).await?;
+                let muxer = KcpMuxer::new(stream, MuxConfig::default(), true);
+                spawn_muxer(&muxer);
+                let stream = muxer.open().await?;
+                Ok::<_, CliError>((muxer, stream))
+            })?
+        }
+    };
+
+    pipe(stream)?;
+    // Make sure the peer got everything, including our end of input
+    block_on(linger(&muxer, LINGER));
+    Ok(())
+}
+
+/// Run both directions until each of them saw its end
+fn pipe(stream: KcpMuxStream) -> Result<(), CliError> {
+    let mut upstream = stream.clone();
+    let stdin_thread = thread::spawn(move || -> io::Result<()> {
+        block_on(async {
+            let mut stdin = io::stdin().lock();
+            let mut buf = vec![0u8; BUFFER_SIZE];
+            loop {
+                let n = stdin.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                upstream.send(&buf[..n]).await?;
+            }
+            // Half-close: the peer sees the end of input, we keep reading
+            upstream.close().await?;
+            Ok(())
+        })
+    });
+
+    let mut downstream = stream;
+    block_on(async {
+        let mut stdout = io::stdout().lock();
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let n = downstream.recv(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            stdout.write_all(&buf[..n])?;
+        }
+        stdout.flush()?;
+        Ok::<_, CliError>(())
+    })?;
+
+    stdin_thread
+        .join()
+        .map_err(|_| CliError::Failed("stdin thread panicked".to_string()))??;
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//! `smol-kcp client`: line based request/reply client
+
+use std::{
+    io::{self, BufRead, Write},
+    net::SocketAddr,
+};
+
+use futures_lite::future::block_on;
+use smol_kcp::KcpStream;
+
+use crate::{args::Args, help, CliError};
+
+const USAGE: &str = "\
+Usage: smol-kcp client <addr> [KCP options]
+
+Send every line read from stdin as one message and print the reply, e.g.
+to talk to `smol-kcp server`.";
+
+pub fn run(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, USAGE) {
+        return Ok(());
+    }
+    let config = args.config()?;
+    let addr: SocketAddr = args.positional("server address")?;
+    args.finish()?;
+
+    block_on(async {
+        let mut stream = KcpStream::connect(&config, addr).await?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut stdout = io::stdout();
+
+        for line in io::stdin().lock().lines() {
+            let line = line?;
+            stream.send(line.as_bytes()).await?;
+            let n = stream.recv(&mut buf).await?;
+            stdout.write_all(&buf[..n])?;
+            stdout.write_all(b"\n")?;
+            stdout.flush()?;
+        }
+        Ok(())
+    })
+}
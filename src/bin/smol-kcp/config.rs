@@ -0,0 +1,301 @@
+//! Configuration file and reload on SIGHUP
+//!
+//! The file is a small TOML subset: `[section]` headers and `key = value`
+//! lines with strings, numbers and booleans. `[kcp]` holds the KCP options
+//! of every command, a section named after the command holds its options,
+//! with `mode` standing for the `client`/`server` argument. `[daemon]`
+//! holds the global daemon options:
+//!
+//! ```toml
+//! [log]
+//! level = "info"
+//!
+//! [daemon]
+//! pidfile = "/var/run/smol-kcp.pid"
+//! log_syslog = true
+//!
+//! [kcp]
+//! preset = "wan"
+//!
+//! [tunnel]
+//! mode = "server"
+//! listen = "kcp://0.0.0.0:4000"
+//! target = "tcp://127.0.0.1:22"
+//! ```
+//!
+//! Command line options win over the file. On SIGHUP the file is read
+//! again: the log level changes right away, KCP options apply to sessions
+//! accepted afterwards. Listen addresses and targets need a restart.
+
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{atomic::Ordering, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use event_listener::Event;
+use futures_lite::future;
+use log::{debug, info, warn, LevelFilter};
+use smol_kcp::{KcpConfig, KcpListener, KcpResult, KcpStream};
+
+use crate::{args::Args, daemon, signal, CliError};
+
+/// Parsed configuration file
+#[derive(Debug, Clone, Default)]
+pub struct ConfigFile {
+    /// `(section, key, value)` in file order, values as written
+    entries: Vec<(String, String, Value)>,
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    String(String),
+    Bool(bool),
+    /// Numbers are kept as text and parsed by the option they feed
+    Number(String),
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, CliError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| CliError::Failed(format!("reading {}: {}", path.display(), e)))?;
+        text.parse()
+            .map_err(|e| CliError::Failed(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Log level from `[log] level`
+    pub fn log_level(&self) -> Result<Option<LevelFilter>, CliError> {
+        match self.get("log", "level") {
+            Some(Value::String(level)) => level
+                .parse()
+                .map(Some)
+                .map_err(|_| CliError::Failed(format!("invalid log level `{}`", level))),
+            Some(_) => Err(CliError::Failed("log level must be a string".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Add the options of `[kcp]`, `[daemon]` and `[command]` the command
+    /// line lacks
+    pub fn apply(&self, command: &str, args: &mut Args) {
+        for (section, key, value) in &self.entries {
+            if !matches!(section.as_str(), "kcp" | "daemon") && section != command {
+                continue;
+            }
+            if key == "mode" {
+                if let Value::String(mode) = value {
+                    args.default_positional(mode);
+                }
+                continue;
+            }
+            let name = format!("--{}", key.replace('_', "-"));
+            match value {
+                Value::Bool(true) => args.default_flag(&name),
+                Value::Bool(false) => {}
+                Value::String(value) | Value::Number(value) => args.default_opt(&name, value),
+            }
+        }
+    }
+
+    /// KCP configuration of `command` with the given command line
+    pub fn kcp_config(&self, command: &str, argv: Vec<String>) -> Result<KcpConfig, CliError> {
+        let mut args = Args::new(argv);
+        self.apply(command, &mut args);
+        args.config()
+    }
+
+    fn get(&self, section: &str, key: &str) -> Option<&Value> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(s, k, _)| s == section && k == key)
+            .map(|(_, _, value)| value)
+    }
+}
+
+impl FromStr for ConfigFile {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut file = Self::default();
+        let mut section = String::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |msg: &str| format!("line {}: {}", number + 1, msg);
+
+            if let Some(name) = line.strip_prefix('[') {
+                let name = name
+                    .strip_suffix(']')
+                    .ok_or_else(|| error("unterminated section header"))?;
+                section = name.trim().to_string();
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`"))?;
+            let key = key.trim();
+            if key.is_empty() {
+                return Err(error("missing key"));
+            }
+            let value = parse_value(value.trim()).ok_or_else(|| error("unsupported value"))?;
+            file.entries.push((section.clone(), key.to_string(), value));
+        }
+        Ok(file)
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    match value {
+        "true" => return Some(Value::Bool(true)),
+        "false" => return Some(Value::Bool(false)),
+        _ => {}
+    }
+    if let Some(string) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        return Some(Value::String(string.replace("\\\"", "\"").replace("\\\\", "\\")));
+    }
+    if let Some(string) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        return Some(Value::String(string.to_string()));
+    }
+    let number = value.replace('_', "");
+    number
+        .parse::<f64>()
+        .is_ok()
+        .then_some(Value::Number(number))
+}
+
+/// Set when the command runs with `--config`
+static RELOADER: OnceLock<Reloader> = OnceLock::new();
+
+/// Re-reads the configuration file on SIGHUP
+///
+/// A background thread polls for the signal, applies the log level and
+/// leaves the new KCP options for the accept loop, see [`accept`].
+struct Reloader {
+    pending: Mutex<Option<KcpConfig>>,
+    reloaded: Event,
+}
+
+/// Watch for SIGHUP and reload `path` for `command`
+///
+/// `argv` are the arguments of the command, they keep precedence over the
+/// file. The log level is only touched with `adjust_log_level`, i.e. when
+/// `RUST_LOG` does not decide it.
+pub fn watch(
+    path: PathBuf,
+    command: String,
+    argv: Vec<String>,
+    adjust_log_level: bool,
+) -> Result<(), CliError> {
+    let hangup = signal::on_hangup()?;
+    let reloader = RELOADER.get_or_init(|| Reloader {
+        pending: Mutex::new(None),
+        reloaded: Event::new(),
+    });
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(500));
+        if !hangup.swap(false, Ordering::AcqRel) {
+            continue;
+        }
+        info!("SIGHUP: reloading {}", path.display());
+        let file = match ConfigFile::load(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("keeping the old configuration: {}", e);
+                continue;
+            }
+        };
+        if adjust_log_level {
+            match file.log_level() {
+                Ok(level) => log::set_max_level(level.unwrap_or(LevelFilter::Error)),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        match file.kcp_config(&command, argv.clone()) {
+            Ok(config) => {
+                *reloader.pending.lock().unwrap() = Some(config);
+                reloader.reloaded.notify(usize::MAX);
+            }
+            Err(e) => warn!("keeping the old KCP options: {}", e),
+        }
+    });
+    Ok(())
+}
+
+/// Accept the next session, switching to reloaded KCP options first
+///
+/// While draining for shutdown, new sessions are dropped, accepting goes
+/// on to feed the established ones.
+pub async fn accept(listener: &mut KcpListener) -> KcpResult<(KcpStream, SocketAddr)> {
+    accept_with(listener, |_| {}).await
+}
+
+/// [`accept`] for commands that override options, `adjust` is applied to
+/// reloaded options
+pub async fn accept_with(
+    listener: &mut KcpListener,
+    adjust: impl Fn(&mut KcpConfig),
+) -> KcpResult<(KcpStream, SocketAddr)> {
+    loop {
+        let (stream, peer) = match RELOADER.get() {
+            Some(reloader) => reloader.accept(listener, &adjust).await?,
+            None => listener.accept().await?,
+        };
+        if daemon::draining() {
+            debug!("shutting down, ignoring new session from {}", peer);
+            continue;
+        }
+        return Ok((stream, peer));
+    }
+}
+
+impl Reloader {
+    async fn accept(
+        &self,
+        listener: &mut KcpListener,
+        adjust: &impl Fn(&mut KcpConfig),
+    ) -> KcpResult<(KcpStream, SocketAddr)> {
+        loop {
+            if let Some(mut config) = self.pending.lock().unwrap().take() {
+                adjust(&mut config);
+                info!("new sessions use the reloaded KCP options");
+                listener.set_config(config);
+            }
+            let reloaded = self.reloaded.listen();
+            if self.pending.lock().unwrap().is_some() {
+                continue;
+            }
+            // Interrupting accept only loses the datagram being handled,
+            // which the peer sends again
+            let accepted = future::or(async { Some(listener.accept().await) }, async {
+                reloaded.await;
+                None
+            })
+            .await;
+            if let Some(accepted) = accepted {
+                return accepted;
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! `smol-kcp conformance`: ikcp wire conformance checks
+
+use std::{net::SocketAddr, time::Duration};
+
+use futures_lite::future::block_on;
+use smol_kcp::conformance;
+
+use crate::{args::Args, help, CliError};
+
+const USAGE: &str = "\
+Usage: smol-kcp conformance [<addr>] [options]
+
+Replay the ikcp golden vectors through the local KCP engine and compare
+every byte. With an address, also play the scripted exchange against the
+peer, which has to run an echo service speaking plain KCP (`smol-kcp
+server`, kcp-go's echo example, an ikcp test server). Exits with 1 when a
+check fails.
+
+Options:
+  --timeout <secs>    Time the peer gets for each step [default: 2]";
+
+pub fn run(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, USAGE) {
+        return Ok(());
+    }
+    let timeout = Duration::from_secs_f64(args.opt("--timeout")?.unwrap_or(2.0));
+    let peer = args
+        .next_positional()
+        .map(|addr| {
+            addr.parse::<SocketAddr>()
+                .map_err(|_| CliError::Usage(format!("invalid peer address `{}`", addr)))
+        })
+        .transpose()?;
+    args.finish()?;
+
+    let mut report = conformance::verify_engine();
+    if let Some(peer) = peer {
+        let exchange = block_on(conformance::run_exchange(peer, timeout))?;
+        report.checks.extend(exchange.checks);
+    }
+    println!("{}", report);
+
+    if report.passed() {
+        Ok(())
+    } else {
+        Err(CliError::Failed("conformance checks failed".to_string()))
+    }
+}
@@ -0,0 +1,165 @@
+//! Running as a service: background mode, pidfile and SIGTERM drain
+//!
+//! procd and systemd keep the process in the foreground and only need the
+//! pidfile and a clean SIGTERM; `--daemon` is there for plain init scripts.
+//! On SIGTERM new sessions are refused while the established ones get
+//! `--drain-timeout` to finish, then the process exits.
+//!
+//! A procd init script for OpenWrt:
+//!
+//! ```sh
+//! start_service() {
+//!     procd_open_instance
+//!     procd_set_param command /usr/bin/smol-kcp tunnel --config /etc/smol-kcp.toml --log-syslog
+//!     procd_set_param pidfile /var/run/smol-kcp.pid
+//!     procd_set_param respawn
+//!     procd_close_instance
+//! }
+//!
+//! reload_service() {
+//!     procd_send_signal smol-kcp
+//! }
+//! ```
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{args::Args, signal, CliError};
+
+/// Sessions that must finish before shutting down
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Global service options
+pub struct Options {
+    /// Detach from the terminal
+    pub daemon: bool,
+    pub pidfile: Option<PathBuf>,
+    /// Log to syslog instead of stderr
+    pub syslog: bool,
+    pub drain_timeout: Duration,
+}
+
+impl Options {
+    pub fn parse(args: &mut Args) -> Result<Self, CliError> {
+        let pidfile = args
+            .opt::<PathBuf>("--pidfile")?
+            .map(|path| env::current_dir().map(|dir| dir.join(path)))
+            .transpose()?;
+        Ok(Self {
+            daemon: args.flag("--daemon"),
+            pidfile,
+            syslog: args.flag("--log-syslog"),
+            drain_timeout: Duration::from_secs_f64(args.opt("--drain-timeout")?.unwrap_or(10.0)),
+        })
+    }
+}
+
+/// Move to the background
+///
+/// Must run before any thread is started, only the calling thread
+/// survives the fork.
+#[cfg(unix)]
+pub fn daemonize() -> Result<(), CliError> {
+    use std::{fs::OpenOptions, io, os::fd::AsRawFd};
+
+    // SAFETY: no other threads exist yet, the child continues with a copy
+    // of this single-threaded process
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error().into()),
+        0 => {}
+        _ => process::exit(0),
+    }
+    // SAFETY: plain system calls without memory arguments
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    env::set_current_dir("/")?;
+
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for fd in 0..=2 {
+        // SAFETY: both descriptors are open, dup2 replaces the standard ones
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> Result<(), CliError> {
+    Err(CliError::Usage("--daemon is only supported on Unix".to_string()))
+}
+
+/// Write the pidfile and drain on SIGTERM
+pub fn start(options: &Options) -> Result<(), CliError> {
+    if let Some(pidfile) = &options.pidfile {
+        fs::write(pidfile, format!("{}\n", process::id()))
+            .map_err(|e| CliError::Failed(format!("writing {}: {}", pidfile.display(), e)))?;
+    }
+
+    let terminate = signal::on_terminate()?;
+    let pidfile = options.pidfile.clone();
+    let timeout = options.drain_timeout;
+    thread::spawn(move || {
+        while !terminate.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(200));
+        }
+        drain(timeout);
+        if let Some(pidfile) = pidfile {
+            let _ = fs::remove_file(pidfile);
+        }
+        process::exit(0);
+    });
+    Ok(())
+}
+
+fn drain(timeout: Duration) {
+    DRAINING.store(true, Ordering::Release);
+    let active = ACTIVE.load(Ordering::Acquire);
+    if active > 0 {
+        info!("SIGTERM: waiting for {} sessions to finish", active);
+    }
+
+    let start = Instant::now();
+    while ACTIVE.load(Ordering::Acquire) > 0 {
+        if start.elapsed() >= timeout {
+            warn!(
+                "shutting down with {} sessions still open",
+                ACTIVE.load(Ordering::Acquire)
+            );
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    info!("SIGTERM: shutting down");
+}
+
+/// Whether shutdown has started and new sessions should be refused
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Acquire)
+}
+
+/// Keeps shutdown waiting while alive
+pub struct Session(());
+
+impl Session {
+    pub fn begin() -> Self {
+        ACTIVE.fetch_add(1, Ordering::AcqRel);
+        Self(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::AcqRel);
+    }
+}
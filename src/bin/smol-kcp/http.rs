@@ -0,0 +1,301 @@
+//! HTTP stats endpoint
+//!
+//! `--stats-listen 127.0.0.1:9100` serves the statistics of the listeners
+//! and of every tracked session:
+//!
+//! - `GET /stats` as JSON, for LuCI pages
+//! - `GET /metrics` in the Prometheus text format, for Prometheus and
+//!   collectd
+//!
+//! One request is served at a time, which is plenty for scrapers and
+//! keeps the endpoint cheap on a router.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures_lite::future::block_on;
+use log::{debug, info};
+use smol_kcp::{KcpStats, KcpStream};
+
+/// Largest request head accepted
+const MAX_REQUEST: usize = 8 * 1024;
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    next_id: 0,
+    listeners: BTreeMap::new(),
+    sessions: BTreeMap::new(),
+});
+
+struct Registry {
+    next_id: u64,
+    /// Sessions accepted so far by local address
+    listeners: BTreeMap<SocketAddr, u64>,
+    sessions: BTreeMap<u64, Entry>,
+}
+
+struct Entry {
+    local: SocketAddr,
+    peer: SocketAddr,
+    accepted: bool,
+    started: Instant,
+    stream: KcpStream,
+}
+
+/// Keeps a session listed while alive
+pub struct Tracked(u64);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        REGISTRY.lock().unwrap().sessions.remove(&self.0);
+    }
+}
+
+/// List a session in the stats, `accepted` for sessions of a listener
+pub async fn track(stream: &KcpStream, accepted: bool) -> Tracked {
+    let local = stream
+        .local_addr()
+        .await
+        .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let peer = stream.peer_addr().await;
+
+    let mut registry = REGISTRY.lock().unwrap();
+    if accepted {
+        *registry.listeners.entry(local).or_default() += 1;
+    }
+    let id = registry.next_id;
+    registry.next_id += 1;
+    registry.sessions.insert(
+        id,
+        Entry {
+            local,
+            peer,
+            accepted,
+            started: Instant::now(),
+            stream: stream.clone_handle(),
+        },
+    );
+    Tracked(id)
+}
+
+/// Serve the endpoint on a background thread
+pub fn serve(addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("stats on http://{}/stats", listener.local_addr()?);
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let result = conn.and_then(handle);
+            if let Err(e) = result {
+                debug!("stats request failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(mut conn: TcpStream) -> io::Result<()> {
+    conn.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = conn.read(&mut buf)?;
+        if n == 0 || request.len() + n > MAX_REQUEST {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&request);
+    let mut parts = head.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/stats") => ("200 OK", "application/json", json(&snapshot())),
+        ("GET", "/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            prometheus(&snapshot()),
+        ),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_string()),
+    };
+    write!(
+        conn,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// Statistics of one session at the time of the request
+struct SessionSnapshot {
+    local: SocketAddr,
+    peer: SocketAddr,
+    accepted: bool,
+    uptime: Duration,
+    stats: KcpStats,
+}
+
+struct Snapshot {
+    listeners: Vec<(SocketAddr, u64)>,
+    sessions: Vec<SessionSnapshot>,
+}
+
+fn snapshot() -> Snapshot {
+    // Copy the handles out, collecting the stats locks every session
+    let (listeners, entries): (Vec<_>, Vec<_>) = {
+        let registry = REGISTRY.lock().unwrap();
+        (
+            registry.listeners.iter().map(|(a, n)| (*a, *n)).collect(),
+            registry
+                .sessions
+                .values()
+                .map(|e| (e.local, e.peer, e.accepted, e.started, e.stream.clone_handle()))
+                .collect(),
+        )
+    };
+
+    let sessions = entries
+        .into_iter()
+        .map(|(local, peer, accepted, started, stream)| SessionSnapshot {
+            local,
+            peer,
+            accepted,
+            uptime: started.elapsed(),
+            stats: block_on(stream.stats()),
+        })
+        .collect();
+    Snapshot {
+        listeners,
+        sessions,
+    }
+}
+
+fn json(snapshot: &Snapshot) -> String {
+    let mut json = String::from("{\"listeners\":[");
+    for (i, (local, accepted)) in snapshot.listeners.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let sessions: Vec<_> = snapshot
+            .sessions
+            .iter()
+            .filter(|s| s.accepted && s.local == *local)
+            .collect();
+        let _ = write!(
+            json,
+            "{{\"local\":\"{}\",\"sessions\":{},\"accepted\":{},\"bytes_sent\":{},\"bytes_received\":{}}}",
+            local,
+            sessions.len(),
+            accepted,
+            sessions.iter().map(|s| s.stats.bytes_sent).sum::<u64>(),
+            sessions.iter().map(|s| s.stats.bytes_received).sum::<u64>()
+        );
+    }
+    json.push_str("],\"sessions\":[");
+
+    for (i, session) in snapshot.sessions.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let stats = &session.stats;
+        let _ = write!(
+            json,
+            "{{\"local\":\"{}\",\"peer\":\"{}\",\"accepted\":{},\"uptime_secs\":{:.1},\
+             \"srtt_ms\":{},\"rttvar_ms\":{},\"rto_ms\":{},\"cwnd\":{},\"rmt_wnd\":{},\
+             \"snd_queue\":{},\"snd_buf\":{},\"rcv_queue\":{},\"rcv_buf\":{},\
+             \"bytes_sent\":{},\"bytes_received\":{},\"packets_sent\":{},\"packets_received\":{},\
+             \"retransmissions\":{}}}",
+            session.local,
+            session.peer,
+            session.accepted,
+            session.uptime.as_secs_f64(),
+            stats.srtt.as_millis(),
+            stats.rttvar.as_millis(),
+            stats.rto.as_millis(),
+            stats.cwnd,
+            stats.rmt_wnd,
+            stats.snd_queue_len,
+            stats.snd_buf_len,
+            stats.rcv_queue_len,
+            stats.rcv_buf_len,
+            stats.bytes_sent,
+            stats.bytes_received,
+            stats.packets_sent,
+            stats.packets_received,
+            stats.retransmissions
+        );
+    }
+    json.push_str("]}");
+    json
+}
+
+/// Name, type, help and value of a per-session metric
+type SessionMetric = (&'static str, &'static str, &'static str, fn(&KcpStats) -> f64);
+
+const SESSION_METRICS: [SessionMetric; 8] = [
+    ("kcp_bytes_sent_total", "counter", "UDP payload bytes sent", |s| s.bytes_sent as f64),
+    ("kcp_bytes_received_total", "counter", "UDP payload bytes received", |s| {
+        s.bytes_received as f64
+    }),
+    ("kcp_packets_sent_total", "counter", "Datagrams sent", |s| s.packets_sent as f64),
+    ("kcp_packets_received_total", "counter", "Datagrams received", |s| {
+        s.packets_received as f64
+    }),
+    ("kcp_retransmissions_total", "counter", "Data segments sent more than once", |s| {
+        s.retransmissions as f64
+    }),
+    ("kcp_srtt_seconds", "gauge", "Smoothed round trip time", |s| s.srtt.as_secs_f64()),
+    ("kcp_cwnd_segments", "gauge", "Congestion window", |s| s.cwnd as f64),
+    ("kcp_in_flight_segments", "gauge", "Segments waiting for acknowledgement", |s| {
+        s.snd_buf_len as f64
+    }),
+];
+
+/// Prometheus text format, metric names follow the `metrics` feature of
+/// the library
+fn prometheus(snapshot: &Snapshot) -> String {
+    let mut text = String::new();
+
+    text.push_str("# HELP kcp_sessions Active KCP sessions\n# TYPE kcp_sessions gauge\n");
+    for (local, _) in &snapshot.listeners {
+        let active = snapshot
+            .sessions
+            .iter()
+            .filter(|s| s.accepted && s.local == *local)
+            .count();
+        let _ = writeln!(text, "kcp_sessions{{local=\"{}\"}} {}", local, active);
+    }
+    text.push_str(
+        "# HELP kcp_sessions_accepted_total KCP sessions accepted\n\
+         # TYPE kcp_sessions_accepted_total counter\n",
+    );
+    for (local, accepted) in &snapshot.listeners {
+        let _ = writeln!(text, "kcp_sessions_accepted_total{{local=\"{}\"}} {}", local, accepted);
+    }
+
+    for (name, kind, help, value) in SESSION_METRICS {
+        let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for session in &snapshot.sessions {
+            let _ = writeln!(
+                text,
+                "{}{{local=\"{}\",peer=\"{}\"}} {}",
+                name,
+                session.local,
+                session.peer,
+                value(&session.stats)
+            );
+        }
+    }
+    text
+}
+
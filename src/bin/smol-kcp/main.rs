@@ -0,0 +1,258 @@
+//! `smol-kcp` command line tool
+
+mod args;
+mod bench;
+mod cat;
+mod client;
+mod config;
+mod conformance;
+mod daemon;
+mod http;
+mod probe;
+mod relay;
+mod server;
+mod signal;
+#[cfg(unix)]
+mod syslog;
+mod transfer;
+mod tunnel;
+#[cfg(all(feature = "vpn", target_os = "linux"))]
+mod vpn;
+
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use log::LevelFilter;
+
+use smol_kcp::KcpMuxer;
+
+use crate::args::Args;
+
+const USAGE: &str = "\
+Usage: smol-kcp <command> [options]
+
+Commands:
+  server   Run a KCP echo server
+  client   Send lines from stdin to a KCP server and print the replies
+  tunnel   Forward TCP connections over a multiplexed KCP connection
+  relay    Forward UDP datagrams over KCP
+  cat      Pipe stdin and stdout over KCP, like netcat
+  send     Send a file to `smol-kcp recv`, resuming interrupted transfers
+  recv     Receive files into a directory
+  bench    Measure throughput between two hosts
+  probe    Monitor RTT, jitter and loss of a link
+  conformance  Check ikcp wire conformance locally and against a peer
+  vpn      Layer 3 VPN between TUN interfaces (Linux, `vpn` feature)
+  help     Show this message
+
+Global options:
+  --config <file>          Read options from a TOML file, reloaded on SIGHUP
+  --daemon                 Run in the background
+  --pidfile <file>         Write the process id to a file
+  --log-syslog             Log to syslog instead of stderr
+  --drain-timeout <secs>   Time sessions get to finish on SIGTERM [default: 10]
+  --stats-listen <addr>    Serve /stats (JSON) and /metrics (Prometheus) over HTTP
+
+KCP options (all commands):
+  --preset <default|lan|wan|high-latency|low-bandwidth|kcp-go>
+  --interop <native|kcp-go|ikcp>
+                     Peer implementation, kcp-go and ikcp disable extensions,
+                     ikcp also drops malformed datagrams
+  --mtu <bytes>
+  --stream           Use KCP stream mode
+
+Run `smol-kcp <command> --help` for the options of a command.";
+
+/// Failure of a command, decides the exit code
+pub enum CliError {
+    /// Invalid command line, exit code 2
+    Usage(String),
+    /// The command failed, exit code 1
+    Failed(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usage(msg) | Self::Failed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        Self::Failed(e.to_string())
+    }
+}
+
+impl From<smol_kcp::Error> for CliError {
+    fn from(e: smol_kcp::Error) -> Self {
+        Self::Failed(e.to_string())
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = Args::new(std::env::args().skip(1));
+    let command = args.next_positional();
+    if command.is_none() && args.flag("--help") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    let result = setup(command.as_deref(), &mut args).and_then(|()| run(command, args));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError::Usage(msg)) => {
+            eprintln!("error: {}\n\n{}", msg, USAGE);
+            ExitCode::from(2)
+        }
+        Err(CliError::Failed(msg)) => {
+            eprintln!("error: {}", msg);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Apply `--config`, then set up the process and logging for `command`
+fn setup(command: Option<&str>, args: &mut Args) -> Result<(), CliError> {
+    let path = args
+        .opt::<PathBuf>("--config")?
+        .map(|path| {
+            // Reloads must find the file after `--daemon` changed directory
+            std::fs::canonicalize(&path)
+                .map_err(|e| CliError::Failed(format!("reading {}: {}", path.display(), e)))
+        })
+        .transpose()?;
+    let file = path.as_deref().map(config::ConfigFile::load).transpose()?;
+    let argv = args.to_vec();
+    if let (Some(file), Some(command)) = (&file, command) {
+        file.apply(command, args);
+    }
+
+    let options = daemon::Options::parse(args)?;
+    let stats_listen: Option<SocketAddr> = args.opt("--stats-listen")?;
+    if options.daemon {
+        daemon::daemonize()?;
+    }
+
+    // RUST_LOG wins, otherwise the file decides and may change it later
+    let file_level = file.as_ref().map(|file| file.log_level()).transpose()?.flatten();
+    let adjust_log_level = file.is_some() && std::env::var_os("RUST_LOG").is_none();
+    init_logging(options.syslog, adjust_log_level, file_level)?;
+
+    daemon::start(&options)?;
+    if let Some(addr) = stats_listen {
+        http::serve(addr)?;
+    }
+    match (path, command) {
+        (Some(path), Some(command)) => {
+            config::watch(path, command.to_string(), argv, adjust_log_level)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Log to stderr or syslog
+///
+/// With `adjustable`, `level` is used instead of `RUST_LOG`: everything is
+/// passed to the backend and filtered by [`log::set_max_level`], which can
+/// be changed later.
+fn init_logging(syslog: bool, adjustable: bool, level: Option<LevelFilter>) -> Result<(), CliError> {
+    let env_level = || {
+        std::env::var("RUST_LOG")
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(LevelFilter::Error)
+    };
+
+    if syslog {
+        #[cfg(unix)]
+        {
+            let logger = syslog::SyslogLogger::new(syslog::Syslog::connect("smol-kcp")?);
+            log::set_boxed_logger(Box::new(logger))
+                .map_err(|e| CliError::Failed(e.to_string()))?;
+            let level = if adjustable { level } else { None };
+            log::set_max_level(level.unwrap_or_else(env_level));
+            return Ok(());
+        }
+        #[cfg(not(unix))]
+        return Err(CliError::Usage("--log-syslog is only supported on Unix".to_string()));
+    }
+
+    if adjustable {
+        env_logger::Builder::new()
+            .filter_level(LevelFilter::Trace)
+            .init();
+        log::set_max_level(level.unwrap_or(LevelFilter::Error));
+    } else {
+        env_logger::init();
+    }
+    Ok(())
+}
+
+fn run(command: Option<String>, args: Args) -> Result<(), CliError> {
+    match command.as_deref() {
+        Some("server") => server::run(args),
+        Some("client") => client::run(args),
+        Some("tunnel") => tunnel::run(args),
+        Some("relay") => relay::run(args),
+        Some("cat") => cat::run(args),
+        Some("send") => transfer::send(args),
+        Some("recv") => transfer::recv(args),
+        Some("bench") => bench::run(args),
+        Some("probe") => probe::run(args),
+        Some("conformance") => conformance::run(args),
+        #[cfg(all(feature = "vpn", target_os = "linux"))]
+        Some("vpn") => vpn::run(args),
+        #[cfg(not(all(feature = "vpn", target_os = "linux")))]
+        Some("vpn") => Err(CliError::Usage(
+            "`vpn` needs Linux and a build with the `vpn` feature".to_string(),
+        )),
+        Some("help") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Some(other) => Err(CliError::Usage(format!("unknown command `{}`", other))),
+        None => Err(CliError::Usage("missing command".to_string())),
+    }
+}
+
+/// Print the usage of a command and stop if `--help` was given
+fn help(args: &mut Args, usage: &str) -> bool {
+    if args.flag("--help") {
+        println!("{}", usage);
+        return true;
+    }
+    false
+}
+
+/// Wait until everything sent on the connection was acknowledged
+///
+/// Gives up after `timeout`, e.g. when the peer is gone.
+async fn linger(muxer: &KcpMuxer, timeout: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        let stats = muxer.connection().stats().await;
+        if stats.snd_queue_len + stats.snd_buf_len == 0 {
+            return;
+        }
+        Timer::after(Duration::from_millis(20)).await;
+    }
+}
+
+/// Process the frames of a multiplexer on a background thread
+fn spawn_muxer(muxer: &KcpMuxer) {
+    let muxer = muxer.clone();
+    thread::spawn(move || {
+        if let Err(e) = futures_lite::future::block_on(muxer.run()) {
+            log::debug!("multiplexer stopped: {}", e);
+        }
+    });
+}
@@ -0,0 +1,162 @@
+//! `smol-kcp probe`: link quality monitor
+
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use futures_lite::future::{self, block_on};
+use smol_kcp::{KcpStats, KcpStream};
+
+use crate::{args::Args, help, CliError};
+
+const USAGE: &str = "\
+Usage: smol-kcp probe <addr> [options] [KCP options]
+
+Keep a low-rate KCP session to a `smol-kcp server` open and print one
+line per interval with the probe RTT, KCP's smoothed RTT and jitter and
+the loss estimated from retransmissions:
+
+  status=ok rtt_ms=23.1 srtt_ms=22 jitter_ms=1.4 loss_pct=0.0
+
+`status` turns to `degraded` when a threshold is exceeded or a probe
+times out, which lets a watchdog script switch uplinks.
+
+Options:
+  --interval <secs>    Time between probes [default: 1]
+  --timeout <secs>     Probe timeout [default: 5]
+  --max-rtt <ms>       Degraded above this RTT
+  --max-loss <pct>     Degraded above this loss
+  --syslog             Report to syslog instead of stdout";
+
+/// Probe message: sequence number and padding up to a small datagram
+const PROBE_SIZE: usize = 64;
+
+pub fn run(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, USAGE) {
+        return Ok(());
+    }
+    let config = args.config()?;
+    let interval = Duration::from_secs_f64(args.opt("--interval")?.unwrap_or(1.0));
+    let timeout = Duration::from_secs_f64(args.opt("--timeout")?.unwrap_or(5.0));
+    let max_rtt: Option<f64> = args.opt("--max-rtt")?;
+    let max_loss: Option<f64> = args.opt("--max-loss")?;
+    let syslog = args.flag("--syslog");
+    let addr: SocketAddr = args.positional("server address")?;
+    args.finish()?;
+
+    let mut output = Output::new(syslog)?;
+
+    block_on(async {
+        let mut stream = KcpStream::connect(&config, addr).await?;
+        let mut previous = stream.stats().await;
+        let mut buf = vec![0u8; PROBE_SIZE];
+
+        for seq in 0u64.. {
+            let next = Instant::now() + interval;
+            let mut probe = vec![0u8; PROBE_SIZE];
+            probe[..8].copy_from_slice(&seq.to_le_bytes());
+
+            let start = Instant::now();
+            stream.send(&probe).await?;
+            let answered = recv_probe(&mut stream, &mut buf, seq, timeout).await?;
+
+            let stats = stream.stats().await;
+            let estimate = stream.rtt().await;
+            let loss = loss_pct(&previous, &stats);
+            previous = stats;
+
+            let rtt_ms = answered.then(|| start.elapsed().as_secs_f64() * 1000.0);
+            let degraded = match rtt_ms {
+                None => true,
+                Some(rtt_ms) => {
+                    max_rtt.is_some_and(|max| rtt_ms > max) || max_loss.is_some_and(|max| loss > max)
+                }
+            };
+            let line = format!(
+                "status={} rtt_ms={} srtt_ms={} jitter_ms={:.1} loss_pct={:.1}",
+                if degraded { "degraded" } else { "ok" },
+                rtt_ms.map_or("timeout".to_string(), |ms| format!("{:.1}", ms)),
+                estimate.srtt.as_millis(),
+                estimate.jitter.as_secs_f64() * 1000.0,
+                loss
+            );
+            output.report(&line, degraded)?;
+
+            Timer::at(next).await;
+        }
+        Ok(())
+    })
+}
+
+/// Wait for the echo of probe `seq`, `false` on timeout
+///
+/// Late echoes of earlier probes are skipped.
+async fn recv_probe(
+    stream: &mut KcpStream,
+    buf: &mut [u8],
+    seq: u64,
+    timeout: Duration,
+) -> Result<bool, CliError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let received = future::or(async { Some(stream.recv(buf).await) }, async {
+            Timer::at(deadline).await;
+            None
+        })
+        .await;
+        match received {
+            None => return Ok(false),
+            Some(Err(e)) => return Err(e.into()),
+            Some(Ok(n)) if n >= 8 && buf[..8] == seq.to_le_bytes() => return Ok(true),
+            Some(Ok(_)) => continue,
+        }
+    }
+}
+
+/// Share of data segments sent again since the previous sample, in percent
+fn loss_pct(previous: &KcpStats, current: &KcpStats) -> f64 {
+    let segments = current.segments_sent.saturating_sub(previous.segments_sent);
+    let retransmissions = current.retransmissions.saturating_sub(previous.retransmissions);
+    if segments == 0 {
+        return 0.0;
+    }
+    retransmissions as f64 * 100.0 / segments as f64
+}
+
+/// Where reports go
+enum Output {
+    Stdout(io::Stdout),
+    #[cfg(unix)]
+    Syslog(crate::syslog::Syslog),
+}
+
+impl Output {
+    fn new(syslog: bool) -> Result<Self, CliError> {
+        if !syslog {
+            return Ok(Self::Stdout(io::stdout()));
+        }
+        #[cfg(unix)]
+        return Ok(Self::Syslog(crate::syslog::Syslog::connect("smol-kcp-probe")?));
+        #[cfg(not(unix))]
+        Err(CliError::Usage("--syslog is only supported on Unix".to_string()))
+    }
+
+    fn report(&mut self, line: &str, degraded: bool) -> io::Result<()> {
+        match self {
+            Self::Stdout(stdout) => {
+                let mut stdout = stdout.lock();
+                writeln!(stdout, "{}", line)?;
+                stdout.flush()
+            }
+            #[cfg(unix)]
+            Self::Syslog(syslog) => {
+                use crate::syslog::Severity;
+                let severity = if degraded { Severity::Warning } else { Severity::Info };
+                syslog.send(severity, line)
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! `smol-kcp relay`: UDP datagrams over KCP
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use async_io::Timer;
+use futures_lite::future::{self, block_on};
+use log::{debug, info, warn};
+use smol_kcp::{
+    proxy_protocol::{self, Transport},
+    KcpConfig, KcpListener, KcpStream,
+};
+
+use crate::{args::{Args, Remote}, config, daemon, help, http, CliError};
+
+const USAGE: &str = "\
+Usage: smol-kcp relay client --listen udp://<addr> --remote kcp://<addr> [options]
+       smol-kcp relay server --listen kcp://<addr> --target udp://<addr> [options]
+
+Forward UDP datagrams (e.g. WireGuard or game traffic) reliably over KCP.
+Every local UDP peer of the client gets its own KCP connection, every
+datagram is carried as one KCP message. The host name of --remote is
+resolved again for every new connection, so servers on a dynamic IP are
+found.
+
+Options:
+  --idle-timeout <secs>   Close flows without traffic [default: 120]
+  --proxy-protocol        Server: start every datagram to the target with a
+                          PROXY protocol v2 header carrying the client address";
+
+/// Largest UDP payload
+const MAX_DATAGRAM: usize = 65535;
+
+pub fn run(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, USAGE) {
+        return Ok(());
+    }
+    let side = args.next_positional();
+    let mut config = args.config()?;
+    // Datagram boundaries must survive the trip
+    config.stream = false;
+    let idle = Duration::from_secs(args.opt("--idle-timeout")?.unwrap_or(120));
+
+    match side.as_deref() {
+        Some("client") => {
+            let listen = args.endpoint("--listen", "udp")?;
+            let remote = args.remote("--remote", "kcp")?;
+            args.finish()?;
+            client(config, listen, remote, idle)
+        }
+        Some("server") => {
+            let listen = args.endpoint("--listen", "kcp")?;
+            let target = args.endpoint("--target", "udp")?;
+            let proxy_protocol = args.flag("--proxy-protocol");
+            args.finish()?;
+            server(config, listen, target, idle, proxy_protocol)
+        }
+        _ => Err(CliError::Usage("expected `relay client` or `relay server`".to_string())),
+    }
+}
+
+fn client(
+    config: KcpConfig,
+    listen: SocketAddr,
+    mut remote: Remote,
+    idle: Duration,
+) -> Result<(), CliError> {
+    let udp = UdpSocket::bind(listen)?;
+    info!("relay udp://{} -> kcp://{}", udp.local_addr()?, remote);
+
+    let flows: Arc<Mutex<HashMap<SocketAddr, KcpStream>>> = Default::default();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (n, peer) = udp.recv_from(&mut buf)?;
+
+        let existing = flows.lock().unwrap().get(&peer).map(KcpStream::clone_handle);
+        let mut stream = match existing {
+            Some(stream) => stream,
+            None => {
+                let stream = match block_on(KcpStream::connect(&config, remote.resolve())) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("connecting to {} failed: {}", remote, e);
+                        continue;
+                    }
+                };
+                debug!("new flow from {}", peer);
+                flows.lock().unwrap().insert(peer, stream.clone_handle());
+
+                let replies = udp.try_clone()?;
+                let receiver = stream.clone_handle();
+                let flows = flows.clone();
+                thread::spawn(move || {
+                    let _tracked = block_on(http::track(&receiver, false));
+                    let result = block_on(forward_to_udp(receiver, idle, |datagram| {
+                        replies.send_to(datagram, peer).map(drop)
+                    }));
+                    if let Err(e) = result {
+                        debug!("flow from {} ended: {}", peer, e);
+                    }
+                    flows.lock().unwrap().remove(&peer);
+                });
+                stream
+            }
+        };
+
+        if let Err(e) = block_on(stream.send(&buf[..n])) {
+            warn!("relaying datagram from {} failed: {}", peer, e);
+        }
+    }
+}
+
+fn server(
+    config: KcpConfig,
+    listen: SocketAddr,
+    target: SocketAddr,
+    idle: Duration,
+    proxy_protocol: bool,
+) -> Result<(), CliError> {
+    block_on(async {
+        let mut listener = KcpListener::bind(config, listen).await?;
+        let local = listener.local_addr()?;
+        info!("relay kcp://{} -> udp://{}", local, target);
+
+        loop {
+            // Reloaded options keep the forced message mode
+            let (stream, peer) = config::accept_with(&mut listener, |config| config.stream = false).await?;
+            debug!("new flow from {}", peer);
+            let header = proxy_protocol.then(|| proxy_protocol::encode(peer, local, Transport::Datagram));
+            if let Err(e) = serve_flow(stream, target, idle, header) {
+                warn!("relaying flow from {} failed: {}", peer, e);
+            }
+        }
+    })
+}
+
+/// Connect a KCP flow to the target through its own UDP socket, starting
+/// every datagram with `header`
+fn serve_flow(
+    stream: KcpStream,
+    target: SocketAddr,
+    idle: Duration,
+    header: Option<Vec<u8>>,
+) -> io::Result<()> {
+    let bind = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let udp = UdpSocket::bind(bind)?;
+    udp.connect(target)?;
+    udp.set_read_timeout(Some(idle))?;
+
+    let replies = udp.try_clone()?;
+    let mut sender = stream.clone_handle();
+    let done = Arc::new(AtomicBool::new(false));
+    let finished = done.clone();
+    thread::spawn(move || {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        while !finished.load(Ordering::Relaxed) {
+            let n = match replies.recv(&mut buf) {
+                Ok(n) => n,
+                // Read timeout, check whether the flow is still alive
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(_) => break,
+            };
+            if block_on(sender.send(&buf[..n])).is_err() {
+                break;
+            }
+        }
+    });
+
+    let session = daemon::Session::begin();
+    thread::spawn(move || {
+        let _session = session;
+        let _tracked = block_on(http::track(&stream, true));
+        let _ = block_on(forward_to_udp(stream, idle, |datagram| {
+            match &header {
+                Some(header) => udp.send(&[header, datagram].concat()),
+                None => udp.send(datagram),
+            }
+            .map(drop)
+        }));
+        done.store(true, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+/// Deliver the messages of a KCP stream as datagrams until it is idle
+async fn forward_to_udp<F>(mut stream: KcpStream, idle: Duration, mut send: F) -> io::Result<()>
+where
+    F: FnMut(&[u8]) -> io::Result<()>,
+{
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let received = future::or(async { Some(stream.recv(&mut buf).await) }, async {
+            Timer::after(idle).await;
+            None
+        })
+        .await;
+
+        match received {
+            Some(Ok(n)) => send(&buf[..n])?,
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(io::Error::new(io::ErrorKind::TimedOut, "idle")),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! `smol-kcp server`: echo server
+
+use std::{net::SocketAddr, thread};
+
+use futures_lite::future::block_on;
+use log::{info, warn};
+use smol_kcp::{KcpListener, KcpStream};
+
+use crate::{args::Args, config, daemon, help, http, CliError};
+
+const USAGE: &str = "\
+Usage: smol-kcp server [--listen <addr>] [KCP options]
+
+Echo every message received back to its sender.
+
+Options:
+  --listen <addr>    Address to listen on [default: 0.0.0.0:8080]";
+
+pub fn run(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, USAGE) {
+        return Ok(());
+    }
+    let listen = args.addr("--listen", "0.0.0.0:8080")?;
+    let config = args.config()?;
+    args.finish()?;
+
+    block_on(async {
+        let mut listener = KcpListener::bind(config, listen).await?;
+        info!("echo server listening on {}", listener.local_addr()?);
+
+        loop {
+            let (stream, peer) = config::accept(&mut listener).await?;
+            info!("accepted connection from {}", peer);
+            let session = daemon::Session::begin();
+            let tracked = http::track(&stream, true).await;
+            thread::spawn(move || {
+                block_on(echo(stream, peer));
+                drop((session, tracked));
+            });
+        }
+    })
+}
+
+async fn echo(mut stream: KcpStream, peer: SocketAddr) {
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = match stream.recv(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                warn!("receive from {} failed: {}", peer, e);
+                break;
+            }
+        };
+        if let Err(e) = stream.send(&buf[..n]).await {
+            warn!("send to {} failed: {}", peer, e);
+            break;
+        }
+    }
+    info!("connection from {} closed", peer);
+}
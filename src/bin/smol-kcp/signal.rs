@@ -0,0 +1,50 @@
+//! Signal flags polled by background threads
+//!
+//! The handlers only raise an atomic flag, everything else happens on a
+//! normal thread.
+
+use std::sync::atomic::AtomicBool;
+
+use crate::CliError;
+
+static HANGUP: AtomicBool = AtomicBool::new(false);
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+/// Flag raised by every SIGHUP
+pub fn on_hangup() -> Result<&'static AtomicBool, CliError> {
+    #[cfg(unix)]
+    unix::install(libc::SIGHUP, unix::hangup)?;
+    Ok(&HANGUP)
+}
+
+/// Flag raised by SIGTERM
+pub fn on_terminate() -> Result<&'static AtomicBool, CliError> {
+    #[cfg(unix)]
+    unix::install(libc::SIGTERM, unix::terminate)?;
+    Ok(&TERMINATE)
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{io, sync::atomic::Ordering};
+
+    use super::{HANGUP, TERMINATE};
+
+    pub extern "C" fn hangup(_: libc::c_int) {
+        HANGUP.store(true, Ordering::Release);
+    }
+
+    pub extern "C" fn terminate(_: libc::c_int) {
+        TERMINATE.store(true, Ordering::Release);
+    }
+
+    pub fn install(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) -> io::Result<()> {
+        // SAFETY: the handlers only store to an atomic, which is
+        // async-signal-safe
+        let previous = unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+        if previous == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
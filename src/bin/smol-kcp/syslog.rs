@@ -0,0 +1,73 @@
+//! Minimal syslog client over the local `/dev/log` socket
+
+use std::{io, os::unix::net::UnixDatagram, process};
+
+use log::{Level, Log, Metadata, Record};
+
+/// Syslog severities used by the CLI
+#[derive(Debug, Clone, Copy)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+/// `LOG_DAEMON` facility
+const FACILITY_DAEMON: u8 = 3;
+
+pub struct Syslog {
+    socket: UnixDatagram,
+    ident: String,
+}
+
+impl Syslog {
+    /// Connect to the local syslog daemon (`logd` on OpenWrt)
+    pub fn connect(ident: &str) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect("/dev/log")?;
+        Ok(Self {
+            socket,
+            ident: ident.to_string(),
+        })
+    }
+
+    /// Send one message, the daemon adds the timestamp
+    pub fn send(&self, severity: Severity, msg: &str) -> io::Result<()> {
+        let priority = FACILITY_DAEMON * 8 + severity as u8;
+        let line = format!("<{}>{}[{}]: {}", priority, self.ident, process::id(), msg);
+        self.socket.send(line.as_bytes()).map(drop)
+    }
+}
+
+/// `log` backend writing `target: message` records to syslog
+pub struct SyslogLogger(Syslog);
+
+impl SyslogLogger {
+    pub fn new(syslog: Syslog) -> Self {
+        Self(syslog)
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let severity = match record.level() {
+            Level::Error => Severity::Error,
+            Level::Warn => Severity::Warning,
+            Level::Info => Severity::Info,
+            Level::Debug | Level::Trace => Severity::Debug,
+        };
+        let msg = format!("{}: {}", record.target(), record.args());
+        // Nowhere left to report a failing syslog
+        let _ = self.0.send(severity, &msg);
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,432 @@
+//! `smol-kcp send` and `smol-kcp recv`: file transfer with resume
+//!
+//! Every transfer is one multiplexed stream. All integers are little
+//! endian.
+//!
+//! ```text
+//! send -> recv  OFFER   "SKF1" size:u64 name_len:u16 name
+//! recv -> send  RESUME  offset:u64 prefix_crc:u32
+//! send -> recv  START   offset:u64
+//! send -> recv  CHUNK   len:u32 crc:u32 data       (repeated)
+//! send -> recv  END     len=0:u32 file_crc:u32
+//! recv -> send  RESULT  status:u8 msg_len:u16 msg
+//! ```
+//!
+//! The receiver writes to `<name>.part` and offers its length for resume
+//! together with the CRC-32 of what it has. The sender starts over at 0
+//! when that prefix differs from its file. Once the CRC-32 of the whole
+//! file matches, the part file is renamed to its final name.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures_lite::future::block_on;
+use log::{info, warn};
+use smol_kcp::{Error, KcpListener, KcpMuxStream, KcpMuxer, KcpStream, MuxConfig};
+
+use crate::{args::Args, config, daemon, help, linger, spawn_muxer, CliError};
+
+const SEND_USAGE: &str = "\
+Usage: smol-kcp send <file> <addr> [options] [KCP options]
+
+Send a file to a `smol-kcp recv`. An interrupted transfer continues where
+it stopped when the same file is sent again.
+
+Options:
+  --name <name>       Name to store the file under [default: file name]
+  --chunk <bytes>     Chunk size [default: 32768]";
+
+const RECV_USAGE: &str = "\
+Usage: smol-kcp recv <dir> [options] [KCP options]
+
+Receive files from `smol-kcp send` into a directory. Partial files are
+kept as `<name>.part` until complete, so a new `send` resumes them.
+
+Options:
+  --listen <addr>     Address to listen on [default: 0.0.0.0:9002]
+  --once              Exit after the first transfer";
+
+const MAGIC: &[u8; 4] = b"SKF1";
+const STATUS_OK: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+/// Largest chunk a receiver accepts
+const MAX_CHUNK: usize = 1024 * 1024;
+/// How long to wait for outstanding acknowledgements before exiting
+const LINGER: Duration = Duration::from_secs(5);
+
+pub fn send(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, SEND_USAGE) {
+        return Ok(());
+    }
+    let config = args.config()?;
+    let name: Option<String> = args.opt("--name")?;
+    let chunk: usize = args.opt("--chunk")?.unwrap_or(32 * 1024);
+    let path: PathBuf = args.positional("file")?;
+    let addr: SocketAddr = args.positional("receiver address")?;
+    args.finish()?;
+    if chunk == 0 || chunk > MAX_CHUNK {
+        return Err(CliError::Usage(format!("--chunk must be 1 to {}", MAX_CHUNK)));
+    }
+
+    let name = match name {
+        Some(name) => name,
+        None => path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| CliError::Usage(format!("no file name in {}", path.display())))?
+            .to_string(),
+    };
+    let mut file = File::open(&path)
+        .map_err(|e| CliError::Failed(format!("opening {}: {}", path.display(), e)))?;
+    let size = file.metadata()?.len();
+
+    block_on(async {
+        let stream = KcpStream::connect(&config, addr).await?;
+        let muxer = KcpMuxer::new(stream, MuxConfig::default(), true);
+        spawn_muxer(&muxer);
+        let mut stream = muxer.open().await?;
+
+        let start = Instant::now();
+        let offset = offer(&mut stream, &mut file, &name, size).await?;
+        if offset > 0 {
+            info!("resuming {} at byte {}", name, offset);
+        }
+        let file_crc = send_chunks(&mut stream, &mut file, offset, chunk).await?;
+        stream.close().await?;
+
+        let result = read_result(&mut stream).await;
+        linger(&muxer, LINGER).await;
+        result?;
+
+        eprintln!(
+            "sent {} ({} bytes, {} new) in {:.1}s, crc32 {:08x}",
+            name,
+            size,
+            size - offset,
+            start.elapsed().as_secs_f64(),
+            file_crc
+        );
+        Ok(())
+    })
+}
+
+/// Offer the file and agree on where to start
+async fn offer(
+    stream: &mut KcpMuxStream,
+    file: &mut File,
+    name: &str,
+    size: u64,
+) -> Result<u64, CliError> {
+    let mut msg = MAGIC.to_vec();
+    msg.extend_from_slice(&size.to_le_bytes());
+    msg.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    msg.extend_from_slice(name.as_bytes());
+    stream.send(&msg).await?;
+
+    let mut resume = [0u8; 12];
+    read_exact(stream, &mut resume).await?;
+    let offset = u64::from_le_bytes(resume[..8].try_into().unwrap());
+    let prefix_crc = u32::from_le_bytes(resume[8..].try_into().unwrap());
+
+    // Only resume when the receiver holds the same beginning
+    let offset = if offset > 0 && offset <= size && file_crc(file, offset)? == prefix_crc {
+        offset
+    } else {
+        0
+    };
+    stream.send(&offset.to_le_bytes()).await?;
+    Ok(offset)
+}
+
+/// Send the file from `offset`, returns the CRC-32 of the whole file
+async fn send_chunks(
+    stream: &mut KcpMuxStream,
+    file: &mut File,
+    offset: u64,
+    chunk: usize,
+) -> Result<u32, CliError> {
+    let mut crc = Crc32::new();
+    let mut buf = vec![0u8; chunk];
+
+    // The resumed prefix only counts towards the checksum
+    file.seek(SeekFrom::Start(0))?;
+    let mut remaining = offset;
+    while remaining > 0 {
+        let n = file.read(&mut buf[..chunk.min(remaining as usize)])?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buf[..n]);
+        remaining -= n as u64;
+    }
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buf[..n]);
+        let mut header = (n as u32).to_le_bytes().to_vec();
+        header.extend_from_slice(&Crc32::checksum(&buf[..n]).to_le_bytes());
+        stream.send(&header).await?;
+        stream.send(&buf[..n]).await?;
+    }
+
+    let crc = crc.finish();
+    let mut end = 0u32.to_le_bytes().to_vec();
+    end.extend_from_slice(&crc.to_le_bytes());
+    stream.send(&end).await?;
+    Ok(crc)
+}
+
+async fn read_result(stream: &mut KcpMuxStream) -> Result<(), CliError> {
+    let mut head = [0u8; 3];
+    read_exact(stream, &mut head).await?;
+    let mut msg = vec![0u8; u16::from_le_bytes([head[1], head[2]]) as usize];
+    read_exact(stream, &mut msg).await?;
+    match head[0] {
+        STATUS_OK => Ok(()),
+        _ => Err(CliError::Failed(format!(
+            "receiver: {}",
+            String::from_utf8_lossy(&msg)
+        ))),
+    }
+}
+
+pub fn recv(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, RECV_USAGE) {
+        return Ok(());
+    }
+    let config = args.config()?;
+    let listen = args.addr("--listen", "0.0.0.0:9002")?;
+    let once = args.flag("--once");
+    let dir: PathBuf = args.positional("directory")?;
+    args.finish()?;
+    if !dir.is_dir() {
+        return Err(CliError::Usage(format!("{} is not a directory", dir.display())));
+    }
+
+    block_on(async {
+        let mut listener = KcpListener::bind(config, listen).await?;
+        info!("receiving into {} on {}", dir.display(), listener.local_addr()?);
+
+        loop {
+            let (stream, peer) = config::accept(&mut listener).await?;
+            info!("connection from {}", peer);
+            let muxer = KcpMuxer::new(stream, MuxConfig::default(), false);
+            spawn_muxer(&muxer);
+
+            if once {
+                // Keep feeding the session while the transfer runs
+                thread::spawn(move || {
+                    block_on(async { while listener.accept().await.is_ok() {} })
+                });
+                let stream = muxer.accept().await?;
+                let result = receive(stream, &dir).await;
+                linger(&muxer, LINGER).await;
+                return result;
+            }
+
+            let dir = dir.clone();
+            let session = daemon::Session::begin();
+            thread::spawn(move || {
+                block_on(async {
+                    while let Ok(stream) = muxer.accept().await {
+                        if let Err(e) = receive(stream, &dir).await {
+                            warn!("transfer from {} failed: {}", peer, e);
+                        }
+                    }
+                });
+                drop(session);
+            });
+        }
+    })
+}
+
+/// Receive one file and report the outcome to the sender
+async fn receive(mut stream: KcpMuxStream, dir: &Path) -> Result<(), CliError> {
+    let result = receive_file(&mut stream, dir).await;
+    let (status, msg) = match &result {
+        Ok(_) => (STATUS_OK, String::new()),
+        Err(e) => (STATUS_FAILED, e.to_string()),
+    };
+    let mut reply = vec![status];
+    reply.extend_from_slice(&(msg.len() as u16).to_le_bytes());
+    reply.extend_from_slice(msg.as_bytes());
+    // The sender may be gone already, the outcome is logged either way
+    if stream.send(&reply).await.is_ok() {
+        let _ = stream.close().await;
+    }
+
+    let (name, size, offset) = result?;
+    info!("received {} ({} bytes, resumed at {})", name, size, offset);
+    Ok(())
+}
+
+/// Returns the file name, its size and the resume offset
+async fn receive_file(
+    stream: &mut KcpMuxStream,
+    dir: &Path,
+) -> Result<(String, u64, u64), CliError> {
+    let mut head = [0u8; 14];
+    read_exact(stream, &mut head).await?;
+    if &head[..4] != MAGIC {
+        return Err(CliError::Failed("not a file transfer".to_string()));
+    }
+    let size = u64::from_le_bytes(head[4..12].try_into().unwrap());
+    let mut name = vec![0u8; u16::from_le_bytes([head[12], head[13]]) as usize];
+    read_exact(stream, &mut name).await?;
+    let name = String::from_utf8(name)
+        .ok()
+        .filter(|name| is_plain_name(name))
+        .ok_or_else(|| CliError::Failed("invalid file name".to_string()))?;
+
+    let target = dir.join(&name);
+    let part = dir.join(format!("{}.part", name));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part)?;
+
+    // Offer what we have, the sender decides whether it matches
+    let have = file.metadata()?.len();
+    let have = if have <= size { have } else { 0 };
+    let mut resume = have.to_le_bytes().to_vec();
+    resume.extend_from_slice(&file_crc(&mut file, have)?.to_le_bytes());
+    stream.send(&resume).await?;
+
+    let mut start = [0u8; 8];
+    read_exact(stream, &mut start).await?;
+    let offset = u64::from_le_bytes(start);
+    if offset > have {
+        return Err(CliError::Failed(format!("invalid resume offset {}", offset)));
+    }
+    file.set_len(offset)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut received = offset;
+    let mut buf = vec![0u8; MAX_CHUNK];
+    let expected_crc = loop {
+        let mut header = [0u8; 8];
+        read_exact(stream, &mut header).await?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if len == 0 {
+            break crc;
+        }
+        if len > MAX_CHUNK || received + len as u64 > size {
+            return Err(CliError::Failed(format!("invalid chunk of {} bytes", len)));
+        }
+        read_exact(stream, &mut buf[..len]).await?;
+        if Crc32::checksum(&buf[..len]) != crc {
+            return Err(CliError::Failed(format!("chunk at byte {} corrupted", received)));
+        }
+        file.write_all(&buf[..len])?;
+        received += len as u64;
+    };
+    file.flush()?;
+
+    if received != size {
+        return Err(CliError::Failed(format!(
+            "incomplete: {} of {} bytes",
+            received, size
+        )));
+    }
+    if file_crc(&mut file, size)? != expected_crc {
+        // Start over next time instead of resuming a broken file
+        drop(file);
+        let _ = fs::remove_file(&part);
+        return Err(CliError::Failed("file checksum mismatch".to_string()));
+    }
+    fs::rename(&part, &target)?;
+    Ok((name, size, offset))
+}
+
+/// A file name without any path components
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0'])
+}
+
+/// Fill `buf` completely, the peer closing early is an error
+async fn read_exact(stream: &mut KcpMuxStream, buf: &mut [u8]) -> Result<(), Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.recv(&mut buf[filled..]).await? {
+            0 => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "peer closed the stream").into())
+            }
+            n => filled += n,
+        }
+    }
+    Ok(())
+}
+
+/// CRC-32 of the first `len` bytes of a file
+fn file_crc(file: &mut File, len: u64) -> io::Result<u32> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut crc = Crc32::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = buf.len().min(remaining as usize);
+        let n = file.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        crc.update(&buf[..n]);
+        remaining -= n as u64;
+    }
+    Ok(crc.finish())
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib and gzip)
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(!0)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC_TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+
+    fn checksum(data: &[u8]) -> u32 {
+        let mut crc = Self::new();
+        crc.update(data);
+        crc.finish()
+    }
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
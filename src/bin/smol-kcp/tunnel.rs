@@ -0,0 +1,240 @@
+//! `smol-kcp tunnel`: TCP over multiplexed KCP
+//!
+//! Each side runs on one executor: the multiplexer and both directions of
+//! every tunneled connection are tasks on it.
+
+use std::{
+    io,
+    net::{Shutdown, SocketAddr},
+};
+
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+use log::{debug, info, warn};
+use smol::{
+    net::{TcpListener, TcpStream},
+    Executor,
+};
+use smol_kcp::{
+    proxy_protocol::{self, Transport},
+    KcpConfig, KcpListener, KcpMuxStream, KcpMuxer, KcpStream, MuxConfig,
+};
+
+use crate::{args::{Args, Remote}, config, daemon, help, http, CliError};
+
+const USAGE: &str = "\
+Usage: smol-kcp tunnel client --listen tcp://<addr> --remote kcp://<addr> [options]
+       smol-kcp tunnel server --listen kcp://<addr> --target tcp://<addr> [options]
+
+The client accepts TCP connections and carries each of them as a stream of
+one multiplexed KCP connection to the server, which connects them to the
+target. The client reconnects when the KCP connection was lost, resolving
+the host name of --remote again so servers on a dynamic IP are found.
+
+Options:
+  --stream-window <bytes>   Receive window of each tunneled connection
+                            [default: 262144]
+  --socks5 <addr>           Client: reach the server through the UDP relay of
+                            a SOCKS5 proxy (no authentication)
+  --proxy-protocol          Server: start every connection to the target with
+                            a PROXY protocol v2 header carrying the address
+                            of the tunnel client
+
+Example:
+  server$ smol-kcp tunnel server --listen kcp://0.0.0.0:4000 --target tcp://127.0.0.1:22
+  router$ smol-kcp tunnel client --listen tcp://0.0.0.0:2222 --remote kcp://server:4000 --preset wan";
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Runs the multiplexers and every tunneled connection
+static EXECUTOR: Executor<'static> = Executor::new();
+
+pub fn run(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, USAGE) {
+        return Ok(());
+    }
+    let side = args.next_positional();
+    let config = args.config()?;
+    let mux_config = mux_config(&mut args)?;
+
+    match side.as_deref() {
+        Some("client") => {
+            let listen = args.endpoint("--listen", "tcp")?;
+            let remote = args.remote("--remote", "kcp")?;
+            let socks5 = args.opt_endpoint("--socks5", "socks5")?;
+            args.finish()?;
+            client(config, mux_config, listen, remote, socks5)
+        }
+        Some("server") => {
+            let listen = args.endpoint("--listen", "kcp")?;
+            let target = args.endpoint("--target", "tcp")?;
+            let proxy_protocol = args.flag("--proxy-protocol");
+            args.finish()?;
+            server(config, mux_config, listen, target, proxy_protocol)
+        }
+        _ => Err(CliError::Usage("expected `tunnel client` or `tunnel server`".to_string())),
+    }
+}
+
+fn mux_config(args: &mut Args) -> Result<MuxConfig, CliError> {
+    let mut config = MuxConfig::default();
+    if let Some(window) = args.opt("--stream-window")? {
+        config.stream_window = window;
+    }
+    Ok(config)
+}
+
+fn client(
+    config: KcpConfig,
+    mux_config: MuxConfig,
+    listen: SocketAddr,
+    mut remote: Remote,
+    socks5: Option<SocketAddr>,
+) -> Result<(), CliError> {
+    future::block_on(EXECUTOR.run(async {
+        let connect = |remote: SocketAddr| connect(&config, mux_config, remote, socks5);
+        let (mut muxer, mut _tracked) = connect(remote.addr()).await?;
+
+        let tcp_listener = TcpListener::bind(listen).await?;
+        match socks5 {
+            Some(proxy) => info!(
+                "tunnel tcp://{} -> kcp://{} via socks5://{}",
+                tcp_listener.local_addr()?,
+                remote,
+                proxy
+            ),
+            None => info!("tunnel tcp://{} -> kcp://{}", tcp_listener.local_addr()?, remote),
+        }
+
+        loop {
+            let tcp = match tcp_listener.accept().await {
+                Ok((tcp, _)) => tcp,
+                Err(e) => {
+                    warn!("TCP accept failed: {}", e);
+                    continue;
+                }
+            };
+            let stream = match muxer.open().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // The KCP connection is gone, start a new one
+                    warn!("KCP connection to {} lost ({}), reconnecting", remote, e);
+                    (muxer, _tracked) = connect(remote.resolve()).await?;
+                    muxer.open().await?
+                }
+            };
+            EXECUTOR.spawn(bridge(tcp, stream)).detach();
+        }
+    }))
+}
+
+/// Open the client's KCP connection and start its multiplexer
+async fn connect(
+    config: &KcpConfig,
+    mux_config: MuxConfig,
+    remote: SocketAddr,
+    socks5: Option<SocketAddr>,
+) -> Result<(KcpMuxer, http::Tracked), CliError> {
+    let stream = match socks5 {
+        Some(proxy) => KcpStream::connect_via_socks5(config, proxy, remote).await?,
+        None => KcpStream::connect(config, remote).await?,
+    };
+    let tracked = http::track(&stream, false).await;
+    let muxer = KcpMuxer::new(stream, mux_config, true);
+    EXECUTOR.spawn(run_muxer(muxer.clone())).detach();
+    Ok((muxer, tracked))
+}
+
+fn server(
+    config: KcpConfig,
+    mux_config: MuxConfig,
+    listen: SocketAddr,
+    target: SocketAddr,
+    proxy_protocol: bool,
+) -> Result<(), CliError> {
+    future::block_on(EXECUTOR.run(async {
+        let mut listener = KcpListener::bind(config, listen).await?;
+        let local = listener.local_addr()?;
+        info!("tunnel kcp://{} -> tcp://{}", local, target);
+
+        loop {
+            let (stream, peer) = config::accept(&mut listener).await?;
+            info!("tunnel client {} connected", peer);
+            let tracked = http::track(&stream, true).await;
+            let muxer = KcpMuxer::new(stream, mux_config, false);
+            EXECUTOR.spawn(run_muxer(muxer.clone())).detach();
+            let header = proxy_protocol.then(|| proxy_protocol::encode(peer, local, Transport::Stream));
+            EXECUTOR
+                .spawn(async move {
+                    serve_streams(muxer, target, header).await;
+                    drop(tracked);
+                })
+                .detach();
+        }
+    }))
+}
+
+async fn run_muxer(muxer: KcpMuxer) {
+    if let Err(e) = muxer.run().await {
+        debug!("multiplexer stopped: {}", e);
+    }
+}
+
+/// Connect every stream opened by the tunnel client to the target, sending
+/// `header` first
+async fn serve_streams(muxer: KcpMuxer, target: SocketAddr, header: Option<Vec<u8>>) {
+    while let Ok(stream) = muxer.accept().await {
+        let header = header.clone();
+        EXECUTOR
+            .spawn(async move {
+                let connected = async {
+                    let mut tcp = TcpStream::connect(target).await?;
+                    if let Some(header) = &header {
+                        tcp.write_all(header).await?;
+                    }
+                    io::Result::Ok(tcp)
+                };
+                match connected.await {
+                    Ok(tcp) => bridge(tcp, stream).await,
+                    // Dropping the stream closes it
+                    Err(e) => warn!("connecting to {} failed: {}", target, e),
+                }
+            })
+            .detach();
+    }
+}
+
+/// Copy data both ways between a TCP connection and a KCP stream
+///
+/// A half that reaches EOF closes its direction and waits for the other.
+/// One that fails ends both: the TCP connection is shut down and the
+/// stream closed, so neither end waits for data that cannot come.
+async fn bridge(tcp: TcpStream, stream: KcpMuxStream) {
+    // Shutdown waits for both directions
+    let _session = daemon::Session::begin();
+    let (mut tcp_reader, mut tcp_writer) = (tcp.clone(), tcp.clone());
+    let (mut upstream, mut downstream) = (stream.clone(), stream);
+
+    let up = async {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            match tcp_reader.read(&mut buf).await? {
+                0 => return upstream.close().await.map_err(io::Error::from),
+                n => upstream.send(&buf[..n]).await?,
+            };
+        }
+    };
+    let down = async {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            match downstream.recv(&mut buf).await? {
+                0 => return tcp_writer.shutdown(Shutdown::Write),
+                n => tcp_writer.write_all(&buf[..n]).await?,
+            }
+        }
+    };
+    if let Err(e) = future::try_zip(up, down).await {
+        debug!("tunneled connection failed: {}", e);
+        let _ = tcp.shutdown(Shutdown::Both);
+    }
+    // The dropped stream handles close it if neither half did
+}
@@ -0,0 +1,147 @@
+//! `smol-kcp vpn`: IP packets between TUN interfaces over KCP
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use futures_lite::future::{self, block_on};
+use log::{info, warn};
+use smol_kcp::{KcpConfig, KcpListener, KcpStream, TunDevice};
+
+use crate::{args::Args, config, help, http, CliError};
+
+const USAGE: &str = "\
+Usage: smol-kcp vpn client --remote kcp://<addr> [options]
+       smol-kcp vpn server --listen kcp://<addr> [options]
+
+Connect two TUN interfaces into a point-to-point layer 3 VPN, every IP
+packet is carried as one KCP message. The server serves one client, a new
+connection replaces the previous one. Needs root or CAP_NET_ADMIN.
+
+Options:
+  --tun <name>          Interface name, %d is replaced by a free number
+                        [default: kcp%d]
+  --address <ip/len>    IPv4 address of the interface, e.g. 10.8.0.1/24;
+                        without it the interface is left for `ip` to set up
+  --tun-mtu <bytes>     Interface MTU [default: KCP MTU - 24]
+
+Example:
+  server# smol-kcp vpn server --listen kcp://0.0.0.0:4000 --address 10.8.0.1/24
+  client# smol-kcp vpn client --remote kcp://server:4000 --address 10.8.0.2/24 --preset wan";
+
+/// KCP header in front of every segment
+const KCP_OVERHEAD: usize = 24;
+
+pub fn run(mut args: Args) -> Result<(), CliError> {
+    if help(&mut args, USAGE) {
+        return Ok(());
+    }
+    let side = args.next_positional();
+    let mut config = args.config()?;
+    // Packet boundaries must survive the trip
+    config.stream = false;
+    let tun = tun(&mut args, &config)?;
+
+    match side.as_deref() {
+        Some("client") => {
+            let remote = args.endpoint("--remote", "kcp")?;
+            args.finish()?;
+            client(config, tun, remote)
+        }
+        Some("server") => {
+            let listen = args.endpoint("--listen", "kcp")?;
+            args.finish()?;
+            server(config, tun, listen)
+        }
+        _ => Err(CliError::Usage("expected `vpn client` or `vpn server`".to_string())),
+    }
+}
+
+/// Create and configure the interface from the options
+fn tun(args: &mut Args, config: &KcpConfig) -> Result<TunDevice, CliError> {
+    let name: String = args.opt("--tun")?.unwrap_or_else(|| "kcp%d".to_string());
+    let address = args.opt::<String>("--address")?.map(|a| parse_address(&a)).transpose()?;
+    let mtu = match args.opt("--tun-mtu")? {
+        Some(mtu) => mtu,
+        None => config.mtu.saturating_sub(KCP_OVERHEAD) as u32,
+    };
+
+    let tun = TunDevice::create(&name)
+        .map_err(|e| CliError::Failed(format!("creating TUN interface {}: {}", name, e)))?;
+    let configure = || -> std::io::Result<()> {
+        tun.set_mtu(mtu)?;
+        if let Some((addr, prefix)) = address {
+            tun.set_ipv4(addr, prefix)?;
+        }
+        tun.up()
+    };
+    configure()
+        .map_err(|e| CliError::Failed(format!("configuring {}: {}", tun.name(), e)))?;
+
+    match address {
+        Some((addr, prefix)) => info!("{} up with {}/{}, mtu {}", tun.name(), addr, prefix, mtu),
+        None => info!("{} up, mtu {}", tun.name(), mtu),
+    }
+    Ok(tun)
+}
+
+/// Parse `10.8.0.1/24`, a missing length means /32
+fn parse_address(value: &str) -> Result<(Ipv4Addr, u8), CliError> {
+    let invalid = || CliError::Usage(format!("invalid value `{}` for --address", value));
+    let (addr, prefix) = match value.split_once('/') {
+        Some((addr, prefix)) => (addr, prefix.parse().map_err(|_| invalid())?),
+        None => (value, 32),
+    };
+    if prefix > 32 {
+        return Err(invalid());
+    }
+    Ok((addr.parse().map_err(|_| invalid())?, prefix))
+}
+
+fn client(config: KcpConfig, tun: TunDevice, remote: SocketAddr) -> Result<(), CliError> {
+    block_on(async {
+        let stream = KcpStream::connect(&config, remote).await?;
+        let _tracked = http::track(&stream, false).await;
+        info!("vpn {} -> kcp://{}", tun.name(), remote);
+        tun.forward(stream).await?;
+        Ok(())
+    })
+}
+
+fn server(config: KcpConfig, tun: TunDevice, listen: SocketAddr) -> Result<(), CliError> {
+    block_on(async {
+        let mut listener = KcpListener::bind(config, listen).await?;
+        info!("vpn kcp://{} -> {}", listener.local_addr()?, tun.name());
+
+        let mut active: Option<(KcpStream, http::Tracked)> = None;
+        loop {
+            // Keep accepting while forwarding, the listener feeds the
+            // session only while it accepts
+            let accepted = future::or(
+                async {
+                    Some(config::accept_with(&mut listener, |config| config.stream = false).await)
+                },
+                async {
+                    match &active {
+                        Some((stream, _)) => {
+                            if let Err(e) = tun.forward(stream.clone_handle()).await {
+                                warn!("vpn session failed: {}", e);
+                            }
+                            None
+                        }
+                        None => future::pending().await,
+                    }
+                },
+            )
+            .await;
+
+            match accepted {
+                Some(accepted) => {
+                    let (stream, peer) = accepted?;
+                    info!("vpn client {} connected", peer);
+                    let tracked = http::track(&stream, true).await;
+                    active = Some((stream, tracked));
+                }
+                None => active = None,
+            }
+        }
+    })
+}
@@ -0,0 +1,68 @@
+//! Typed message channel over a [`KcpStream`]
+//!
+//! Values are serialized with postcard and framed with
+//! [`LengthDelimitedCodec`], so the channel works in both stream and message
+//! mode.
+
+use std::{io, marker::PhantomData};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    error::KcpResult,
+    framed::{KcpFramed, LengthDelimitedCodec},
+    stream::KcpStream,
+};
+
+/// Default limit for a single encoded value
+const DEFAULT_MAX_LEN: usize = 1024 * 1024;
+
+/// Sends and receives values of type `T` over a [`KcpStream`]
+pub struct KcpChannel<T> {
+    framed: KcpFramed<LengthDelimitedCodec>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> KcpChannel<T> {
+    /// Wrap a stream, accepting values of up to 1 MiB encoded
+    pub fn new(stream: KcpStream) -> Self {
+        Self::with_max_len(stream, DEFAULT_MAX_LEN)
+    }
+
+    /// Wrap a stream with a custom limit for encoded values
+    ///
+    /// Incoming values announcing a larger size fail with
+    /// [`io::ErrorKind::InvalidData`] instead of being buffered.
+    pub fn with_max_len(stream: KcpStream, max_len: usize) -> Self {
+        let codec = LengthDelimitedCodec::with_max_frame_length(max_len);
+        Self {
+            framed: KcpFramed::new(stream, codec),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Serialize and send a value
+    pub async fn send(&mut self, value: &T) -> KcpResult<()> {
+        let encoded = postcard::to_allocvec(value).map_err(invalid_data)?;
+        self.framed.send(&encoded[..]).await
+    }
+
+    /// Receive and deserialize the next value
+    pub async fn recv(&mut self) -> KcpResult<T> {
+        match self.framed.recv().await? {
+            Some(frame) => Ok(postcard::from_bytes(&frame).map_err(invalid_data)?),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    /// Get the underlying stream back
+    ///
+    /// Bytes already read from the stream but not yet decoded are lost.
+    pub fn into_inner(self) -> KcpStream {
+        self.framed.into_inner()
+    }
+}
+
+fn invalid_data(err: postcard::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,63 @@
+//! CRC32C datagram trailer
+//!
+//! With [`KcpConfig::checksum`] every datagram ends in the CRC32C
+//! (Castagnoli) of everything before it, little-endian:
+//!
+//! ```text
+//! | datagram ... | crc32c (4, LE) |
+//! ```
+//!
+//! The trailer is the outermost layer: it covers the routing header of
+//! [`crate::route`] and sits inside the SOCKS5 UDP header. Datagrams whose
+//! checksum does not match are dropped before they reach KCP, which then
+//! retransmits as for a lost one. Both ends must enable it, there is no
+//! negotiation.
+//!
+//! [`KcpConfig::checksum`]: crate::KcpConfig::checksum
+
+/// Size of the trailer
+pub(crate) const TRAILER_LEN: usize = 4;
+
+/// Reflected Castagnoli polynomial
+const POLY: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC32C of `data`
+pub(crate) fn crc32c(data: &[u8]) -> u32 {
+    let crc = data.iter().fold(!0u32, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ crc >> 8
+    });
+    !crc
+}
+
+/// Append the trailer to `datagram`
+pub(crate) fn append(datagram: &mut Vec<u8>) {
+    let crc = crc32c(datagram);
+    datagram.extend_from_slice(&crc.to_le_bytes());
+}
+
+/// The datagram without its trailer, `None` if the checksum does not
+/// match
+pub(crate) fn strip(datagram: &[u8]) -> Option<&[u8]> {
+    let split = datagram.len().checked_sub(TRAILER_LEN)?;
+    let (data, trailer) = datagram.split_at(split);
+    let expected = u32::from_le_bytes(trailer.try_into().ok()?);
+    (crc32c(data) == expected).then_some(data)
+}
@@ -1,5 +1,7 @@
-use std::{io::Write, time::Duration};
-use kcp::Kcp;
+use std::{borrow::Cow, time::Duration};
+use smol_kcp_core::Connection;
+
+use crate::{checksum, packet, stats::StatsHistoryConfig};
 
 /// KCP NoDelay configuration
 #[derive(Debug, Clone, Copy)]
@@ -27,6 +29,7 @@ impl Default for KcpNoDelayConfig {
 
 impl KcpNoDelayConfig {
     /// Fastest configuration for low latency
+    #[cfg(feature = "presets")]
     pub const fn fastest() -> Self {
         Self {
             nodelay: true,
@@ -50,6 +53,7 @@ impl KcpNoDelayConfig {
     /// - Low latency for interactive response
     /// - Aggressive retransmission for reliability
     /// - Disabled congestion control for consistent performance
+    #[cfg(feature = "presets")]
     pub const fn optimized() -> Self {
         Self {
             nodelay: true,
@@ -60,6 +64,7 @@ impl KcpNoDelayConfig {
     }
 
     /// Configuration for high-latency networks (satellite, cellular)
+    #[cfg(feature = "presets")]
     pub const fn high_latency() -> Self {
         Self {
             nodelay: true,
@@ -70,6 +75,112 @@ impl KcpNoDelayConfig {
     }
 }
 
+/// When data queued by a send goes out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Every send flushes, the data leaves at once
+    #[default]
+    PerSend,
+    /// Sends only queue, the data leaves with the next update; bulk
+    /// writers get full segments and fewer datagrams
+    ///
+    /// Updates run while the stream waits in `send` or `recv`, call
+    /// [`KcpStream::flush`](crate::KcpStream::flush) after the last write.
+    OnInterval,
+    /// Like [`FlushPolicy::OnInterval`], but a send flushes once at least
+    /// `bytes` wait to be sent
+    OnThreshold {
+        /// Unsent bytes that trigger a flush
+        bytes: usize,
+    },
+}
+
+/// Byte order of a KCP header field on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// Least significant byte first, as ikcp and kcp-go
+    #[default]
+    Little,
+    /// Most significant byte first
+    Big,
+}
+
+/// Byte order of every multi-byte field of the KCP segment header
+///
+/// ikcp writes all of them little-endian. Forks that serialize big-endian,
+/// or only some fields so, are matched by setting the fields here; the
+/// single-byte `cmd` and `frg` are the same everywhere. smol-kcp's own
+/// extension packets are not affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderFormat {
+    /// Conversation id
+    pub conv: ByteOrder,
+    /// Receive window
+    pub wnd: ByteOrder,
+    /// Timestamp
+    pub ts: ByteOrder,
+    /// Sequence number
+    pub sn: ByteOrder,
+    /// Next sequence number expected by the sender
+    pub una: ByteOrder,
+    /// Payload length
+    pub len: ByteOrder,
+}
+
+impl HeaderFormat {
+    /// ikcp's format
+    pub const LITTLE_ENDIAN: Self = Self::uniform(ByteOrder::Little);
+    /// Every field big-endian
+    pub const BIG_ENDIAN: Self = Self::uniform(ByteOrder::Big);
+
+    /// Every field in `order`
+    pub const fn uniform(order: ByteOrder) -> Self {
+        Self {
+            conv: order,
+            wnd: order,
+            ts: order,
+            sn: order,
+            una: order,
+            len: order,
+        }
+    }
+
+    fn is_native(&self) -> bool {
+        *self == Self::LITTLE_ENDIAN
+    }
+
+    /// Conversation id of a received datagram
+    pub(crate) fn conv(&self, datagram: &[u8]) -> Option<u32> {
+        let bytes = datagram.get(..4)?.try_into().ok()?;
+        match self.conv {
+            ByteOrder::Big if packet::ext_cmd(datagram).is_none() => Some(u32::from_be_bytes(bytes)),
+            _ => Some(u32::from_le_bytes(bytes)),
+        }
+    }
+
+    /// A received datagram in ikcp's format
+    pub(crate) fn decode<'a>(&self, datagram: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.is_native() || packet::ext_cmd(datagram).is_some() {
+            return Cow::Borrowed(datagram);
+        }
+        Cow::Owned(packet::transcode(datagram, self, &Self::LITTLE_ENDIAN))
+    }
+
+    /// A datagram of KCP in this format for the wire
+    pub(crate) fn encode<'a>(&self, datagram: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.is_native() || packet::ext_cmd(datagram).is_some() {
+            return Cow::Borrowed(datagram);
+        }
+        Cow::Owned(packet::transcode(datagram, &Self::LITTLE_ENDIAN, self))
+    }
+}
+
+impl Default for HeaderFormat {
+    fn default() -> Self {
+        Self::LITTLE_ENDIAN
+    }
+}
+
 /// KCP configuration
 #[derive(Debug, Clone, Copy)]
 pub struct KcpConfig {
@@ -81,8 +192,94 @@ pub struct KcpConfig {
     pub wnd_size: (u16, u16),
     /// Session expire duration
     pub session_expire: Option<Duration>,
+    /// How long a client waits for the first datagram of the server while
+    /// its data goes unanswered before failing with
+    /// [`Error::HandshakeTimeout`](crate::Error::HandshakeTimeout),
+    /// counted from when the session opened
+    ///
+    /// Fails fast on a wrong address or a server that is down instead of
+    /// waiting for `session_expire`. `None` leaves it to `session_expire`.
+    pub handshake_timeout: Option<Duration>,
     /// Stream mode
     pub stream: bool,
+    /// Keep a history of periodic statistics samples
+    pub stats_history: Option<StatsHistoryConfig>,
+    /// Collect RTT and send-to-ack latency histograms
+    pub latency_histograms: bool,
+    /// Implementation on the other end
+    pub interop: KcpInterop,
+    /// Source of conversation ids for new connections and conv 0 clients,
+    /// zero is skipped; `None` uses `rand` or, without the `rand` feature,
+    /// the OS-seeded hasher keys of std
+    pub conv_generator: Option<fn() -> u32>,
+    /// Limit on the bytes buffered by one session: KCP's send and receive
+    /// queues and buffers, pending output and unread messages
+    ///
+    /// Sending waits for ACKs while the limit is reached, a session whose
+    /// peer pushes it over the limit anyway is dropped. `None` is
+    /// unlimited.
+    pub max_session_memory: Option<usize>,
+    /// Messages still queued this long after the send are dropped instead
+    /// of sent, so a stalled link does not build up stale messages
+    ///
+    /// Message mode only, see
+    /// [`KcpStream::send_with_deadline`](crate::KcpStream::send_with_deadline)
+    /// for a deadline per message. `None` keeps messages until they are
+    /// sent.
+    pub message_ttl: Option<Duration>,
+    /// How long ACKs may be held to batch several into one datagram
+    ///
+    /// `Some(Duration::ZERO)` sends them as soon as the data arrives, for
+    /// the lowest latency; a few tens of ms cut the pure-ACK packets on
+    /// constrained uplinks at the cost of a slightly higher RTT seen by
+    /// the peer. `None` sends them with every update, every
+    /// `nodelay.interval`.
+    pub ack_delay: Option<Duration>,
+    /// When data queued by a send goes out, see [`FlushPolicy`]
+    pub flush_policy: FlushPolicy,
+    /// Offer the server a random 64-bit session id at the handshake and
+    /// route datagrams by it once accepted, instead of by address and
+    /// conv, which survives NAT rebindings that reuse the old address
+    ///
+    /// Applies to client streams, not multipath ones; listeners always
+    /// accept the offer. Needs [`KcpInterop::Native`], servers that do not
+    /// answer keep the classic format.
+    pub session_ids: bool,
+    /// Byte order of the KCP header fields, for forks that do not write
+    /// them little-endian like ikcp
+    pub header_format: HeaderFormat,
+    /// End every datagram in a CRC32C of its content and drop those that
+    /// do not match, against corruption the UDP checksum missed
+    ///
+    /// Both ends must enable it. The 4-byte trailer is taken from `mtu`.
+    pub checksum: bool,
+}
+
+/// KCP implementation of the peer
+///
+/// The KCP segment format is the same everywhere, the differences are in
+/// what each side puts on the wire next to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KcpInterop {
+    /// smol-kcp on both ends, extension packets (unreliable messages) are
+    /// enabled
+    #[default]
+    Native,
+    /// [xtaci/kcp-go](https://github.com/xtaci/kcp-go) sessions without
+    /// encryption and FEC (`kcp.Dial`, `kcp.Listen`, or `DialWithOptions`
+    /// with a nil block and zero shards)
+    ///
+    /// Only KCP segments are sent, and a listener takes conv 0 from a
+    /// client as-is instead of assigning one. kcp-go writes larger than
+    /// the MSS arrive as separate messages, as kcp-go is stream oriented.
+    KcpGo,
+    /// Strict conformance with the reference C ikcp
+    ///
+    /// Like [`KcpInterop::KcpGo`], and every incoming datagram must consist
+    /// of well-formed segments of the session's conversation only, anything
+    /// else is dropped as a whole before it reaches KCP. See
+    /// [`crate::conformance`] to check a peer before deployment.
+    Ikcp,
 }
 
 impl Default for KcpConfig {
@@ -92,15 +289,28 @@ impl Default for KcpConfig {
             nodelay: KcpNoDelayConfig::normal(),
             wnd_size: (256, 256),
             session_expire: Some(Duration::from_secs(90)),
+            handshake_timeout: Some(Duration::from_secs(10)),
             stream: false,
+            stats_history: None,
+            latency_histograms: false,
+            interop: KcpInterop::Native,
+            conv_generator: None,
+            max_session_memory: None,
+            message_ttl: None,
+            ack_delay: None,
+            flush_policy: FlushPolicy::PerSend,
+            session_ids: false,
+            header_format: HeaderFormat::LITTLE_ENDIAN,
+            checksum: false,
         }
     }
 }
 
 impl KcpConfig {
     /// Apply configuration to KCP instance
-    pub fn apply_config<W: Write>(&self, kcp: &mut Kcp<W>) {
-        kcp.set_mtu(self.mtu).expect("invalid MTU");
+    pub fn apply_config(&self, kcp: &mut Connection) {
+        let trailer = if self.checksum { checksum::TRAILER_LEN } else { 0 };
+        kcp.set_mtu(self.mtu.saturating_sub(trailer)).expect("invalid MTU");
         kcp.set_nodelay(
             self.nodelay.nodelay,
             self.nodelay.interval,
@@ -108,47 +318,104 @@ impl KcpConfig {
             self.nodelay.nc,
         );
         kcp.set_wndsize(self.wnd_size.0, self.wnd_size.1);
+        kcp.set_ack_delay(self.ack_delay_ms());
+    }
+
+    /// [`KcpConfig::ack_delay`] on KCP's clock
+    pub(crate) fn ack_delay_ms(&self) -> Option<u32> {
+        self.ack_delay
+            .map(|delay| delay.as_millis().min(u32::MAX as u128) as u32)
+    }
+
+    /// A fresh nonzero conversation id
+    pub(crate) fn new_conv(&self) -> u32 {
+        let generate = self.conv_generator.unwrap_or(random_conv);
+        let mut conv = generate();
+        while conv == 0 {
+            conv = generate();
+        }
+        conv
     }
 
     /// Optimized configuration for local networks (LAN/WiFi)
     /// - Optimized for low latency and high throughput
     /// - Large windows for bulk data transfer
     /// - Stream mode for continuous data flow
+    #[cfg(feature = "presets")]
     pub fn lan() -> Self {
         Self {
             mtu: 1400,
             nodelay: KcpNoDelayConfig::optimized(),
             wnd_size: (512, 512), // Larger windows for high throughput
             session_expire: Some(Duration::from_secs(300)), // 5 min timeout
+            handshake_timeout: Some(Duration::from_secs(10)),
             stream: true, // Stream mode for continuous data flow
+            stats_history: None,
+            latency_histograms: false,
+            interop: KcpInterop::Native,
+            conv_generator: None,
+            max_session_memory: None,
+            message_ttl: None,
+            ack_delay: None,
+            flush_policy: FlushPolicy::PerSend,
+            session_ids: false,
+            header_format: HeaderFormat::LITTLE_ENDIAN,
+            checksum: false,
         }
     }
 
     /// Optimized configuration for WAN/Internet connections
     /// - Balanced latency and reliability
     /// - Conservative window sizes for variable bandwidth
+    #[cfg(feature = "presets")]
     pub fn wan() -> Self {
         Self {
             mtu: 1200, // Smaller MTU to avoid fragmentation
             nodelay: KcpNoDelayConfig::optimized(),
             wnd_size: (256, 256), // Conservative windows
             session_expire: Some(Duration::from_secs(180)), // 3 min timeout
+            handshake_timeout: Some(Duration::from_secs(10)),
             stream: true,
+            stats_history: None,
+            latency_histograms: false,
+            interop: KcpInterop::Native,
+            conv_generator: None,
+            max_session_memory: None,
+            message_ttl: None,
+            ack_delay: None,
+            flush_policy: FlushPolicy::PerSend,
+            session_ids: false,
+            header_format: HeaderFormat::LITTLE_ENDIAN,
+            checksum: false,
         }
     }
 
     /// Configuration for high-latency/lossy networks (satellite, cellular)
+    #[cfg(feature = "presets")]
     pub fn high_latency() -> Self {
         Self {
             mtu: 1000, // Even smaller MTU for problematic links
             nodelay: KcpNoDelayConfig::high_latency(),
             wnd_size: (128, 256), // Smaller send window, larger receive
             session_expire: Some(Duration::from_secs(600)), // 10 min timeout
+            handshake_timeout: Some(Duration::from_secs(30)),
             stream: true,
+            stats_history: None,
+            latency_histograms: false,
+            interop: KcpInterop::Native,
+            conv_generator: None,
+            max_session_memory: None,
+            message_ttl: None,
+            ack_delay: None,
+            flush_policy: FlushPolicy::PerSend,
+            session_ids: false,
+            header_format: HeaderFormat::LITTLE_ENDIAN,
+            checksum: false,
         }
     }
 
     /// Configuration for low-bandwidth connections
+    #[cfg(feature = "presets")]
     pub fn low_bandwidth() -> Self {
         Self {
             mtu: 800, // Small MTU for efficiency
@@ -160,7 +427,62 @@ impl KcpConfig {
             },
             wnd_size: (64, 128), // Small windows
             session_expire: Some(Duration::from_secs(300)),
+            handshake_timeout: Some(Duration::from_secs(10)),
             stream: true,
+            stats_history: None,
+            latency_histograms: false,
+            interop: KcpInterop::Native,
+            conv_generator: None,
+            max_session_memory: None,
+            message_ttl: None,
+            ack_delay: None,
+            flush_policy: FlushPolicy::PerSend,
+            session_ids: false,
+            header_format: HeaderFormat::LITTLE_ENDIAN,
+            checksum: false,
         }
     }
-}
\ No newline at end of file
+
+    /// Defaults of a kcp-go session: ikcp's 100ms interval without nodelay,
+    /// windows of 32 (send) and 128 (receive) segments and message mode
+    #[cfg(feature = "presets")]
+    pub fn kcp_go() -> Self {
+        Self {
+            mtu: 1400,
+            nodelay: KcpNoDelayConfig::default(),
+            wnd_size: (32, 128),
+            session_expire: Some(Duration::from_secs(90)),
+            handshake_timeout: Some(Duration::from_secs(10)),
+            stream: false,
+            stats_history: None,
+            latency_histograms: false,
+            interop: KcpInterop::KcpGo,
+            conv_generator: None,
+            max_session_memory: None,
+            message_ttl: None,
+            ack_delay: None,
+            flush_policy: FlushPolicy::PerSend,
+            session_ids: false,
+            header_format: HeaderFormat::LITTLE_ENDIAN,
+            checksum: false,
+        }
+    }
+}
+
+/// Unpredictable token, e.g. to check the peer receives at an address
+pub(crate) fn random_token() -> u64 {
+    (random_conv() as u64) << 32 | random_conv() as u64
+}
+
+#[cfg(feature = "rand")]
+fn random_conv() -> u32 {
+    rand::random()
+}
+
+/// Every `RandomState` gets new keys from a per-thread OS-seeded source
+#[cfg(not(feature = "rand"))]
+fn random_conv() -> u32 {
+    use std::hash::{BuildHasher, RandomState};
+
+    RandomState::new().hash_one(std::time::Instant::now()) as u32
+}
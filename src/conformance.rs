@@ -0,0 +1,587 @@
+//! ikcp wire conformance
+//!
+//! A scripted exchange with golden vectors: the datagrams a tester sends
+//! and the datagrams a reference ikcp receiver with default settings
+//! answers. The vectors come from the reference ikcp.c
+//! (skywind3000/kcp), not from this crate: `conformance/ikcp_vectors.c`
+//! drives an ikcp tester and an ikcp receiver with a fixed clock and prints
+//! them, so they are reproducible byte for byte:
+//!
+//! ```text
+//! cc -I kcp conformance/ikcp_vectors.c kcp/ikcp.c -o ikcp_vectors
+//! ./ikcp_vectors
+//! ```
+//!
+//! Each reply is annotated with the ikcp.c logic producing it.
+//!
+//! [`verify_engine`] replays the script locally and compares the bytes,
+//! [`run_exchange`] plays the tester against a remote peer running an echo
+//! service (`smol-kcp server`, kcp-go's echo example, an ikcp test server)
+//! and checks its answers. Fields that depend on the peer's clock and
+//! configuration (timestamps of its own segments, windows) are not
+//! compared.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use futures_lite::future;
+use smol_kcp_core::Connection;
+
+use crate::{
+    error::KcpResult,
+    packet::{self, SegmentHeader, KCP_CMD_ACK, KCP_CMD_PUSH, KCP_CMD_WASK},
+};
+
+/// Conversation used by the script
+pub const CONV: u32 = 0x4b43_5001;
+
+/// MTU of the tester, small so a short message needs three fragments
+const TESTER_MTU: usize = 100;
+/// Receive window the tester advertises
+const TESTER_WND: u16 = 128;
+/// Clock of the tester when sending the first and the second message
+const T_PUSH: u32 = 1000;
+const T_FRAGMENTS: u32 = 1100;
+/// Clock of the reference receiver
+const T_RECEIVER: u32 = 5000;
+
+/// First message, a single segment
+const MESSAGE: &[u8] = b"smol-kcp conformance";
+/// Length of the second message, three fragments at the tester's MTU
+const FRAGMENTED_LEN: usize = 200;
+
+fn fragmented_message() -> Vec<u8> {
+    (0..FRAGMENTED_LEN).map(|i| (i * 7 + 1) as u8).collect()
+}
+
+/// One step of the scripted exchange
+#[derive(Debug)]
+pub struct GoldenVector {
+    pub name: &'static str,
+    /// Datagrams the tester sends, in order
+    pub sent: &'static [&'static [u8]],
+    /// Datagrams the reference receiver answers, one flush per datagram
+    /// received
+    pub reply: &'static [&'static [u8]],
+}
+
+/// The scripted exchange
+pub static GOLDEN_VECTORS: [GoldenVector; 4] = [
+    GoldenVector {
+        name: "push",
+        // ikcp_input acks sn 0 with the segment's ts and moves it to
+        // rcv_queue: ikcp_flush answers wnd = rcv_wnd - nrcv_que = 127 and
+        // una = rcv_nxt = 1
+        sent: &[
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x51, 0x00, 0x80, 0x00, 0xe8, 0x03, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00,
+                0x73, 0x6d, 0x6f, 0x6c, 0x2d, 0x6b, 0x63, 0x70, 0x20, 0x63, 0x6f, 0x6e,
+                0x66, 0x6f, 0x72, 0x6d, 0x61, 0x6e, 0x63, 0x65,
+            ],
+        ],
+        reply: &[
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x52, 0x00, 0x7f, 0x00, 0xe8, 0x03, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+        ],
+    },
+    GoldenVector {
+        name: "window probe",
+        // WASK sets IKCP_ASK_TELL, ikcp_flush answers WINS with its segment
+        // template: ts and sn are zero, the message is still queued
+        sent: &[
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x53, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+        ],
+        reply: &[
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x54, 0x00, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+        ],
+    },
+    GoldenVector {
+        name: "fragments out of order",
+        // sn 3 and 2 wait in rcv_buf, each acked with una 1; sn 1 completes
+        // the run, rcv_nxt moves to 4 and nrcv_que to 4 (wnd 124)
+        sent: &[
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x51, 0x00, 0x80, 0x00, 0x4c, 0x04, 0x00, 0x00,
+                0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x00, 0x00, 0x00,
+                0x29, 0x30, 0x37, 0x3e, 0x45, 0x4c, 0x53, 0x5a, 0x61, 0x68, 0x6f, 0x76,
+                0x7d, 0x84, 0x8b, 0x92, 0x99, 0xa0, 0xa7, 0xae, 0xb5, 0xbc, 0xc3, 0xca,
+                0xd1, 0xd8, 0xdf, 0xe6, 0xed, 0xf4, 0xfb, 0x02, 0x09, 0x10, 0x17, 0x1e,
+                0x25, 0x2c, 0x33, 0x3a, 0x41, 0x48, 0x4f, 0x56, 0x5d, 0x64, 0x6b, 0x72,
+            ],
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x51, 0x01, 0x80, 0x00, 0x4c, 0x04, 0x00, 0x00,
+                0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4c, 0x00, 0x00, 0x00,
+                0x15, 0x1c, 0x23, 0x2a, 0x31, 0x38, 0x3f, 0x46, 0x4d, 0x54, 0x5b, 0x62,
+                0x69, 0x70, 0x77, 0x7e, 0x85, 0x8c, 0x93, 0x9a, 0xa1, 0xa8, 0xaf, 0xb6,
+                0xbd, 0xc4, 0xcb, 0xd2, 0xd9, 0xe0, 0xe7, 0xee, 0xf5, 0xfc, 0x03, 0x0a,
+                0x11, 0x18, 0x1f, 0x26, 0x2d, 0x34, 0x3b, 0x42, 0x49, 0x50, 0x57, 0x5e,
+                0x65, 0x6c, 0x73, 0x7a, 0x81, 0x88, 0x8f, 0x96, 0x9d, 0xa4, 0xab, 0xb2,
+                0xb9, 0xc0, 0xc7, 0xce, 0xd5, 0xdc, 0xe3, 0xea, 0xf1, 0xf8, 0xff, 0x06,
+                0x0d, 0x14, 0x1b, 0x22,
+            ],
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x51, 0x02, 0x80, 0x00, 0x4c, 0x04, 0x00, 0x00,
+                0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4c, 0x00, 0x00, 0x00,
+                0x01, 0x08, 0x0f, 0x16, 0x1d, 0x24, 0x2b, 0x32, 0x39, 0x40, 0x47, 0x4e,
+                0x55, 0x5c, 0x63, 0x6a, 0x71, 0x78, 0x7f, 0x86, 0x8d, 0x94, 0x9b, 0xa2,
+                0xa9, 0xb0, 0xb7, 0xbe, 0xc5, 0xcc, 0xd3, 0xda, 0xe1, 0xe8, 0xef, 0xf6,
+                0xfd, 0x04, 0x0b, 0x12, 0x19, 0x20, 0x27, 0x2e, 0x35, 0x3c, 0x43, 0x4a,
+                0x51, 0x58, 0x5f, 0x66, 0x6d, 0x74, 0x7b, 0x82, 0x89, 0x90, 0x97, 0x9e,
+                0xa5, 0xac, 0xb3, 0xba, 0xc1, 0xc8, 0xcf, 0xd6, 0xdd, 0xe4, 0xeb, 0xf2,
+                0xf9, 0x00, 0x07, 0x0e,
+            ],
+        ],
+        reply: &[
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x52, 0x00, 0x7f, 0x00, 0x4c, 0x04, 0x00, 0x00,
+                0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x52, 0x00, 0x7f, 0x00, 0x4c, 0x04, 0x00, 0x00,
+                0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x52, 0x00, 0x7c, 0x00, 0x4c, 0x04, 0x00, 0x00,
+                0x01, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+        ],
+    },
+    GoldenVector {
+        name: "duplicate",
+        // sn 1 < rcv_nxt: acked again and dropped by ikcp_parse_data
+        sent: &[
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x51, 0x02, 0x80, 0x00, 0x4c, 0x04, 0x00, 0x00,
+                0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4c, 0x00, 0x00, 0x00,
+                0x01, 0x08, 0x0f, 0x16, 0x1d, 0x24, 0x2b, 0x32, 0x39, 0x40, 0x47, 0x4e,
+                0x55, 0x5c, 0x63, 0x6a, 0x71, 0x78, 0x7f, 0x86, 0x8d, 0x94, 0x9b, 0xa2,
+                0xa9, 0xb0, 0xb7, 0xbe, 0xc5, 0xcc, 0xd3, 0xda, 0xe1, 0xe8, 0xef, 0xf6,
+                0xfd, 0x04, 0x0b, 0x12, 0x19, 0x20, 0x27, 0x2e, 0x35, 0x3c, 0x43, 0x4a,
+                0x51, 0x58, 0x5f, 0x66, 0x6d, 0x74, 0x7b, 0x82, 0x89, 0x90, 0x97, 0x9e,
+                0xa5, 0xac, 0xb3, 0xba, 0xc1, 0xc8, 0xcf, 0xd6, 0xdd, 0xe4, 0xeb, 0xf2,
+                0xf9, 0x00, 0x07, 0x0e,
+            ],
+        ],
+        reply: &[
+            &[
+                0x01, 0x50, 0x43, 0x4b, 0x52, 0x00, 0x7c, 0x00, 0x4c, 0x04, 0x00, 0x00,
+                0x01, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+        ],
+    },
+];
+
+/// Datagrams a strict receiver has to drop, with the reason
+pub static MALFORMED_VECTORS: [(&str, &[u8]); 5] = [
+    (
+        "truncated header",
+        &[0x01, 0x50, 0x43, 0x4b, 0x52, 0x00, 0x80, 0x00, 0x00, 0x00],
+    ),
+    (
+        "unknown command",
+        &[
+            0x01, 0x50, 0x43, 0x4b, 0x50, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+    ),
+    (
+        "length past the end",
+        &[
+            0x01, 0x50, 0x43, 0x4b, 0x51, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+            0x61, 0x62,
+        ],
+    ),
+    (
+        "trailing bytes",
+        &[
+            0x01, 0x50, 0x43, 0x4b, 0x52, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x02, 0x03,
+        ],
+    ),
+    (
+        "acknowledgement with payload",
+        &[
+            0x01, 0x50, 0x43, 0x4b, 0x52, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x61,
+        ],
+    ),
+];
+
+/// Check that a datagram holds nothing but well-formed KCP segments of
+/// `conv`, as [`crate::KcpInterop::Ikcp`] requires
+pub fn check_datagram(datagram: &[u8], conv: u32) -> Result<(), &'static str> {
+    packet::validate(datagram, Some(conv))
+}
+
+/// A step replayed on the local engine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedVector {
+    pub name: &'static str,
+    pub sent: Vec<Vec<u8>>,
+    pub reply: Vec<Vec<u8>>,
+}
+
+/// Run the script through the local KCP engine with a fixed clock
+///
+/// Mirrors `conformance/ikcp_vectors.c`, [`verify_engine`] compares the
+/// result with the vectors ikcp.c produced.
+pub fn record_vectors() -> KcpResult<Vec<RecordedVector>> {
+    let mut tester = Connection::new(CONV);
+    tester.set_mtu(TESTER_MTU)?;
+    // No congestion window, all three fragments go out in one flush
+    tester.set_nodelay(false, 100, 0, true);
+
+    let mut receiver = Connection::new(CONV);
+    receiver.update(T_RECEIVER);
+    receiver.drain_transmit().for_each(drop);
+
+    let mut answer = |sent: &[Vec<u8>]| -> KcpResult<Vec<Vec<u8>>> {
+        let mut reply = Vec::new();
+        for datagram in sent {
+            receiver.handle_input(datagram, T_RECEIVER)?;
+            receiver.flush()?;
+            reply.extend(receiver.drain_transmit());
+        }
+        Ok(reply)
+    };
+
+    tester.send(MESSAGE)?;
+    tester.update(T_PUSH);
+    let push: Vec<_> = tester.drain_transmit().collect();
+
+    // ikcp_flush sends a probe with the ACK template: sn and ts are zero
+    let probe = vec![SegmentHeader {
+        conv: CONV,
+        cmd: KCP_CMD_WASK,
+        frg: 0,
+        wnd: TESTER_WND,
+        ts: 0,
+        sn: 0,
+        una: 0,
+        len: 0,
+    }
+    .encode(&[])];
+
+    tester.send(&fragmented_message())?;
+    tester.update(T_FRAGMENTS);
+    let mut fragments: Vec<_> = tester.drain_transmit().collect();
+    fragments.reverse();
+    let duplicate = vec![fragments[fragments.len() - 1].clone()];
+
+    Ok(vec![
+        RecordedVector {
+            name: "push",
+            reply: answer(&push)?,
+            sent: push,
+        },
+        RecordedVector {
+            name: "window probe",
+            reply: answer(&probe)?,
+            sent: probe,
+        },
+        RecordedVector {
+            name: "fragments out of order",
+            reply: answer(&fragments)?,
+            sent: fragments,
+        },
+        RecordedVector {
+            name: "duplicate",
+            reply: answer(&duplicate)?,
+            sent: duplicate,
+        },
+    ])
+}
+
+/// Outcome of one check
+#[derive(Debug, Clone)]
+pub struct ConformanceCheck {
+    pub name: String,
+    pub passed: bool,
+    /// What differed, empty when passed
+    pub detail: String,
+}
+
+/// Outcome of [`verify_engine`] or [`run_exchange`]
+#[derive(Debug, Clone, Default)]
+pub struct ConformanceReport {
+    pub checks: Vec<ConformanceCheck>,
+}
+
+impl ConformanceReport {
+    /// Whether every check passed
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    fn check(&mut self, name: impl Into<String>, result: Result<(), String>) {
+        let (passed, detail) = match result {
+            Ok(()) => (true, String::new()),
+            Err(detail) => (false, detail),
+        };
+        self.checks.push(ConformanceCheck {
+            name: name.into(),
+            passed,
+            detail,
+        });
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            if check.passed {
+                writeln!(f, "ok    {}", check.name)?;
+            } else {
+                writeln!(f, "FAIL  {}: {}", check.name, check.detail)?;
+            }
+        }
+        let passed = self.checks.iter().filter(|check| check.passed).count();
+        write!(f, "{}/{} checks passed", passed, self.checks.len())
+    }
+}
+
+/// Replay the script through the local KCP engine and compare every byte
+/// with the golden vectors, then check the strict datagram validation
+pub fn verify_engine() -> ConformanceReport {
+    let mut report = ConformanceReport::default();
+    match record_vectors() {
+        Ok(recorded) => {
+            for (golden, recorded) in GOLDEN_VECTORS.iter().zip(&recorded) {
+                let result = compare_datagrams("sent", golden.sent, &recorded.sent)
+                    .and_then(|()| compare_datagrams("reply", golden.reply, &recorded.reply));
+                report.check(format!("engine: {}", golden.name), result);
+            }
+        }
+        Err(e) => report.check("engine", Err(e.to_string())),
+    }
+
+    let golden_valid = GOLDEN_VECTORS
+        .iter()
+        .flat_map(|golden| golden.sent.iter().chain(golden.reply))
+        .try_for_each(|datagram| check_datagram(datagram, CONV).map_err(str::to_string));
+    report.check("strict: golden datagrams accepted", golden_valid);
+    for (name, datagram) in &MALFORMED_VECTORS {
+        let result = match check_datagram(datagram, CONV) {
+            Ok(()) => Err("accepted".to_string()),
+            Err(_) => Ok(()),
+        };
+        report.check(format!("strict: {} rejected", name), result);
+    }
+    report
+}
+
+fn compare_datagrams(what: &str, golden: &[&[u8]], recorded: &[Vec<u8>]) -> Result<(), String> {
+    if golden.len() != recorded.len() {
+        return Err(format!(
+            "{} {} datagrams, golden {}",
+            what,
+            recorded.len(),
+            golden.len()
+        ));
+    }
+    for (i, (golden, recorded)) in golden.iter().zip(recorded).enumerate() {
+        if *golden != recorded.as_slice() {
+            let at = golden
+                .iter()
+                .zip(recorded)
+                .position(|(a, b)| a != b)
+                .unwrap_or(golden.len().min(recorded.len()));
+            return Err(format!("{} datagram {} differs at byte {}", what, i, at));
+        }
+    }
+    Ok(())
+}
+
+/// Play the tester against `peer`, an echo service speaking plain KCP
+///
+/// Each step waits up to `timeout` for the answers. The peer has to accept
+/// the conversation [`CONV`] from a new address and echo what it receives.
+pub async fn run_exchange(peer: SocketAddr, timeout: Duration) -> io::Result<ConformanceReport> {
+    let bind: SocketAddr = match peer.ip() {
+        IpAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        IpAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let udp = UdpSocket::bind(bind)?;
+    udp.connect(peer)?;
+    let mut tester = Tester {
+        udp: Async::new(udp)?,
+        received: Vec::new(),
+        pushed: BTreeMap::new(),
+        malformed: None,
+    };
+
+    let mut report = ConformanceReport::default();
+    for golden in &GOLDEN_VECTORS {
+        let start = tester.received.len();
+        for datagram in golden.sent {
+            tester.send(datagram).await?;
+        }
+
+        let expected: Vec<SegmentHeader> = golden
+            .reply
+            .iter()
+            .flat_map(|datagram| packet::segments(datagram))
+            .collect();
+        let deadline = Instant::now() + timeout;
+        let result = loop {
+            let seen = &tester.received[start..];
+            let missing = expected.iter().find(|want| !seen.iter().any(|got| matches(want, got)));
+            let want_una = expected.iter().map(|s| s.una).max().unwrap_or(0);
+            let got_una = seen.iter().map(|s| s.una).max().unwrap_or(0);
+            match missing {
+                None if got_una == want_una => break Ok(()),
+                _ if Instant::now() >= deadline => {
+                    break Err(match missing {
+                        Some(want) => format!("no answer matching {}", want),
+                        None => format!("cumulative ack una={}, expected {}", got_una, want_una),
+                    })
+                }
+                _ => tester.receive(deadline).await?,
+            }
+        };
+        report.check(format!("peer: {}", golden.name), result);
+    }
+
+    // The echo arrives in segments of the peer's choosing
+    let mut want = MESSAGE.to_vec();
+    want.extend(fragmented_message());
+    let deadline = Instant::now() + timeout;
+    while tester.echoed().len() < want.len() && Instant::now() < deadline {
+        tester.receive(deadline).await?;
+    }
+    let echoed = tester.echoed();
+    let result = if echoed == want {
+        Ok(())
+    } else if echoed.len() < want.len() {
+        Err(format!("{} of {} bytes echoed", echoed.len(), want.len()))
+    } else {
+        Err("echo differs from the data sent".to_string())
+    };
+    report.check("peer: echo", result);
+
+    let result = match tester.malformed {
+        None => Ok(()),
+        Some(reason) => Err(reason.to_string()),
+    };
+    report.check("peer: well-formed datagrams", result);
+    Ok(report)
+}
+
+/// Whether a segment of the peer answers like the reference receiver,
+/// ignoring its clock, its window and the cumulative ack
+fn matches(want: &SegmentHeader, got: &SegmentHeader) -> bool {
+    want.conv == got.conv
+        && want.cmd == got.cmd
+        && want.frg == got.frg
+        && want.sn == got.sn
+        && want.len == got.len
+        // An ACK echoes the timestamp of the acknowledged segment
+        && (want.cmd != KCP_CMD_ACK || want.ts == got.ts)
+}
+
+struct Tester {
+    udp: Async<UdpSocket>,
+    /// Every control segment of the peer
+    received: Vec<SegmentHeader>,
+    /// Data pushed by the peer by sn
+    pushed: BTreeMap<u32, Vec<u8>>,
+    /// First problem found in a datagram of the peer
+    malformed: Option<&'static str>,
+}
+
+impl Tester {
+    /// Wait for a datagram until `deadline` and acknowledge its data
+    async fn receive(&mut self, deadline: Instant) -> io::Result<()> {
+        let mut buf = [0u8; 65536];
+        let received = future::or(async { Some(self.udp.recv(&mut buf).await) }, async {
+            Timer::at(deadline).await;
+            None
+        })
+        .await;
+        let n = match received {
+            Some(Err(e)) if unreachable(&e) => return Ok(()),
+            Some(result) => result?,
+            None => return Ok(()),
+        };
+
+        let datagram = &buf[..n];
+        if let Err(reason) = check_datagram(datagram, CONV) {
+            self.malformed.get_or_insert(reason);
+        }
+        let mut acks = Vec::new();
+        for (segment, data) in packet::segments_with_data(datagram) {
+            if segment.cmd == KCP_CMD_PUSH {
+                self.pushed.entry(segment.sn).or_insert_with(|| data.to_vec());
+                acks.push(segment);
+            } else {
+                self.received.push(segment);
+            }
+        }
+
+        let una = self.rcv_nxt();
+        for segment in acks {
+            let ack = SegmentHeader {
+                conv: CONV,
+                cmd: KCP_CMD_ACK,
+                frg: 0,
+                wnd: TESTER_WND,
+                ts: segment.ts,
+                sn: segment.sn,
+                una,
+                len: 0,
+            };
+            self.send(&ack.encode(&[])).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a datagram, a peer that is not there yet fails the checks later
+    async fn send(&self, datagram: &[u8]) -> io::Result<()> {
+        match self.udp.send(datagram).await {
+            Err(e) if !unreachable(&e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Next sn expected from the peer
+    fn rcv_nxt(&self) -> u32 {
+        let mut sn = 0;
+        while self.pushed.contains_key(&sn) {
+            sn += 1;
+        }
+        sn
+    }
+
+    /// Data of the peer received in order
+    fn echoed(&self) -> Vec<u8> {
+        (0..self.rcv_nxt())
+            .flat_map(|sn| self.pushed[&sn].iter().copied())
+            .collect()
+    }
+}
+
+/// ICMP errors for earlier datagrams
+fn unreachable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
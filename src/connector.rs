@@ -0,0 +1,194 @@
+//! Client sessions to many servers on one UDP socket
+//!
+//! [`KcpConnector`] binds one socket and opens every outbound session on
+//! it, datagrams are handed to their session by the server address and
+//! conv. There is no task of its own: whichever of the connector's streams
+//! waits for input reads the socket and feeds what arrives to the session
+//! it belongs to, as an accept loop does for a listener.
+
+use std::{
+    collections::HashMap,
+    io, mem,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex, Weak},
+};
+
+use async_io::Async;
+use async_lock::Mutex;
+
+use crate::{
+    config::{HeaderFormat, KcpConfig},
+    error::KcpResult,
+    logging::trace,
+    socket::{canonical_addr, configure_udp, KcpSocket},
+    stream::KcpStream,
+};
+
+type Sessions = HashMap<(SocketAddr, u32), Weak<Mutex<KcpSocket>>>;
+
+/// Socket and sessions shared by the streams of a connector
+pub(crate) struct Demux {
+    udp: Arc<Async<std::net::UdpSocket>>,
+    header_format: HeaderFormat,
+    sessions: Mutex<Sessions>,
+    /// Sessions dropped while a stream reading the socket held them
+    abandoned: StdMutex<Vec<Arc<Mutex<KcpSocket>>>>,
+}
+
+impl Demux {
+    /// Receive one datagram and feed it to its session
+    ///
+    /// Datagrams of unknown sessions are dropped. Input errors belong to
+    /// the session they arrived for, not to the stream that read them.
+    pub async fn receive(&self, buf: &mut [u8]) -> KcpResult<()> {
+        let (n, from) = match self.udp.recv_from(buf).await {
+            Ok((n, from)) => (n, canonical_addr(from)),
+            // ICMP errors for earlier datagrams, KCP retransmits anyway
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let datagram = &buf[..n];
+        let Some(conv) = self.header_format.conv(datagram) else {
+            return Ok(());
+        };
+        let session = self.sessions.lock().await.get(&(from, conv)).and_then(Weak::upgrade);
+        let Some(session) = session else {
+            trace!("dropped a datagram of unknown conv {} from {}", conv, from);
+            return Ok(());
+        };
+        let mut socket = session.lock().await;
+        if let Err(e) = socket.input(datagram) {
+            trace!("{} dropped a datagram: {}", socket.tag(), e);
+        }
+        if let Err(e) = socket.send_output().await {
+            trace!("{} send error: {}", socket.tag(), e);
+        }
+        drop(socket);
+        self.close_abandoned().await;
+        Ok(())
+    }
+
+    /// Close a session whose streams were all dropped
+    ///
+    /// The dropping stream is its last user and sends the close frame at
+    /// once, unless a stream reading the socket holds the session. That
+    /// one closes it instead once it let go, see
+    /// [`Demux::close_abandoned`].
+    pub(crate) fn close_dropped(&self, session: &Arc<Mutex<KcpSocket>>) {
+        // Held across the attempt, so the reader cannot miss the session
+        let mut abandoned = self.abandoned.lock().unwrap();
+        match session.try_lock() {
+            Some(mut socket) => {
+                socket.close_dropped();
+                socket.send_output_now();
+            }
+            None => abandoned.push(session.clone()),
+        }
+    }
+
+    async fn close_abandoned(&self) {
+        let abandoned = mem::take(&mut *self.abandoned.lock().unwrap());
+        for session in abandoned {
+            let mut socket = session.lock().await;
+            socket.close_dropped();
+            if let Err(e) = socket.send_output().await {
+                trace!("{} close frame not sent: {}", socket.tag(), e);
+            }
+        }
+    }
+}
+
+/// KCP client opening sessions to any number of servers on one UDP socket
+///
+/// Instead of an ephemeral port per [`KcpStream::connect`], all streams of
+/// the connector share the socket bound by [`KcpConnector::bind`], e.g. for
+/// a client relaying through many upstream servers. Streams work as
+/// connected ones, except that they cannot
+/// [`rebind`](KcpStream::rebind). Multipath and SOCKS5 sessions need a
+/// socket of their own.
+pub struct KcpConnector {
+    config: KcpConfig,
+    pub(crate) demux: Arc<Demux>,
+}
+
+impl KcpConnector {
+    /// Bind the shared socket to `addr`, e.g. `0.0.0.0:0`
+    ///
+    /// Sessions use `config`. Servers must be reachable from the address
+    /// family of `addr`.
+    pub async fn bind(config: KcpConfig, addr: SocketAddr) -> KcpResult<Self> {
+        let udp = std::net::UdpSocket::bind(addr)?;
+        configure_udp(&udp)?;
+        Ok(Self {
+            config,
+            demux: Arc::new(Demux {
+                udp: Arc::new(Async::new(udp)?),
+                header_format: config.header_format,
+                sessions: Mutex::new(HashMap::new()),
+                abandoned: StdMutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// Connect to a KCP server
+    pub async fn connect(&self, addr: SocketAddr) -> KcpResult<KcpStream> {
+        self.connect_conv(addr, None).await
+    }
+
+    /// Connect to a KCP server with the given conversation id
+    ///
+    /// Fails with `AddrInUse` if the connector has a session with `addr`
+    /// and `conv` already.
+    pub async fn connect_with_conv(&self, addr: SocketAddr, conv: u32) -> KcpResult<KcpStream> {
+        self.connect_conv(addr, Some(conv)).await
+    }
+
+    async fn connect_conv(&self, addr: SocketAddr, conv: Option<u32>) -> KcpResult<KcpStream> {
+        // Sessions are found by the address datagrams arrive from
+        let addr = canonical_addr(addr);
+        let mut sessions = self.demux.sessions.lock().await;
+        sessions.retain(|_, session| session.strong_count() > 0);
+        let conv = match conv {
+            Some(conv) if sessions.contains_key(&(addr, conv)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("conv {} with {} is in use", conv, addr),
+                )
+                .into());
+            }
+            Some(conv) => conv,
+            None => loop {
+                let conv = self.config.new_conv();
+                if !sessions.contains_key(&(addr, conv)) {
+                    break conv;
+                }
+            },
+        };
+
+        let mut socket = KcpSocket::new(&self.config, conv, self.demux.udp.clone(), addr, self.config.stream)?;
+        if self.config.session_ids {
+            socket.offer_session_id();
+        }
+        let tag = socket.tag();
+        let ring = socket.ring().clone();
+        let socket = Arc::new(Mutex::new(socket));
+        sessions.insert((addr, conv), Arc::downgrade(&socket));
+        drop(sessions);
+        trace!("{} connecting on the shared socket", tag);
+
+        let mut stream = KcpStream::from_socket(socket, ring, self.demux.udp.clone(), tag);
+        stream.demux = Some(self.demux.clone());
+        Ok(stream)
+    }
+
+    /// Number of open sessions
+    pub async fn session_count(&self) -> usize {
+        let sessions = self.demux.sessions.lock().await;
+        sessions.values().filter(|session| session.strong_count() > 0).count()
+    }
+
+    /// Local address of the shared socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.demux.udp.get_ref().local_addr()
+    }
+}
@@ -0,0 +1,610 @@
+//! Zero-config discovery of KCP services on the LAN with mDNS / DNS-SD
+//!
+//! A listener announces itself as an instance of a service type such as
+//! `_mykcp._udp`, clients browse for the type and connect to the first
+//! instance that answers:
+//!
+//! ```ignore
+//! // router
+//! let listener = KcpListener::bind(config, "0.0.0.0:4000".parse()?).await?;
+//! let announcer = listener.announcer("_mykcp._udp", "living-room")?;
+//! std::thread::spawn(move || block_on(announcer.run()));
+//!
+//! // set-top box
+//! let stream = KcpStream::discover_and_connect(&config, "_mykcp._udp").await?;
+//! ```
+//!
+//! Only IPv4 multicast (224.0.0.251:5353) is used, and instance names are
+//! not probed for conflicts, so pick names unique on the link. Other mDNS
+//! responders (Avahi, Bonjour) on the same host keep working, the port is
+//! shared on Unix.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use futures_lite::future;
+
+use crate::{
+    logging::{debug, trace},
+    socket::bind_shared,
+};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on unique records, caches replace older ones
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// TTLs recommended by RFC 6762 section 10
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+
+/// Name that enumerates all service types (RFC 6763 section 9)
+const SERVICES: &str = "_services._dns-sd._udp.local";
+
+/// Time [`crate::KcpStream::discover_and_connect`] waits for an answer
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Answers mDNS queries for one service instance
+///
+/// Nothing is announced until [`ServiceAnnouncer::run`] is driven. Dropping
+/// the announcer tells the LAN the instance is gone.
+pub struct ServiceAnnouncer {
+    socket: Async<UdpSocket>,
+    service: String,
+    instance: String,
+    host: String,
+    port: u16,
+    addrs: Vec<Ipv4Addr>,
+}
+
+impl ServiceAnnouncer {
+    /// Announce `instance` of `service` (e.g. `_mykcp._udp`) on `port`
+    ///
+    /// `addr` is the address the service is bound to, an unspecified
+    /// address announces every IPv4 address of the host.
+    pub fn new(service: &str, instance: &str, addr: SocketAddr) -> io::Result<Self> {
+        let service = fqdn(service);
+        if instance.is_empty() || instance.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "instance name must be 1 to 63 bytes",
+            ));
+        }
+
+        let socket = bind_shared(MDNS_PORT)?;
+        socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_ttl_v4(255)?;
+        socket.set_multicast_loop_v4(true)?;
+
+        let addrs = match addr.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => vec![ip],
+            _ => host_addrs(),
+        };
+        Ok(Self {
+            socket: Async::new(socket)?,
+            instance: format!("{}.{}", instance, service),
+            service,
+            host: format!("{}.local", hostname()),
+            port: addr.port(),
+            addrs,
+        })
+    }
+
+    /// Full name of the announced instance
+    pub fn instance_name(&self) -> &str {
+        &self.instance
+    }
+
+    /// Announce the instance, then answer queries until the socket fails
+    pub async fn run(&self) -> io::Result<()> {
+        // RFC 6762 section 8.3: at least two announcements a second apart
+        let announcement = self.response(0, &[], SERVICE_TTL, HOST_TTL);
+        self.send(&announcement, group()).await?;
+        Timer::after(Duration::from_secs(1)).await;
+        self.send(&announcement, group()).await?;
+        debug!("mDNS announced {} on port {}", self.instance, self.port);
+
+        let mut buf = vec![0u8; 9000];
+        loop {
+            let (n, from) = self.socket.recv_from(&mut buf).await?;
+            let Some(message) = Message::parse(&buf[..n]) else {
+                continue;
+            };
+            if message.is_response() {
+                continue;
+            }
+            let asked: Vec<&Question> = message
+                .questions
+                .iter()
+                .filter(|q| self.answers(q))
+                .collect();
+            if asked.is_empty() {
+                continue;
+            }
+            trace!("mDNS query for {} from {}", asked[0].name, from);
+
+            // One-shot queriers not on port 5353 get a unicast reply with
+            // their id and question (RFC 6762 section 6.7)
+            if from.port() == MDNS_PORT {
+                let reply = self.response(0, &[], SERVICE_TTL, HOST_TTL);
+                self.send(&reply, group()).await?;
+            } else {
+                let reply = self.response(message.id, &asked, 10, 10);
+                self.send(&reply, from).await?;
+            }
+        }
+    }
+
+    fn answers(&self, question: &Question) -> bool {
+        let name = question.name.as_str();
+        match question.qtype {
+            TYPE_PTR => name.eq_ignore_ascii_case(&self.service) || name.eq_ignore_ascii_case(SERVICES),
+            TYPE_SRV | TYPE_TXT => name.eq_ignore_ascii_case(&self.instance),
+            TYPE_A => name.eq_ignore_ascii_case(&self.host),
+            TYPE_ANY => [&self.service, &self.instance, &self.host]
+                .iter()
+                .any(|own| name.eq_ignore_ascii_case(own)),
+            _ => false,
+        }
+    }
+
+    /// All records of the instance, the PTR as answer and the rest as
+    /// additional records
+    fn response(&self, id: u16, questions: &[&Question], service_ttl: u32, host_ttl: u32) -> Vec<u8> {
+        let mut answers = vec![
+            Record::ptr(SERVICES, &self.service, service_ttl),
+            Record::ptr(&self.service, &self.instance, service_ttl),
+        ];
+        let mut additional = vec![
+            Record::srv(&self.instance, &self.host, self.port, host_ttl),
+            Record::txt(&self.instance, service_ttl),
+        ];
+        additional.extend(self.addrs.iter().map(|ip| Record::a(&self.host, *ip, host_ttl)));
+        if questions.iter().any(|q| q.qtype != TYPE_PTR) {
+            answers.append(&mut additional);
+        }
+        encode_response(id, questions, &answers, &additional)
+    }
+
+    async fn send(&self, message: &[u8], to: SocketAddr) -> io::Result<()> {
+        self.socket.send_to(message, to).await.map(drop)
+    }
+}
+
+impl Drop for ServiceAnnouncer {
+    fn drop(&mut self) {
+        // Goodbye: the same records with a TTL of zero
+        let goodbye = self.response(0, &[], 0, 0);
+        let _ = self.socket.get_ref().send_to(&goodbye, group());
+    }
+}
+
+/// An instance found by [`browse`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInstance {
+    /// Instance name, e.g. `living-room._mykcp._udp.local`
+    pub name: String,
+    /// Host name, e.g. `router.local`
+    pub host: String,
+    /// Addresses to connect to, with the announced port
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// Find the instances of `service` (e.g. `_mykcp._udp`) within `timeout`
+pub async fn browse(service: &str, timeout: Duration) -> io::Result<Vec<ServiceInstance>> {
+    query(service, timeout, false).await
+}
+
+/// Find the first instance of `service` that answers within `timeout`
+pub async fn discover(service: &str, timeout: Duration) -> io::Result<Option<ServiceInstance>> {
+    Ok(query(service, timeout, true).await?.into_iter().next())
+}
+
+async fn query(service: &str, timeout: Duration, first: bool) -> io::Result<Vec<ServiceInstance>> {
+    let service = fqdn(service);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+    let socket = Async::new(socket)?;
+    let query = encode_query(&service);
+
+    let deadline = Instant::now() + timeout;
+    let mut cache = Cache::default();
+    let mut buf = vec![0u8; 9000];
+    let mut next_query = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        if now >= next_query {
+            socket.send_to(&query, group()).await?;
+            next_query = now + Duration::from_secs(1);
+        }
+
+        let wake = next_query.min(deadline);
+        let received = future::or(async { Some(socket.recv_from(&mut buf).await) }, async {
+            Timer::at(wake).await;
+            None
+        })
+        .await;
+        let Some((n, from)) = received.transpose()? else {
+            continue;
+        };
+        if let Some(message) = Message::parse(&buf[..n]).filter(Message::is_response) {
+            cache.add(&message, from.ip());
+        }
+        if first && !cache.resolve(&service).is_empty() {
+            break;
+        }
+    }
+    Ok(cache.resolve(&service))
+}
+
+/// Records collected while browsing
+#[derive(Default)]
+struct Cache {
+    /// Instances by service
+    ptr: Vec<(String, String)>,
+    /// Host and port by instance
+    srv: HashMap<String, (String, u16)>,
+    /// Addresses by host
+    a: HashMap<String, Vec<IpAddr>>,
+    /// Source of the answer naming the instance, if no A record comes
+    source: HashMap<String, IpAddr>,
+}
+
+impl Cache {
+    fn add(&mut self, message: &Message, source: IpAddr) {
+        for record in &message.records {
+            let name = record.name.to_ascii_lowercase();
+            match &record.data {
+                Data::Ptr(target) if record.ttl > 0 => {
+                    let entry = (name, target.to_ascii_lowercase());
+                    if !self.ptr.contains(&entry) {
+                        self.ptr.push(entry);
+                    }
+                }
+                Data::Srv { target, port } if record.ttl > 0 => {
+                    self.srv.insert(name.clone(), (target.to_ascii_lowercase(), *port));
+                    self.source.insert(name, source);
+                }
+                Data::A(ip) if record.ttl > 0 => {
+                    let addrs = self.a.entry(name).or_default();
+                    if !addrs.contains(&IpAddr::V4(*ip)) {
+                        addrs.push(IpAddr::V4(*ip));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Instances of `service` with a known port
+    fn resolve(&self, service: &str) -> Vec<ServiceInstance> {
+        let service = service.to_ascii_lowercase();
+        self.ptr
+            .iter()
+            .filter(|(name, _)| *name == service)
+            .filter_map(|(_, instance)| {
+                let (host, port) = self.srv.get(instance)?;
+                let ips = match self.a.get(host) {
+                    Some(ips) => ips.clone(),
+                    None => vec![*self.source.get(instance)?],
+                };
+                Some(ServiceInstance {
+                    name: instance.clone(),
+                    host: host.clone(),
+                    addrs: ips.into_iter().map(|ip| SocketAddr::new(ip, *port)).collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+fn group() -> SocketAddr {
+    SocketAddrV4::new(MDNS_GROUP, MDNS_PORT).into()
+}
+
+/// `_mykcp._udp` and `_mykcp._udp.local.` both become `_mykcp._udp.local`
+fn fqdn(name: &str) -> String {
+    let name = name.trim_end_matches('.');
+    if name.to_ascii_lowercase().ends_with(".local") {
+        name.to_string()
+    } else {
+        format!("{}.local", name)
+    }
+}
+
+struct Question {
+    name: String,
+    qtype: u16,
+}
+
+enum Data {
+    Ptr(String),
+    Srv { target: String, port: u16 },
+    Txt,
+    A(Ipv4Addr),
+    Other,
+}
+
+struct Record {
+    name: String,
+    ttl: u32,
+    data: Data,
+}
+
+impl Record {
+    fn ptr(name: &str, target: &str, ttl: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            ttl,
+            data: Data::Ptr(target.to_string()),
+        }
+    }
+
+    fn srv(name: &str, target: &str, port: u16, ttl: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            ttl,
+            data: Data::Srv {
+                target: target.to_string(),
+                port,
+            },
+        }
+    }
+
+    fn txt(name: &str, ttl: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            ttl,
+            data: Data::Txt,
+        }
+    }
+
+    fn a(name: &str, ip: Ipv4Addr, ttl: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            ttl,
+            data: Data::A(ip),
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_name(buf, &self.name);
+        let (rtype, class) = match self.data {
+            Data::Ptr(_) => (TYPE_PTR, CLASS_IN),
+            Data::Srv { .. } => (TYPE_SRV, CLASS_IN | CACHE_FLUSH),
+            Data::Txt => (TYPE_TXT, CLASS_IN | CACHE_FLUSH),
+            Data::A(_) => (TYPE_A, CLASS_IN | CACHE_FLUSH),
+            Data::Other => return,
+        };
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&class.to_be_bytes());
+        buf.extend_from_slice(&self.ttl.to_be_bytes());
+
+        let mut rdata = Vec::new();
+        match &self.data {
+            Data::Ptr(target) => write_name(&mut rdata, target),
+            Data::Srv { target, port } => {
+                // Priority and weight
+                rdata.extend_from_slice(&[0, 0, 0, 0]);
+                rdata.extend_from_slice(&port.to_be_bytes());
+                write_name(&mut rdata, target);
+            }
+            // A single empty string, TXT data must not be empty
+            Data::Txt => rdata.push(0),
+            Data::A(ip) => rdata.extend_from_slice(&ip.octets()),
+            Data::Other => {}
+        }
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&rdata);
+    }
+}
+
+/// A parsed DNS message, records of all sections together
+struct Message {
+    id: u16,
+    flags: u16,
+    questions: Vec<Question>,
+    records: Vec<Record>,
+}
+
+impl Message {
+    fn is_response(&self) -> bool {
+        self.flags & 0x8000 != 0
+    }
+
+    fn parse(msg: &[u8]) -> Option<Self> {
+        let read_u16 = |at: usize| -> Option<u16> {
+            Some(u16::from_be_bytes(msg.get(at..at + 2)?.try_into().ok()?))
+        };
+        let id = read_u16(0)?;
+        let flags = read_u16(2)?;
+        let qdcount = read_u16(4)?;
+        let rrcount = read_u16(6)? as usize + read_u16(8)? as usize + read_u16(10)? as usize;
+
+        let mut pos = 12;
+        let mut questions = Vec::new();
+        for _ in 0..qdcount {
+            let name = read_name(msg, &mut pos)?;
+            let qtype = read_u16(pos)?;
+            pos += 4;
+            questions.push(Question { name, qtype });
+        }
+
+        let mut records = Vec::new();
+        for _ in 0..rrcount {
+            let name = read_name(msg, &mut pos)?;
+            let rtype = read_u16(pos)?;
+            let ttl = u32::from_be_bytes(msg.get(pos + 4..pos + 8)?.try_into().ok()?);
+            let len = read_u16(pos + 8)? as usize;
+            let start = pos + 10;
+            let rdata = msg.get(start..start + len)?;
+            let data = match rtype {
+                TYPE_PTR => Data::Ptr(read_name(msg, &mut start.clone())?),
+                TYPE_SRV if len >= 6 => Data::Srv {
+                    port: read_u16(start + 4)?,
+                    target: read_name(msg, &mut (start + 6))?,
+                },
+                TYPE_A if len == 4 => Data::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+                _ => Data::Other,
+            };
+            records.push(Record { name, ttl, data });
+            pos = start + len;
+        }
+
+        Some(Self {
+            id,
+            flags,
+            questions,
+            records,
+        })
+    }
+}
+
+/// Read a possibly compressed name at `pos` and move past it
+fn read_name(msg: &[u8], pos: &mut usize) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut at = *pos;
+    let mut jumped = false;
+    // Bounds pointer loops
+    for _ in 0..128 {
+        let len = *msg.get(at)? as usize;
+        match len {
+            0 => {
+                if !jumped {
+                    *pos = at + 1;
+                }
+                return Some(labels.join("."));
+            }
+            0xc0.. => {
+                let offset = ((len & 0x3f) << 8) | *msg.get(at + 1)? as usize;
+                if !jumped {
+                    *pos = at + 2;
+                    jumped = true;
+                }
+                at = offset;
+            }
+            1..=63 => {
+                let label = msg.get(at + 1..at + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+fn encode_query(service: &str) -> Vec<u8> {
+    let mut msg = vec![0u8; 12];
+    msg[5] = 1;
+    write_name(&mut msg, service);
+    msg.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    msg
+}
+
+fn encode_response(id: u16, questions: &[&Question], answers: &[Record], additional: &[Record]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(512);
+    msg.extend_from_slice(&id.to_be_bytes());
+    // Response, authoritative
+    msg.extend_from_slice(&0x8400u16.to_be_bytes());
+    for count in [questions.len(), answers.len(), 0, additional.len()] {
+        msg.extend_from_slice(&(count as u16).to_be_bytes());
+    }
+    for question in questions {
+        write_name(&mut msg, &question.name);
+        msg.extend_from_slice(&question.qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for record in answers.iter().chain(additional) {
+        record.encode(&mut msg);
+    }
+    msg
+}
+
+/// IPv4 addresses of the host, loopback only if there is nothing else
+#[cfg(unix)]
+fn host_addrs() -> Vec<Ipv4Addr> {
+    let mut ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs fills in a list freed below
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Vec::new();
+    }
+    let mut addrs = Vec::new();
+    let mut next = ifaddrs;
+    // SAFETY: walking the list returned by getifaddrs
+    while let Some(ifa) = unsafe { next.as_ref() } {
+        // SAFETY: ifa_addr is null or points to a sockaddr of its family
+        if let Some(addr) = unsafe { ifa.ifa_addr.as_ref() } {
+            if addr.sa_family == libc::AF_INET as libc::sa_family_t {
+                // SAFETY: AF_INET addresses are sockaddr_in
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                addrs.push(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)));
+            }
+        }
+        next = ifa.ifa_next;
+    }
+    // SAFETY: the list came from getifaddrs
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    if addrs.iter().any(|ip| !ip.is_loopback()) {
+        addrs.retain(|ip| !ip.is_loopback());
+    }
+    addrs.dedup();
+    addrs
+}
+
+/// Clients fall back to the source address of the answer
+#[cfg(not(unix))]
+fn host_addrs() -> Vec<Ipv4Addr> {
+    Vec::new()
+}
+
+/// First label of the host name, only letters, digits and dashes
+fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: gethostname writes at most buf.len() bytes
+        if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } == 0 {
+            let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            let name: String = String::from_utf8_lossy(&buf[..end])
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .take(63)
+                .collect();
+            if !name.is_empty() {
+                return name;
+            }
+        }
+    }
+    "smol-kcp".to_string()
+}
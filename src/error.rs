@@ -0,0 +1,132 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    io::{self, ErrorKind},
+};
+
+use smol_kcp_core::Error as EngineError;
+
+use crate::socket::CloseCode;
+
+/// Errors of the public API
+///
+/// Session failures have their own variants, so callers can tell a peer
+/// that went away from a full window or a broken socket.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The peer never answered within
+    /// [`KcpConfig::handshake_timeout`](crate::KcpConfig::handshake_timeout)
+    HandshakeTimeout,
+    /// The session or stream was closed, locally or by the peer
+    ConnectionClosed,
+    /// The peer closed the session for a reason other than a normal
+    /// close, e.g. the server shut down
+    ClosedByPeer(CloseCode),
+    /// The peer reset the session, e.g. it restarted with a new conversation
+    ConnectionReset,
+    /// Nothing listens on the peer's port, the session expired while ICMP
+    /// reported it unreachable
+    ConnectionRefused,
+    /// Nothing was heard from the peer within `session_expire` while data
+    /// waited for acknowledgement
+    SessionExpired,
+    /// The send window or memory limit leaves no room for the data now
+    ///
+    /// Only from calls that do not wait for room, `AsyncWrite` stays
+    /// pending until there is.
+    WindowExhausted,
+    /// The session was dropped for exceeding a memory limit
+    MemoryLimitExceeded,
+    /// The UDP socket or another I/O operation failed
+    Transport(io::Error),
+    /// The KCP engine rejected a segment or call
+    Protocol(EngineError),
+}
+
+/// Former name of [`Error`]
+#[deprecated(note = "use `smol_kcp::Error`")]
+pub type KcpError = Error;
+
+pub type KcpResult<T> = Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::HandshakeTimeout => f.write_str("handshake timed out"),
+            Error::ConnectionClosed => f.write_str("connection closed"),
+            Error::ClosedByPeer(code) => write!(f, "connection closed by peer: {}", code),
+            Error::ConnectionReset => f.write_str("connection reset by peer"),
+            Error::ConnectionRefused => f.write_str("connection refused"),
+            Error::SessionExpired => f.write_str("session expired, the peer stopped answering"),
+            Error::WindowExhausted => f.write_str("send window exhausted"),
+            Error::MemoryLimitExceeded => {
+                f.write_str("session dropped for exceeding a memory limit")
+            }
+            Error::Transport(e) => fmt::Display::fmt(e, f),
+            Error::Protocol(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e),
+            Error::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl From<EngineError> for Error {
+    fn from(e: EngineError) -> Self {
+        Error::Protocol(e)
+    }
+}
+
+impl Error {
+    /// The `io::ErrorKind` this error surfaces as through `AsyncRead`,
+    /// `AsyncWrite` and other `io::Error` based APIs
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::HandshakeTimeout | Error::SessionExpired => ErrorKind::TimedOut,
+            Error::ConnectionClosed => ErrorKind::BrokenPipe,
+            Error::ClosedByPeer(CloseCode::IdleTimeout) => ErrorKind::TimedOut,
+            Error::ClosedByPeer(CloseCode::ProtocolError) => ErrorKind::InvalidData,
+            Error::ClosedByPeer(CloseCode::Application(_)) => ErrorKind::ConnectionReset,
+            Error::ClosedByPeer(CloseCode::Overloaded) => ErrorKind::ConnectionRefused,
+            Error::ClosedByPeer(_) => ErrorKind::ConnectionAborted,
+            Error::ConnectionReset => ErrorKind::ConnectionReset,
+            Error::ConnectionRefused => ErrorKind::ConnectionRefused,
+            Error::WindowExhausted => ErrorKind::WouldBlock,
+            Error::MemoryLimitExceeded => ErrorKind::OutOfMemory,
+            Error::Transport(e) => e.kind(),
+            Error::Protocol(e) => match e {
+                EngineError::RecvQueueEmpty | EngineError::ExpectingFragment => {
+                    ErrorKind::WouldBlock
+                }
+                EngineError::InvalidMtu(_)
+                | EngineError::UserBufTooBig
+                | EngineError::UserBufTooSmall => ErrorKind::InvalidInput,
+                EngineError::NeedUpdate => ErrorKind::Other,
+                _ => ErrorKind::InvalidData,
+            },
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Transport(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Connection and listener event notifications
+
+use std::{
+    any::Any,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Something noteworthy happened on a connection or listener
+///
+/// Delivered to the handler registered with
+/// [`KcpStream::set_event_handler`](crate::KcpStream::set_event_handler),
+/// or with
+/// [`KcpListener::set_event_handler`](crate::KcpListener::set_event_handler)
+/// for events of the listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KcpEvent {
+    /// A data segment was sent again
+    Retransmitted {
+        /// Sequence number of the segment
+        sn: u32,
+    },
+    /// Segments were resent early because of duplicate ACKs
+    FastResend {
+        /// Number of segments resent
+        segments: u32,
+    },
+    /// Segments hit their retransmission timeout; KCP backs off their RTO
+    /// and collapses the congestion window
+    RtoBackoff {
+        /// Number of segments that timed out
+        segments: u32,
+        /// Current base retransmission timeout
+        rto: Duration,
+    },
+    /// More segments are waiting than the send or remote window admits
+    WindowStalled {
+        /// Segments queued or in flight
+        waiting: usize,
+        /// Local send window
+        snd_wnd: u16,
+        /// Window advertised by the peer
+        rmt_wnd: u16,
+    },
+    /// The send window has room again after a stall
+    WindowResumed,
+    /// A segment reached the retransmission limit, the peer is unreachable
+    DeadLink,
+    /// The peer dropped its end of the session, reads return the data
+    /// received so far and then end
+    PeerClosed,
+    /// The peer proved it is reachable at a new address and the session
+    /// follows it there, after a NAT rebinding or when the client roamed
+    PeerMigrated {
+        /// Address the session was using
+        from: SocketAddr,
+        /// Address the session uses from now on
+        to: SocketAddr,
+    },
+    /// The session buffered more than
+    /// [`KcpConfig::max_session_memory`](crate::KcpConfig::max_session_memory)
+    /// and was closed
+    MemoryLimitExceeded {
+        /// Bytes buffered when the limit was hit
+        buffered: usize,
+        /// Configured limit
+        limit: usize,
+    },
+    /// The listener banned a source IP for sending malformed packets or
+    /// failing handshakes, see
+    /// [`KcpListener::set_auto_ban`](crate::KcpListener::set_auto_ban)
+    SourceBanned {
+        ip: IpAddr,
+        /// Offenses within the window that led to the ban
+        offenses: u32,
+        /// How long the ban lasts
+        duration: Duration,
+    },
+}
+
+/// Callback receiving [`KcpEvent`]s
+///
+/// Called synchronously while the connection is locked, so it must be
+/// cheap and must not call back into the stream.
+pub type KcpEventHandler = Arc<dyn Fn(&KcpEvent) + Send + Sync>;
+
+/// Application data attached to a session, e.g. the identity a client
+/// authenticated as
+///
+/// Set with [`KcpStream::set_user_data`](crate::KcpStream::set_user_data)
+/// and listed in [`SessionInfo`](crate::SessionInfo). Event handlers see
+/// it by capturing the same `Arc`.
+pub type UserData = Arc<dyn Any + Send + Sync>;
@@ -0,0 +1,247 @@
+//! C ABI for embedding smol-kcp in C programs
+//!
+//! All calls block the calling thread. Failures return `NULL` or `-1`,
+//! [`kcp_last_error`] describes the most recent failure of the thread.
+//! The header `include/smol_kcp.h` is generated with
+//! `cbindgen --config cbindgen.toml --output include/smol_kcp.h`.
+//!
+//! Accepted sessions only receive data while [`kcp_serve`] runs, so the
+//! accept callback must hand the connection to another thread and return.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    net::{SocketAddr, ToSocketAddrs},
+    ptr, slice,
+};
+
+use futures_lite::future::block_on;
+
+use crate::{Error, KcpConfig, KcpListener, KcpStream};
+
+/// A KCP connection
+pub struct KcpHandle(KcpStream);
+
+/// A listening KCP socket
+pub struct KcpServer(KcpListener);
+
+/// Called by [`kcp_serve`] for every new connection
+///
+/// The callback owns `conn` and frees it with [`kcp_close`]. `peer` is only
+/// valid during the call. Returning non-zero stops [`kcp_serve`].
+pub type KcpAcceptCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, conn: *mut KcpHandle, peer: *const c_char) -> c_int>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(msg: impl ToString) {
+    let msg = CString::new(msg.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// Message of the last failed call on this thread, `NULL` if none
+///
+/// The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn kcp_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+/// Parse and resolve a `host:port` C string
+///
+/// # Safety
+///
+/// `addr` must be `NULL` or a valid NUL terminated string.
+unsafe fn parse_addr(addr: *const c_char) -> Option<SocketAddr> {
+    if addr.is_null() {
+        set_error("address is NULL");
+        return None;
+    }
+    let addr = match CStr::from_ptr(addr).to_str() {
+        Ok(addr) => addr,
+        Err(_) => {
+            set_error("address is not valid UTF-8");
+            return None;
+        }
+    };
+    match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => Some(addr),
+        Ok(None) => {
+            set_error(format!("{} did not resolve to an address", addr));
+            None
+        }
+        Err(e) => {
+            set_error(format!("invalid address {}: {}", addr, e));
+            None
+        }
+    }
+}
+
+/// Connect to a KCP server at `host:port` with the default configuration
+///
+/// # Safety
+///
+/// `addr` must be `NULL` or a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_connect(addr: *const c_char) -> *mut KcpHandle {
+    let Some(addr) = parse_addr(addr) else {
+        return ptr::null_mut();
+    };
+    match block_on(KcpStream::connect(&KcpConfig::default(), addr)) {
+        Ok(stream) => Box::into_raw(Box::new(KcpHandle(stream))),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Second handle to the same connection
+///
+/// One thread can send on one handle while another receives on the other.
+/// Each handle is freed with [`kcp_close`].
+///
+/// # Safety
+///
+/// `conn` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_dup(conn: *const KcpHandle) -> *mut KcpHandle {
+    match conn.as_ref() {
+        Some(conn) => Box::into_raw(Box::new(KcpHandle(conn.0.clone_handle()))),
+        None => {
+            set_error("connection is NULL");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Send `len` bytes, returns the number of bytes queued or -1
+///
+/// # Safety
+///
+/// `conn` must be a live handle not used by another thread at the same
+/// time, `buf` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_send(conn: *mut KcpHandle, buf: *const u8, len: usize) -> isize {
+    let Some(conn) = conn.as_mut() else {
+        set_error("connection is NULL");
+        return -1;
+    };
+    if buf.is_null() && len > 0 {
+        set_error("buffer is NULL");
+        return -1;
+    }
+    let data = if len == 0 { &[][..] } else { slice::from_raw_parts(buf, len) };
+    result(block_on(conn.0.send(data)))
+}
+
+/// Receive into `buf`, blocks until data arrives
+///
+/// Returns the number of bytes received or -1.
+///
+/// # Safety
+///
+/// `conn` must be a live handle not used by another thread at the same
+/// time, `buf` must point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_recv(conn: *mut KcpHandle, buf: *mut u8, len: usize) -> isize {
+    let Some(conn) = conn.as_mut() else {
+        set_error("connection is NULL");
+        return -1;
+    };
+    if buf.is_null() || len == 0 {
+        set_error("buffer is empty");
+        return -1;
+    }
+    result(block_on(conn.0.recv(slice::from_raw_parts_mut(buf, len))))
+}
+
+fn result(result: Result<usize, Error>) -> isize {
+    match result {
+        Ok(n) => n as isize,
+        Err(e) => {
+            set_error(e);
+            -1
+        }
+    }
+}
+
+/// Free a connection handle, `NULL` is ignored
+///
+/// # Safety
+///
+/// `conn` must be `NULL` or a handle not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_close(conn: *mut KcpHandle) {
+    if !conn.is_null() {
+        drop(Box::from_raw(conn));
+    }
+}
+
+/// Listen on `host:port` with the default configuration
+///
+/// # Safety
+///
+/// `addr` must be `NULL` or a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_listen(addr: *const c_char) -> *mut KcpServer {
+    let Some(addr) = parse_addr(addr) else {
+        return ptr::null_mut();
+    };
+    match block_on(KcpListener::bind(KcpConfig::default(), addr)) {
+        Ok(listener) => Box::into_raw(Box::new(KcpServer(listener))),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Accept connections and pass each to `callback`
+///
+/// Runs until the callback returns non-zero (returns 0) or the socket
+/// fails (returns -1).
+///
+/// # Safety
+///
+/// `server` must be a live server used by no other thread, `callback`
+/// must be safe to call with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_serve(
+    server: *mut KcpServer,
+    callback: KcpAcceptCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let (Some(server), Some(callback)) = (server.as_mut(), callback) else {
+        set_error("server or callback is NULL");
+        return -1;
+    };
+    loop {
+        let (stream, peer) = match block_on(server.0.accept()) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                set_error(e);
+                return -1;
+            }
+        };
+        let peer = CString::new(peer.to_string()).unwrap_or_default();
+        let conn = Box::into_raw(Box::new(KcpHandle(stream)));
+        if callback(user_data, conn, peer.as_ptr()) != 0 {
+            return 0;
+        }
+    }
+}
+
+/// Close a listening socket, `NULL` is ignored
+///
+/// # Safety
+///
+/// `server` must be `NULL` or a server not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_listener_close(server: *mut KcpServer) {
+    if !server.is_null() {
+        drop(Box::from_raw(server));
+    }
+}
@@ -0,0 +1,203 @@
+//! Frame-oriented adapter over a [`KcpStream`]
+//!
+//! Stream mode delivers a plain byte stream, so protocols that need message
+//! boundaries plug a codec into [`KcpFramed`] instead of reimplementing the
+//! buffering. [`LengthDelimitedCodec`] covers the common case.
+
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::{error::KcpResult, stream::KcpStream};
+
+/// Size of the read buffer used to pull bytes from the stream
+const READ_CHUNK: usize = 4096;
+
+/// Decodes frames from a byte buffer
+pub trait Decoder {
+    /// Decoded frame type
+    type Item;
+
+    /// Decode one frame from the front of `src`
+    ///
+    /// Returns `Ok(None)` when more bytes are needed; consumed bytes must be
+    /// removed from `src`.
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>>;
+}
+
+/// Encodes frames into a byte buffer
+pub trait Encoder<Item> {
+    /// Append the encoding of `item` to `dst`
+    fn encode(&mut self, item: Item, dst: &mut BytesMut) -> io::Result<()>;
+}
+
+/// Frames prefixed with their length as a little-endian `u32`
+#[derive(Debug, Clone, Copy)]
+pub struct LengthDelimitedCodec {
+    max_frame_length: usize,
+}
+
+impl Default for LengthDelimitedCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LengthDelimitedCodec {
+    const PREFIX_LEN: usize = 4;
+
+    /// Codec accepting frames of up to 8 MiB
+    pub const fn new() -> Self {
+        Self::with_max_frame_length(8 * 1024 * 1024)
+    }
+
+    /// Codec with a custom frame size limit
+    ///
+    /// Frames above the limit fail with [`io::ErrorKind::InvalidData`] in
+    /// both directions, so a hostile peer cannot make the receiver buffer
+    /// arbitrary amounts of data.
+    pub const fn with_max_frame_length(max_frame_length: usize) -> Self {
+        Self { max_frame_length }
+    }
+
+    /// Maximum frame length
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+
+    fn check_len(&self, len: usize) -> io::Result<()> {
+        if len > self.max_frame_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame exceeds maximum length",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for LengthDelimitedCodec {
+    type Item = BytesMut;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        if src.len() < Self::PREFIX_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        self.check_len(len)?;
+
+        if src.len() < Self::PREFIX_LEN + len {
+            src.reserve(Self::PREFIX_LEN + len - src.len());
+            return Ok(None);
+        }
+        src.advance(Self::PREFIX_LEN);
+        Ok(Some(src.split_to(len)))
+    }
+}
+
+impl Encoder<&[u8]> for LengthDelimitedCodec {
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+        self.check_len(item.len())?;
+        dst.reserve(Self::PREFIX_LEN + item.len());
+        dst.put_u32_le(item.len() as u32);
+        dst.put_slice(item);
+        Ok(())
+    }
+}
+
+impl Encoder<Bytes> for LengthDelimitedCodec {
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        self.encode(&item[..], dst)
+    }
+}
+
+/// A [`KcpStream`] sending and receiving frames through a codec
+pub struct KcpFramed<C> {
+    stream: KcpStream,
+    codec: C,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    eof: bool,
+}
+
+impl<C> KcpFramed<C> {
+    /// Wrap a stream with a codec
+    pub fn new(stream: KcpStream, codec: C) -> Self {
+        Self {
+            stream,
+            codec,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            eof: false,
+        }
+    }
+
+    /// Get a reference to the underlying stream
+    pub fn get_ref(&self) -> &KcpStream {
+        &self.stream
+    }
+
+    /// Get a mutable reference to the underlying stream
+    ///
+    /// Reading from the stream directly corrupts the framing.
+    pub fn get_mut(&mut self) -> &mut KcpStream {
+        &mut self.stream
+    }
+
+    /// Get a reference to the codec
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Get a mutable reference to the codec
+    pub fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+
+    /// Get the underlying stream back
+    ///
+    /// Bytes already read from the stream but not yet decoded are lost.
+    pub fn into_inner(self) -> KcpStream {
+        self.stream
+    }
+
+    /// Encode and send a frame
+    pub async fn send<I>(&mut self, item: I) -> KcpResult<()>
+    where
+        C: Encoder<I>,
+    {
+        self.write_buf.clear();
+        self.codec.encode(item, &mut self.write_buf)?;
+        self.stream.send(&self.write_buf).await?;
+        Ok(())
+    }
+
+    /// Receive the next frame, `Ok(None)` once the stream reached EOF
+    pub async fn recv(&mut self) -> KcpResult<Option<C::Item>>
+    where
+        C: Decoder,
+    {
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            if let Some(item) = self.codec.decode(&mut self.read_buf)? {
+                return Ok(Some(item));
+            }
+            if self.eof {
+                if self.read_buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream closed in the middle of a frame",
+                )
+                .into());
+            }
+
+            let n = self.stream.recv(&mut chunk).await?;
+            if n == 0 {
+                self.eof = true;
+            }
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
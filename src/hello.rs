@@ -0,0 +1,80 @@
+//! Application payload and service name of the connect handshake
+//!
+//! A client connecting with
+//! [`KcpStream::connect_with_hello`](crate::KcpStream::connect_with_hello)
+//! or [`KcpStream::connect_to_service`](crate::KcpStream::connect_to_service)
+//! wraps every datagram in a [`CMD_HELLO`] header until the first datagram
+//! of the server arrives:
+//!
+//! ```text
+//! | conv (4, LE) | cmd (1) | service len (1) | service ... | payload len (2, LE) | payload ... | datagram ... |
+//! ```
+//!
+//! Whichever datagram opens the session on the server carries both, so
+//! they are there when the stream is accepted, with no round trip of their
+//! own and nothing to retransmit. An empty service name or payload stands
+//! for none. The KCP MTU of the client is lowered by the header while it
+//! wraps. Needs a smol-kcp listener or message socket.
+//!
+//! [`CMD_HELLO`]: crate::packet::CMD_HELLO
+
+use crate::packet;
+
+/// Largest payload a client may attach
+pub(crate) const MAX_HELLO_LEN: usize = 512;
+
+/// Longest service name
+pub(crate) const MAX_SERVICE_LEN: usize = u8::MAX as usize;
+
+/// What a client tells in its handshake
+pub(crate) struct Hello<'a> {
+    pub service: Option<&'a str>,
+    pub payload: Option<&'a [u8]>,
+}
+
+/// Header body announcing `service` and `payload`, `None` if either is
+/// too long
+pub(crate) fn encode(service: &str, payload: &[u8]) -> Option<Vec<u8>> {
+    if service.len() > MAX_SERVICE_LEN || payload.len() > MAX_HELLO_LEN {
+        return None;
+    }
+    let mut body = Vec::with_capacity(3 + service.len() + payload.len());
+    body.push(service.len() as u8);
+    body.extend_from_slice(service.as_bytes());
+    body.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    body.extend_from_slice(payload);
+    Some(body)
+}
+
+/// Bytes the header with `body` from [`encode`] adds to every datagram
+pub(crate) fn overhead(body: &[u8]) -> usize {
+    packet::HEADER_LEN + body.len()
+}
+
+/// `datagram` behind a [`packet::CMD_HELLO`] header with `body`
+pub(crate) fn wrap(conv: u32, body: &[u8], datagram: &[u8]) -> Vec<u8> {
+    let mut wrapped = Vec::with_capacity(overhead(body) + datagram.len());
+    wrapped.extend_from_slice(&conv.to_le_bytes());
+    wrapped.push(packet::CMD_HELLO);
+    wrapped.extend_from_slice(body);
+    wrapped.extend_from_slice(datagram);
+    wrapped
+}
+
+/// Handshake and inner datagram of a [`packet::CMD_HELLO`] packet
+pub(crate) fn unwrap(datagram: &[u8]) -> Option<(Hello<'_>, &[u8])> {
+    let body = datagram.get(packet::HEADER_LEN..)?;
+    let (&service_len, body) = body.split_first()?;
+    let (service, body) = body.split_at_checked(service_len as usize)?;
+    let service = std::str::from_utf8(service).ok()?;
+    let len = u16::from_le_bytes(body.get(..2)?.try_into().ok()?) as usize;
+    if len > MAX_HELLO_LEN {
+        return None;
+    }
+    let (payload, inner) = body[2..].split_at_checked(len)?;
+    let hello = Hello {
+        service: (!service.is_empty()).then_some(service),
+        payload: (!payload.is_empty()).then_some(payload),
+    };
+    Some((hello, inner))
+}
@@ -0,0 +1,183 @@
+//! Latency histograms with bounded relative error
+//!
+//! Values are recorded in milliseconds into log-linear buckets in the spirit
+//! of HdrHistogram: values below 64 get exact buckets, larger values are
+//! grouped into 32 sub-buckets per power of two (about 3% relative error).
+//! A histogram takes a few kilobytes regardless of how many values it sees.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use smol_kcp_core::serial;
+
+/// Values below this are stored exactly
+const LINEAR_LIMIT: u32 = 64;
+/// Sub-buckets per power of two above the linear range
+const SUB_BUCKETS: u32 = 32;
+/// Largest recordable value, larger ones are clamped (KCP caps RTO at 60s)
+const MAX_VALUE: u32 = 120_000;
+
+/// Percentile summary of a latency histogram
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LatencySummary {
+    /// Number of recorded values
+    pub count: u64,
+    /// Smallest recorded value
+    pub min: Duration,
+    /// Median
+    pub p50: Duration,
+    /// 95th percentile
+    pub p95: Duration,
+    /// 99th percentile
+    pub p99: Duration,
+    /// Largest recorded value
+    pub max: Duration,
+}
+
+/// Latency percentiles collected for a connection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LatencyReport {
+    /// Round trip time of ACKed segments
+    pub rtt: LatencySummary,
+    /// Time from the application's send call until all of its data was
+    /// acknowledged by the peer
+    pub send_to_ack: LatencySummary,
+}
+
+pub(crate) struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    min: u32,
+    max: u32,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; bucket_index(MAX_VALUE) + 1],
+            count: 0,
+            min: u32::MAX,
+            max: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, value: Duration) {
+        let ms = value.as_millis().min(MAX_VALUE as u128) as u32;
+        self.buckets[bucket_index(ms)] += 1;
+        self.count += 1;
+        self.min = self.min.min(ms);
+        self.max = self.max.max(ms);
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        if self.count == 0 {
+            return LatencySummary::default();
+        }
+        let ms = |value: u32| Duration::from_millis(value as u64);
+        LatencySummary {
+            count: self.count,
+            min: ms(self.min),
+            p50: ms(self.percentile(50.0)),
+            p95: ms(self.percentile(95.0)),
+            p99: ms(self.percentile(99.0)),
+            max: ms(self.max),
+        }
+    }
+
+    fn percentile(&self, percentile: f64) -> u32 {
+        let rank = ((percentile / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_value(index).clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+}
+
+fn bucket_index(value: u32) -> usize {
+    if value < LINEAR_LIMIT {
+        return value as usize;
+    }
+    // value has its highest bit at `exp`, keep the next 5 bits
+    let exp = 31 - value.leading_zeros();
+    let shift = exp - SUB_BUCKETS.trailing_zeros();
+    let sub = (value >> shift) - SUB_BUCKETS;
+    let group = exp - LINEAR_LIMIT.trailing_zeros();
+    (LINEAR_LIMIT + group * SUB_BUCKETS + sub) as usize
+}
+
+/// Upper bound of the values stored in a bucket
+fn bucket_value(index: usize) -> u32 {
+    let index = index as u32;
+    if index < LINEAR_LIMIT {
+        return index;
+    }
+    let group = (index - LINEAR_LIMIT) / SUB_BUCKETS;
+    let sub = (index - LINEAR_LIMIT) % SUB_BUCKETS;
+    let shift = group + LINEAR_LIMIT.trailing_zeros() - SUB_BUCKETS.trailing_zeros();
+    ((SUB_BUCKETS + sub + 1) << shift) - 1
+}
+
+/// Upper bound on sends waiting for their acknowledgement
+const MAX_PENDING_SENDS: usize = 4096;
+
+/// Collects the latency histograms of a connection
+#[derive(Default)]
+pub(crate) struct LatencyTracker {
+    rtt: LatencyHistogram,
+    send_to_ack: LatencyHistogram,
+    /// Data segments handed to KCP so far, KCP numbers them from 0 in order
+    enqueued: u32,
+    /// Last sequence number of each application send and its start time
+    pending: VecDeque<(u32, Instant)>,
+}
+
+impl LatencyTracker {
+    /// Record an application send that added `new_segments` to the queue
+    ///
+    /// Zero new segments means the data was appended to the last queued
+    /// segment (stream mode).
+    pub fn on_send(&mut self, new_segments: usize, now: Instant) {
+        self.enqueued = self.enqueued.wrapping_add(new_segments as u32);
+        let last_sn = self.enqueued.wrapping_sub(1);
+        if self.pending.len() == MAX_PENDING_SENDS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((last_sn, now));
+    }
+
+    /// Everything below `una` has been acknowledged by the peer
+    pub fn on_una(&mut self, una: u32, now: Instant) {
+        while let Some(&(last_sn, sent_at)) = self.pending.front() {
+            if serial::diff(last_sn, una) >= 0 {
+                break;
+            }
+            self.send_to_ack.record(now.duration_since(sent_at));
+            self.pending.pop_front();
+        }
+    }
+
+    pub fn on_rtt(&mut self, rtt: Duration) {
+        self.rtt.record(rtt);
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        LatencyReport {
+            rtt: self.rtt.summary(),
+            send_to_ack: self.send_to_ack.summary(),
+        }
+    }
+
+    /// Clear the histograms, keeping track of in-flight sends
+    pub fn reset(&mut self) {
+        self.rtt = LatencyHistogram::default();
+        self.send_to_ack = LatencyHistogram::default();
+    }
+}
@@ -0,0 +1,89 @@
+//! hyper integration for serving HTTP over KCP
+//!
+//! [`KcpHyperIo`] implements hyper's `rt::Read` and `rt::Write` for a
+//! [`KcpStream`], so an accepted session can be handed to
+//! `hyper::server::conn::http1::Builder::serve_connection`:
+//!
+//! ```ignore
+//! let mut listener = KcpListener::bind(KcpConfig::default(), addr).await?;
+//! loop {
+//!     let (io, _peer) = listener.accept_hyper().await?;
+//!     smol::spawn(http1::Builder::new().serve_connection(io, service_fn(handle))).detach();
+//! }
+//! ```
+//!
+//! Accepted sessions only receive data while the listener is accepting,
+//! so keep the accept loop running while connections are served.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_lite::{ready, AsyncRead, AsyncWrite};
+use hyper::rt::{Read, ReadBufCursor, Write};
+
+use crate::stream::KcpStream;
+
+/// Adapter between a [`KcpStream`] and hyper's I/O traits
+///
+/// Delegates to the stream's `AsyncRead` and `AsyncWrite`, which keep
+/// reads and writes apart, so hyper can read the next request while a
+/// response is being sent.
+pub struct KcpHyperIo {
+    stream: KcpStream,
+    /// Initialized bytes to read into, hyper's buffer may not be
+    scratch: Vec<u8>,
+}
+
+impl KcpHyperIo {
+    pub fn new(stream: KcpStream) -> Self {
+        Self {
+            stream,
+            scratch: Vec::new(),
+        }
+    }
+}
+
+impl From<KcpStream> for KcpHyperIo {
+    fn from(stream: KcpStream) -> Self {
+        Self::new(stream)
+    }
+}
+
+impl Read for KcpHyperIo {
+    /// Reads at most what `buf` has room for now, the stream keeps the
+    /// rest of a message for the next read
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        this.scratch.resize(buf.remaining(), 0);
+        let n = ready!(Pin::new(&mut this.stream).poll_read(cx, &mut this.scratch))?;
+        buf.put_slice(&this.scratch[..n]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Write for KcpHyperIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// Flushes, waits until the peer acknowledged everything and closes
+    /// the session
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
@@ -2,14 +2,91 @@
 //! 
 //! This library provides a minimal KCP (reliable UDP) implementation
 //! designed for resource-constrained environments like OpenWrt.
+//!
+//! The protocol state machine lives in the `no_std` crate
+//! `smol-kcp-core`, re-exported as [`core`]; this crate adds sockets,
+//! timers and the async API on top.
 
-pub use config::{KcpConfig, KcpNoDelayConfig};
-pub use listener::KcpListener;
+#[cfg(feature = "codec")]
+pub use channel::KcpChannel;
+pub use config::{ByteOrder, FlushPolicy, HeaderFormat, KcpConfig, KcpInterop, KcpNoDelayConfig};
+pub use connector::KcpConnector;
+#[allow(deprecated)]
+pub use error::KcpError;
+pub use error::{Error, KcpResult};
+#[cfg(feature = "discovery")]
+pub use discovery::{ServiceAnnouncer, ServiceInstance};
+pub use events::{KcpEvent, KcpEventHandler, UserData};
+pub use framed::{Decoder, Encoder, KcpFramed, LengthDelimitedCodec};
+pub use histogram::{LatencyReport, LatencySummary};
+#[cfg(feature = "http")]
+pub use hyper_io::KcpHyperIo;
+pub use listener::{AcceptInfo, AutoBanConfig, KcpListener};
+pub use message::{Reliability, SendOptions};
+pub use message_socket::KcpMessageSocket;
+pub use multipath::{MultipathMode, PathStats};
+#[cfg(feature = "multicast")]
+pub use multicast::{KcpMulticastReceiver, KcpMulticastSender, MulticastConfig};
+pub use mux::{KcpMuxStream, KcpMuxer, MuxConfig};
+pub use pcap::PcapWriter;
+#[cfg(feature = "portmap")]
+pub use portmap::{MappingProtocol, PortMapping};
+pub use pool::{KcpPool, PoolConfig, PooledStream};
+pub use shard::{KcpShardedListener, ShardConfig};
+pub use socket::{CloseCode, KcpSocket, SessionInfo, SessionState};
+pub use stats::{
+    KcpStats, NetworkConditions, OneWayDelay, RttEstimate, SendLimit, StatsHistoryConfig, StatsSample,
+    WindowStatus,
+};
 pub use stream::KcpStream;
+#[cfg(all(feature = "vpn", target_os = "linux"))]
+pub use vpn::TunDevice;
 
+#[cfg(feature = "codec")]
+mod channel;
+mod checksum;
 mod config;
+mod connector;
+pub mod conformance;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+mod error;
+mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod framed;
+mod hello;
+mod histogram;
+#[cfg(feature = "http")]
+mod hyper_io;
 mod listener;
+mod logging;
+mod message;
+mod message_socket;
+#[cfg(feature = "multicast")]
+pub mod multicast;
+mod multipath;
+mod mux;
+mod packet;
+mod pcap;
+mod pool;
+#[cfg(feature = "portmap")]
+pub mod portmap;
+pub mod proxy_protocol;
+#[cfg(feature = "python")]
+mod python;
+mod ratelimit;
+mod reset;
+mod ring;
+mod route;
+mod shard;
 mod socket;
+mod socks5;
+mod stats;
 mod stream;
+mod telemetry;
+#[cfg(all(feature = "vpn", target_os = "linux"))]
+mod vpn;
 
-pub use kcp::{Error as KcpError, KcpResult};
\ No newline at end of file
+/// Sans-io protocol state machine, `no_std` + `alloc`
+pub use smol_kcp_core as core;
\ No newline at end of file
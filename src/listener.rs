@@ -1,107 +1,1696 @@
 use std::{
-    collections::HashMap,
-    io,
-    net::SocketAddr,
-    sync::Arc,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    io, mem,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::{Duration, Instant},
 };
 
-use async_io::Async;
+use async_io::{Async, Timer};
 use async_lock::Mutex;
-use kcp::KcpResult;
-use log::{debug, error, trace};
+use event_listener::{Event, EventListener};
+use futures_lite::future;
 
-use crate::{config::KcpConfig, socket::KcpSocket, stream::KcpStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{
+    AsRawSocket, AsSocket, BorrowedSocket, FromRawSocket, IntoRawSocket, RawSocket,
+};
+
+use crate::{
+    checksum,
+    config::{KcpConfig, KcpInterop},
+    error::{Error, KcpResult},
+    events::{KcpEvent, KcpEventHandler},
+    hello,
+    logging::{debug, error, trace, warn, Level},
+    packet,
+    pcap::PcapWriter,
+    proxy_protocol,
+    ratelimit::{LogLimiter, OffenseCounter},
+    reset,
+    route::{self, RouteTable},
+    shard,
+    socket::{
+        canonical_addr, configure_udp, is_ipv6, send_addr, CloseCode, CloseReason, ExportedState, KcpSocket,
+        SessionInfo, SessionTag,
+    },
+    stream::KcpStream,
+    telemetry::ListenerMetrics,
+};
+
+/// How often the memory of all sessions is summed up against the budget
+const BUDGET_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sessions the application has not used for this long may be evicted
+const EVICT_IDLE: Duration = Duration::from_secs(5);
+
+/// How often a draining listener looks for sessions that ended without
+/// a datagram arriving
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handlers [`KcpListener::serve`] runs at once unless set otherwise
+#[cfg(feature = "serve")]
+const DEFAULT_SERVE_LIMIT: usize = 1024;
+
+/// A connection handler as spawned by [`KcpListener::serve`]
+#[cfg(feature = "serve")]
+type ServeTask<'a> = std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'a>>;
+
+/// Handler of a named service, see [`KcpListener::register`]
+#[cfg(feature = "serve")]
+type ServiceHandler = Box<dyn FnMut(KcpStream, SocketAddr) -> ServeTask<'static> + Send + Sync>;
+
+type Sessions = HashMap<SocketAddr, Arc<Mutex<KcpSocket>>>;
+
+/// Memory budget shared by all sessions of a listener
+struct MemoryBudget {
+    limit: usize,
+    /// Bytes buffered by all sessions at the last check
+    used: usize,
+    next_check: Instant,
+}
+
+/// Sessions of a listener by conversation id, next to the map by address
+///
+/// Entries of sessions dropped by the application are pruned as the
+/// index grows, [`KcpListener::find_conv`] skips those gone from the
+/// listener.
+#[derive(Default)]
+struct ConvIndex {
+    /// Clients may pick the same conv, each has its session listed
+    sessions: HashMap<u32, Vec<Weak<Mutex<KcpSocket>>>>,
+    /// Size at which dead entries are pruned next
+    prune_at: usize,
+}
+
+impl ConvIndex {
+    fn insert(&mut self, conv: u32, session: &Arc<Mutex<KcpSocket>>) {
+        if self.sessions.len() >= self.prune_at {
+            self.sessions.retain(|_, sessions| {
+                sessions.retain(|session| session.strong_count() > 0);
+                !sessions.is_empty()
+            });
+            self.prune_at = (self.sessions.len() * 2).max(64);
+        }
+        self.sessions.entry(conv).or_default().push(Arc::downgrade(session));
+    }
+
+    /// Live sessions listed under `conv`
+    fn get(&self, conv: u32) -> impl Iterator<Item = Arc<Mutex<KcpSocket>>> + '_ {
+        self.sessions
+            .get(&conv)
+            .into_iter()
+            .flatten()
+            .filter_map(Weak::upgrade)
+    }
+}
+
+/// Sessions whose streams were all dropped, for the listener owning them
+/// to close
+///
+/// The last stream handle cannot await the session lock or the socket in
+/// `Drop`, it hands the session over and wakes the accept loop instead.
+#[derive(Default)]
+pub(crate) struct Abandoned {
+    sessions: StdMutex<Vec<Arc<Mutex<KcpSocket>>>>,
+    event: Event,
+}
+
+impl Abandoned {
+    pub(crate) fn push(&self, session: Arc<Mutex<KcpSocket>>) {
+        self.sessions.lock().unwrap().push(session);
+        self.event.notify(usize::MAX);
+    }
+
+    fn take(&self) -> Vec<Arc<Mutex<KcpSocket>>> {
+        mem::take(&mut *self.sessions.lock().unwrap())
+    }
+
+    fn listen(&self) -> EventListener {
+        self.event.listen()
+    }
+}
+
+/// What a client told when it connected, see
+/// [`KcpListener::accept_with_info`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcceptInfo {
+    pub peer: SocketAddr,
+    pub conv: u32,
+    /// Payload the client connected with, see
+    /// [`KcpStream::connect_with_hello`]
+    pub hello: Option<Vec<u8>>,
+    /// Service the client asked for, see
+    /// [`KcpStream::connect_to_service`]
+    pub service: Option<String>,
+}
+
+/// When [`KcpListener::set_auto_ban`] bans a source IP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoBanConfig {
+    /// Malformed packets and failed handshakes an IP may send within
+    /// `window`, the next one bans it
+    pub max_offenses: u32,
+    pub window: Duration,
+    /// How long the ban lasts
+    pub cooldown: Duration,
+}
+
+impl Default for AutoBanConfig {
+    fn default() -> Self {
+        Self {
+            max_offenses: 20,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(600),
+        }
+    }
+}
 
 /// KCP listener for accepting connections
 pub struct KcpListener {
     udp: Arc<Async<std::net::UdpSocket>>,
     config: KcpConfig,
-    sessions: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<KcpSocket>>>>>,
+    sessions: Arc<Mutex<Sessions>>,
+    metrics: ListenerMetrics,
+    pcap: Option<PcapWriter>,
+    malformed_log: LogLimiter,
+    input_error_log: LogLimiter,
+    send_error_log: LogLimiter,
+    budget: Option<MemoryBudget>,
+    /// Sessions one client IP may have open, see
+    /// [`KcpListener::set_max_sessions_per_ip`]
+    max_sessions_per_ip: Option<usize>,
+    rejected_log: LogLimiter,
+    conv_mismatch_log: LogLimiter,
+    corrupted_log: LogLimiter,
+    /// Sessions by the 64-bit id their clients route with
+    routes: RouteTable,
+    /// Sessions by conversation id, for joins and migrations
+    convs: ConvIndex,
+    /// Sessions the application dropped, closed by the accept loop
+    abandoned: Arc<Abandoned>,
+    /// New sessions are refused, see [`KcpListener::drain`]
+    draining: bool,
+    /// Client IPs whose datagrams are dropped, until when, see
+    /// [`KcpListener::ban`]
+    banned: HashMap<IpAddr, Instant>,
+    /// See [`KcpListener::set_auto_ban`]
+    auto_ban: Option<AutoBanConfig>,
+    offenses: OffenseCounter,
+    event_handler: Option<KcpEventHandler>,
+    /// Key reset tokens are derived from, see [`KcpListener::set_reset_key`]
+    reset_key: Option<[u8; 16]>,
+    /// Datagrams start with a PROXY protocol header, see
+    /// [`KcpListener::set_proxy_protocol`]
+    proxy_protocol: bool,
+    #[cfg(feature = "serve")]
+    serve_limit: usize,
+    #[cfg(feature = "serve")]
+    overloaded_log: LogLimiter,
+    /// Handlers by service name, see [`KcpListener::register`]
+    #[cfg(feature = "serve")]
+    services: HashMap<String, ServiceHandler>,
+    /// Index and count of the workers of a [`KcpShardedListener`] when
+    /// this is one of them, allocated convs must map to this worker
+    ///
+    /// [`KcpShardedListener`]: crate::KcpShardedListener
+    shard: Option<(usize, usize)>,
 }
 
 impl KcpListener {
     /// Bind to an address
+    ///
+    /// A dual-stack socket bound to `[::]` knows IPv4 clients by their
+    /// IPv4 address, never as `::ffff:a.b.c.d`.
     pub async fn bind(config: KcpConfig, addr: SocketAddr) -> KcpResult<Self> {
         let udp = std::net::UdpSocket::bind(addr)?;
+        Self::from_std(config, udp)
+    }
+
+    /// Create a listener on an already bound UDP socket
+    ///
+    /// For sockets the caller configured itself (buffer sizes, DSCP,
+    /// `SO_REUSEPORT`, ...) or inherited from a parent process. The socket
+    /// is switched to non-blocking mode.
+    pub fn bind_with_socket(config: KcpConfig, udp: std::net::UdpSocket) -> KcpResult<Self> {
+        let addr = udp.local_addr()?;
+        // A connected socket only receives from one peer
+        if let Ok(peer) = udp.peer_addr() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("listener socket {} is connected to {}", addr, peer),
+            )
+            .into());
+        }
+        debug!("listening on the given UDP socket bound to {}", addr);
+        Self::from_std(config, udp)
+    }
+
+    /// Create a listener from an inherited UDP socket fd
+    ///
+    /// Intended for supervisors (procd, inetd-style wrappers) that bind the
+    /// port themselves and pass the fd number on to the service.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open UDP socket owned by the caller; ownership is
+    /// transferred to the listener. Other sockets fail with
+    /// [`io::ErrorKind::InvalidInput`] and are closed.
+    #[cfg(unix)]
+    pub unsafe fn from_fd(config: KcpConfig, fd: RawFd) -> KcpResult<Self> {
+        let udp = std::net::UdpSocket::from_raw_fd(fd);
+        // A TCP socket has a local address too, ask for the socket type;
+        // fails with ENOTSOCK if the fd is not a socket
+        let mut kind: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        if libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+        if kind != libc::SOCK_DGRAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {} is not a datagram socket", fd),
+            )
+            .into());
+        }
+        let addr = udp.local_addr()?;
+        debug!("inherited UDP socket fd {} bound to {}", fd, addr);
+        Self::from_std(config, udp)
+    }
+
+    /// Create a listener from a systemd socket-activation fd
+    ///
+    /// Returns `Ok(None)` when the process was not socket-activated, so
+    /// callers can fall back to [`KcpListener::bind`]. The `LISTEN_*`
+    /// variables are left in the environment, as `sd_listen_fds(0)` does:
+    /// unsetting them is unsound while other threads may read it. Child
+    /// processes ignore them since `LISTEN_PID` names this process.
+    #[cfg(unix)]
+    pub fn from_listen_fds(config: KcpConfig) -> KcpResult<Option<Self>> {
+        const SD_LISTEN_FDS_START: RawFd = 3;
+
+        let pid = std::env::var("LISTEN_PID").ok();
+        let fds = std::env::var("LISTEN_FDS").ok();
+
+        match pid.and_then(|pid| pid.parse::<u32>().ok()) {
+            Some(pid) if pid == std::process::id() => {}
+            _ => return Ok(None),
+        }
+
+        let count = fds.and_then(|n| n.parse::<RawFd>().ok()).unwrap_or(0);
+        if count < 1 {
+            return Ok(None);
+        }
+        if count > 1 {
+            debug!("{} sockets passed, using the first one", count);
+        }
+
+        // SAFETY: systemd hands ownership of fds starting at 3 to the process
+        unsafe { Self::from_fd(config, SD_LISTEN_FDS_START).map(Some) }
+    }
+
+    /// Create a listener from an already bound UDP socket
+    pub(crate) fn from_std(config: KcpConfig, udp: std::net::UdpSocket) -> KcpResult<Self> {
+        configure_udp(&udp)?;
         let udp = Arc::new(Async::new(udp)?);
 
+        let metrics = ListenerMetrics::new(udp.get_ref().local_addr().ok());
+
         Ok(Self {
             udp,
             config,
             sessions: Arc::new(Mutex::new(HashMap::new())),
+            metrics,
+            pcap: None,
+            malformed_log: LogLimiter::new("malformed packets", Level::Error),
+            input_error_log: LogLimiter::new("input errors", Level::Error),
+            send_error_log: LogLimiter::new("send errors", Level::Error),
+            budget: None,
+            max_sessions_per_ip: None,
+            rejected_log: LogLimiter::new("sessions rejected over the memory budget", Level::Warn),
+            conv_mismatch_log: LogLimiter::new("packets with a foreign conv", Level::Debug),
+            corrupted_log: LogLimiter::new("datagrams failing their checksum", Level::Warn),
+            routes: RouteTable::default(),
+            convs: ConvIndex::default(),
+            abandoned: Arc::default(),
+            draining: false,
+            banned: HashMap::new(),
+            auto_ban: None,
+            offenses: OffenseCounter::default(),
+            event_handler: None,
+            reset_key: None,
+            proxy_protocol: false,
+            #[cfg(feature = "serve")]
+            serve_limit: DEFAULT_SERVE_LIMIT,
+            #[cfg(feature = "serve")]
+            overloaded_log: LogLimiter::new("connections refused over the serve limit", Level::Warn),
+            #[cfg(feature = "serve")]
+            services: HashMap::new(),
+            shard: None,
         })
     }
 
     /// Accept a new connection
+    ///
+    /// Returns the stream and the address of the client, taken from the
+    /// PROXY header with [`KcpListener::set_proxy_protocol`].
     pub async fn accept(&mut self) -> KcpResult<(KcpStream, SocketAddr)> {
+        let mut buf = vec![0u8; 65536];
         loop {
-            let mut buf = vec![0u8; 65536];
-            let (n, peer_addr) = self.udp.recv_from(&mut buf).await?;
-            
-            if n < kcp::KCP_OVERHEAD {
-                error!("packet too short: {} bytes", n);
-                continue;
+            let (n, peer_addr) = self.recv_datagram(&mut buf).await?;
+            if let Some(accepted) = self.handle_datagram(&buf[..n], peer_addr).await? {
+                return Ok(accepted);
+            }
+        }
+    }
+
+    /// Accept a new connection along with the handshake payload of the
+    /// client
+    ///
+    /// Clients connecting with [`KcpStream::connect_with_hello`] attach a
+    /// payload, e.g. an auth token or their version, that arrives with
+    /// the first datagram instead of a hello message of its own, those
+    /// connecting with [`KcpStream::connect_to_service`] the name of the
+    /// service they want. Both are `None` for other clients.
+    pub async fn accept_with_info(&mut self) -> KcpResult<(KcpStream, AcceptInfo)> {
+        let (stream, peer) = self.accept().await?;
+        let info = {
+            let socket = stream.socket.lock().await;
+            AcceptInfo {
+                peer,
+                conv: socket.conv(),
+                hello: socket.peer_hello().map(<[u8]>::to_vec),
+                service: socket.peer_service().map(str::to_owned),
             }
+        };
+        Ok((stream, info))
+    }
 
-            let packet = &buf[..n];
-            let mut conv = kcp::get_conv(packet);
+    /// Accept connections and run `handler` for each on a task of smol's
+    /// global executor, see [`KcpListener::serve_on`]
+    #[cfg(feature = "serve")]
+    pub async fn serve<H, F>(&mut self, handler: H) -> KcpResult<()>
+    where
+        H: FnMut(KcpStream, SocketAddr) -> F,
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.serve_with(smol::spawn, handler).await
+    }
 
-            // Allocate conv if needed
-            if conv == 0 {
-                conv = {
-                    let mut new_conv = rand::random::<u32>();
-                    while new_conv == 0 {
-                        new_conv = rand::random();
+    /// Accept connections and run `handler` for each on a task of
+    /// `executor`
+    ///
+    /// The accept loop feeds the sessions, so it must keep running for the
+    /// handlers to make progress; it returns only when receiving on the
+    /// socket fails. At most [`KcpListener::set_serve_limit`] handlers run
+    /// at once, clients connecting beyond that are closed with
+    /// [`CloseCode::Overloaded`]. Clients asking for a service given to
+    /// [`KcpListener::register`] go to its handler, all others to
+    /// `handler`. The session ends when the handler drops its stream.
+    ///
+    /// ```no_run
+    /// # async fn run(mut listener: smol_kcp::KcpListener) -> smol_kcp::KcpResult<()> {
+    /// let executor = smol::Executor::new();
+    /// executor
+    ///     .run(listener.serve_on(&executor, |mut stream, _| async move {
+    ///         let mut buf = [0; 1024];
+    ///         while let Ok(n @ 1..) = stream.recv(&mut buf).await {
+    ///             let _ = stream.send(&buf[..n]).await;
+    ///         }
+    ///     }))
+    ///     .await
+    /// # }
+    /// ```
+    #[cfg(feature = "serve")]
+    pub async fn serve_on<'a, H, F>(&mut self, executor: &smol::Executor<'a>, handler: H) -> KcpResult<()>
+    where
+        H: FnMut(KcpStream, SocketAddr) -> F,
+        F: std::future::Future<Output = ()> + Send + 'a,
+    {
+        self.serve_with(|task| executor.spawn(task), handler).await
+    }
+
+    #[cfg(feature = "serve")]
+    async fn serve_with<'a, S, H, F>(&mut self, spawn: S, mut handler: H) -> KcpResult<()>
+    where
+        S: Fn(ServeTask<'a>) -> smol::Task<()>,
+        H: FnMut(KcpStream, SocketAddr) -> F,
+        F: std::future::Future<Output = ()> + Send + 'a,
+    {
+        let slots = Arc::new(async_lock::Semaphore::new(self.serve_limit));
+        loop {
+            let (stream, peer_addr) = self.accept().await?;
+            // Released when the handler is done, even if it panics
+            let Some(slot) = slots.try_acquire_arc() else {
+                if self.overloaded_log.allow(peer_addr) {
+                    warn!("{} handlers running, refused {}", self.serve_limit, peer_addr);
+                }
+                let _ = stream.close_with(CloseCode::Overloaded).await;
+                continue;
+            };
+            let service = stream.socket.lock().await.peer_service().map(str::to_owned);
+            let task = match service.and_then(|name| self.services.get_mut(&name)) {
+                Some(registered) => registered(stream, peer_addr),
+                None => Box::pin(handler(stream, peer_addr)),
+            };
+            spawn(Box::pin(async move {
+                let _slot = slot;
+                task.await;
+            }))
+            .detach();
+        }
+    }
+
+    /// Run `handler` for the connections of clients asking for `service`
+    ///
+    /// Lets several services share one port: clients pick one with
+    /// [`KcpStream::connect_to_service`] and [`KcpListener::serve`] hands
+    /// their streams to its handler instead of the default one. A second
+    /// handler for the same name replaces the first.
+    ///
+    /// ```no_run
+    /// # async fn run(mut listener: smol_kcp::KcpListener) -> smol_kcp::KcpResult<()> {
+    /// listener.register("echo", |mut stream, _| async move {
+    ///     let mut buf = [0; 1024];
+    ///     while let Ok(n @ 1..) = stream.recv(&mut buf).await {
+    ///         let _ = stream.send(&buf[..n]).await;
+    ///     }
+    /// });
+    /// listener.register("time", |mut stream, _| async move {
+    ///     let now = format!("{:?}", std::time::SystemTime::now());
+    ///     let _ = stream.send(now.as_bytes()).await;
+    /// });
+    /// listener.serve(|_, peer| async move { eprintln!("{} asked for no known service", peer) }).await
+    /// # }
+    /// ```
+    #[cfg(feature = "serve")]
+    pub fn register<H, F>(&mut self, service: &str, mut handler: H)
+    where
+        H: FnMut(KcpStream, SocketAddr) -> F + Send + Sync + 'static,
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let handler: ServiceHandler = Box::new(move |stream, peer| Box::pin(handler(stream, peer)));
+        self.services.insert(service.to_owned(), handler);
+    }
+
+    /// Receive the next datagram on the listener's socket
+    ///
+    /// Closes the sessions the application dropped while it waits.
+    pub(crate) async fn recv_datagram(&self, buf: &mut [u8]) -> KcpResult<(usize, SocketAddr)> {
+        loop {
+            let abandoned = self.abandoned.listen();
+            self.close_abandoned().await;
+            let received = future::or(async { Some(self.udp.recv_from(buf).await) }, async {
+                abandoned.await;
+                None
+            })
+            .await;
+            let Some(received) = received else {
+                continue;
+            };
+            match received {
+                // Clients of a dual-stack socket have one address
+                Ok((n, peer)) => return Ok((n, canonical_addr(peer))),
+                // ICMP errors for earlier datagrams must not stop the listener
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {
+                    debug!("ignoring UDP connection reset: {}", e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Feed a datagram to its session, returns the stream if it opened a
+    /// new one
+    pub(crate) async fn handle_datagram(
+        &mut self,
+        packet: &[u8],
+        peer_addr: SocketAddr,
+    ) -> KcpResult<Option<(KcpStream, SocketAddr)>> {
+        if self.is_banned(peer_addr.ip()) {
+            return Ok(None);
+        }
+        // The relay's header comes before anything else in the datagram
+        let mut client_addr = peer_addr;
+        let packet = if self.proxy_protocol {
+            match proxy_protocol::parse(packet) {
+                // Health checks of the relay carry nothing after the header
+                Some((_, [])) => return Ok(None),
+                Some((header, rest)) => {
+                    if let Some(source) = header.source {
+                        client_addr = source;
+                    }
+                    rest
+                }
+                None => {
+                    if self.malformed_log.allow(peer_addr) {
+                        error!("datagram without a PROXY header from {}", peer_addr);
                     }
-                    new_conv
+                    self.offense(peer_addr).await;
+                    return Ok(None);
+                }
+            }
+        } else {
+            packet
+        };
+        if client_addr != peer_addr && self.is_banned(client_addr.ip()) {
+            return Ok(None);
+        }
+        let native = self.config.interop == KcpInterop::Native;
+        // Nothing in a datagram is trusted before its checksum
+        let packet = if self.config.checksum {
+            match checksum::strip(packet) {
+                Some(data) => data,
+                None => {
+                    if self.corrupted_log.allow(peer_addr) {
+                        warn!("datagram from {} failed its checksum", peer_addr);
+                    }
+                    let session = self.sessions.lock().await.get(&peer_addr).cloned();
+                    match session {
+                        Some(session) => session.lock().await.note_corrupted(),
+                        // Clients on a noisy link are not banned for it
+                        None => self.offense(peer_addr).await,
+                    }
+                    return Ok(None);
+                }
+            }
+        } else {
+            packet
+        };
+        // Datagrams routed by session id carry a classic one
+        let mut route_id = None;
+        let packet = match packet::ext_cmd(packet) {
+            Some(packet::CMD_ROUTED) if native => match route::unwrap(packet) {
+                Some((id, inner)) => {
+                    route_id = Some(id);
+                    inner
+                }
+                None => {
+                    if self.malformed_log.allow(peer_addr) {
+                        error!("malformed routed packet from {}: {} bytes", peer_addr, packet.len());
+                    }
+                    self.offense(peer_addr).await;
+                    return Ok(None);
+                }
+            },
+            _ => packet,
+        };
+        // So are those of clients sending a handshake payload
+        let mut hello = None;
+        let packet = match packet::ext_cmd(packet) {
+            Some(packet::CMD_HELLO) if native => match hello::unwrap(packet) {
+                Some((handshake, inner)) => {
+                    hello = Some(handshake);
+                    inner
+                }
+                None => {
+                    if self.malformed_log.allow(peer_addr) {
+                        error!("malformed handshake packet from {}: {} bytes", peer_addr, packet.len());
+                    }
+                    self.offense(peer_addr).await;
+                    return Ok(None);
+                }
+            },
+            _ => packet,
+        };
+        let min_len = match packet::ext_cmd(packet) {
+            Some(_) if native => packet::HEADER_LEN,
+            _ => smol_kcp_core::KCP_OVERHEAD,
+        };
+        let n = packet.len();
+        if n < min_len {
+            if self.malformed_log.allow(peer_addr) {
+                error!("packet too short from {}: {} bytes", peer_addr, n);
+            }
+            self.offense(peer_addr).await;
+            return Ok(None);
+        }
+
+        let mut conv = self.config.header_format.conv(packet).unwrap_or_default();
+
+        let mut sessions = self.sessions.lock().await;
+        Self::check_budget(&mut self.budget, &self.metrics, &mut sessions).await;
+
+        if native && packet::ext_cmd(packet) == Some(packet::CMD_PATH) {
+            self.join_path(&mut sessions, conv, packet, peer_addr).await;
+            return Ok(None);
+        }
+        if native && packet::ext_cmd(packet) == Some(packet::CMD_SESSION_ID) {
+            Self::accept_route(&mut self.routes, &sessions, self.reset_key.as_ref(), conv, packet, peer_addr).await;
+            return Ok(None);
+        }
+
+        // The id decides the session, the address may have been handed to
+        // another client by a NAT
+        if let Some(id) = route_id {
+            let Some(session) = self.routes.get(id) else {
+                trace!("dropped a packet of unknown session id {:016x} from {}", id, peer_addr);
+                drop(sessions);
+                self.reset(conv, id, peer_addr).await;
+                return Ok(None);
+            };
+            if !sessions.get(&peer_addr).is_some_and(|known| Arc::ptr_eq(known, &session)) {
+                let open = {
+                    let socket = session.lock().await;
+                    socket.check_open().is_ok() && !socket.is_peer_closed()
                 };
-                debug!("allocated conv {} for peer {}", conv, peer_addr);
+                if open {
+                    self.migrate(&mut sessions, session, packet, peer_addr).await;
+                }
+                return Ok(None);
             }
+        }
+        
+        // Check if session exists
+        if let Some(session) = sessions.get(&peer_addr).cloned() {
+            let mut socket = session.lock().await;
+            if socket.conv() != conv {
+                // A client restarted on the same address starts over at
+                // sn 0, anything else is a stray packet of an old session
+                if !packet::opens_conversation(&self.config.header_format.decode(packet)) {
+                    if self.conv_mismatch_log.allow(peer_addr) {
+                        debug!("{} dropped a packet of conv {}", socket.tag(), conv);
+                    }
+                    return Ok(None);
+                }
+                debug!("{} reset, the peer opened conv {}", socket.tag(), conv);
+                socket.close(CloseReason::PeerRestarted);
+                let paths = socket.path_peers();
+                drop(socket);
+                Self::remove_session(&mut sessions, peer_addr, &paths);
+                self.metrics.session_closed();
+            } else {
+                Self::forget_dropped_paths(&mut sessions, &mut socket);
+                if !sessions.contains_key(&peer_addr) {
+                    trace!("{} dropped a packet of a removed path from {}", socket.tag(), peer_addr);
+                    return Ok(None);
+                }
+                socket.note_input_from(peer_addr);
+                if let Err(e) = socket.input_verified(packet) {
+                    if socket.check_open().is_err() {
+                        // Closed for exceeding its memory limit, expired
+                        // or dropped by the application
+                        debug!("{} dropped: {}", socket.tag(), e);
+                        socket.queue_close_frame();
+                        let _ = socket.send_output().await;
+                        let paths = socket.path_peers();
+                        drop(socket);
+                        Self::remove_session(&mut sessions, peer_addr, &paths);
+                        self.metrics.session_closed();
+                        return Ok(None);
+                    }
+                    if self.input_error_log.allow(peer_addr) {
+                        error!("{} input error: {}", socket.tag(), e);
+                    }
+                }
+                if let Err(e) = socket.send_output().await {
+                    if self.send_error_log.allow(peer_addr) {
+                        error!("{} send error: {}", socket.tag(), e);
+                    }
+                }
+                if socket.is_peer_closed() {
+                    // The stream keeps the data received so far
+                    let paths = socket.path_peers();
+                    drop(socket);
+                    Self::remove_session(&mut sessions, peer_addr, &paths);
+                    self.metrics.session_closed();
+                }
+                return Ok(None);
+            }
+        }
 
-            let mut sessions = self.sessions.lock().await;
-            
-            // Check if session exists
-            if let Some(socket) = sessions.get(&peer_addr) {
+        // Close frames of sessions already gone
+        if packet::ext_cmd(packet) == Some(packet::CMD_CLOSE) {
+            return Ok(None);
+        }
+
+        // A session carrying on from a new address, after a NAT rebinding
+        // or when the client roamed
+        if native && conv != 0 && !packet::opens_conversation(&self.config.header_format.decode(packet)) {
+            if let Some(session) = self.find_conv(&sessions, conv).await {
+                self.migrate(&mut sessions, session, packet, peer_addr).await;
+                return Ok(None);
+            }
+        }
+
+        if self.draining {
+            drop(sessions);
+            trace!("draining, refused a session from {}", peer_addr);
+            self.refuse(conv, peer_addr, CloseCode::ServerShutdown).await;
+            return Ok(None);
+        }
+
+        if self.max_sessions_per_ip.is_some_and(|max| Self::sessions_of_ip(&sessions, peer_addr.ip()) >= max) {
+            drop(sessions);
+            if self.rejected_log.allow(peer_addr) {
+                warn!("{} has too many sessions, rejected another one", peer_addr.ip());
+            }
+            self.refuse(conv, peer_addr, CloseCode::Overloaded).await;
+            return Ok(None);
+        }
+
+        if self.budget.as_ref().is_some_and(|budget| budget.used > budget.limit) {
+            drop(sessions);
+            if self.rejected_log.allow(peer_addr) {
+                warn!("memory budget exhausted, rejected a session from {}", peer_addr);
+            }
+            self.refuse(conv, peer_addr, CloseCode::Overloaded).await;
+            self.offense(peer_addr).await;
+            return Ok(None);
+        }
+
+        // Allocate conv if needed, kcp-go clients may pick 0 themselves
+        let allocated = conv == 0 && native;
+        if allocated {
+            conv = self.new_conv();
+            debug!("{} conv allocated", SessionTag::new(conv, peer_addr));
+        }
+
+        // Create new session
+        let mut socket = KcpSocket::new(
+            &self.config,
+            conv,
+            self.udp.clone(),
+            peer_addr,
+            self.config.stream,
+        )?;
+
+        if allocated {
+            socket.trace().conv_allocated();
+        }
+        if let Some(hello) = hello {
+            socket.set_peer_hello(&hello);
+        }
+        socket.set_pcap(self.pcap.clone());
+        let ring = socket.ring().clone();
+        let socket = Arc::new(Mutex::new(socket));
+        
+        // Input the first packet
+        {
+            let mut s = socket.lock().await;
+            if let Err(e) = s.input_verified(packet) {
+                if self.input_error_log.allow(peer_addr) {
+                    error!("{} initial input error: {}", s.tag(), e);
+                }
+                drop(s);
+                drop(sessions);
+                self.offense(peer_addr).await;
+                return Ok(None);
+            }
+            if let Err(e) = s.send_output().await {
+                if self.send_error_log.allow(peer_addr) {
+                    error!("{} send error: {}", s.tag(), e);
+                }
+            }
+        }
+
+        sessions.insert(peer_addr, socket.clone());
+        drop(sessions);
+        self.convs.insert(conv, &socket);
+        self.metrics.session_opened();
+
+        let tag = SessionTag::new(conv, peer_addr);
+        trace!("{} accepted new connection from {}", tag, client_addr);
+
+        let mut stream = KcpStream::from_socket(socket, ring, self.udp.clone(), tag);
+        stream.owner = Some(self.abandoned.clone());
+
+        Ok(Some((stream, client_addr)))
+    }
+
+    /// Tell a client routing by a session id this listener does not know
+    /// that the session is gone, if there is a reset key
+    async fn reset(&mut self, conv: u32, id: u64, peer_addr: SocketAddr) {
+        let Some(key) = &self.reset_key else {
+            return;
+        };
+        let body = reset::body(id, reset::token(key, conv, id));
+        let mut frame = packet::encode(conv, packet::CMD_RESET, &body);
+        if self.config.checksum {
+            checksum::append(&mut frame);
+        }
+        trace!("reset session id {:016x} of {}", id, peer_addr);
+        if let Err(e) = self.udp.send_to(&frame, self.wire_addr(peer_addr)).await {
+            if self.send_error_log.allow(peer_addr) {
+                error!("reset of {} not sent: {}", peer_addr, e);
+            }
+        }
+    }
+
+    /// Tell a client opening a session that it is refused and why
+    ///
+    /// Peers without the smol-kcp extensions cannot be told, their
+    /// handshake times out.
+    async fn refuse(&self, conv: u32, peer_addr: SocketAddr, code: CloseCode) {
+        if self.config.interop != KcpInterop::Native {
+            return;
+        }
+        let mut frame = packet::encode(conv, packet::CMD_CLOSE, &code.encode());
+        if self.config.checksum {
+            checksum::append(&mut frame);
+        }
+        if let Err(e) = self.udp.send_to(&frame, self.wire_addr(peer_addr)).await {
+            trace!("refusal to {} not sent: {}", peer_addr, e);
+        }
+    }
+
+    /// Address the listener's socket sends to `peer` at
+    fn wire_addr(&self, peer: SocketAddr) -> SocketAddr {
+        send_addr(peer, is_ipv6(self.udp.get_ref()))
+    }
+
+    /// Close the sessions whose streams were all dropped, telling their
+    /// peers with a close frame, and remove them
+    async fn close_abandoned(&self) {
+        let abandoned = self.abandoned.take();
+        if abandoned.is_empty() {
+            return;
+        }
+        let mut sessions = self.sessions.lock().await;
+        for session in abandoned {
+            let mut socket = session.lock().await;
+            socket.close_dropped();
+            if let Err(e) = socket.send_output().await {
+                trace!("{} close frame not sent: {}", socket.tag(), e);
+            }
+            // Exported for a hot restart, the datagrams go to the new process
+            if socket.check_open().is_ok() {
+                continue;
+            }
+            let (tag, peer) = (socket.tag(), socket.peer_addr());
+            let paths = socket.path_peers();
+            drop(socket);
+            if sessions.get(&peer).is_some_and(|known| Arc::ptr_eq(known, &session)) {
+                debug!("{} dropped by the application", tag);
+                Self::remove_session(&mut sessions, peer, &paths);
+                self.metrics.session_closed();
+            }
+        }
+    }
+
+    /// Remove the sessions closed by either side, returns the number of
+    /// addresses left
+    async fn prune_ended(&mut self) -> usize {
+        let mut sessions = self.sessions.lock().await;
+        let mut ended = Vec::new();
+        for socket in Self::unique(&sessions) {
+            let socket = socket.lock().await;
+            if socket.check_open().is_err() || socket.is_peer_closed() {
+                ended.push((socket.peer_addr(), socket.path_peers()));
+            }
+        }
+        for (addr, paths) in ended {
+            Self::remove_session(&mut sessions, addr, &paths);
+            self.metrics.session_closed();
+        }
+        sessions.len()
+    }
+
+    /// Add the source of a multipath join as a path of the session with
+    /// the join's conv, see [`crate::multipath`]
+    ///
+    /// Joins of unknown convs are dropped, the client repeats them.
+    async fn join_path(
+        &self,
+        sessions: &mut Sessions,
+        conv: u32,
+        packet: &[u8],
+        peer_addr: SocketAddr,
+    ) {
+        let mut session = sessions.get(&peer_addr).cloned();
+        if session.is_none() {
+            session = self.find_conv(sessions, conv).await;
+        }
+        let Some(session) = session else {
+            trace!("dropped a path join of unknown conv {} from {}", conv, peer_addr);
+            return;
+        };
+
+        let mut socket = session.lock().await;
+        let body = &packet[packet::HEADER_LEN..];
+        if socket.conv() != conv || !socket.join_path(&self.config, peer_addr, body) {
+            trace!("{} refused a path join from {}", socket.tag(), peer_addr);
+            return;
+        }
+        if let Err(e) = socket.send_output().await {
+            trace!("{} path join echo not sent: {}", socket.tag(), e);
+        }
+        Self::forget_dropped_paths(sessions, &mut socket);
+        drop(socket);
+        sessions.entry(peer_addr).or_insert(session);
+    }
+
+    /// Stop routing the addresses of paths `socket` removed unvalidated
+    fn forget_dropped_paths(sessions: &mut Sessions, socket: &mut KcpSocket) {
+        for peer in socket.take_dropped_paths() {
+            if peer != socket.peer_addr() {
+                sessions.remove(&peer);
+            }
+        }
+    }
+
+    /// Route the session an offer of a session id arrived from by that
+    /// id and echo the offer, see [`crate::route`]
+    ///
+    /// Offers arriving before the session opened are dropped, the client
+    /// repeats them.
+    async fn accept_route(
+        routes: &mut RouteTable,
+        sessions: &Sessions,
+        reset_key: Option<&[u8; 16]>,
+        conv: u32,
+        packet: &[u8],
+        peer_addr: SocketAddr,
+    ) {
+        let body = &packet[packet::HEADER_LEN..];
+        let (Some(session), Some(id)) = (sessions.get(&peer_addr), route::parse_id(body)) else {
+            return;
+        };
+        let mut socket = session.lock().await;
+        if socket.conv() != conv || !routes.insert(id, session) {
+            trace!("{} refused session id {:016x}", socket.tag(), id);
+            return;
+        }
+        socket.accept_session_id(id, reset_key);
+        if let Err(e) = socket.send_output().await {
+            trace!("{} session id echo not sent: {}", socket.tag(), e);
+        }
+    }
+
+    /// Follow a session to the new address `peer_addr` once the peer
+    /// answered a challenge from there
+    ///
+    /// Until then its datagrams from there are dropped, a spoofed source
+    /// address cannot feed the session; the peer's KCP resends them once
+    /// the session moved. The session keeps sending to the validated
+    /// address.
+    async fn migrate(
+        &self,
+        sessions: &mut Sessions,
+        session: Arc<Mutex<KcpSocket>>,
+        packet: &[u8],
+        peer_addr: SocketAddr,
+    ) {
+        let mut socket = session.lock().await;
+        if packet::ext_cmd(packet) == Some(packet::CMD_RESPONSE) {
+            let Some(from) = socket.complete_migration(peer_addr, &packet[packet::HEADER_LEN..])
+            else {
+                return;
+            };
+            // Whatever waited for the peer goes out to the new address
+            if let Err(e) = socket.flush() {
+                trace!("{} flush after migration failed: {}", socket.tag(), e);
+            }
+            if let Err(e) = socket.send_output().await {
+                trace!("{} send error: {}", socket.tag(), e);
+            }
+            drop(socket);
+            sessions.remove(&from);
+            sessions.insert(peer_addr, session);
+            return;
+        }
+
+        trace!("{} dropped a packet from unvalidated {}", socket.tag(), peer_addr);
+        if let Some(challenge) = socket.migration_challenge(peer_addr) {
+            if let Err(e) = self.udp.send_to(&challenge, self.wire_addr(peer_addr)).await {
+                trace!("{} challenge to {} not sent: {}", socket.tag(), peer_addr, e);
+            }
+        }
+    }
+
+    /// Sessions with a path from `ip`
+    fn sessions_of_ip(sessions: &Sessions, ip: IpAddr) -> usize {
+        let mut seen = HashSet::new();
+        sessions
+            .iter()
+            .filter(|(addr, socket)| addr.ip() == ip && seen.insert(Arc::as_ptr(socket)))
+            .count()
+    }
+
+    /// Session with conversation id `conv` under any address
+    async fn find_conv(&self, sessions: &Sessions, conv: u32) -> Option<Arc<Mutex<KcpSocket>>> {
+        for session in self.convs.get(conv) {
+            // Sessions the listener removed may live on in their streams
+            let peer = session.lock().await.peer_addr();
+            if sessions.get(&peer).is_some_and(|known| Arc::ptr_eq(known, &session)) {
+                return Some(session);
+            }
+        }
+        None
+    }
+
+    /// Remove a session under its address and those of its other paths
+    fn remove_session(sessions: &mut Sessions, peer_addr: SocketAddr, paths: &[SocketAddr]) {
+        sessions.remove(&peer_addr);
+        for peer in paths {
+            sessions.remove(peer);
+        }
+    }
+
+    /// Conversation id for a client that left it to the server
+    fn new_conv(&self) -> u32 {
+        let Some((index, count)) = self.shard else {
+            return self.config.new_conv();
+        };
+        // Gives up on a generator that keeps returning convs of other
+        // workers, their datagrams are then forwarded to the wrong one
+        let mut conv = self.config.new_conv();
+        for _ in 0..1000 {
+            if shard::shard_of(conv, count) == index {
+                break;
+            }
+            conv = self.config.new_conv();
+        }
+        conv
+    }
+
+    /// Conversation id of a datagram received by this listener
+    pub(crate) fn conv_of(&self, datagram: &[u8]) -> Option<u32> {
+        self.config.header_format.conv(datagram)
+    }
+
+    /// Make this listener worker `index` of `count` of a sharded listener
+    pub(crate) fn set_shard(&mut self, index: usize, count: usize) {
+        self.shard = Some((index, count));
+    }
+
+    /// Resume a session accepted by an earlier process, see
+    /// [`KcpStream::export_state`]
+    ///
+    /// The listener must be bound to the same address, e.g. inherited with
+    /// [`KcpListener::from_fd`]. Sessions use the listener's configuration
+    /// for the options outside the KCP state. Import before accepting, so
+    /// datagrams of the session are not taken for a new connection.
+    pub async fn import_session(&mut self, state: &[u8]) -> KcpResult<KcpStream> {
+        let state = ExportedState::parse(state)?;
+        let mut socket = KcpSocket::import_state(&self.config, self.udp.clone(), &state)?;
+        socket.set_pcap(self.pcap.clone());
+        let tag = socket.tag();
+        let conv = socket.conv();
+        let ring = socket.ring().clone();
+        let socket = Arc::new(Mutex::new(socket));
+
+        self.sessions.lock().await.insert(canonical_addr(state.peer), socket.clone());
+        self.convs.insert(conv, &socket);
+        self.metrics.session_opened();
+        debug!("{} session imported", tag);
+
+        let mut stream = KcpStream::from_socket(socket, ring, self.udp.clone(), tag);
+        stream.owner = Some(self.abandoned.clone());
+        Ok(stream)
+    }
+
+    /// Accept a new connection wrapped for hyper
+    #[cfg(feature = "http")]
+    pub async fn accept_hyper(&mut self) -> KcpResult<(crate::KcpHyperIo, SocketAddr)> {
+        let (stream, peer) = self.accept().await?;
+        Ok((stream.into(), peer))
+    }
+
+    /// Announcer of this listener as `instance` of `service` (e.g.
+    /// `_mykcp._udp`) on the LAN, nothing is announced until it runs
+    #[cfg(feature = "discovery")]
+    pub fn announcer(&self, service: &str, instance: &str) -> io::Result<crate::ServiceAnnouncer> {
+        crate::ServiceAnnouncer::new(service, instance, self.local_addr()?)
+    }
+
+    /// Forward the listener's port from the gateway, e.g. a home router,
+    /// see [`crate::portmap`]; the mapping lapses unless it runs
+    #[cfg(feature = "portmap")]
+    pub async fn port_mapping(&self) -> io::Result<crate::PortMapping> {
+        crate::PortMapping::request(self.local_addr()?.port()).await
+    }
+
+    /// Sum up the memory of all sessions when due and evict sessions over
+    /// the budget
+    ///
+    /// The most memory-hungry sessions the application has not used for
+    /// [`EVICT_IDLE`] go first. Busy sessions are never evicted, instead new
+    /// sessions are rejected until usage is back under the budget.
+    async fn check_budget(
+        budget: &mut Option<MemoryBudget>,
+        metrics: &ListenerMetrics,
+        sessions: &mut Sessions,
+    ) {
+        let Some(budget) = budget else {
+            return;
+        };
+        let now = Instant::now();
+        if now < budget.next_check {
+            return;
+        }
+        budget.next_check = now + BUDGET_CHECK_INTERVAL;
+
+        let mut usage = Vec::with_capacity(sessions.len());
+        for (addr, socket) in sessions.iter() {
+            let socket = socket.lock().await;
+            // Multipath sessions are counted under their primary address
+            if socket.peer_addr() != *addr {
+                continue;
+            }
+            usage.push((*addr, socket.buffered_bytes(), socket.idle_time()));
+        }
+        budget.used = usage.iter().map(|(_, bytes, _)| bytes).sum();
+        if budget.used <= budget.limit {
+            return;
+        }
+
+        usage.retain(|(_, _, idle)| *idle >= EVICT_IDLE);
+        usage.sort_unstable_by_key(|(_, bytes, _)| Reverse(*bytes));
+        for (addr, bytes, _) in usage {
+            if budget.used <= budget.limit {
+                break;
+            }
+            if let Some(socket) = sessions.remove(&addr) {
                 let mut socket = socket.lock().await;
-                if let Err(e) = socket.input(packet) {
-                    error!("input error: {}", e);
+                warn!(
+                    "{} evicted holding {} bytes, memory budget of {} bytes exceeded",
+                    socket.tag(),
+                    bytes,
+                    budget.limit
+                );
+                socket.close(CloseReason::MemoryExceeded);
+                socket.queue_close_frame();
+                let _ = socket.send_output().await;
+                for peer in socket.path_peers() {
+                    sessions.remove(&peer);
                 }
+                budget.used -= bytes;
+                metrics.session_closed();
+            }
+        }
+    }
+
+    /// Limit the bytes buffered by all sessions together, `None` is
+    /// unlimited
+    ///
+    /// Usage is summed up at most every 100ms while accepting. Over the
+    /// budget, the sessions holding the most memory that the application
+    /// has not sent or received on for 5s are dropped, their streams fail
+    /// with `OutOfMemory`. New sessions are refused with
+    /// [`CloseCode::Overloaded`] until usage is back under the budget, each
+    /// refusal counts as an offense of [`KcpListener::set_auto_ban`].
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.budget = budget.map(|limit| MemoryBudget {
+            limit,
+            used: 0,
+            next_check: Instant::now(),
+        });
+    }
+
+    /// Limit the sessions one client IP may have open at once, `None`
+    /// (the default) is unlimited
+    ///
+    /// Keeps a single client, e.g. one leaking connections, from taking
+    /// all the sessions a server can hold. Sessions beyond the limit are
+    /// refused with [`CloseCode::Overloaded`], their clients fail with
+    /// `ConnectionRefused` instead of waiting for the handshake to time
+    /// out. Clients behind one NAT share their IP, leave room for them.
+    pub fn set_max_sessions_per_ip(&mut self, max: Option<usize>) {
+        self.max_sessions_per_ip = max;
+    }
+
+    /// Bytes buffered by all sessions at the last budget check, `None`
+    /// without a budget
+    pub fn memory_usage(&self) -> Option<usize> {
+        self.budget.as_ref().map(|budget| budget.used)
+    }
+
+    /// Key to derive the reset tokens of sessions from, `None` (the
+    /// default) sends no resets
+    ///
+    /// Clients routing by session id ([`KcpConfig::session_ids`]) are given
+    /// a token derived from the key when their id is accepted. Datagrams
+    /// of an id the listener does not know, e.g. because it restarted and
+    /// lost its sessions, are answered with that token, and the client
+    /// fails with [`Error::ConnectionReset`](crate::Error::ConnectionReset)
+    /// right away instead of when the session expires; see
+    /// [`crate::reset`]. Keep the key across restarts and secret, e.g.
+    /// random bytes in a file only the server reads.
+    pub fn set_reset_key(&mut self, key: Option<[u8; 16]>) {
+        self.reset_key = key;
+    }
+
+    /// Expect a PROXY protocol v2 header at the start of every datagram,
+    /// off by default
+    ///
+    /// For listeners behind a UDP relay or load balancer that prepends
+    /// one: [`KcpListener::accept`] returns the client address from the
+    /// header, datagrams without a valid header are dropped. Sessions,
+    /// replies and the per-IP session limit still go by the address of
+    /// the relay, bans apply to either address. See [`crate::proxy_protocol`].
+    pub fn set_proxy_protocol(&mut self, enabled: bool) {
+        self.proxy_protocol = enabled;
+    }
+
+    /// Handlers [`KcpListener::serve`] runs at once, 1024 by default
+    ///
+    /// Takes effect when serving starts.
+    #[cfg(feature = "serve")]
+    pub fn set_serve_limit(&mut self, limit: usize) {
+        self.serve_limit = limit;
+    }
+
+    /// Configuration used for sessions accepted from now on
+    ///
+    /// Established sessions keep the configuration they were accepted with.
+    pub fn set_config(&mut self, config: KcpConfig) {
+        self.config = config;
+    }
+
+    /// Edit the configuration of the current sessions and of those
+    /// accepted from now on
+    ///
+    /// `f` is called on the listener's configuration and on the one of
+    /// every session. Running sessions take over the changes to timing,
+    /// windows and limits, see [`KcpSocket::reconfigure`], e.g. to back
+    /// off on a congested uplink without a restart.
+    pub async fn reconfigure(&mut self, f: impl Fn(&mut KcpConfig)) {
+        f(&mut self.config);
+        let sessions = self.sessions.lock().await;
+        for socket in Self::unique(&sessions) {
+            socket.lock().await.reconfigure(&f);
+        }
+    }
+
+    /// Capture the datagrams of all sessions, `None` stops capturing
+    ///
+    /// Applies to existing sessions and to sessions accepted later.
+    pub async fn set_pcap(&mut self, pcap: Option<PcapWriter>) {
+        for socket in Self::unique(&*self.sessions.lock().await) {
+            socket.lock().await.set_pcap(pcap.clone());
+        }
+        self.pcap = pcap;
+    }
+
+    /// Send a message to every active session
+    ///
+    /// Returns the number of sessions the whole message was queued on.
+    /// Sessions that ended are skipped, those failing to send or queueing
+    /// only part of the message are logged and not counted.
+    pub async fn broadcast(&self, buf: &[u8]) -> KcpResult<usize> {
+        self.broadcast_filtered(buf, |_| true).await
+    }
+
+    /// Send a message to every active session whose peer matches `pred`
+    ///
+    /// Multipath sessions get the message once, `pred` sees the address
+    /// of the path they send on.
+    pub async fn broadcast_filtered<F>(&self, buf: &[u8], mut pred: F) -> KcpResult<usize>
+    where
+        F: FnMut(SocketAddr) -> bool,
+    {
+        // Snapshot the targets so accept() is not blocked while sending
+        let targets: Vec<_> = Self::unique(&*self.sessions.lock().await).cloned().collect();
+
+        let mut delivered = 0;
+        for socket in targets {
+            let mut socket = socket.lock().await;
+            // Ended sessions wait for the next datagram to be reaped
+            if socket.check_open().is_err() || socket.is_peer_closed() {
                 continue;
             }
+            if !pred(socket.peer_addr()) {
+                continue;
+            }
+            let result = match socket.send(buf).and_then(|n| socket.flush().map(|()| n)) {
+                Ok(n) => socket.send_output().await.map(|()| n).map_err(Into::into),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(n) if n == buf.len() => delivered += 1,
+                // Stream mode queues what fits the session's memory limit
+                Ok(n) => warn!("{} broadcast cut to {} of {} bytes", socket.tag(), n, buf.len()),
+                Err(Error::WindowExhausted) => {
+                    debug!("{} broadcast skipped, send window full", socket.tag())
+                }
+                Err(e) => error!("{} broadcast failed: {}", socket.tag(), e),
+            }
+        }
+
+        trace!("broadcast {} bytes to {} sessions", buf.len(), delivered);
+        Ok(delivered)
+    }
 
-            // Create new session
-            let socket = KcpSocket::new(
-                &self.config,
-                conv,
-                self.udp.clone(),
-                peer_addr,
-                self.config.stream,
-            )?;
+    /// The sessions of this listener, in no particular order
+    ///
+    /// Multipath sessions are listed once, under the path they send on.
+    /// Sessions that ended stay listed until a datagram or
+    /// [`KcpListener::drain`] reaps them.
+    pub async fn sessions(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().await;
+        let mut infos = Vec::with_capacity(sessions.len());
+        for socket in Self::unique(&sessions) {
+            infos.push(socket.lock().await.info());
+        }
+        infos
+    }
+
+    /// Number of sessions, see [`KcpListener::sessions`]
+    pub async fn session_count(&self) -> usize {
+        Self::unique(&*self.sessions.lock().await).count()
+    }
 
-            let socket = Arc::new(Mutex::new(socket));
-            
-            // Input the first packet
-            {
-                let mut s = socket.lock().await;
-                if let Err(e) = s.input(packet) {
-                    error!("initial input error: {}", e);
-                    continue;
+    /// Every session once, multipath sessions are listed under each path
+    fn unique(sessions: &Sessions) -> impl Iterator<Item = &Arc<Mutex<KcpSocket>>> {
+        let mut seen = HashSet::with_capacity(sessions.len());
+        sessions.values().filter(move |socket| seen.insert(Arc::as_ptr(socket)))
+    }
+
+    /// End the session of conversation `conv` with `peer` and tell the
+    /// peer, returns whether there was one
+    ///
+    /// The client fails with [`Error::ClosedByPeer`] carrying
+    /// [`CloseCode::Disconnected`], the accepted stream with
+    /// [`Error::ConnectionClosed`]. Nothing stops the client from
+    /// connecting again.
+    ///
+    /// [`Error::ConnectionClosed`]: crate::Error::ConnectionClosed
+    /// [`Error::ClosedByPeer`]: crate::Error::ClosedByPeer
+    pub async fn disconnect(&mut self, peer: SocketAddr, conv: u32) -> bool {
+        let peer = canonical_addr(peer);
+        let mut sessions = self.sessions.lock().await;
+        let Some(session) = sessions.get(&peer).cloned() else {
+            return false;
+        };
+        let mut socket = session.lock().await;
+        if socket.conv() != conv {
+            return false;
+        }
+        if socket.check_open().is_ok() {
+            socket.close(CloseReason::Closed(CloseCode::Disconnected));
+            if !socket.is_peer_closed() {
+                socket.queue_close_frame();
+            }
+            if let Err(e) = socket.send_output().await {
+                if self.send_error_log.allow(peer) {
+                    error!("{} send error: {}", socket.tag(), e);
                 }
             }
+        }
+        debug!("{} disconnected", socket.tag());
+        let paths = socket.path_peers();
+        drop(socket);
+        Self::remove_session(&mut sessions, peer, &paths);
+        self.metrics.session_closed();
+        true
+    }
 
-            sessions.insert(peer_addr, socket.clone());
-            drop(sessions);
+    /// Drop everything `ip` sends for `duration` and end its sessions
+    ///
+    /// Datagrams of a banned IP are dropped before they are looked at,
+    /// it cannot open sessions, join paths or move sessions to its
+    /// address. Its current sessions end at once without telling the
+    /// client, their streams fail with [`Error::ConnectionClosed`]. A
+    /// later ban of the same IP replaces the duration.
+    pub async fn ban(&mut self, ip: IpAddr, duration: Duration) {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        self.banned.retain(|_, until| *until > now);
+        self.banned.insert(ip, now + duration);
+        debug!("banned {} for {:?}", ip, duration);
+
+        let mut sessions = self.sessions.lock().await;
+        let banned: Vec<_> = {
+            let mut seen = HashSet::new();
+            sessions
+                .iter()
+                .filter(|(addr, socket)| addr.ip() == ip && seen.insert(Arc::as_ptr(socket)))
+                .map(|(&addr, socket)| (addr, socket.clone()))
+                .collect()
+        };
+        for (addr, session) in banned {
+            let mut socket = session.lock().await;
+            socket.close(CloseReason::Closed(CloseCode::Disconnected));
+            debug!("{} ended, its peer is banned", socket.tag());
+            let paths = socket.path_peers();
+            drop(socket);
+            Self::remove_session(&mut sessions, addr, &paths);
+            self.metrics.session_closed();
+        }
+    }
+
+    /// Ban source IPs sending malformed packets or failing handshakes,
+    /// `None` (the default) bans none
+    ///
+    /// Datagrams too short or with broken extension headers, datagrams
+    /// failing their checksum from an address without a session, first
+    /// datagrams of a session that KCP rejects and sessions refused over
+    /// the memory budget count as offenses.
+    /// An IP with more than [`AutoBanConfig::max_offenses`] within
+    /// [`AutoBanConfig::window`] is banned for
+    /// [`AutoBanConfig::cooldown`] as with [`KcpListener::ban`], and
+    /// [`KcpEvent::SourceBanned`] goes to the handler of
+    /// [`KcpListener::set_event_handler`].
+    pub fn set_auto_ban(&mut self, auto_ban: Option<AutoBanConfig>) {
+        self.auto_ban = auto_ban;
+    }
+
+    /// Register a callback for events of the listener, see
+    /// [`KcpEvent::SourceBanned`]
+    ///
+    /// Sessions have handlers of their own, see
+    /// [`KcpStream::set_event_handler`].
+    pub fn set_event_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&KcpEvent) + Send + Sync + 'static,
+    {
+        self.event_handler = Some(Arc::new(handler));
+    }
+
+    /// Remove the event callback
+    pub fn clear_event_handler(&mut self) {
+        self.event_handler = None;
+    }
+
+    /// Account an offense of the IP of `peer_addr` and ban it once it has
+    /// too many, see [`KcpListener::set_auto_ban`]
+    async fn offense(&mut self, peer_addr: SocketAddr) {
+        let Some(auto_ban) = self.auto_ban else {
+            return;
+        };
+        let ip = peer_addr.ip();
+        let offenses = self.offenses.record(ip, auto_ban.window);
+        if offenses <= auto_ban.max_offenses {
+            return;
+        }
+        self.offenses.forget(ip);
+        warn!(
+            "{} sent {} bad datagrams within {:?}, banned for {:?}",
+            ip, offenses, auto_ban.window, auto_ban.cooldown
+        );
+        self.ban(ip, auto_ban.cooldown).await;
+        if let Some(handler) = &self.event_handler {
+            handler(&KcpEvent::SourceBanned {
+                ip,
+                offenses,
+                duration: auto_ban.cooldown,
+            });
+        }
+    }
 
-            trace!("accepted new connection from {}", peer_addr);
+    /// Lift the ban of `ip`, returns whether it was banned
+    pub fn unban(&mut self, ip: IpAddr) -> bool {
+        self.banned.remove(&ip.to_canonical()).is_some_and(|until| until > Instant::now())
+    }
 
-            let stream = KcpStream::from_socket(socket);
+    /// Whether datagrams of `ip` are dropped, see [`KcpListener::ban`]
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.get(&ip.to_canonical()).is_some_and(|&until| until > Instant::now())
+    }
 
-            return Ok((stream, peer_addr));
+    /// Close every session, telling the peers the server shuts down
+    ///
+    /// Accepted streams fail with [`Error::ConnectionClosed`] afterwards,
+    /// their peers with [`Error::ClosedByPeer`]. Returns the number of
+    /// sessions closed; clients connecting later are accepted as usual.
+    ///
+    /// [`Error::ConnectionClosed`]: crate::Error::ConnectionClosed
+    /// [`Error::ClosedByPeer`]: crate::Error::ClosedByPeer
+    pub async fn shutdown(&mut self) -> usize {
+        let sessions: Vec<_> = {
+            let mut sessions = self.sessions.lock().await;
+            let unique = Self::unique(&sessions).cloned().collect();
+            sessions.clear();
+            unique
+        };
+        let mut closed = 0;
+        for socket in sessions {
+            let mut socket = socket.lock().await;
+            self.metrics.session_closed();
+            if socket.check_open().is_err() {
+                continue;
+            }
+            socket.close(CloseReason::Closed(CloseCode::ServerShutdown));
+            if !socket.is_peer_closed() {
+                socket.queue_close_frame();
+            }
+            if let Err(e) = socket.send_output().await {
+                if self.send_error_log.allow(socket.peer_addr()) {
+                    error!("{} send error: {}", socket.tag(), e);
+                }
+            }
+            closed += 1;
         }
+        debug!("shutdown closed {} sessions", closed);
+        closed
+    }
+
+    /// Stop taking new sessions and wait for the existing ones to end
+    ///
+    /// Unlike [`KcpListener::shutdown`], accepted sessions carry on: this
+    /// feeds them as [`KcpListener::accept`] does and resolves once each
+    /// was closed by either side or expired. Clients opening a session are
+    /// refused with [`CloseCode::ServerShutdown`] from now on, and
+    /// `accept` returns no new connections. For a rolling restart, drain
+    /// with a deadline and `shutdown` whatever is left:
+    ///
+    /// ```no_run
+    /// # async fn restart(mut listener: smol_kcp::KcpListener) -> smol_kcp::KcpResult<()> {
+    /// use std::time::Duration;
+    /// use futures_lite::future;
+    ///
+    /// let deadline = async {
+    ///     async_io::Timer::after(Duration::from_secs(30)).await;
+    ///     Ok(())
+    /// };
+    /// future::or(listener.drain(), deadline).await?;
+    /// listener.shutdown().await;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn drain(&mut self) -> KcpResult<()> {
+        self.draining = true;
+        debug!("draining {} sessions", self.sessions.lock().await.len());
+        let mut buf = vec![0u8; 65536];
+        while self.prune_ended().await > 0 {
+            let received = future::or(async { Some(self.recv_datagram(&mut buf).await) }, async {
+                Timer::after(DRAIN_CHECK_INTERVAL).await;
+                None
+            })
+            .await;
+            if let Some(received) = received {
+                let (n, peer_addr) = received?;
+                self.handle_datagram(&buf[..n], peer_addr).await?;
+            }
+        }
+        debug!("drained");
+        Ok(())
+    }
+
+    /// Whether [`KcpListener::drain`] was called
+    pub fn is_draining(&self) -> bool {
+        self.draining
     }
 
     /// Get local address
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.udp.get_ref().local_addr()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(unix)]
+impl AsRawFd for KcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.udp.as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for KcpListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.udp.get_ref().as_fd()
+    }
+}
+
+#[cfg(unix)]
+impl FromRawFd for KcpListener {
+    /// Wrap a bound UDP socket fd using the default configuration
+    ///
+    /// # Panics
+    ///
+    /// Panics if the socket cannot be registered with the reactor.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        let udp = std::net::UdpSocket::from_raw_fd(fd);
+        Self::from_std(KcpConfig::default(), udp).expect("failed to register UDP socket")
+    }
+}
+
+#[cfg(unix)]
+impl IntoRawFd for KcpListener {
+    /// Release the UDP socket fd
+    ///
+    /// Accepted streams share the socket, so if any are still alive a
+    /// duplicated fd is returned instead.
+    fn into_raw_fd(self) -> RawFd {
+        match Arc::try_unwrap(self.udp) {
+            Ok(udp) => udp
+                .into_inner()
+                .expect("failed to deregister UDP socket")
+                .into_raw_fd(),
+            Err(udp) => udp
+                .get_ref()
+                .try_clone()
+                .expect("failed to duplicate UDP socket")
+                .into_raw_fd(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for KcpListener {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.udp.as_raw_socket()
+    }
+}
+
+#[cfg(windows)]
+impl AsSocket for KcpListener {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.udp.get_ref().as_socket()
+    }
+}
+
+#[cfg(windows)]
+impl FromRawSocket for KcpListener {
+    /// Wrap a bound UDP socket handle using the default configuration
+    ///
+    /// # Panics
+    ///
+    /// Panics if the socket cannot be registered with the reactor.
+    unsafe fn from_raw_socket(sock: RawSocket) -> Self {
+        let udp = std::net::UdpSocket::from_raw_socket(sock);
+        Self::from_std(KcpConfig::default(), udp).expect("failed to register UDP socket")
+    }
+}
+
+#[cfg(windows)]
+impl IntoRawSocket for KcpListener {
+    /// Release the UDP socket handle
+    ///
+    /// Accepted streams share the socket, so if any are still alive a
+    /// duplicated handle is returned instead.
+    fn into_raw_socket(self) -> RawSocket {
+        match Arc::try_unwrap(self.udp) {
+            Ok(udp) => udp
+                .into_inner()
+                .expect("failed to deregister UDP socket")
+                .into_raw_socket(),
+            Err(udp) => udp
+                .get_ref()
+                .try_clone()
+                .expect("failed to duplicate UDP socket")
+                .into_raw_socket(),
+        }
+    }
+}
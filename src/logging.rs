@@ -0,0 +1,76 @@
+//! Logging through the `log` facade, or nowhere
+//!
+//! Without the `log` feature the macros below take the place of the ones
+//! from `log`: arguments are still type checked, nothing is compiled in.
+
+#[cfg(feature = "log")]
+pub(crate) use log::{debug, error, log, log_enabled, trace, warn, Level};
+
+#[cfg(not(feature = "log"))]
+mod disabled {
+    /// Stand-in for `log::Level`
+    #[allow(dead_code)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) enum Level {
+        Error,
+        Warn,
+        Info,
+        Debug,
+        Trace,
+    }
+
+    macro_rules! log {
+        (target: $target:expr, $level:expr, $($arg:tt)+) => {{
+            let _ = ($target, $level);
+            if false {
+                let _ = format_args!($($arg)+);
+            }
+        }};
+        ($level:expr, $($arg:tt)+) => {{
+            let _ = $level;
+            if false {
+                let _ = format_args!($($arg)+);
+            }
+        }};
+    }
+
+    macro_rules! log_enabled {
+        (target: $target:expr, $level:expr) => {{
+            let _ = ($target, $level);
+            false
+        }};
+    }
+
+    macro_rules! debug {
+        (target: $target:expr, $($arg:tt)+) => {
+            $crate::logging::log!(target: $target, $crate::logging::Level::Debug, $($arg)+)
+        };
+        ($($arg:tt)+) => {
+            $crate::logging::log!($crate::logging::Level::Debug, $($arg)+)
+        };
+    }
+
+    macro_rules! error {
+        ($($arg:tt)+) => {
+            $crate::logging::log!($crate::logging::Level::Error, $($arg)+)
+        };
+    }
+
+    macro_rules! trace {
+        ($($arg:tt)+) => {
+            $crate::logging::log!($crate::logging::Level::Trace, $($arg)+)
+        };
+    }
+
+    // `warn` alone clashes with the built-in attribute
+    macro_rules! log_warn {
+        ($($arg:tt)+) => {
+            $crate::logging::log!($crate::logging::Level::Warn, $($arg)+)
+        };
+    }
+
+    pub(crate) use {debug, error, log, log_enabled, log_warn as warn, trace};
+}
+
+#[cfg(not(feature = "log"))]
+pub(crate) use disabled::{debug, error, log, log_enabled, trace, warn, Level};
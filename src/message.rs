@@ -0,0 +1,292 @@
+//! Per-message reliability for message mode
+//!
+//! Reliable messages go through the KCP send queue as usual. Unreliable and
+//! partially reliable messages bypass it and travel as standalone
+//! [`CMD_MSG`](crate::packet::CMD_MSG) packets, so they are never ordered
+//! behind (or block) reliable data. Partially reliable messages are
+//! acknowledged by the receiver and retransmitted until acknowledged, their
+//! retransmission budget is spent, or they expire.
+//!
+//! The lane also carries urgent bytes as [`CMD_URGENT`] packets: a single
+//! byte out of band, e.g. "abort transfer", retransmitted until
+//! acknowledged and delivered apart from all other data, so it arrives
+//! while the ordered stream waits for a lost segment.
+//!
+//! [`CMD_URGENT`]: crate::packet::CMD_URGENT
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::packet::{self, CMD_MSG, CMD_MSG_ACK, CMD_URGENT};
+
+const FLAG_ACK_REQUESTED: u8 = 0x01;
+
+/// Message body header: flags (1) | id (4, LE)
+const MSG_HEADER_LEN: usize = 5;
+
+/// Number of recently received ids remembered for duplicate detection
+const RECENT_IDS: usize = 256;
+
+/// Urgent bytes waiting for their ack, further sends are refused
+const MAX_URGENT_PENDING: usize = 32;
+
+/// Received urgent bytes not read yet, further ones are not acknowledged
+/// so the peer keeps retransmitting them
+const MAX_URGENT_RECEIVED: usize = 64;
+
+/// Delivery guarantee of a single message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Reliability {
+    /// Ordered and retransmitted until acknowledged
+    #[default]
+    Reliable,
+    /// Sent once, may be lost, duplicated or reordered
+    Unreliable,
+    /// Retransmitted until acknowledged or one of the limits is hit,
+    /// delivery is unordered
+    Partial {
+        /// Give up after this many retransmissions
+        max_retransmits: Option<u32>,
+        /// Give up once the message is older than this
+        lifetime: Option<Duration>,
+    },
+}
+
+/// Options for [`KcpStream::send_msg_with`](crate::KcpStream::send_msg_with)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendOptions {
+    /// Delivery guarantee
+    pub reliability: Reliability,
+    /// Reliable messages of higher priority are queued ahead of those of
+    /// lower priority that were not sent yet, 0 (the default) queues
+    /// behind everything
+    ///
+    /// Lets control messages or keyframes overtake bulk data without a
+    /// [`KcpMuxer`](crate::KcpMuxer). Message mode only.
+    pub priority: u8,
+}
+
+impl SendOptions {
+    /// Ordered, fully reliable delivery (the default)
+    pub const fn reliable() -> Self {
+        Self {
+            reliability: Reliability::Reliable,
+            priority: 0,
+        }
+    }
+
+    /// Fire-and-forget delivery
+    pub const fn unreliable() -> Self {
+        Self {
+            reliability: Reliability::Unreliable,
+            priority: 0,
+        }
+    }
+
+    /// Retransmit at most `max_retransmits` times
+    pub const fn max_retransmits(max_retransmits: u32) -> Self {
+        Self {
+            reliability: Reliability::Partial {
+                max_retransmits: Some(max_retransmits),
+                lifetime: None,
+            },
+            priority: 0,
+        }
+    }
+
+    /// Retransmit until acknowledged or `lifetime` has passed
+    pub const fn lifetime(lifetime: Duration) -> Self {
+        Self {
+            reliability: Reliability::Partial {
+                max_retransmits: None,
+                lifetime: Some(lifetime),
+            },
+            priority: 0,
+        }
+    }
+
+    /// The same options with `priority`, see [`SendOptions::priority`]
+    pub const fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+struct PendingMessage {
+    id: u32,
+    packet: Vec<u8>,
+    last_sent: Instant,
+    retransmits_left: Option<u32>,
+    expires: Option<Instant>,
+    urgent: bool,
+}
+
+/// State of the unreliable / partially reliable message lane
+#[derive(Default)]
+pub(crate) struct MessageLane {
+    next_id: u32,
+    pending: VecDeque<PendingMessage>,
+    received: VecDeque<Vec<u8>>,
+    recent_ids: VecDeque<u32>,
+    urgent: VecDeque<u8>,
+}
+
+impl MessageLane {
+    /// Largest payload that fits a single datagram of `mtu` bytes
+    pub fn max_payload(mtu: usize) -> usize {
+        mtu.saturating_sub(packet::HEADER_LEN + MSG_HEADER_LEN)
+    }
+
+    /// Build the packet for a new message, tracking it when acks are needed
+    pub fn send(&mut self, conv: u32, payload: &[u8], reliability: Reliability, now: Instant) -> Vec<u8> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let partial = matches!(reliability, Reliability::Partial { .. });
+        let mut body = Vec::with_capacity(MSG_HEADER_LEN + payload.len());
+        body.push(if partial { FLAG_ACK_REQUESTED } else { 0 });
+        body.extend_from_slice(&id.to_le_bytes());
+        body.extend_from_slice(payload);
+        let packet = packet::encode(conv, CMD_MSG, &body);
+
+        if let Reliability::Partial {
+            max_retransmits,
+            lifetime,
+        } = reliability
+        {
+            self.pending.push_back(PendingMessage {
+                id,
+                packet: packet.clone(),
+                last_sent: now,
+                retransmits_left: max_retransmits,
+                expires: lifetime.map(|lifetime| now + lifetime),
+                urgent: false,
+            });
+        }
+
+        packet
+    }
+
+    /// Build the packet for an urgent byte and track it until acknowledged,
+    /// `None` while too many are unacknowledged
+    pub fn send_urgent(&mut self, conv: u32, byte: u8, now: Instant) -> Option<Vec<u8>> {
+        if self.pending.iter().filter(|msg| msg.urgent).count() >= MAX_URGENT_PENDING {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut body = [0; 5];
+        body[..4].copy_from_slice(&id.to_le_bytes());
+        body[4] = byte;
+        let packet = packet::encode(conv, CMD_URGENT, &body);
+        self.pending.push_back(PendingMessage {
+            id,
+            packet: packet.clone(),
+            last_sent: now,
+            retransmits_left: None,
+            expires: None,
+            urgent: true,
+        });
+        Some(packet)
+    }
+
+    /// Handle an incoming lane packet, returns an ack to send back if any
+    pub fn input(&mut self, conv: u32, cmd: u8, body: &[u8]) -> Option<Vec<u8>> {
+        match cmd {
+            CMD_MSG if body.len() >= MSG_HEADER_LEN => {
+                let flags = body[0];
+                let id = u32::from_le_bytes([body[1], body[2], body[3], body[4]]);
+
+                if !self.recent_ids.contains(&id) {
+                    if self.recent_ids.len() == RECENT_IDS {
+                        self.recent_ids.pop_front();
+                    }
+                    self.recent_ids.push_back(id);
+                    self.received.push_back(body[MSG_HEADER_LEN..].to_vec());
+                }
+
+                (flags & FLAG_ACK_REQUESTED != 0)
+                    .then(|| packet::encode(conv, CMD_MSG_ACK, &id.to_le_bytes()))
+            }
+            CMD_URGENT if body.len() == 5 => {
+                let id = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+                if !self.recent_ids.contains(&id) {
+                    if self.urgent.len() == MAX_URGENT_RECEIVED {
+                        return None;
+                    }
+                    if self.recent_ids.len() == RECENT_IDS {
+                        self.recent_ids.pop_front();
+                    }
+                    self.recent_ids.push_back(id);
+                    self.urgent.push_back(body[4]);
+                }
+                Some(packet::encode(conv, CMD_MSG_ACK, &id.to_le_bytes()))
+            }
+            CMD_MSG_ACK if body.len() >= 4 => {
+                let id = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+                self.pending.retain(|msg| msg.id != id);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Collect messages due for retransmission and drop exhausted ones
+    pub fn poll_retransmit(&mut self, now: Instant, rto: Duration) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        self.pending.retain_mut(|msg| {
+            if msg.expires.is_some_and(|expires| now >= expires) {
+                return false;
+            }
+            if now.duration_since(msg.last_sent) < rto {
+                return true;
+            }
+            match msg.retransmits_left {
+                Some(0) => return false,
+                Some(ref mut left) => *left -= 1,
+                None => {}
+            }
+            msg.last_sent = now;
+            due.push(msg.packet.clone());
+            true
+        });
+        due
+    }
+
+    /// Next received message
+    pub fn pop_received(&mut self) -> Option<Vec<u8>> {
+        self.received.pop_front()
+    }
+
+    /// Next received urgent byte
+    pub fn pop_urgent(&mut self) -> Option<u8> {
+        self.urgent.pop_front()
+    }
+
+    /// Id of the next message sent
+    pub fn next_id(&self) -> u32 {
+        self.next_id
+    }
+
+    /// Lane continuing the ids of an earlier one, so the peer does not
+    /// take new messages for duplicates
+    pub fn with_next_id(next_id: u32) -> Self {
+        Self {
+            next_id,
+            ..Default::default()
+        }
+    }
+
+    /// Bytes held by partially reliable messages waiting for their ack
+    pub fn pending_bytes(&self) -> usize {
+        self.pending.iter().map(|msg| msg.packet.len()).sum()
+    }
+
+    /// Bytes held by received messages not read yet
+    pub fn received_bytes(&self) -> usize {
+        self.received.iter().map(Vec::len).sum()
+    }
+}
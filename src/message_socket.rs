@@ -0,0 +1,346 @@
+//! Connectionless server API
+//!
+//! [`KcpMessageSocket`] keeps the KCP sessions of all peers inside one
+//! object and hands out their messages as `(conv, peer, message)` tuples,
+//! replies are addressed by peer. No [`KcpStream`](crate::KcpStream) or
+//! task exists per client, which suits request/response services with
+//! many mostly idle peers. Sessions are driven by
+//! [`KcpMessageSocket::recv_from`], keep calling it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use futures_lite::future;
+
+use crate::{
+    checksum,
+    config::{KcpConfig, KcpInterop},
+    error::{Error, KcpResult},
+    hello,
+    logging::{debug, error, trace, warn, Level},
+    packet,
+    ratelimit::LogLimiter,
+    socket::{canonical_addr, configure_udp, CloseCode, CloseReason, KcpSocket, SessionTag},
+};
+
+struct Peer {
+    socket: KcpSocket,
+    /// Listed in `ready`
+    queued: bool,
+}
+
+/// KCP server socket delivering the messages of all peers
+///
+/// Sessions open when a client sends its first segment and end when the
+/// peer closes them, on [`KcpMessageSocket::close`], when they expire
+/// with data in flight (see [`KcpConfig::session_expire`]) or after the
+/// idle timeout. Multipath joins and address migration are not
+/// supported, a client moving to a new address starts a new session.
+pub struct KcpMessageSocket {
+    udp: Arc<Async<std::net::UdpSocket>>,
+    config: KcpConfig,
+    peers: HashMap<SocketAddr, Peer>,
+    /// Peers that may have received messages, in arrival order
+    ready: VecDeque<SocketAddr>,
+    next_update: Instant,
+    idle_timeout: Option<Duration>,
+    buf: Vec<u8>,
+    malformed_log: LogLimiter,
+    input_error_log: LogLimiter,
+    send_error_log: LogLimiter,
+    corrupted_log: LogLimiter,
+}
+
+impl KcpMessageSocket {
+    /// Bind to an address
+    pub async fn bind(config: KcpConfig, addr: SocketAddr) -> KcpResult<Self> {
+        let udp = std::net::UdpSocket::bind(addr)?;
+        configure_udp(&udp)?;
+        Ok(Self {
+            udp: Arc::new(Async::new(udp)?),
+            config,
+            peers: HashMap::new(),
+            ready: VecDeque::new(),
+            next_update: Instant::now(),
+            idle_timeout: None,
+            buf: vec![0; 65536],
+            malformed_log: LogLimiter::new("malformed packets", Level::Error),
+            input_error_log: LogLimiter::new("input errors", Level::Error),
+            send_error_log: LogLimiter::new("send errors", Level::Error),
+            corrupted_log: LogLimiter::new("datagrams failing their checksum", Level::Warn),
+        })
+    }
+
+    /// Drop sessions that received nothing for `timeout`, `None` (the
+    /// default) keeps idle sessions
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Receive the next message of any peer
+    ///
+    /// Returns the conversation id, the peer and the message. While
+    /// waiting, datagrams are fed to their sessions and retransmissions
+    /// and ACKs go out.
+    pub async fn recv_from(&mut self) -> KcpResult<(u32, SocketAddr, Vec<u8>)> {
+        loop {
+            while let Some(&addr) = self.ready.front() {
+                let Some(peer) = self.peers.get_mut(&addr) else {
+                    self.ready.pop_front();
+                    continue;
+                };
+                if let Some(msg) = peer.socket.ring().pop() {
+                    return Ok((peer.socket.conv(), addr, msg));
+                }
+                // Messages left in KCP while the ring was full
+                if !peer.socket.take_received().unwrap_or(false) {
+                    peer.queued = false;
+                    self.ready.pop_front();
+                }
+            }
+
+            let update = Timer::at(self.next_update);
+            let received = future::or(async { Some(self.udp.recv_from(&mut self.buf).await) }, async {
+                update.await;
+                None
+            })
+            .await;
+            match received {
+                Some(Ok((n, addr))) => {
+                    let datagram = self.buf[..n].to_vec();
+                    // Peers of a dual-stack socket have one address
+                    self.handle_datagram(&datagram, canonical_addr(addr)).await?;
+                }
+                // ICMP errors for earlier datagrams must not stop the socket
+                Some(Err(e)) if e.kind() == io::ErrorKind::ConnectionReset => {
+                    debug!("ignoring UDP connection reset: {}", e);
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => self.update().await,
+            }
+        }
+    }
+
+    /// Send a message to the session of `peer`
+    ///
+    /// Fails with [`Error::WindowExhausted`] instead of waiting while the
+    /// peer's send window is full, and with `NotFound` without a session.
+    pub async fn send_to(&mut self, peer: SocketAddr, buf: &[u8]) -> KcpResult<usize> {
+        let peer = canonical_addr(peer);
+        let Some(session) = self.peers.get_mut(&peer) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no session with {}", peer),
+            )
+            .into());
+        };
+        let socket = &mut session.socket;
+        if socket.send_room(buf.len(), 0)? < buf.len() {
+            return Err(Error::WindowExhausted);
+        }
+        let sent = socket.send(buf)?;
+        socket.flush_sent()?;
+        socket.send_output().await?;
+        Ok(sent)
+    }
+
+    /// End the session of `peer` and tell the peer, returns whether there
+    /// was one
+    pub async fn close(&mut self, peer: SocketAddr) -> bool {
+        let Some(mut session) = self.peers.remove(&canonical_addr(peer)) else {
+            return false;
+        };
+        session.socket.close(CloseReason::Closed(CloseCode::Normal));
+        session.socket.queue_close_frame();
+        let _ = session.socket.send_output().await;
+        true
+    }
+
+    /// Peers with a session
+    pub fn peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers.keys().copied()
+    }
+
+    /// Handshake payload the session of `peer` was opened with, see
+    /// [`KcpStream::connect_with_hello`](crate::KcpStream::connect_with_hello)
+    pub fn peer_hello(&self, peer: SocketAddr) -> Option<&[u8]> {
+        self.peers.get(&canonical_addr(peer))?.socket.peer_hello()
+    }
+
+    /// Service the session of `peer` was opened for, see
+    /// [`KcpStream::connect_to_service`](crate::KcpStream::connect_to_service)
+    pub fn peer_service(&self, peer: SocketAddr) -> Option<&str> {
+        self.peers.get(&canonical_addr(peer))?.socket.peer_service()
+    }
+
+    /// Number of sessions
+    pub fn session_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Get local address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.get_ref().local_addr()
+    }
+
+    /// Feed a datagram to its session, opening one for a new client
+    async fn handle_datagram(&mut self, packet: &[u8], addr: SocketAddr) -> KcpResult<()> {
+        let native = self.config.interop == KcpInterop::Native;
+        // Nothing in a datagram is trusted before its checksum
+        let packet = if self.config.checksum {
+            match checksum::strip(packet) {
+                Some(data) => data,
+                None => {
+                    if self.corrupted_log.allow(addr) {
+                        warn!("datagram from {} failed its checksum", addr);
+                    }
+                    if let Some(peer) = self.peers.get_mut(&addr) {
+                        peer.socket.note_corrupted();
+                    }
+                    return Ok(());
+                }
+            }
+        } else {
+            packet
+        };
+        let mut hello = None;
+        let packet = match packet::ext_cmd(packet) {
+            Some(packet::CMD_HELLO) if native => match hello::unwrap(packet) {
+                Some((handshake, inner)) => {
+                    hello = Some(handshake);
+                    inner
+                }
+                None => {
+                    if self.malformed_log.allow(addr) {
+                        error!("malformed handshake packet from {}: {} bytes", addr, packet.len());
+                    }
+                    return Ok(());
+                }
+            },
+            _ => packet,
+        };
+        let min_len = match packet::ext_cmd(packet) {
+            Some(_) if native => packet::HEADER_LEN,
+            _ => smol_kcp_core::KCP_OVERHEAD,
+        };
+        if packet.len() < min_len {
+            if self.malformed_log.allow(addr) {
+                error!("packet too short from {}: {} bytes", addr, packet.len());
+            }
+            return Ok(());
+        }
+        let mut conv = self.config.header_format.conv(packet).unwrap_or_default();
+
+        if let Some(peer) = self.peers.get(&addr) {
+            if peer.socket.conv() == conv {
+                self.input(addr, packet).await;
+                return Ok(());
+            }
+            // A client restarted on the same address starts over at sn 0
+            if !packet::opens_conversation(&self.config.header_format.decode(packet)) {
+                trace!("{} dropped a packet of conv {}", peer.socket.tag(), conv);
+                return Ok(());
+            }
+            debug!("{} reset, the peer opened conv {}", peer.socket.tag(), conv);
+            self.peers.remove(&addr);
+        }
+
+        if packet::ext_cmd(packet).is_some() {
+            return Ok(());
+        }
+        if conv == 0 && native {
+            conv = self.config.new_conv();
+            debug!("{} conv allocated", SessionTag::new(conv, addr));
+        }
+        let mut socket = KcpSocket::new(&self.config, conv, self.udp.clone(), addr, self.config.stream)?;
+        if let Some(hello) = hello {
+            socket.set_peer_hello(&hello);
+        }
+        trace!("{} new session", socket.tag());
+        self.peers.insert(
+            addr,
+            Peer {
+                socket,
+                queued: false,
+            },
+        );
+        self.input(addr, packet).await;
+        Ok(())
+    }
+
+    /// Input a datagram to the session of `addr`, dropping the session if
+    /// that ends it
+    async fn input(&mut self, addr: SocketAddr, packet: &[u8]) {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        let socket = &mut peer.socket;
+        let result = socket.input_verified(packet);
+        if let Err(e) = socket.send_output().await {
+            if self.send_error_log.allow(addr) {
+                error!("{} send error: {}", socket.tag(), e);
+            }
+        }
+        if let Err(e) = result {
+            if socket.check_open().is_err() {
+                debug!("{} dropped: {}", socket.tag(), e);
+                socket.queue_close_frame();
+                let _ = socket.send_output().await;
+                self.peers.remove(&addr);
+                return;
+            }
+            if self.input_error_log.allow(addr) {
+                error!("{} input error: {}", socket.tag(), e);
+            }
+        }
+        if !peer.queued && !peer.socket.ring().is_empty() {
+            peer.queued = true;
+            self.ready.push_back(addr);
+        }
+        // Messages received before the close are still delivered
+        if peer.socket.is_peer_closed() && !peer.queued {
+            self.peers.remove(&addr);
+        }
+    }
+
+    /// Run the timers of all sessions and drop those that ended
+    async fn update(&mut self) {
+        let now = Instant::now();
+        self.next_update = now + Duration::from_millis(self.config.nodelay.interval.max(10) as u64);
+
+        let mut ended = Vec::new();
+        for (&addr, peer) in &mut self.peers {
+            let socket = &mut peer.socket;
+            if self.idle_timeout.is_some_and(|timeout| socket.since_input() >= timeout) {
+                debug!("{} idle for {:?}", socket.tag(), socket.since_input());
+                socket.close(CloseReason::Expired);
+                socket.queue_close_frame();
+                let _ = socket.send_output().await;
+                ended.push(addr);
+                continue;
+            }
+            if let Err(e) = socket.flush() {
+                debug!("{} dropped: {}", socket.tag(), e);
+                ended.push(addr);
+                continue;
+            }
+            if let Err(e) = socket.send_output().await {
+                if self.send_error_log.allow(addr) {
+                    error!("{} send error: {}", socket.tag(), e);
+                }
+            }
+            if socket.is_peer_closed() && !peer.queued {
+                ended.push(addr);
+            }
+        }
+        for addr in ended {
+            self.peers.remove(&addr);
+        }
+    }
+}
@@ -0,0 +1,575 @@
+//! Experimental reliable multicast for one-to-many transfers on a LAN
+//!
+//! A [`KcpMulticastSender`] sends each data segment once to an IPv4
+//! multicast group, however many devices listen, and retransmits it to
+//! the group only if a receiver reports it missing:
+//!
+//! ```ignore
+//! let group = "239.255.75.75:7575".parse()?;
+//!
+//! // update server
+//! let mut sender = KcpMulticastSender::bind(MulticastConfig::new(group), 7).await?;
+//! sender.wait_for_receivers(12, Duration::from_secs(10)).await?;
+//! for chunk in firmware.chunks(64 * 1024) {
+//!     sender.send(chunk).await?;
+//! }
+//! sender.flush().await?;
+//!
+//! // each device
+//! let mut receiver = KcpMulticastReceiver::join(MulticastConfig::new(group), 7).await?;
+//! let chunk = receiver.recv().await?;
+//! ```
+//!
+//! Data travels as KCP PUSH segments, `wnd` holding the fragment count of
+//! the message. The sender multicasts its window next to the data, and
+//! every receiver answers with its una and the sequence numbers it misses,
+//! unicast from a port of its own:
+//!
+//! ```text
+//! tail:     | conv (4) | 0x6d | una (4) | nxt (4) |
+//! feedback: | conv (4) | 0x6c | una (4) | missing sn (4) ... |
+//! ```
+//!
+//! The window only moves on once every receiver heard from within
+//! [`MulticastConfig::receiver_timeout`] acknowledged it. A receiver that
+//! joins late gets the messages that start after it joined. There is no
+//! congestion control and no authentication, keep the group on a trusted
+//! link.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use futures_lite::future;
+use smol_kcp_core::{serial, Error as EngineError, KCP_OVERHEAD};
+
+use crate::{
+    error::{Error, KcpResult},
+    logging::{debug, trace},
+    packet::{self, SegmentHeader, CMD_MULTICAST_FEEDBACK, CMD_MULTICAST_TAIL, HEADER_LEN, KCP_CMD_PUSH},
+    socket::{bind_shared, canonical_addr},
+};
+
+/// Fragments of a message, `frg` counts down from one less
+const MAX_FRAGMENTS: usize = 256;
+
+/// Multicast configuration, shared by the sender and its receivers
+#[derive(Debug, Clone, Copy)]
+pub struct MulticastConfig {
+    /// Group and port the data is sent to
+    pub group: SocketAddrV4,
+    /// Largest datagram sent
+    pub mtu: usize,
+    /// Segments in flight before [`KcpMulticastSender::send`] waits for
+    /// the receivers
+    pub window: u32,
+    /// Interval of the sender's window announcements, receivers answer
+    /// each one
+    pub interval: Duration,
+    /// Receivers not heard from for this long are dropped, the window no
+    /// longer waits for them
+    pub receiver_timeout: Duration,
+    /// Multicast TTL, 1 keeps the traffic on the link
+    pub ttl: u32,
+}
+
+impl MulticastConfig {
+    /// Defaults for a transfer to `group`
+    pub fn new(group: SocketAddrV4) -> Self {
+        Self {
+            group,
+            mtu: 1400,
+            window: 64,
+            interval: Duration::from_millis(20),
+            receiver_timeout: Duration::from_secs(3),
+            ttl: 1,
+        }
+    }
+
+    fn mss(&self) -> usize {
+        self.mtu - KCP_OVERHEAD
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if !self.group.ip().is_multicast() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a multicast group"));
+        }
+        if self.mtu <= KCP_OVERHEAD + 4 || self.window == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid mtu or window"));
+        }
+        Ok(())
+    }
+}
+
+struct Segment {
+    frg: u8,
+    count: u16,
+    data: Vec<u8>,
+    /// Last retransmission
+    resent: Option<Instant>,
+}
+
+struct Receiver {
+    una: u32,
+    heard: Instant,
+}
+
+/// Sends messages to every receiver of a multicast group
+///
+/// The receivers are only heard while the sender is driven by
+/// [`send`](Self::send), [`flush`](Self::flush) or
+/// [`wait_for_receivers`](Self::wait_for_receivers).
+pub struct KcpMulticastSender {
+    socket: Async<UdpSocket>,
+    config: MulticastConfig,
+    conv: u32,
+    start: Instant,
+    /// Segments from `snd_una` to `snd_nxt`
+    snd_buf: VecDeque<Segment>,
+    snd_una: u32,
+    snd_nxt: u32,
+    receivers: HashMap<SocketAddr, Receiver>,
+    next_tail: Instant,
+    retransmissions: u64,
+    buf: Vec<u8>,
+}
+
+impl KcpMulticastSender {
+    /// Send conversation `conv` to `config.group`
+    pub async fn bind(config: MulticastConfig, conv: u32) -> KcpResult<Self> {
+        config.check()?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_multicast_ttl_v4(config.ttl)?;
+        // Receivers on the sending host see the data too
+        socket.set_multicast_loop_v4(true)?;
+        Ok(Self {
+            socket: Async::new(socket)?,
+            config,
+            conv,
+            start: Instant::now(),
+            snd_buf: VecDeque::new(),
+            snd_una: 0,
+            snd_nxt: 0,
+            receivers: HashMap::new(),
+            next_tail: Instant::now(),
+            retransmissions: 0,
+            buf: vec![0; 65536],
+        })
+    }
+
+    /// Queue a message and multicast its segments, waiting while the
+    /// window is full
+    ///
+    /// Messages are split into at most 256 segments of `mtu - 24` bytes.
+    pub async fn send(&mut self, data: &[u8]) -> KcpResult<()> {
+        let mss = self.config.mss();
+        let count = data.len().div_ceil(mss).max(1);
+        if count > MAX_FRAGMENTS {
+            return Err(Error::Protocol(EngineError::UserBufTooBig));
+        }
+        for (i, chunk) in data.chunks(mss).chain(data.is_empty().then_some(&[][..])).enumerate() {
+            while self.in_flight() >= self.config.window {
+                self.poll().await?;
+            }
+            let segment = Segment {
+                frg: (count - 1 - i) as u8,
+                count: count as u16,
+                data: chunk.to_vec(),
+                resent: None,
+            };
+            let sn = self.snd_nxt;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.snd_buf.push_back(segment);
+            self.transmit(sn, self.snd_buf.len() - 1).await?;
+        }
+        if Instant::now() >= self.next_tail {
+            self.tick().await?;
+        }
+        Ok(())
+    }
+
+    /// Wait until every receiver acknowledged everything sent
+    ///
+    /// Returns right away without receivers, messages sent before the
+    /// first one joined are not delivered.
+    pub async fn flush(&mut self) -> KcpResult<()> {
+        self.advance();
+        while self.in_flight() > 0 {
+            self.poll().await?;
+        }
+        Ok(())
+    }
+
+    /// Wait until `count` receivers joined or `timeout` passed, returns the
+    /// number of receivers
+    pub async fn wait_for_receivers(&mut self, count: usize, timeout: Duration) -> KcpResult<usize> {
+        let deadline = Instant::now() + timeout;
+        // poll returns at least once per interval
+        while self.receivers.len() < count && Instant::now() < deadline {
+            self.poll().await?;
+        }
+        Ok(self.receivers.len())
+    }
+
+    /// Addresses the receivers send their feedback from
+    pub fn receivers(&self) -> Vec<SocketAddr> {
+        self.receivers.keys().copied().collect()
+    }
+
+    /// Segments multicast again after receivers reported them missing
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions
+    }
+
+    /// Local address the feedback is received on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.get_ref().local_addr()
+    }
+
+    fn in_flight(&self) -> u32 {
+        self.snd_nxt.wrapping_sub(self.snd_una)
+    }
+
+    /// Handle one feedback packet or announce the window when due
+    async fn poll(&mut self) -> KcpResult<()> {
+        let next = self.next_tail;
+        let socket = &self.socket;
+        let buf = &mut self.buf;
+        let received = future::or(async { socket.recv_from(buf).await.map(Some) }, async {
+            Timer::at(next).await;
+            Ok(None)
+        })
+        .await?;
+        if let Some((n, from)) = received {
+            let feedback = self.buf[..n].to_vec();
+            self.feedback(&feedback, canonical_addr(from)).await?;
+        }
+        if Instant::now() >= self.next_tail {
+            self.tick().await?;
+        }
+        Ok(())
+    }
+
+    async fn feedback(&mut self, datagram: &[u8], from: SocketAddr) -> KcpResult<()> {
+        if packet::conv(datagram) != Some(self.conv)
+            || packet::ext_cmd(datagram) != Some(CMD_MULTICAST_FEEDBACK)
+        {
+            return Ok(());
+        }
+        let body = &datagram[HEADER_LEN..];
+        if body.len() < 4 || !body.len().is_multiple_of(4) {
+            trace!("malformed multicast feedback from {}", from);
+            return Ok(());
+        }
+        let mut sns = body.chunks_exact(4).map(|sn| u32::from_le_bytes([sn[0], sn[1], sn[2], sn[3]]));
+        let una = sns.next().unwrap_or_default();
+        let acked = una.wrapping_sub(self.snd_una);
+        if acked > self.in_flight() {
+            // Behind the window, or ahead of anything sent
+            trace!("multicast feedback from {} with una {} outside the window", from, una);
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let receiver = self.receivers.entry(from).or_insert_with(|| {
+            debug!("multicast receiver {} joined conv {}", from, self.conv);
+            Receiver { una, heard: now }
+        });
+        if serial::after(una, receiver.una) {
+            receiver.una = una;
+        }
+        receiver.heard = now;
+
+        // Receivers that lost the same segment all report it, one
+        // retransmission per half interval reaches them all
+        let holdoff = self.config.interval / 2;
+        for sn in sns {
+            let offset = sn.wrapping_sub(self.snd_una) as usize;
+            match self.snd_buf.get(offset) {
+                Some(segment) if segment.resent.is_none_or(|at| at.elapsed() >= holdoff) => {}
+                _ => continue,
+            }
+            self.snd_buf[offset].resent = Some(now);
+            self.transmit(sn, offset).await?;
+            self.retransmissions += 1;
+        }
+        self.advance();
+        Ok(())
+    }
+
+    /// Drop silent receivers and announce the window to the group
+    async fn tick(&mut self) -> KcpResult<()> {
+        let timeout = self.config.receiver_timeout;
+        self.receivers.retain(|addr, receiver| {
+            let alive = receiver.heard.elapsed() < timeout;
+            if !alive {
+                debug!("multicast receiver {} timed out", addr);
+            }
+            alive
+        });
+        self.advance();
+
+        let mut body = [0; 8];
+        body[..4].copy_from_slice(&self.snd_una.to_le_bytes());
+        body[4..].copy_from_slice(&self.snd_nxt.to_le_bytes());
+        let tail = packet::encode(self.conv, CMD_MULTICAST_TAIL, &body);
+        self.socket.send_to(&tail, SocketAddr::V4(self.config.group)).await?;
+        self.next_tail = Instant::now() + self.config.interval;
+        Ok(())
+    }
+
+    /// Free the segments every receiver acknowledged
+    fn advance(&mut self) {
+        let acked = self
+            .receivers
+            .values()
+            .map(|receiver| receiver.una.wrapping_sub(self.snd_una))
+            .min()
+            .unwrap_or_else(|| self.in_flight());
+        self.snd_buf.drain(..acked as usize);
+        self.snd_una = self.snd_una.wrapping_add(acked);
+    }
+
+    /// Multicast the segment at `offset` in the window
+    async fn transmit(&self, sn: u32, offset: usize) -> KcpResult<()> {
+        let segment = &self.snd_buf[offset];
+        let header = SegmentHeader {
+            conv: self.conv,
+            cmd: KCP_CMD_PUSH,
+            frg: segment.frg,
+            wnd: segment.count,
+            ts: self.start.elapsed().as_millis() as u32,
+            sn,
+            una: self.snd_una,
+            len: segment.data.len() as u32,
+        };
+        let datagram = header.encode(&segment.data);
+        self.socket.send_to(&datagram, SocketAddr::V4(self.config.group)).await?;
+        Ok(())
+    }
+}
+
+struct Fragment {
+    frg: u8,
+    count: u16,
+    data: Vec<u8>,
+}
+
+/// Receives the messages of a [`KcpMulticastSender`] in order
+pub struct KcpMulticastReceiver {
+    socket: Async<UdpSocket>,
+    /// Feedback goes out from its own port, receivers sharing a host are
+    /// told apart by it
+    feedback: Async<UdpSocket>,
+    config: MulticastConfig,
+    conv: u32,
+    /// Source address of the first data or window announcement
+    sender: Option<SocketAddr>,
+    /// Next sn to deliver, `None` until the sender was heard
+    rcv_nxt: Option<u32>,
+    /// No message was delivered yet, the receiver may skip ahead
+    fresh: bool,
+    /// One past the highest sn the sender is known to have sent
+    snd_nxt: u32,
+    rcv_buf: HashMap<u32, Fragment>,
+    /// Fragments of the message being reassembled, `None` while skipping
+    /// the rest of a message joined in the middle
+    message: Option<Vec<u8>>,
+    ready: VecDeque<Vec<u8>>,
+    last_feedback: Option<Instant>,
+    /// una of the last feedback
+    reported: u32,
+    buf: Vec<u8>,
+}
+
+impl KcpMulticastReceiver {
+    /// Join `config.group` and receive conversation `conv`
+    ///
+    /// Other receivers on the host can join the same group and port.
+    pub async fn join(config: MulticastConfig, conv: u32) -> KcpResult<Self> {
+        config.check()?;
+        let socket = bind_shared(config.group.port())?;
+        socket.join_multicast_v4(config.group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        let feedback = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        Ok(Self {
+            socket: Async::new(socket)?,
+            feedback: Async::new(feedback)?,
+            config,
+            conv,
+            sender: None,
+            rcv_nxt: None,
+            fresh: true,
+            snd_nxt: 0,
+            rcv_buf: HashMap::new(),
+            message: None,
+            ready: VecDeque::new(),
+            last_feedback: None,
+            reported: 0,
+            buf: vec![0; 65536],
+        })
+    }
+
+    /// Next message
+    ///
+    /// Fails with [`Error::ConnectionReset`] if the sender moved its window
+    /// past data this receiver has not got, after dropping it as silent.
+    /// Waits forever if the sender went away, wrap it in a timeout.
+    pub async fn recv(&mut self) -> KcpResult<Vec<u8>> {
+        loop {
+            if let Some(message) = self.ready.pop_front() {
+                return Ok(message);
+            }
+            let (n, from) = self.socket.recv_from(&mut self.buf).await?;
+            let datagram = self.buf[..n].to_vec();
+            self.input(&datagram, canonical_addr(from)).await?;
+        }
+    }
+
+    /// Address of the sender, once heard
+    pub fn sender(&self) -> Option<SocketAddr> {
+        self.sender
+    }
+
+    async fn input(&mut self, datagram: &[u8], from: SocketAddr) -> KcpResult<()> {
+        if packet::conv(datagram) != Some(self.conv) || *self.sender.get_or_insert(from) != from {
+            return Ok(());
+        }
+        if packet::ext_cmd(datagram) == Some(CMD_MULTICAST_TAIL) {
+            let Some(body) = datagram.get(HEADER_LEN..HEADER_LEN + 8) else {
+                return Ok(());
+            };
+            let una = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+            let nxt = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+            self.sync(una)?;
+            if serial::after(nxt, self.snd_nxt) {
+                self.snd_nxt = nxt;
+            }
+            return self.send_feedback().await;
+        }
+        if packet::ext_cmd(datagram).is_some() {
+            return Ok(());
+        }
+
+        let mut new_hole = false;
+        for (header, data) in packet::segments_with_data(datagram) {
+            if header.cmd != KCP_CMD_PUSH || header.conv != self.conv || header.wnd == 0 {
+                continue;
+            }
+            self.sync(header.una)?;
+            let rcv_nxt = self.rcv_nxt.unwrap_or(header.una);
+            if header.sn.wrapping_sub(rcv_nxt) >= self.config.window {
+                continue;
+            }
+            if serial::after(header.sn, self.snd_nxt) {
+                new_hole = true;
+            }
+            if serial::after(header.sn.wrapping_add(1), self.snd_nxt) {
+                self.snd_nxt = header.sn.wrapping_add(1);
+            }
+            self.rcv_buf.entry(header.sn).or_insert_with(|| Fragment {
+                frg: header.frg,
+                count: header.wnd,
+                data: data.to_vec(),
+            });
+        }
+        self.deliver();
+
+        // Report new holes right away, and acknowledge every half window
+        // and the end of a burst so the sender never waits for its next
+        // announcement
+        let due = self
+            .last_feedback
+            .is_none_or(|at| at.elapsed() >= self.config.interval / 2);
+        let rcv_nxt = self.rcv_nxt.unwrap_or_default();
+        let acked = rcv_nxt.wrapping_sub(self.reported);
+        let caught_up = rcv_nxt == self.snd_nxt && acked > 0;
+        if new_hole || ((!self.rcv_buf.is_empty() || caught_up) && due) || acked >= self.config.window / 2 {
+            self.send_feedback().await?;
+        }
+        Ok(())
+    }
+
+    /// Start at the sender's window, or fail if it moved past missing data
+    fn sync(&mut self, una: u32) -> KcpResult<()> {
+        match self.rcv_nxt {
+            None => {
+                trace!("multicast conv {} joined at sn {}", self.conv, una);
+                self.rcv_nxt = Some(una);
+                self.snd_nxt = una;
+            }
+            Some(rcv_nxt) if serial::after(una, rcv_nxt) => {
+                if !self.fresh {
+                    debug!("multicast sender moved past sn {} without this receiver", rcv_nxt);
+                    return Err(Error::ConnectionReset);
+                }
+                // The window moved on before the sender heard of us
+                self.rcv_buf.retain(|&sn, _| !serial::before(sn, una));
+                self.rcv_nxt = Some(una);
+                self.message = None;
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    fn deliver(&mut self) {
+        let Some(mut rcv_nxt) = self.rcv_nxt else {
+            return;
+        };
+        while let Some(fragment) = self.rcv_buf.remove(&rcv_nxt) {
+            rcv_nxt = rcv_nxt.wrapping_add(1);
+            if u16::from(fragment.frg) + 1 == fragment.count {
+                self.message = Some(Vec::with_capacity(fragment.count as usize * fragment.data.len()));
+            }
+            if let Some(message) = &mut self.message {
+                message.extend_from_slice(&fragment.data);
+                if fragment.frg == 0 {
+                    self.ready.extend(self.message.take());
+                    self.fresh = false;
+                }
+            }
+        }
+        self.rcv_nxt = Some(rcv_nxt);
+    }
+
+    /// Tell the sender what arrived and what is missing
+    async fn send_feedback(&mut self) -> KcpResult<()> {
+        let Some((feedback, sender)) = self.feedback_packet() else {
+            return Ok(());
+        };
+        self.feedback.send_to(&feedback, sender).await?;
+        self.last_feedback = Some(Instant::now());
+        self.reported = self.rcv_nxt.unwrap_or_default();
+        Ok(())
+    }
+
+    fn feedback_packet(&self) -> Option<(Vec<u8>, SocketAddr)> {
+        let (sender, rcv_nxt) = (self.sender?, self.rcv_nxt?);
+        let max_missing = (self.config.mtu - HEADER_LEN - 4) / 4;
+        let pending = self.snd_nxt.wrapping_sub(rcv_nxt).min(self.config.window);
+        let mut body = Vec::with_capacity(4 + 4 * max_missing.min(pending as usize));
+        body.extend_from_slice(&rcv_nxt.to_le_bytes());
+        let missing = (0..pending)
+            .map(|offset| rcv_nxt.wrapping_add(offset))
+            .filter(|sn| !self.rcv_buf.contains_key(sn))
+            .take(max_missing);
+        for sn in missing {
+            body.extend_from_slice(&sn.to_le_bytes());
+        }
+        Some((packet::encode(self.conv, CMD_MULTICAST_FEEDBACK, &body), sender))
+    }
+}
+
+impl Drop for KcpMulticastReceiver {
+    fn drop(&mut self) {
+        // Acknowledge the last messages, a flushing sender need not wait
+        // for this receiver to time out
+        if let Some((feedback, sender)) = self.feedback_packet() {
+            let _ = self.feedback.get_ref().send_to(&feedback, sender);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Multipath bonding (experimental)
+//!
+//! One KCP conversation sends over several UDP sockets, e.g. bound to a
+//! DSL and an LTE interface. The first socket opens the session as usual,
+//! every further path joins it with a [`CMD_PATH`] packet on that path:
+//!
+//! ```text
+//! | conv (4, LE) | cmd (1) | path id (1) | mode (1) |
+//! ```
+//!
+//! The server adds the source address as a path of the session with that
+//! conv and echoes the packet back on it, which activates the path on the
+//! client. Joins are repeated until echoed. The server only sends on the
+//! new path once the client echoes a [`CMD_CHALLENGE`] token from there,
+//! so a join with a spoofed source address cannot redirect the session's
+//! output. A path that answers none of its challenges is removed again,
+//! spoofed joins cannot hold on to the session's path slots.
+//!
+//! KCP itself resolves the reordering and duplicates that come with it:
+//! segments are put back in order by sn and duplicates are dropped.
+//! Each side picks the paths for its own output, see [`MultipathMode`].
+//!
+//! [`CMD_PATH`]: crate::packet::CMD_PATH
+//! [`CMD_CHALLENGE`]: crate::packet::CMD_CHALLENGE
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::Async;
+use smol_kcp_core::serial;
+
+use crate::{
+    config::random_token,
+    packet::{self, KCP_CMD_PUSH},
+    stats::{RttEstimate, RttEstimator},
+};
+
+/// Paths of one session, the primary path included
+pub(crate) const MAX_PATHS: usize = 8;
+
+/// Interval between joins of a path that was not confirmed yet, and
+/// between challenges of a path that was not validated yet
+const JOIN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Challenges sent on a joined path before it is removed
+const MAX_CHALLENGES: u32 = 5;
+
+/// How a multipath session spreads its output over the paths
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MultipathMode {
+    /// Every data segment goes over the path expected to deliver it first,
+    /// judged by the path's RTT and the data in flight on it, so the
+    /// bandwidth of all paths adds up; retransmissions, ACKs and window
+    /// probes take the path with the lowest RTT
+    #[default]
+    Stripe,
+    /// Every datagram goes over all paths, the first copy to arrive wins;
+    /// costs the bandwidth of all paths for the loss and latency of the
+    /// best one at any moment
+    Duplicate,
+}
+
+impl MultipathMode {
+    fn to_byte(self) -> u8 {
+        match self {
+            MultipathMode::Stripe => 0,
+            MultipathMode::Duplicate => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(MultipathMode::Stripe),
+            1 => Some(MultipathMode::Duplicate),
+            _ => None,
+        }
+    }
+}
+
+/// Statistics of one path of a multipath session
+#[derive(Debug, Clone, Copy)]
+pub struct PathStats {
+    /// Local address of the path's socket
+    pub local: SocketAddr,
+    /// Address of the peer on this path
+    pub peer: SocketAddr,
+    /// RTT of the data segments sent on this path
+    pub rtt: RttEstimate,
+    /// Datagrams sent on this path
+    pub packets_sent: u64,
+    /// Datagrams received on this path
+    pub packets_received: u64,
+    /// The peer confirmed the path, it carries output
+    pub active: bool,
+}
+
+struct Path {
+    id: u8,
+    udp: Arc<Async<std::net::UdpSocket>>,
+    peer: SocketAddr,
+    connected: bool,
+    rtt: RttEstimator,
+    /// Data segments sent on this path and not acknowledged yet
+    in_flight: usize,
+    packets_sent: u64,
+    packets_received: u64,
+    active: bool,
+    last_join: Option<Instant>,
+    /// Validation of a path joined on the server, until the client echoed
+    /// the token from its address
+    challenge: Option<PathChallenge>,
+}
+
+struct PathChallenge {
+    token: [u8; 8],
+    sent: u32,
+    last_sent: Option<Instant>,
+}
+
+impl Path {
+    fn new(id: u8, udp: Arc<Async<std::net::UdpSocket>>, peer: SocketAddr, active: bool) -> Self {
+        Self {
+            id,
+            connected: udp.get_ref().peer_addr().is_ok(),
+            udp,
+            peer,
+            rtt: RttEstimator::default(),
+            in_flight: 0,
+            packets_sent: 0,
+            packets_received: 0,
+            active,
+            last_join: None,
+            challenge: None,
+        }
+    }
+
+    /// Expected cost of sending one more segment on this path
+    fn cost(&self) -> u64 {
+        let srtt = self.rtt.estimate().srtt.as_millis().max(1) as u64;
+        srtt * (self.in_flight as u64 + 1)
+    }
+}
+
+/// Paths of a session and the bookkeeping to schedule output on them
+pub(crate) struct PathSet {
+    mode: MultipathMode,
+    paths: Vec<Path>,
+    /// Path of the latest transmission of every unacknowledged sn
+    sent_on: HashMap<u32, usize>,
+    /// Highest una seen, entries before it are acknowledged
+    una: u32,
+    /// Path the datagram being input arrived on
+    input_path: usize,
+}
+
+impl PathSet {
+    /// Paths starting with the session's primary one
+    pub fn new(
+        mode: MultipathMode,
+        udp: Arc<Async<std::net::UdpSocket>>,
+        peer: SocketAddr,
+    ) -> Self {
+        Self {
+            mode,
+            paths: vec![Path::new(0, udp, peer, true)],
+            sent_on: HashMap::new(),
+            una: 0,
+            input_path: 0,
+        }
+    }
+
+    pub fn mode(&self) -> MultipathMode {
+        self.mode
+    }
+
+    /// Add a client path, it carries output once the server echoed its
+    /// join
+    pub fn add(&mut self, udp: Arc<Async<std::net::UdpSocket>>, peer: SocketAddr) -> Option<u8> {
+        if self.paths.len() >= MAX_PATHS {
+            return None;
+        }
+        let id = self.paths.len() as u8;
+        self.paths.push(Path::new(id, udp, peer, false));
+        Some(id)
+    }
+
+    /// Add the path a join arrived from on the server, returns the answer
+    /// to send back on it
+    ///
+    /// A repeated join of a known path is answered again, its echo may
+    /// have been lost. A new path carries no output until it answered its
+    /// challenge, see [`PathSet::poll_challenges`].
+    pub fn join(
+        &mut self,
+        udp: &Arc<Async<std::net::UdpSocket>>,
+        peer: SocketAddr,
+        conv: u32,
+        body: &[u8],
+    ) -> Option<(usize, Vec<u8>)> {
+        let [id, mode] = *body else {
+            return None;
+        };
+        let index = match self.paths.iter().position(|path| path.peer == peer) {
+            Some(index) => index,
+            None if self.paths.len() < MAX_PATHS => {
+                self.mode = MultipathMode::from_byte(mode)?;
+                let mut path = Path::new(id, udp.clone(), peer, false);
+                path.challenge = Some(PathChallenge {
+                    token: random_token().to_le_bytes(),
+                    sent: 0,
+                    last_sent: None,
+                });
+                self.paths.push(path);
+                self.paths.len() - 1
+            }
+            None => return None,
+        };
+        Some((index, packet::encode(conv, packet::CMD_PATH, body)))
+    }
+
+    /// Activate the client path a join echo names
+    pub fn confirm(&mut self, body: &[u8]) -> bool {
+        let [id, _] = *body else {
+            return false;
+        };
+        match self.paths.iter_mut().find(|path| path.id == id) {
+            Some(path) if !path.active => {
+                path.active = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Joins due on client paths not confirmed yet
+    pub fn poll_joins(&mut self, conv: u32, now: Instant) -> Vec<(usize, Vec<u8>)> {
+        let mode = self.mode.to_byte();
+        let mut joins = Vec::new();
+        for (index, path) in self.paths.iter_mut().enumerate() {
+            if path.active
+                || path.challenge.is_some()
+                || path.last_join.is_some_and(|at| now - at < JOIN_INTERVAL)
+            {
+                continue;
+            }
+            path.last_join = Some(now);
+            joins.push((index, packet::encode(conv, packet::CMD_PATH, &[path.id, mode])));
+        }
+        joins
+    }
+
+    /// Challenges due on server paths not validated yet
+    pub fn poll_challenges(&mut self, conv: u32, now: Instant) -> Vec<(usize, Vec<u8>)> {
+        let mut challenges = Vec::new();
+        for (index, path) in self.paths.iter_mut().enumerate() {
+            let Some(challenge) = &mut path.challenge else {
+                continue;
+            };
+            if challenge.sent >= MAX_CHALLENGES || challenge.last_sent.is_some_and(|at| now - at < JOIN_INTERVAL) {
+                continue;
+            }
+            challenge.sent += 1;
+            challenge.last_sent = Some(now);
+            challenges.push((index, packet::encode(conv, packet::CMD_CHALLENGE, &challenge.token)));
+        }
+        challenges
+    }
+
+    /// Remove the server paths that did not answer any of their
+    /// challenges, returns their peer addresses
+    ///
+    /// Frees the slots joins with spoofed source addresses took. A client
+    /// whose challenges were all lost joins again.
+    pub fn expire_challenges(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let mut expired = Vec::new();
+        let mut index = 0;
+        while index < self.paths.len() {
+            let exhausted = self.paths[index].challenge.as_ref().is_some_and(|challenge| {
+                challenge.sent >= MAX_CHALLENGES
+                    && challenge.last_sent.is_some_and(|at| now - at >= JOIN_INTERVAL)
+            });
+            if !exhausted {
+                index += 1;
+                continue;
+            }
+            expired.push(self.paths.remove(index).peer);
+            // The path carried no data, only the paths after it move
+            for sent_on in self.sent_on.values_mut() {
+                if *sent_on > index {
+                    *sent_on -= 1;
+                }
+            }
+            if self.input_path == index {
+                self.input_path = 0;
+            } else if self.input_path > index {
+                self.input_path -= 1;
+            }
+        }
+        expired
+    }
+
+    /// Activate the server path the input arrived on if `token` answers
+    /// its challenge
+    pub fn validate(&mut self, token: &[u8]) -> bool {
+        let path = &mut self.paths[self.input_path];
+        match &path.challenge {
+            Some(challenge) if challenge.token == token => {}
+            _ => return false,
+        }
+        path.challenge = None;
+        path.active = true;
+        true
+    }
+
+    /// Path the datagram being input arrived on
+    pub fn input_path(&self) -> usize {
+        self.input_path
+    }
+
+    /// Index of the server path to `peer`
+    pub fn position(&self, peer: SocketAddr) -> Option<usize> {
+        self.paths.iter().position(|path| path.peer == peer)
+    }
+
+    /// Remember the path the next input arrived on
+    pub fn note_input(&mut self, index: usize) {
+        if let Some(path) = self.paths.get_mut(index) {
+            path.packets_received += 1;
+            self.input_path = index;
+        }
+    }
+
+    /// Paths to send `datagram` on
+    pub fn targets(&mut self, datagram: &[u8], retransmitted: bool) -> Vec<usize> {
+        let active = self.paths.iter().enumerate().filter(|(_, path)| path.active);
+        if self.mode == MultipathMode::Duplicate {
+            return active.map(|(index, _)| index).collect();
+        }
+        let carries_data = packet::ext_cmd(datagram).is_none()
+            && packet::segments(datagram).any(|segment| segment.cmd == KCP_CMD_PUSH);
+        let best = if carries_data && !retransmitted {
+            active.min_by_key(|(_, path)| path.cost())
+        } else {
+            active.min_by_key(|(_, path)| path.rtt.estimate().srtt)
+        };
+        best.map(|(index, _)| vec![index]).unwrap_or_default()
+    }
+
+    /// Account a datagram sent on a path
+    pub fn on_sent(&mut self, index: usize, datagram: &[u8]) {
+        self.paths[index].packets_sent += 1;
+        if self.mode == MultipathMode::Duplicate || packet::ext_cmd(datagram).is_some() {
+            return;
+        }
+        for segment in packet::segments(datagram) {
+            if segment.cmd != KCP_CMD_PUSH {
+                continue;
+            }
+            if let Some(previous) = self.sent_on.insert(segment.sn, index) {
+                self.paths[previous].in_flight -= 1;
+            }
+            self.paths[index].in_flight += 1;
+        }
+    }
+
+    /// Feed the RTT of an acknowledged sn to the path that carried it
+    ///
+    /// Duplicated segments went everywhere, the ACK's path is credited.
+    pub fn on_ack(&mut self, sn: u32, rtt: u32) {
+        let index = match self.sent_on.remove(&sn) {
+            Some(index) => {
+                self.paths[index].in_flight -= 1;
+                index
+            }
+            None => self.input_path,
+        };
+        self.paths[index].rtt.update(rtt);
+    }
+
+    /// Forget segments acknowledged cumulatively
+    pub fn on_una(&mut self, una: u32) {
+        if !serial::after(una, self.una) {
+            return;
+        }
+        self.una = una;
+        let paths = &mut self.paths;
+        self.sent_on.retain(|&sn, &mut index| {
+            let acked = serial::before(sn, una);
+            if acked {
+                paths[index].in_flight -= 1;
+            }
+            !acked
+        });
+    }
+
+    pub fn udp(&self, index: usize) -> &Arc<Async<std::net::UdpSocket>> {
+        &self.paths[index].udp
+    }
+
+    /// Whether the path's socket is connected to its peer
+    pub fn is_connected(&self, index: usize) -> bool {
+        self.paths[index].connected
+    }
+
+    pub fn peer(&self, index: usize) -> SocketAddr {
+        self.paths[index].peer
+    }
+
+    /// Peer addresses of all paths
+    pub fn peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.paths.iter().map(|path| path.peer)
+    }
+
+    pub fn stats(&self) -> Vec<PathStats> {
+        self.paths
+            .iter()
+            .map(|path| PathStats {
+                local: path
+                    .udp
+                    .get_ref()
+                    .local_addr()
+                    .unwrap_or_else(|_| SocketAddr::new(std::net::Ipv4Addr::UNSPECIFIED.into(), 0)),
+                peer: path.peer,
+                rtt: path.rtt.estimate(),
+                packets_sent: path.packets_sent,
+                packets_received: path.packets_received,
+                active: path.active,
+            })
+            .collect()
+    }
+}
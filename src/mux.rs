@@ -0,0 +1,635 @@
+//! smux-style stream multiplexing over a single KCP connection
+//!
+//! Every frame starts with an 8 byte header followed by `len` bytes of data:
+//!
+//! ```text
+//! | ver (1) | cmd (1) | len (2, LE) | stream id (4, LE) |
+//! ```
+//!
+//! Each logical stream has its own receive window; the receiver reports
+//! consumed bytes with `UPD` frames so one slow stream cannot stall the
+//! others sharing the connection. A stream whose peer sends past the
+//! window is reset: it is answered with `FIN` and its reads and writes
+//! fail with [`Error::ConnectionReset`].
+//!
+//! Dropping the last handle of a stream half-closes it like
+//! [`KcpMuxStream::close`] and forgets it; frames still arriving for it
+//! are ignored.
+//!
+//! Outgoing frames are scheduled by stream priority: when several streams
+//! are waiting to write, frames of higher priority streams go first and
+//! streams of equal priority are served in arrival order. Control frames
+//! always go ahead of data.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io, mem,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+};
+
+use async_lock::Mutex;
+use event_listener::Event;
+use futures_lite::future;
+
+use crate::{
+    error::{Error, KcpResult},
+    logging::{debug, trace},
+    socket::SessionTag,
+    stream::KcpStream,
+};
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 8;
+
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+const CMD_UPD: u8 = 4;
+
+/// Scheduling priority of control frames
+const PRIORITY_CONTROL: u8 = u8::MAX;
+
+/// Multiplexer configuration
+#[derive(Debug, Clone, Copy)]
+pub struct MuxConfig {
+    /// Maximum payload carried by a single frame
+    pub max_frame_size: u16,
+    /// Per-stream receive window in bytes
+    ///
+    /// Also the window assumed for the peer's streams until it reports
+    /// one, both ends must use the same value.
+    pub stream_window: u32,
+    /// Priority given to streams that do not set their own
+    pub default_priority: u8,
+}
+
+impl Default for MuxConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 32 * 1024,
+            stream_window: 256 * 1024,
+            default_priority: 128,
+        }
+    }
+}
+
+/// Multiplexes many [`KcpMuxStream`]s over one [`KcpStream`]
+///
+/// Incoming frames are only processed while [`KcpMuxer::run`] is being
+/// polled, so it must be spawned or raced alongside the users of the
+/// muxer.
+#[derive(Clone)]
+pub struct KcpMuxer {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    config: MuxConfig,
+    tag: SessionTag,
+    reader: Mutex<KcpStream>,
+    writer: Mutex<KcpStream>,
+    /// Handle for introspection only, never sends or receives
+    connection: KcpStream,
+    send_queue: StdMutex<SendQueue>,
+    send_event: Event,
+    streams: StdMutex<HashMap<u32, Arc<StreamState>>>,
+    accept_queue: StdMutex<VecDeque<Arc<StreamState>>>,
+    accept_event: Event,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+}
+
+/// Frames waiting for the writer, grouped by priority
+#[derive(Default)]
+struct SendQueue {
+    frames: BTreeMap<u8, VecDeque<QueuedFrame>>,
+}
+
+struct QueuedFrame {
+    frame: Vec<u8>,
+    done: Arc<AtomicBool>,
+}
+
+impl SendQueue {
+    fn push(&mut self, priority: u8, frame: QueuedFrame) {
+        self.frames.entry(priority).or_default().push_back(frame);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    fn pop(&mut self) -> Option<QueuedFrame> {
+        let mut entry = self.frames.last_entry()?;
+        let frame = entry.get_mut().pop_front();
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        frame
+    }
+}
+
+struct StreamState {
+    id: u32,
+    /// Live [`KcpMuxStream`] handles, the last one to drop closes
+    handles: AtomicUsize,
+    priority: AtomicU8,
+    inner: StdMutex<StreamInner>,
+    event: Event,
+}
+
+#[derive(Default)]
+struct StreamInner {
+    recv_buf: VecDeque<u8>,
+    /// Total bytes handed to the application
+    consumed: u32,
+    /// `consumed` value last reported to the peer
+    consumed_reported: u32,
+    /// Total bytes written by the application
+    sent: u32,
+    /// Total bytes the peer reported as consumed
+    peer_consumed: u32,
+    peer_window: u32,
+    fin_received: bool,
+    fin_sent: bool,
+    /// The peer overran the receive window
+    reset: bool,
+}
+
+impl KcpMuxer {
+    /// Create a muxer on top of an established stream
+    ///
+    /// Both ends must agree on who is the client: the client allocates odd
+    /// stream ids and the server even ones so opens never collide.
+    pub fn new(stream: KcpStream, config: MuxConfig, client: bool) -> Self {
+        let writer = stream.clone_handle();
+        let connection = stream.clone_handle();
+        Self {
+            shared: Arc::new(Shared {
+                config,
+                tag: stream.tag,
+                reader: Mutex::new(stream),
+                writer: Mutex::new(writer),
+                connection,
+                send_queue: StdMutex::new(SendQueue::default()),
+                send_event: Event::new(),
+                streams: StdMutex::new(HashMap::new()),
+                accept_queue: StdMutex::new(VecDeque::new()),
+                accept_event: Event::new(),
+                next_id: AtomicU32::new(if client { 1 } else { 2 }),
+                closed: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// The underlying KCP connection, e.g. for [`KcpStream::stats`]
+    ///
+    /// Sending or receiving on it directly corrupts the multiplexed
+    /// framing.
+    pub fn connection(&self) -> &KcpStream {
+        &self.shared.connection
+    }
+
+    /// Open a new logical stream with the default priority
+    pub async fn open(&self) -> KcpResult<KcpMuxStream> {
+        self.open_with_priority(self.shared.config.default_priority).await
+    }
+
+    /// Open a new logical stream with the given priority
+    ///
+    /// Higher values are scheduled first. Ids are never reused, after
+    /// about 2^31 opens the muxer refuses with `ErrorKind::Other`.
+    pub async fn open_with_priority(&self, priority: u8) -> KcpResult<KcpMuxStream> {
+        self.shared.check_open()?;
+        let id = self
+            .shared
+            .next_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| id.checked_add(2))
+            .map_err(|_| io::Error::other("mux stream ids exhausted"))?;
+        let state = self.shared.register(id);
+        state.priority.store(priority, Ordering::Relaxed);
+        self.shared.write_frame(CMD_SYN, id, &[], PRIORITY_CONTROL).await?;
+        debug!("{} mux opened stream {}", self.shared.tag, id);
+        Ok(KcpMuxStream::new(self.shared.clone(), state))
+    }
+
+    /// Accept a stream opened by the peer
+    pub async fn accept(&self) -> KcpResult<KcpMuxStream> {
+        loop {
+            if let Some(state) = self.shared.accept_queue.lock().unwrap().pop_front() {
+                return Ok(KcpMuxStream::new(self.shared.clone(), state));
+            }
+            self.shared.check_open()?;
+
+            let listener = self.shared.accept_event.listen();
+            if !self.shared.accept_queue.lock().unwrap().is_empty() || self.shared.is_closed() {
+                continue;
+            }
+            listener.await;
+        }
+    }
+
+    /// Number of currently open logical streams
+    pub fn stream_count(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    /// Process incoming frames until the underlying connection fails
+    ///
+    /// Also sends the frames queued without a waiting writer, such as the
+    /// `FIN` of a dropped stream.
+    pub async fn run(&self) -> KcpResult<()> {
+        let result = future::or(self.read_loop(), self.write_loop()).await;
+        self.shared.close_all();
+        result
+    }
+
+    async fn write_loop(&self) -> KcpResult<()> {
+        loop {
+            let listener = self.shared.send_event.listen();
+            self.shared.check_open()?;
+            if self.shared.send_queue.lock().unwrap().is_empty() {
+                listener.await;
+                continue;
+            }
+            let mut writer = self.shared.writer.lock().await;
+            let result = self.shared.drain_send_queue(&mut writer).await;
+            drop(writer);
+            self.shared.send_event.notify(usize::MAX);
+            result?;
+        }
+    }
+
+    async fn read_loop(&self) -> KcpResult<()> {
+        let mut reader = self.shared.reader.lock().await;
+        let mut buf = vec![0u8; HEADER_LEN + self.shared.config.max_frame_size as usize];
+        let mut pending = Vec::new();
+
+        loop {
+            let n = reader.recv(&mut buf).await?;
+            pending.extend_from_slice(&buf[..n]);
+
+            let mut offset = 0;
+            while pending.len() - offset >= HEADER_LEN {
+                let header = &pending[offset..offset + HEADER_LEN];
+                let cmd = header[1];
+                let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+                let id = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+                if header[0] != VERSION {
+                    return Err(invalid_data("unsupported mux version").into());
+                }
+                if pending.len() - offset < HEADER_LEN + len {
+                    break;
+                }
+
+                let data = &pending[offset + HEADER_LEN..offset + HEADER_LEN + len];
+                self.shared.handle_frame(cmd, id, data)?;
+                offset += HEADER_LEN + len;
+            }
+            pending.drain(..offset);
+        }
+    }
+}
+
+impl Shared {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    fn check_open(&self) -> KcpResult<()> {
+        if self.is_closed() {
+            return Err(Error::ConnectionClosed);
+        }
+        Ok(())
+    }
+
+    fn register(&self, id: u32) -> Arc<StreamState> {
+        let state = Arc::new(StreamState {
+            id,
+            handles: AtomicUsize::new(0),
+            priority: AtomicU8::new(self.config.default_priority),
+            inner: StdMutex::new(StreamInner {
+                peer_window: self.config.stream_window,
+                ..Default::default()
+            }),
+            event: Event::new(),
+        });
+        self.streams.lock().unwrap().insert(id, state.clone());
+        state
+    }
+
+    fn stream(&self, id: u32) -> Option<Arc<StreamState>> {
+        self.streams.lock().unwrap().get(&id).cloned()
+    }
+
+    fn handle_frame(&self, cmd: u8, id: u32, data: &[u8]) -> KcpResult<()> {
+        trace!("{} mux frame cmd={} sid={} len={}", self.tag, cmd, id, data.len());
+        match cmd {
+            CMD_SYN => {
+                if self.stream(id).is_some() {
+                    debug!("{} mux duplicate SYN for stream {}", self.tag, id);
+                    return Ok(());
+                }
+                let state = self.register(id);
+                self.accept_queue.lock().unwrap().push_back(state);
+                self.accept_event.notify(1);
+            }
+            CMD_PSH => {
+                if let Some(state) = self.stream(id) {
+                    let mut inner = state.inner.lock().unwrap();
+                    // Everything past the last reported consumption counts
+                    let unreported = inner.consumed.wrapping_sub(inner.consumed_reported);
+                    let outstanding = unreported as usize + inner.recv_buf.len() + data.len();
+                    if outstanding > self.config.stream_window as usize {
+                        debug!("{} mux stream {} overran its window, resetting", self.tag, id);
+                        inner.recv_buf.clear();
+                        inner.reset = true;
+                        let fin_sent = mem::replace(&mut inner.fin_sent, true);
+                        drop(inner);
+                        self.streams.lock().unwrap().remove(&id);
+                        if !fin_sent {
+                            self.queue_frame(CMD_FIN, id, &[], PRIORITY_CONTROL);
+                        }
+                    } else {
+                        inner.recv_buf.extend(data);
+                    }
+                    state.event.notify(usize::MAX);
+                }
+            }
+            CMD_FIN => {
+                if let Some(state) = self.stream(id) {
+                    let mut inner = state.inner.lock().unwrap();
+                    inner.fin_received = true;
+                    if inner.fin_sent {
+                        self.streams.lock().unwrap().remove(&id);
+                    }
+                    drop(inner);
+                    state.event.notify(usize::MAX);
+                }
+            }
+            CMD_UPD => {
+                if data.len() < 8 {
+                    return Err(invalid_data("short mux window update").into());
+                }
+                if let Some(state) = self.stream(id) {
+                    let mut inner = state.inner.lock().unwrap();
+                    inner.peer_consumed = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                    inner.peer_window = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+                    drop(inner);
+                    state.event.notify(usize::MAX);
+                }
+            }
+            CMD_NOP => {}
+            _ => return Err(invalid_data("unknown mux command").into()),
+        }
+        Ok(())
+    }
+
+    /// Queue a frame and wait until it has been handed to KCP
+    ///
+    /// Whoever holds the writer drains the whole queue highest priority
+    /// first, so frames queued while a write is in progress are reordered
+    /// before they reach the KCP send queue.
+    async fn write_frame(&self, cmd: u8, id: u32, data: &[u8], priority: u8) -> KcpResult<()> {
+        let done = self.queue_frame(cmd, id, data, priority);
+        loop {
+            if done.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            self.check_open()?;
+
+            let listener = self.send_event.listen();
+            if let Some(mut writer) = self.writer.try_lock() {
+                let result = self.drain_send_queue(&mut writer).await;
+                drop(writer);
+                self.send_event.notify(usize::MAX);
+                if let Err(e) = result {
+                    self.close_all();
+                    return Err(e);
+                }
+                continue;
+            }
+            listener.await;
+        }
+    }
+
+    /// Queue a frame without waiting for it, [`KcpMuxer::run`] sends it
+    /// unless a writer gets there first
+    fn queue_frame(&self, cmd: u8, id: u32, data: &[u8], priority: u8) -> Arc<AtomicBool> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + data.len());
+        frame.push(VERSION);
+        frame.push(cmd);
+        frame.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        frame.extend_from_slice(&id.to_le_bytes());
+        frame.extend_from_slice(data);
+
+        let done = Arc::new(AtomicBool::new(false));
+        self.send_queue.lock().unwrap().push(
+            priority,
+            QueuedFrame {
+                frame,
+                done: done.clone(),
+            },
+        );
+        self.send_event.notify(usize::MAX);
+        done
+    }
+
+    async fn drain_send_queue(&self, writer: &mut KcpStream) -> KcpResult<()> {
+        loop {
+            let queued = self.send_queue.lock().unwrap().pop();
+            let Some(queued) = queued else {
+                return Ok(());
+            };
+            writer.send(&queued.frame).await?;
+            queued.done.store(true, Ordering::Release);
+        }
+    }
+
+    fn close_all(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.send_event.notify(usize::MAX);
+        for state in self.streams.lock().unwrap().values() {
+            state.event.notify(usize::MAX);
+        }
+        self.accept_event.notify(usize::MAX);
+    }
+}
+
+/// A logical stream carried by a [`KcpMuxer`]
+///
+/// Clones are handles to the same stream, so one task can send while
+/// another receives.
+pub struct KcpMuxStream {
+    shared: Arc<Shared>,
+    state: Arc<StreamState>,
+}
+
+impl KcpMuxStream {
+    fn new(shared: Arc<Shared>, state: Arc<StreamState>) -> Self {
+        state.handles.fetch_add(1, Ordering::Relaxed);
+        Self { shared, state }
+    }
+
+    /// Stream id, unique within the muxer
+    pub fn id(&self) -> u32 {
+        self.state.id
+    }
+
+    /// Scheduling priority of this stream's data
+    pub fn priority(&self) -> u8 {
+        self.state.priority.load(Ordering::Relaxed)
+    }
+
+    /// Change the scheduling priority, higher values are sent first
+    pub fn set_priority(&self, priority: u8) {
+        self.state.priority.store(priority, Ordering::Relaxed);
+    }
+
+    /// Send all of `buf`, waiting for the peer's window when necessary
+    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        let max_frame = self.shared.config.max_frame_size as usize;
+        let mut written = 0;
+
+        while written < buf.len() {
+            let listener = self.state.event.listen();
+            let available = {
+                let inner = self.state.inner.lock().unwrap();
+                if inner.reset {
+                    return Err(Error::ConnectionReset);
+                }
+                if inner.fin_sent {
+                    return Err(Error::ConnectionClosed);
+                }
+                let in_flight = inner.sent.wrapping_sub(inner.peer_consumed);
+                inner.peer_window.saturating_sub(in_flight) as usize
+            };
+
+            if available == 0 {
+                self.shared.check_open()?;
+                listener.await;
+                continue;
+            }
+
+            let chunk = (buf.len() - written).min(available).min(max_frame);
+            self.shared
+                .write_frame(
+                    CMD_PSH,
+                    self.state.id,
+                    &buf[written..written + chunk],
+                    self.priority(),
+                )
+                .await?;
+            let mut inner = self.state.inner.lock().unwrap();
+            inner.sent = inner.sent.wrapping_add(chunk as u32);
+            written += chunk;
+        }
+
+        Ok(written)
+    }
+
+    /// Receive data, returning `Ok(0)` once the peer closed the stream
+    pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        loop {
+            let listener = self.state.event.listen();
+            let (n, update) = {
+                let mut inner = self.state.inner.lock().unwrap();
+                if inner.reset {
+                    return Err(Error::ConnectionReset);
+                }
+                if inner.recv_buf.is_empty() {
+                    if inner.fin_received {
+                        return Ok(0);
+                    }
+                    (0, None)
+                } else {
+                    let n = inner.recv_buf.len().min(buf.len());
+                    for (dst, src) in buf.iter_mut().zip(inner.recv_buf.drain(..n)) {
+                        *dst = src;
+                    }
+                    inner.consumed = inner.consumed.wrapping_add(n as u32);
+
+                    let window = self.shared.config.stream_window;
+                    let unreported = inner.consumed.wrapping_sub(inner.consumed_reported);
+                    let update = if unreported >= window / 2 {
+                        inner.consumed_reported = inner.consumed;
+                        Some(inner.consumed)
+                    } else {
+                        None
+                    };
+                    (n, update)
+                }
+            };
+
+            if n == 0 {
+                self.shared.check_open()?;
+                listener.await;
+                continue;
+            }
+
+            if let Some(consumed) = update {
+                let mut data = [0u8; 8];
+                data[..4].copy_from_slice(&consumed.to_le_bytes());
+                data[4..].copy_from_slice(&self.shared.config.stream_window.to_le_bytes());
+                self.shared
+                    .write_frame(CMD_UPD, self.state.id, &data, PRIORITY_CONTROL)
+                    .await?;
+            }
+            return Ok(n);
+        }
+    }
+
+    /// Half-close the stream, the peer reads EOF after pending data
+    pub async fn close(&mut self) -> KcpResult<()> {
+        {
+            let mut inner = self.state.inner.lock().unwrap();
+            if inner.fin_sent {
+                return Ok(());
+            }
+            inner.fin_sent = true;
+            if inner.fin_received {
+                self.shared.streams.lock().unwrap().remove(&self.state.id);
+            }
+        }
+        self.shared
+            .write_frame(CMD_FIN, self.state.id, &[], PRIORITY_CONTROL)
+            .await
+    }
+}
+
+impl Clone for KcpMuxStream {
+    fn clone(&self) -> Self {
+        Self::new(self.shared.clone(), self.state.clone())
+    }
+}
+
+/// Dropping the last handle queues a `FIN` unless one was sent and
+/// forgets the stream
+impl Drop for KcpMuxStream {
+    fn drop(&mut self) {
+        if self.state.handles.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+        let fin_sent = mem::replace(&mut self.state.inner.lock().unwrap().fin_sent, true);
+        let id = self.state.id;
+        let mut streams = self.shared.streams.lock().unwrap();
+        // A new SYN may have registered another stream under the id since
+        if streams.get(&id).is_some_and(|state| Arc::ptr_eq(state, &self.state)) {
+            streams.remove(&id);
+        }
+        drop(streams);
+        if !fin_sent && !self.shared.is_closed() {
+            trace!("{} mux stream {} dropped, sending FIN", self.shared.tag, id);
+            self.shared.queue_frame(CMD_FIN, id, &[], PRIORITY_CONTROL);
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,269 @@
+//! Extension packets carried next to KCP segments
+//!
+//! An extension packet reuses the first five bytes of the KCP header (conv
+//! and cmd) with command values outside the range used by KCP (81..=84), so
+//! it can be told apart before the datagram reaches `Kcp::input`:
+//!
+//! ```text
+//! | conv (4, LE) | cmd (1) | body ... |
+//! ```
+
+use std::{fmt, net::SocketAddr};
+
+use crate::{
+    config::{ByteOrder, HeaderFormat},
+    logging::{debug, log_enabled, Level},
+};
+
+/// Size of the extension packet header
+pub(crate) const HEADER_LEN: usize = 5;
+
+/// Partially reliable / unreliable message
+pub(crate) const CMD_MSG: u8 = 0x60;
+/// Acknowledgement of a [`CMD_MSG`] packet
+pub(crate) const CMD_MSG_ACK: u8 = 0x61;
+/// The sender dropped the session, best effort and never acknowledged
+pub(crate) const CMD_CLOSE: u8 = 0x62;
+/// Join of an additional path of a multipath session, echoed by the
+/// server, see [`crate::multipath`]
+pub(crate) const CMD_PATH: u8 = 0x63;
+/// Token sent to a new address of the peer before the session moves there
+pub(crate) const CMD_CHALLENGE: u8 = 0x64;
+/// A [`CMD_CHALLENGE`] token echoed back by the peer
+pub(crate) const CMD_RESPONSE: u8 = 0x65;
+/// Urgent out-of-band byte, acknowledged with [`CMD_MSG_ACK`]
+pub(crate) const CMD_URGENT: u8 = 0x66;
+/// Offer of a 64-bit session id by a client, echoed by the server, see
+/// [`crate::route`]
+pub(crate) const CMD_SESSION_ID: u8 = 0x67;
+/// A datagram behind the client's 64-bit session id
+pub(crate) const CMD_ROUTED: u8 = 0x68;
+/// The ts of the peer's latest data segment and when it arrived, for
+/// one-way delay estimation
+pub(crate) const CMD_TIMESTAMP: u8 = 0x69;
+/// A datagram of a client that carries its handshake payload, see
+/// [`crate::hello`]
+pub(crate) const CMD_HELLO: u8 = 0x6a;
+/// A listener lost the session of a routed datagram, proven with the
+/// session's reset token, see [`crate::reset`]
+pub(crate) const CMD_RESET: u8 = 0x6b;
+/// Acknowledgement and missing sequence numbers of a multicast receiver,
+/// see the `multicast` module
+#[cfg(feature = "multicast")]
+pub(crate) const CMD_MULTICAST_FEEDBACK: u8 = 0x6c;
+/// Window of a multicast sender, sent to the group
+pub(crate) const CMD_MULTICAST_TAIL: u8 = 0x6d;
+
+/// Returns the extension command of a datagram, `None` for KCP segments
+pub(crate) fn ext_cmd(datagram: &[u8]) -> Option<u8> {
+    match datagram.get(4) {
+        Some(&cmd) if (CMD_MSG..=CMD_MULTICAST_TAIL).contains(&cmd) => Some(cmd),
+        _ => None,
+    }
+}
+
+/// Read the conversation id, works for extension packets and KCP segments
+pub(crate) fn conv(datagram: &[u8]) -> Option<u32> {
+    let bytes = datagram.get(..4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Whether a datagram can start a conversation: it is made of KCP segments
+/// and carries the first data segment (sn 0)
+pub(crate) fn opens_conversation(datagram: &[u8]) -> bool {
+    ext_cmd(datagram).is_none()
+        && segments(datagram).any(|segment| segment.cmd == KCP_CMD_PUSH && segment.sn == 0)
+}
+
+/// Build an extension packet
+pub(crate) fn encode(conv: u32, cmd: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_LEN + body.len());
+    packet.extend_from_slice(&conv.to_le_bytes());
+    packet.push(cmd);
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// KCP command: push data
+pub(crate) const KCP_CMD_PUSH: u8 = 81;
+/// KCP command: ack
+pub(crate) const KCP_CMD_ACK: u8 = 82;
+/// KCP command: window probe (ask)
+pub(crate) const KCP_CMD_WASK: u8 = 83;
+/// KCP command: window size (tell)
+pub(crate) const KCP_CMD_WINS: u8 = 84;
+
+/// Header of a single KCP segment
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SegmentHeader {
+    pub conv: u32,
+    pub cmd: u8,
+    pub frg: u8,
+    pub wnd: u16,
+    pub ts: u32,
+    pub sn: u32,
+    pub una: u32,
+    pub len: u32,
+}
+
+/// Iterate over the headers of the KCP segments packed in a datagram
+///
+/// Stops at the first truncated segment.
+pub(crate) fn segments(datagram: &[u8]) -> impl Iterator<Item = SegmentHeader> + '_ {
+    segments_with_data(datagram).map(|(header, _)| header)
+}
+
+/// Iterate over the segments of a datagram with their payload
+///
+/// Stops at the first truncated segment, a payload cut short by the end
+/// of the datagram is returned as far as it goes.
+pub(crate) fn segments_with_data(datagram: &[u8]) -> impl Iterator<Item = (SegmentHeader, &[u8])> + '_ {
+    let mut rest = datagram;
+    std::iter::from_fn(move || {
+        if rest.len() < smol_kcp_core::KCP_OVERHEAD {
+            return None;
+        }
+        let read_u32 = |at: usize| u32::from_le_bytes([rest[at], rest[at + 1], rest[at + 2], rest[at + 3]]);
+        let header = SegmentHeader {
+            conv: read_u32(0),
+            cmd: rest[4],
+            frg: rest[5],
+            wnd: u16::from_le_bytes([rest[6], rest[7]]),
+            ts: read_u32(8),
+            sn: read_u32(12),
+            una: read_u32(16),
+            len: read_u32(20),
+        };
+        let end = smol_kcp_core::KCP_OVERHEAD.saturating_add(header.len as usize).min(rest.len());
+        let data = &rest[smol_kcp_core::KCP_OVERHEAD..end];
+        rest = &rest[end..];
+        Some((header, data))
+    })
+}
+
+impl SegmentHeader {
+    /// Encode the header followed by `data`, `len` is taken from `data`
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut segment = Vec::with_capacity(smol_kcp_core::KCP_OVERHEAD + data.len());
+        segment.extend_from_slice(&self.conv.to_le_bytes());
+        segment.push(self.cmd);
+        segment.push(self.frg);
+        segment.extend_from_slice(&self.wnd.to_le_bytes());
+        segment.extend_from_slice(&self.ts.to_le_bytes());
+        segment.extend_from_slice(&self.sn.to_le_bytes());
+        segment.extend_from_slice(&self.una.to_le_bytes());
+        segment.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        segment.extend_from_slice(data);
+        segment
+    }
+}
+
+/// Rewrite the header of every KCP segment of a datagram from one field
+/// byte order to another
+///
+/// Segment boundaries follow the `len` field as read in `from`; trailing
+/// bytes too short for a header are copied as they are.
+pub(crate) fn transcode(datagram: &[u8], from: &HeaderFormat, to: &HeaderFormat) -> Vec<u8> {
+    // (offset, size, byte order in and out) of the multi-byte fields
+    let fields = [
+        (0, 4, from.conv, to.conv),
+        (6, 2, from.wnd, to.wnd),
+        (8, 4, from.ts, to.ts),
+        (12, 4, from.sn, to.sn),
+        (16, 4, from.una, to.una),
+        (20, 4, from.len, to.len),
+    ];
+    let mut out = datagram.to_vec();
+    let mut at = 0;
+    while out.len() - at >= smol_kcp_core::KCP_OVERHEAD {
+        let header = &mut out[at..at + smol_kcp_core::KCP_OVERHEAD];
+        let len = match from.len {
+            ByteOrder::Little => u32::from_le_bytes([header[20], header[21], header[22], header[23]]),
+            ByteOrder::Big => u32::from_be_bytes([header[20], header[21], header[22], header[23]]),
+        };
+        for (offset, size, from, to) in fields {
+            if from != to {
+                header[offset..offset + size].reverse();
+            }
+        }
+        at = (at + smol_kcp_core::KCP_OVERHEAD).saturating_add(len as usize).min(out.len());
+    }
+    out
+}
+
+/// Check that a datagram holds nothing but well-formed KCP segments
+///
+/// Stricter than ikcp, which applies the segments before the first bad
+/// one and ignores trailing bytes. `conv` is the expected conversation.
+pub(crate) fn validate(datagram: &[u8], conv: Option<u32>) -> Result<(), &'static str> {
+    if datagram.len() < smol_kcp_core::KCP_OVERHEAD {
+        return Err("datagram shorter than a segment header");
+    }
+    let mut rest = datagram;
+    while !rest.is_empty() {
+        if rest.len() < smol_kcp_core::KCP_OVERHEAD {
+            return Err("trailing bytes after the last segment");
+        }
+        let Some((header, data)) = segments_with_data(rest).next() else {
+            return Err("truncated segment header");
+        };
+        if conv.is_some_and(|conv| conv != header.conv) {
+            return Err("conv differs from the session");
+        }
+        if data.len() != header.len as usize {
+            return Err("segment length past the end of the datagram");
+        }
+        match header.cmd {
+            KCP_CMD_PUSH => {}
+            KCP_CMD_ACK | KCP_CMD_WASK | KCP_CMD_WINS => {
+                if header.len != 0 || header.frg != 0 {
+                    return Err("control segment with payload or fragment count");
+                }
+            }
+            _ => return Err("unknown command"),
+        }
+        rest = &rest[smol_kcp_core::KCP_OVERHEAD + data.len()..];
+    }
+    Ok(())
+}
+
+impl fmt::Display for SegmentHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cmd {
+            KCP_CMD_PUSH => f.write_str("PUSH")?,
+            KCP_CMD_ACK => f.write_str("ACK")?,
+            KCP_CMD_WASK => f.write_str("WASK")?,
+            KCP_CMD_WINS => f.write_str("WINS")?,
+            cmd => write!(f, "cmd={}", cmd)?,
+        }
+        write!(
+            f,
+            " conv={} frg={} wnd={} ts={} sn={} una={} len={}",
+            self.conv, self.frg, self.wnd, self.ts, self.sn, self.una, self.len
+        )
+    }
+}
+
+/// Log the segment headers of a datagram at debug level
+///
+/// Extension packets are logged with their command only.
+pub(crate) fn log_datagram(direction: &str, peer: SocketAddr, datagram: &[u8]) {
+    if !log_enabled!(target: "smol_kcp::wire", Level::Debug) {
+        return;
+    }
+    if let Some(cmd) = ext_cmd(datagram) {
+        debug!(
+            target: "smol_kcp::wire",
+            "{} {} ext cmd={:#04x} conv={} len={}",
+            direction,
+            peer,
+            cmd,
+            conv(datagram).unwrap_or_default(),
+            datagram.len() - HEADER_LEN
+        );
+        return;
+    }
+    for segment in segments(datagram) {
+        debug!(target: "smol_kcp::wire", "{} {} {}", direction, peer, segment);
+    }
+}
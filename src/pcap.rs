@@ -0,0 +1,186 @@
+//! Capture of KCP traffic in pcap format
+//!
+//! Datagrams are written as raw IP packets (`LINKTYPE_RAW`) with
+//! synthesized IPv4/IPv6 and UDP headers, including valid checksums, so
+//! captures open in Wireshark like traffic sniffed from an interface.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::logging::warn;
+
+/// Raw IPv4/IPv6 packets without link layer header
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const UDP_PROTOCOL: u8 = 17;
+
+/// Shared pcap sink for sent and received datagrams
+///
+/// Cheap to clone, all clones append to the same capture. Attach it with
+/// [`KcpStream::set_pcap`](crate::KcpStream::set_pcap) or
+/// [`KcpListener::set_pcap`](crate::KcpListener::set_pcap).
+#[derive(Clone)]
+pub struct PcapWriter {
+    inner: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl PcapWriter {
+    /// Create (or truncate) a capture file, a fifo works as well
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Write a capture to any writer, the pcap file header is written
+    /// immediately
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+        writer.flush()?;
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Box::new(writer))),
+        })
+    }
+
+    /// Flush buffered records to the underlying writer
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+
+    /// Append a datagram, write errors are logged and otherwise ignored
+    pub(crate) fn write_datagram(&self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        let packet = ip_packet(src, dst, payload);
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let captured = packet.len().min(SNAPLEN as usize);
+
+        let mut record = Vec::with_capacity(16 + captured);
+        record.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(captured as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet[..captured]);
+
+        let mut writer = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writer.write_all(&record) {
+            warn!("pcap write failed: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for PcapWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PcapWriter").finish_non_exhaustive()
+    }
+}
+
+impl Drop for PcapWriter {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.inner) == 1 {
+            let _ = self.flush();
+        }
+    }
+}
+
+/// Build an IP packet carrying `payload` in a UDP datagram
+///
+/// Mixed address families (a dual-stack socket talking to an IPv4 peer)
+/// are written as IPv6 with the IPv4 address mapped.
+fn ip_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + payload.len()) as u16;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&s.octets());
+            pseudo.extend_from_slice(&d.octets());
+            pseudo.extend_from_slice(&[0, UDP_PROTOCOL]);
+            pseudo.extend_from_slice(&udp_len.to_be_bytes());
+            set_udp_checksum(&mut udp, &pseudo);
+
+            let mut ip = Vec::with_capacity(20 + udp.len());
+            ip.extend_from_slice(&[0x45, 0]);
+            ip.extend_from_slice(&udp_len.saturating_add(20).to_be_bytes());
+            ip.extend_from_slice(&[0, 0, 0x40, 0, 64, UDP_PROTOCOL, 0, 0]);
+            ip.extend_from_slice(&s.octets());
+            ip.extend_from_slice(&d.octets());
+            let checksum = internet_checksum(&[&ip]);
+            ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+            ip.extend_from_slice(&udp);
+            ip
+        }
+        (s, d) => {
+            let (s, d) = (to_ipv6(s), to_ipv6(d));
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&s.octets());
+            pseudo.extend_from_slice(&d.octets());
+            pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, UDP_PROTOCOL]);
+            set_udp_checksum(&mut udp, &pseudo);
+
+            let mut ip = Vec::with_capacity(40 + udp.len());
+            ip.extend_from_slice(&[0x60, 0, 0, 0]);
+            ip.extend_from_slice(&udp_len.to_be_bytes());
+            ip.extend_from_slice(&[UDP_PROTOCOL, 64]);
+            ip.extend_from_slice(&s.octets());
+            ip.extend_from_slice(&d.octets());
+            ip.extend_from_slice(&udp);
+            ip
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn set_udp_checksum(udp: &mut [u8], pseudo_header: &[u8]) {
+    let checksum = match internet_checksum(&[pseudo_header, udp]) {
+        // An all-zero checksum means "none" in UDP, send all ones instead
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// RFC 1071 ones' complement checksum over the concatenated parts
+///
+/// Every part but the last must have an even length.
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        let mut chunks = part.chunks_exact(2);
+        for chunk in &mut chunks {
+            sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        }
+        if let [last] = chunks.remainder() {
+            sum += (*last as u32) << 8;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
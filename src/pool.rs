@@ -0,0 +1,230 @@
+//! Pool of warm client sessions
+//!
+//! [`KcpPool`] keeps the sessions of a [`KcpConnector`] open between
+//! requests. A stream handed out by [`KcpPool::get`] goes back to the pool
+//! when dropped, and the next request to the same server reuses it instead
+//! of waiting for a new session to come up. While [`KcpPool::run`] is
+//! polled, idle sessions are kept alive with KCP window probes, which any
+//! KCP peer answers without sending data.
+
+use std::{
+    collections::HashMap,
+    mem,
+    net::SocketAddr,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, PoisonError, Weak},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use futures_lite::future;
+
+use crate::{
+    connector::KcpConnector,
+    error::KcpResult,
+    logging::{debug, trace},
+    stream::KcpStream,
+};
+
+/// Keepalive probes an idle session may leave unanswered before it is
+/// closed
+const MISSED_KEEPALIVES: u32 = 3;
+
+/// Pool configuration
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    /// Idle sessions kept per server, more are closed when returned
+    pub max_idle_per_server: usize,
+    /// Idle sessions unused for this long are closed
+    pub idle_timeout: Duration,
+    /// Interval of the keepalive probes on idle sessions
+    pub keepalive_interval: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_per_server: 8,
+            idle_timeout: Duration::from_secs(90),
+            keepalive_interval: Duration::from_secs(15),
+        }
+    }
+}
+
+struct Idle {
+    stream: KcpStream,
+    since: Instant,
+}
+
+type IdleSessions = HashMap<SocketAddr, Vec<Idle>>;
+
+/// Reuses the sessions of a [`KcpConnector`] for request/response traffic
+///
+/// A returned stream is only handed out again if its session is open and
+/// everything received on it was read, so read the whole response before
+/// dropping it, or [`detach`](PooledStream::detach) streams abandoned in
+/// the middle of a request. Idle sessions are not driven and their
+/// keepalives not answered unless [`KcpPool::run`] is spawned or raced
+/// alongside the users of the pool.
+pub struct KcpPool {
+    connector: KcpConnector,
+    config: PoolConfig,
+    idle: Arc<Mutex<IdleSessions>>,
+}
+
+impl KcpPool {
+    /// Pool the sessions of `connector`
+    pub fn new(connector: KcpConnector, config: PoolConfig) -> Self {
+        Self {
+            connector,
+            config,
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Stream to the server at `addr`, an idle one if the pool has one
+    pub async fn get(&self, addr: SocketAddr) -> KcpResult<PooledStream> {
+        loop {
+            // The most recently used session is the likeliest to be alive
+            let idle = lock(&self.idle).get_mut(&addr).and_then(Vec::pop);
+            let Some(idle) = idle else {
+                break;
+            };
+            if idle.since.elapsed() < self.config.idle_timeout && idle.stream.is_reusable().await {
+                trace!("{} reused", idle.stream.tag);
+                return Ok(self.pooled(addr, idle.stream));
+            }
+            trace!("{} not reusable, closed", idle.stream.tag);
+        }
+        let stream = self.connector.connect(addr).await?;
+        Ok(self.pooled(addr, stream))
+    }
+
+    /// Number of idle sessions
+    pub fn idle_count(&self) -> usize {
+        lock(&self.idle).values().map(Vec::len).sum()
+    }
+
+    /// Connector the sessions are opened with
+    pub fn connector(&self) -> &KcpConnector {
+        &self.connector
+    }
+
+    /// Read the shared socket and keep the idle sessions alive
+    ///
+    /// Runs until the socket fails. Sessions whose server stopped
+    /// answering, or that stayed unused for
+    /// [`PoolConfig::idle_timeout`], are closed.
+    pub async fn run(&self) -> KcpResult<()> {
+        let mut buf = vec![0; 65536];
+        let mut next = Instant::now() + self.config.keepalive_interval;
+        loop {
+            let due = future::or(
+                async { self.connector.demux.receive(&mut buf).await.map(|()| false) },
+                async {
+                    Timer::at(next).await;
+                    Ok(true)
+                },
+            )
+            .await?;
+            if due {
+                self.keepalive().await;
+                next = Instant::now() + self.config.keepalive_interval;
+            }
+        }
+    }
+
+    fn pooled(&self, addr: SocketAddr, stream: KcpStream) -> PooledStream {
+        PooledStream {
+            stream: Some(stream),
+            addr,
+            idle: Arc::downgrade(&self.idle),
+            max_idle: self.config.max_idle_per_server,
+        }
+    }
+
+    /// Probe every idle session and close those that expired
+    async fn keepalive(&self) {
+        let sessions = mem::take(&mut *lock(&self.idle));
+        let silence = self.config.keepalive_interval * MISSED_KEEPALIVES;
+        let mut alive = IdleSessions::new();
+        for (addr, idle) in sessions {
+            for idle in idle {
+                if idle.since.elapsed() >= self.config.idle_timeout {
+                    trace!("{} idle for {:?}, closed", idle.stream.tag, idle.since.elapsed());
+                    continue;
+                }
+                match idle.stream.keepalive().await {
+                    Ok(since_input) if since_input < silence => alive.entry(addr).or_default().push(idle),
+                    Ok(since_input) => {
+                        debug!("{} no keepalive answer for {:?}", idle.stream.tag, since_input);
+                    }
+                    Err(e) => trace!("{} dropped from the pool: {}", idle.stream.tag, e),
+                }
+            }
+        }
+
+        // Streams returned meanwhile are the most recently used
+        let mut idle = lock(&self.idle);
+        for (addr, returned) in mem::take(&mut *idle) {
+            alive.entry(addr).or_default().extend(returned);
+        }
+        for sessions in alive.values_mut() {
+            let excess = sessions.len().saturating_sub(self.config.max_idle_per_server);
+            sessions.drain(..excess);
+        }
+        alive.retain(|_, sessions| !sessions.is_empty());
+        *idle = alive;
+    }
+}
+
+/// Stream of a [`KcpPool`], returned to the pool when dropped
+pub struct PooledStream {
+    /// Only `None` once detached
+    stream: Option<KcpStream>,
+    addr: SocketAddr,
+    idle: Weak<Mutex<IdleSessions>>,
+    max_idle: usize,
+}
+
+impl PooledStream {
+    /// Take the stream out of the pool, it is closed when dropped instead
+    /// of being reused
+    pub fn detach(mut self) -> KcpStream {
+        self.stream.take().expect("pooled stream detached twice")
+    }
+}
+
+impl Deref for PooledStream {
+    type Target = KcpStream;
+
+    fn deref(&self) -> &KcpStream {
+        self.stream.as_ref().expect("pooled stream used after detach")
+    }
+}
+
+impl DerefMut for PooledStream {
+    fn deref_mut(&mut self) -> &mut KcpStream {
+        self.stream.as_mut().expect("pooled stream used after detach")
+    }
+}
+
+impl Drop for PooledStream {
+    fn drop(&mut self) {
+        let (Some(stream), Some(idle)) = (self.stream.take(), self.idle.upgrade()) else {
+            return;
+        };
+        let mut idle = lock(&idle);
+        let sessions = idle.entry(self.addr).or_default();
+        if sessions.len() < self.max_idle {
+            sessions.push(Idle {
+                stream,
+                since: Instant::now(),
+            });
+        }
+    }
+}
+
+fn lock(idle: &Mutex<IdleSessions>) -> std::sync::MutexGuard<'_, IdleSessions> {
+    idle.lock().unwrap_or_else(PoisonError::into_inner)
+}
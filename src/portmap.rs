@@ -0,0 +1,591 @@
+//! Port mappings on the home gateway
+//!
+//! A server behind a NAT router is only reachable once the router forwards
+//! a port to it. [`PortMapping::request`] asks the gateway for one over PCP
+//! (RFC 6887), NAT-PMP (RFC 6886) and UPnP IGD, in that order, and
+//! [`PortMapping::run`] renews it before it lapses:
+//!
+//! ```ignore
+//! let listener = KcpListener::bind(config, "0.0.0.0:4000".parse()?).await?;
+//! let mut mapping = listener.port_mapping().await?;
+//! println!("reachable at {}", mapping.external_addr());
+//! std::thread::spawn(move || block_on(mapping.run()));
+//! ```
+//!
+//! Only IPv4 UDP ports are mapped. PCP and NAT-PMP talk to the gateway of
+//! the default route, read from `/proc/net/route` on Linux and given to
+//! [`PortMapping::request_via`] elsewhere; UPnP finds the gateway by SSDP
+//! multicast. [`PortMapping::remove`] deletes the mapping, a dropped one
+//! lapses at the end of its lifetime.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+use async_io::{Async, Timer};
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    config::random_token,
+    logging::{debug, warn},
+};
+
+/// Port PCP and NAT-PMP gateways listen on
+const GATEWAY_PORT: u16 = 5351;
+
+/// Lifetime asked for, renewed at half of it
+const LIFETIME: Duration = Duration::from_secs(7200);
+
+/// Wait before retrying a failed renewal
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// First wait for a PCP or NAT-PMP answer, doubled for each of the tries
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const TRIES: u32 = 4;
+
+const SSDP_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(2);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+const IPPROTO_UDP: u8 = 17;
+
+const NATPMP_EXTERNAL_ADDR: u8 = 0;
+const NATPMP_MAP_UDP: u8 = 1;
+
+/// UPnP error of gateways that only take mappings without a lease
+const ONLY_PERMANENT_LEASES: &str = "error 725";
+
+/// Protocol a mapping was obtained with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingProtocol {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+enum Gateway {
+    Pcp { addr: Ipv4Addr, nonce: [u8; 12] },
+    NatPmp { addr: Ipv4Addr },
+    Upnp(ControlUrl),
+}
+
+/// UDP port forwarded by the gateway to this host
+pub struct PortMapping {
+    gateway: Gateway,
+    internal: SocketAddrV4,
+    external: SocketAddrV4,
+    lifetime: Duration,
+}
+
+impl PortMapping {
+    /// Map the same external port to local UDP `port`, through the
+    /// gateway of the default route if it can be found
+    pub async fn request(port: u16) -> io::Result<Self> {
+        Self::request_via(port, default_gateway()).await
+    }
+
+    /// Map the same external port to local UDP `port`, asking `gateway`
+    /// over PCP and NAT-PMP before trying UPnP
+    pub async fn request_via(port: u16, gateway: Option<Ipv4Addr>) -> io::Result<Self> {
+        if let Some(gateway) = gateway {
+            let internal = SocketAddrV4::new(local_ip(SocketAddr::from((gateway, GATEWAY_PORT)))?, port);
+            let nonce = nonce();
+            match pcp_map(gateway, nonce, internal, port, LIFETIME).await {
+                Ok((external, lifetime)) => {
+                    return Ok(Self::mapped(Gateway::Pcp { addr: gateway, nonce }, internal, external, lifetime));
+                }
+                Err(e) => debug!("PCP mapping through {} failed: {}", gateway, e),
+            }
+            match natpmp_map(gateway, port, port, LIFETIME).await {
+                Ok((external, lifetime)) => {
+                    return Ok(Self::mapped(Gateway::NatPmp { addr: gateway }, internal, external, lifetime));
+                }
+                Err(e) => debug!("NAT-PMP mapping through {} failed: {}", gateway, e),
+            }
+        }
+        let control = upnp_discover().await?;
+        let internal = SocketAddrV4::new(local_ip(control.addr)?, port);
+        let (external, lifetime) = upnp_map(&control, internal, port).await?;
+        Ok(Self::mapped(Gateway::Upnp(control), internal, external, lifetime))
+    }
+
+    fn mapped(gateway: Gateway, internal: SocketAddrV4, external: SocketAddrV4, lifetime: Duration) -> Self {
+        let mapping = Self {
+            gateway,
+            internal,
+            external,
+            lifetime,
+        };
+        debug!(
+            "{:?} mapped {} to {} for {:?}",
+            mapping.protocol(),
+            mapping.external,
+            mapping.internal,
+            mapping.lifetime
+        );
+        mapping
+    }
+
+    /// Address clients on the internet connect to
+    pub fn external_addr(&self) -> SocketAddrV4 {
+        self.external
+    }
+
+    /// Local address the gateway forwards to
+    pub fn internal_addr(&self) -> SocketAddrV4 {
+        self.internal
+    }
+
+    /// Lifetime the gateway granted, renewed at half of it
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Protocol the mapping was obtained with
+    pub fn protocol(&self) -> MappingProtocol {
+        match self.gateway {
+            Gateway::Pcp { .. } => MappingProtocol::Pcp,
+            Gateway::NatPmp { .. } => MappingProtocol::NatPmp,
+            Gateway::Upnp(_) => MappingProtocol::Upnp,
+        }
+    }
+
+    /// Renew the mapping now, the external address may change, e.g. after
+    /// the gateway rebooted
+    pub async fn renew(&mut self) -> io::Result<()> {
+        let port = self.internal.port();
+        let (external, lifetime) = match &self.gateway {
+            Gateway::Pcp { addr, nonce } => {
+                pcp_map(*addr, *nonce, self.internal, self.external.port(), LIFETIME).await?
+            }
+            Gateway::NatPmp { addr } => natpmp_map(*addr, port, self.external.port(), LIFETIME).await?,
+            Gateway::Upnp(control) => upnp_map(control, self.internal, self.external.port()).await?,
+        };
+        if external != self.external {
+            debug!("{:?} mapping of {} moved to {}", self.protocol(), self.internal, external);
+        }
+        self.external = external;
+        self.lifetime = lifetime;
+        Ok(())
+    }
+
+    /// Renew the mapping at half its lifetime, never returns
+    ///
+    /// Failed renewals are retried every minute.
+    pub async fn run(&mut self) {
+        let mut wait = self.lifetime / 2;
+        loop {
+            Timer::after(wait).await;
+            wait = match self.renew().await {
+                Ok(()) => self.lifetime / 2,
+                Err(e) => {
+                    warn!("renewing the port mapping of {} failed: {}", self.internal, e);
+                    RETRY_INTERVAL
+                }
+            };
+        }
+    }
+
+    /// Delete the mapping on the gateway
+    pub async fn remove(self) -> io::Result<()> {
+        match &self.gateway {
+            Gateway::Pcp { addr, nonce } => {
+                pcp_map(*addr, *nonce, self.internal, self.external.port(), Duration::ZERO).await?;
+            }
+            Gateway::NatPmp { addr } => {
+                natpmp_map(*addr, self.internal.port(), 0, Duration::ZERO).await?;
+            }
+            Gateway::Upnp(control) => {
+                let port = self.external.port().to_string();
+                let args = [("NewRemoteHost", ""), ("NewExternalPort", &port), ("NewProtocol", "UDP")];
+                control.call("DeletePortMapping", &args).await?;
+            }
+        }
+        debug!("{:?} mapping of {} removed", self.protocol(), self.external);
+        Ok(())
+    }
+}
+
+/// Ask a PCP gateway to map `external_port` to `internal`, a zero
+/// lifetime deletes the mapping
+async fn pcp_map(
+    gateway: Ipv4Addr,
+    nonce: [u8; 12],
+    internal: SocketAddrV4,
+    external_port: u16,
+    lifetime: Duration,
+) -> io::Result<(SocketAddrV4, Duration)> {
+    let mut request = [0u8; 60];
+    request[0] = PCP_VERSION;
+    request[1] = PCP_MAP;
+    request[4..8].copy_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    request[8..24].copy_from_slice(&internal.ip().to_ipv6_mapped().octets());
+    request[24..36].copy_from_slice(&nonce);
+    request[36] = IPPROTO_UDP;
+    request[40..42].copy_from_slice(&internal.port().to_be_bytes());
+    request[42..44].copy_from_slice(&external_port.to_be_bytes());
+    request[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+    // NAT-PMP gateways answer with a version error of the same opcode
+    let response = exchange(gateway, &request, |response| {
+        response.len() >= 4 && response[1] == 0x80 | PCP_MAP
+    })
+    .await?;
+    if response[0] != PCP_VERSION {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "gateway does not speak PCP"));
+    }
+    if response[3] != 0 {
+        return Err(io::Error::other(format!("PCP result code {}", response[3])));
+    }
+    if response.len() < 60 || response[24..36] != nonce {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed PCP answer"));
+    }
+    let granted = u32::from_be_bytes(response[4..8].try_into().unwrap());
+    let port = u16::from_be_bytes([response[42], response[43]]);
+    let ip: [u8; 16] = response[44..60].try_into().unwrap();
+    let ip = std::net::Ipv6Addr::from(ip)
+        .to_ipv4_mapped()
+        .ok_or_else(|| io::Error::other("PCP gateway mapped an IPv6 address"))?;
+    Ok((SocketAddrV4::new(ip, port), Duration::from_secs(granted.into())))
+}
+
+/// Ask a NAT-PMP gateway to map `external_port` to local `port`, a zero
+/// lifetime deletes the mapping
+async fn natpmp_map(
+    gateway: Ipv4Addr,
+    port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> io::Result<(SocketAddrV4, Duration)> {
+    let mut request = [0u8; 12];
+    request[1] = NATPMP_MAP_UDP;
+    request[4..6].copy_from_slice(&port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    let response = exchange(gateway, &request, |response| {
+        response.len() >= 16 && response[0] == 0 && response[1] == 0x80 | NATPMP_MAP_UDP
+    })
+    .await?;
+    natpmp_result(&response)?;
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    let granted = u32::from_be_bytes(response[12..16].try_into().unwrap());
+    if lifetime.is_zero() {
+        return Ok((SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), Duration::ZERO));
+    }
+
+    let response = exchange(gateway, &[0, NATPMP_EXTERNAL_ADDR], |response| {
+        response.len() >= 12 && response[0] == 0 && response[1] == 0x80 | NATPMP_EXTERNAL_ADDR
+    })
+    .await?;
+    natpmp_result(&response)?;
+    let ip: [u8; 4] = response[8..12].try_into().unwrap();
+    Ok((SocketAddrV4::new(ip.into(), external_port), Duration::from_secs(granted.into())))
+}
+
+fn natpmp_result(response: &[u8]) -> io::Result<()> {
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(()),
+        code => Err(io::Error::other(format!("NAT-PMP result code {}", code))),
+    }
+}
+
+/// Send `request` to the PCP/NAT-PMP port of `gateway` until an answer
+/// passing `valid` arrives
+async fn exchange(gateway: Ipv4Addr, request: &[u8], valid: impl Fn(&[u8]) -> bool) -> io::Result<Vec<u8>> {
+    let socket = Async::<UdpSocket>::bind(([0, 0, 0, 0], 0))?;
+    socket.get_ref().connect((gateway, GATEWAY_PORT))?;
+    let mut buf = [0u8; 1100];
+    let mut timeout = INITIAL_TIMEOUT;
+    for _ in 0..TRIES {
+        socket.send(request).await?;
+        let deadline = Timer::after(timeout);
+        let answer = future::or(
+            async {
+                loop {
+                    match socket.recv(&mut buf).await {
+                        Ok(n) if valid(&buf[..n]) => return Some(Ok(buf[..n].to_vec())),
+                        Ok(_) => continue,
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+            },
+            async {
+                deadline.await;
+                None
+            },
+        )
+        .await;
+        if let Some(answer) = answer {
+            return answer;
+        }
+        timeout *= 2;
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer from {}", gateway)))
+}
+
+/// Control URL of the WAN connection service of an Internet Gateway
+/// Device
+struct ControlUrl {
+    addr: SocketAddr,
+    host: String,
+    path: String,
+    service: String,
+}
+
+impl ControlUrl {
+    /// Invoke a SOAP action, returns the response body
+    async fn call(&self, action: &str, args: &[(&str, &str)]) -> io::Result<String> {
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body></s:Envelope>\r\n",
+            action = action,
+            service = self.service,
+            args = args,
+        );
+        let headers = format!(
+            "Content-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\n",
+            self.service, action
+        );
+        let (status, response) = http(self.addr, &self.host, "POST", &self.path, &headers, &body).await?;
+        if status != 200 {
+            let code = tag(&response, "errorCode").unwrap_or("unknown");
+            return Err(io::Error::other(format!("UPnP {} failed with error {}", action, code)));
+        }
+        Ok(response)
+    }
+}
+
+/// Find an Internet Gateway Device on the LAN by SSDP
+async fn upnp_discover() -> io::Result<ControlUrl> {
+    let socket = Async::<UdpSocket>::bind(([0, 0, 0, 0], 0))?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_GROUP
+    );
+    socket.send_to(search.as_bytes(), SSDP_GROUP).await?;
+
+    let mut buf = [0u8; 2048];
+    let mut locations = Vec::new();
+    let deadline = Timer::after(SSDP_TIMEOUT);
+    future::or(
+        async {
+            while let Ok((n, _)) = socket.recv_from(&mut buf).await {
+                let response = String::from_utf8_lossy(&buf[..n]);
+                let location = response.lines().find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.trim().eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+                });
+                if let Some(location) = location {
+                    if !locations.contains(&location) {
+                        locations.push(location);
+                    }
+                }
+            }
+        },
+        async {
+            deadline.await;
+        },
+    )
+    .await;
+
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no UPnP gateway answered");
+    for location in locations {
+        match control_url(&location).await {
+            Ok(control) => return Ok(control),
+            Err(e) => {
+                debug!("UPnP device at {} unusable: {}", location, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Control URL of the WAN connection service in the description at
+/// `location`
+async fn control_url(location: &str) -> io::Result<ControlUrl> {
+    let (addr, host, path) = parse_url(location)?;
+    let (status, description) = http(addr, &host, "GET", &path, "", "").await?;
+    if status != 200 {
+        return Err(io::Error::other(format!("device description returned {}", status)));
+    }
+    for service in description.split("<service>").skip(1) {
+        let Some(service_type) = tag(service, "serviceType") else {
+            continue;
+        };
+        if !service_type.contains(":WANIPConnection:") && !service_type.contains(":WANPPPConnection:") {
+            continue;
+        }
+        let Some(control) = tag(service, "controlURL") else {
+            continue;
+        };
+        let (addr, host, path) = if control.starts_with("http://") {
+            parse_url(control)?
+        } else if control.starts_with('/') {
+            (addr, host.clone(), control.to_string())
+        } else {
+            (addr, host.clone(), format!("/{}", control))
+        };
+        return Ok(ControlUrl {
+            addr,
+            host,
+            path,
+            service: service_type.to_string(),
+        });
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "no WAN connection service"))
+}
+
+/// Map `external_port` to `internal` over UPnP, falling back to a
+/// permanent mapping for gateways that refuse leases
+async fn upnp_map(
+    control: &ControlUrl,
+    internal: SocketAddrV4,
+    external_port: u16,
+) -> io::Result<(SocketAddrV4, Duration)> {
+    let external = external_port.to_string();
+    let port = internal.port().to_string();
+    let client = internal.ip().to_string();
+    let mut lease = LIFETIME.as_secs().to_string();
+    loop {
+        let args = [
+            ("NewRemoteHost", ""),
+            ("NewExternalPort", external.as_str()),
+            ("NewProtocol", "UDP"),
+            ("NewInternalPort", port.as_str()),
+            ("NewInternalClient", client.as_str()),
+            ("NewEnabled", "1"),
+            ("NewPortMappingDescription", "smol-kcp"),
+            ("NewLeaseDuration", lease.as_str()),
+        ];
+        match control.call("AddPortMapping", &args).await {
+            Ok(_) => break,
+            Err(e) if lease != "0" && e.to_string().ends_with(ONLY_PERMANENT_LEASES) => lease = "0".to_string(),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let response = control.call("GetExternalIPAddress", &[]).await?;
+    let ip = tag(&response, "NewExternalIPAddress")
+        .and_then(|ip| ip.trim().parse().ok())
+        .ok_or_else(|| io::Error::other("gateway did not tell its external address"))?;
+    // Permanent mappings are still renewed, the gateway may have rebooted
+    Ok((SocketAddrV4::new(ip, external_port), LIFETIME))
+}
+
+/// Minimal HTTP/1.0 exchange, returns the status and the body
+async fn http(
+    addr: SocketAddr,
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &str,
+    body: &str,
+) -> io::Result<(u16, String)> {
+    let exchange = async {
+        let mut stream = Async::<TcpStream>::connect(addr).await?;
+        let request = format!(
+            "{} {} HTTP/1.0\r\nHost: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            host,
+            headers,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, io::Error>(response)
+    };
+    let response = future::or(exchange, async {
+        Timer::after(HTTP_TIMEOUT).await;
+        Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer from {}", addr)))
+    })
+    .await?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response"))?;
+    Ok((status, body.to_string()))
+}
+
+/// Address, host and path of an `http://` URL
+fn parse_url(url: &str) -> io::Result<(SocketAddr, String, String)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("unsupported URL {}", url));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') {
+        host.to_socket_addrs()
+    } else {
+        (host, 80).to_socket_addrs()
+    }?
+    .find(SocketAddr::is_ipv4)
+    .ok_or_else(invalid)?;
+    Ok((addr, host.to_string(), path.to_string()))
+}
+
+/// Text of the first `<name>` element of `xml`
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
+}
+
+/// Local address packets to `peer` leave from, the one the gateway must
+/// forward to
+fn local_ip(peer: SocketAddr) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(peer)?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "no IPv4 route to the gateway")),
+    }
+}
+
+fn nonce() -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..8].copy_from_slice(&random_token().to_le_bytes());
+    nonce[8..].copy_from_slice(&random_token().to_le_bytes()[..4]);
+    nonce
+}
+
+/// Gateway of the IPv4 default route
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        // Written in host byte order
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        let gateway = Ipv4Addr::from(gateway.to_ne_bytes());
+        (!gateway.is_unspecified()).then_some(gateway)
+    })
+}
+
+/// Gateways are only found by UPnP
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
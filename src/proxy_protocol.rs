@@ -0,0 +1,142 @@
+//! PROXY protocol version 2 headers
+//!
+//! A relay or load balancer in front of a server can prepend a header
+//! telling it the address of the client the relay received from:
+//!
+//! ```text
+//! | signature (12) | 0x21 | family (1) | length (2, BE) | addresses | TLVs |
+//! ```
+//!
+//! In front of UDP every datagram carries one. A [`KcpListener`] given
+//! [`KcpListener::set_proxy_protocol`] strips it and reports the source
+//! address from it in [`KcpListener::accept`], replies still go to the
+//! relay. `smol-kcp relay server` and `smol-kcp tunnel server` emit
+//! headers for their targets with `--proxy-protocol`. The header is not
+//! authenticated, only enable it on listeners reachable through the relay
+//! alone.
+//!
+//! [`KcpListener`]: crate::KcpListener
+//! [`KcpListener::set_proxy_protocol`]: crate::KcpListener::set_proxy_protocol
+//! [`KcpListener::accept`]: crate::KcpListener::accept
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// First bytes of every header
+pub const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Size of the fixed part of a header
+const FIXED_LEN: usize = 16;
+
+/// Version 2 with the PROXY and LOCAL commands
+const PROXY: u8 = 0x21;
+const LOCAL: u8 = 0x20;
+
+const AF_INET: u8 = 0x10;
+const AF_INET6: u8 = 0x20;
+
+/// Transport of the proxied connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// TCP, one header at the start of the connection
+    Stream,
+    /// UDP, one header per datagram
+    Datagram,
+}
+
+/// Addresses of a parsed header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Address of the client, `None` for the LOCAL command relays send
+    /// for their own health checks or for families without addresses
+    pub source: Option<SocketAddr>,
+    /// Address the client sent to
+    pub destination: Option<SocketAddr>,
+}
+
+/// Header for a connection from `source` to `destination`
+///
+/// An IPv4 address paired with an IPv6 one is sent as IPv4-mapped IPv6.
+pub fn encode(source: SocketAddr, destination: SocketAddr, transport: Transport) -> Vec<u8> {
+    let protocol = match transport {
+        Transport::Stream => 0x1,
+        Transport::Datagram => 0x2,
+    };
+    let mut header = Vec::with_capacity(FIXED_LEN + 36);
+    header.extend_from_slice(&SIGNATURE);
+    header.push(PROXY);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(AF_INET | protocol);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            header.push(AF_INET6 | protocol);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_v6(src).octets());
+            header.extend_from_slice(&to_v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+/// Header at the start of `data` and what follows it, `None` if there is
+/// no valid version 2 header
+pub fn parse(data: &[u8]) -> Option<(ProxyHeader, &[u8])> {
+    if data.len() < FIXED_LEN || data[..12] != SIGNATURE {
+        return None;
+    }
+    let command = data[12];
+    let family = data[13] & 0xf0;
+    let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+    let body = data.get(FIXED_LEN..FIXED_LEN + len)?;
+    let rest = &data[FIXED_LEN + len..];
+
+    let addrs = match (command, family) {
+        (LOCAL, _) => None,
+        (PROXY, AF_INET) => {
+            let body: &[u8; 12] = body.get(..12)?.try_into().ok()?;
+            let src: [u8; 4] = body[..4].try_into().ok()?;
+            let dst: [u8; 4] = body[4..8].try_into().ok()?;
+            Some((
+                SocketAddr::new(src.into(), u16::from_be_bytes([body[8], body[9]])),
+                SocketAddr::new(dst.into(), u16::from_be_bytes([body[10], body[11]])),
+            ))
+        }
+        (PROXY, AF_INET6) => {
+            let body: &[u8; 36] = body.get(..36)?.try_into().ok()?;
+            let src: [u8; 16] = body[..16].try_into().ok()?;
+            let dst: [u8; 16] = body[16..32].try_into().ok()?;
+            Some((
+                SocketAddr::new(from_v6(src.into()), u16::from_be_bytes([body[32], body[33]])),
+                SocketAddr::new(from_v6(dst.into()), u16::from_be_bytes([body[34], body[35]])),
+            ))
+        }
+        // Unix sockets and unspecified families carry no IP address
+        (PROXY, _) => None,
+        _ => return None,
+    };
+    let header = ProxyHeader {
+        source: addrs.map(|(src, _)| src),
+        destination: addrs.map(|(_, dst)| dst),
+    };
+    Some((header, rest))
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// IPv4-mapped addresses as the IPv4 address they stand for
+fn from_v6(ip: Ipv6Addr) -> IpAddr {
+    match ip.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip),
+    }
+}
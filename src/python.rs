@@ -0,0 +1,314 @@
+//! Python bindings
+//!
+//! Build the extension module with maturin:
+//!
+//! ```text
+//! maturin develop --features python,pyo3/extension-module
+//! ```
+//!
+//! Every I/O method returns an awaitable for asyncio:
+//!
+//! ```python
+//! import asyncio, smol_kcp
+//!
+//! async def main():
+//!     stream = await smol_kcp.KcpStream.connect("127.0.0.1:8080", preset="wan")
+//!     await stream.send(b"ping")
+//!     print(await stream.recv())
+//!
+//! asyncio.run(main())
+//! ```
+//!
+//! The futures run on their own threads outside of the asyncio loop, the
+//! results are handed back to the loop by pyo3-async-runtimes. Accepted
+//! streams only receive data while `KcpListener.accept` is pending, so
+//! keep an accept task running.
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    future::Future,
+    net::{SocketAddr, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    task::{Context, Poll},
+    thread,
+};
+
+use async_lock::Mutex;
+use event_listener::Event;
+use futures_lite::future::block_on;
+use pyo3::{
+    exceptions::{PyOSError, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+use pyo3_async_runtimes::{
+    generic::{self, ContextExt, JoinError, Runtime},
+    TaskLocals,
+};
+
+use crate::{KcpConfig, KcpListener, KcpStream};
+
+/// Runs every future on a thread of its own with `block_on`
+///
+/// KCP sockets are driven by the async-io reactor, so no executor is
+/// needed and Python gets no second event loop.
+struct ThreadRuntime;
+
+struct ThreadJoinError(Box<dyn Any + Send>);
+
+impl JoinError for ThreadJoinError {
+    fn is_panic(&self) -> bool {
+        true
+    }
+
+    fn into_panic(self) -> Box<dyn Any + Send> {
+        self.0
+    }
+}
+
+type JoinResult = Result<(), ThreadJoinError>;
+
+/// Outcome of a spawned future and the event announcing it
+struct Completion {
+    result: StdMutex<Option<JoinResult>>,
+    done: Event,
+}
+
+impl Runtime for ThreadRuntime {
+    type JoinError = ThreadJoinError;
+    type JoinHandle = Pin<Box<dyn Future<Output = JoinResult> + Send>>;
+
+    fn spawn<F>(fut: F) -> Self::JoinHandle
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let completion = Arc::new(Completion {
+            result: StdMutex::new(None),
+            done: Event::new(),
+        });
+
+        let finished = completion.clone();
+        thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| block_on(fut)));
+            *finished.result.lock().unwrap() = Some(result.map_err(ThreadJoinError));
+            finished.done.notify(usize::MAX);
+        });
+
+        Box::pin(async move {
+            loop {
+                let listener = completion.done.listen();
+                if let Some(result) = completion.result.lock().unwrap().take() {
+                    return result;
+                }
+                listener.await;
+            }
+        })
+    }
+}
+
+thread_local! {
+    static TASK_LOCALS: RefCell<Option<Arc<TaskLocals>>> = const { RefCell::new(None) };
+}
+
+/// Future with its task locals installed while it is polled
+struct Scoped<F> {
+    locals: Arc<TaskLocals>,
+    fut: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let previous = TASK_LOCALS.with(|c| c.replace(Some(self.locals.clone())));
+        let poll = self.fut.as_mut().poll(cx);
+        TASK_LOCALS.with(|c| c.replace(previous));
+        poll
+    }
+}
+
+impl ContextExt for ThreadRuntime {
+    fn scope<F, R>(locals: TaskLocals, fut: F) -> Pin<Box<dyn Future<Output = R> + Send>>
+    where
+        F: Future<Output = R> + Send + 'static,
+    {
+        Box::pin(Scoped {
+            locals: Arc::new(locals),
+            fut: Box::pin(fut),
+        })
+    }
+
+    fn get_task_locals() -> Option<TaskLocals> {
+        TASK_LOCALS.with(|c| {
+            c.borrow()
+                .as_ref()
+                .map(|locals| Python::with_gil(|py| locals.clone_ref(py)))
+        })
+    }
+}
+
+fn future_into_py<F, T>(py: Python<'_>, fut: F) -> PyResult<Bound<'_, PyAny>>
+where
+    F: Future<Output = PyResult<T>> + Send + 'static,
+    T: for<'py> IntoPyObject<'py>,
+{
+    generic::future_into_py::<ThreadRuntime, F, T>(py, fut)
+}
+
+fn os_error(err: impl ToString) -> PyErr {
+    PyOSError::new_err(err.to_string())
+}
+
+fn resolve(addr: &str) -> PyResult<SocketAddr> {
+    addr.to_socket_addrs()
+        .map_err(os_error)?
+        .next()
+        .ok_or_else(|| os_error(format!("{} did not resolve to an address", addr)))
+}
+
+fn preset(name: Option<&str>, stream_mode: bool) -> PyResult<KcpConfig> {
+    let mut config = match name {
+        None | Some("default") => KcpConfig::default(),
+        Some("lan") => KcpConfig::lan(),
+        Some("wan") => KcpConfig::wan(),
+        Some("high-latency") => KcpConfig::high_latency(),
+        Some("low-bandwidth") => KcpConfig::low_bandwidth(),
+        Some("kcp-go") => KcpConfig::kcp_go(),
+        Some(other) => return Err(PyValueError::new_err(format!("unknown preset {:?}", other))),
+    };
+    config.stream = stream_mode;
+    Ok(config)
+}
+
+/// A KCP connection
+///
+/// Sending and receiving use separate handles, so one task can wait in
+/// `recv` while another sends.
+#[pyclass(name = "KcpStream")]
+struct PyKcpStream {
+    reader: Arc<Mutex<KcpStream>>,
+    writer: Arc<Mutex<KcpStream>>,
+    peer: SocketAddr,
+}
+
+impl PyKcpStream {
+    fn new(stream: KcpStream, peer: SocketAddr) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(stream.clone_handle())),
+            reader: Arc::new(Mutex::new(stream)),
+            peer,
+        }
+    }
+}
+
+#[pymethods]
+impl PyKcpStream {
+    /// Connect to `host:port`, preset is one of default, lan, wan,
+    /// high-latency, low-bandwidth or kcp-go
+    #[staticmethod]
+    #[pyo3(signature = (addr, preset = None, stream_mode = false))]
+    fn connect<'py>(
+        py: Python<'py>,
+        addr: String,
+        preset: Option<&str>,
+        stream_mode: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let config = self::preset(preset, stream_mode)?;
+        future_into_py(py, async move {
+            let peer = resolve(&addr)?;
+            let stream = KcpStream::connect(&config, peer).await.map_err(os_error)?;
+            Ok(PyKcpStream::new(stream, peer))
+        })
+    }
+
+    /// Send `data`, resolves to the number of bytes queued
+    fn send<'py>(&self, py: Python<'py>, data: Vec<u8>) -> PyResult<Bound<'py, PyAny>> {
+        let writer = self.writer.clone();
+        future_into_py(py, async move {
+            writer.lock().await.send(&data).await.map_err(os_error)
+        })
+    }
+
+    /// Receive up to `max_size` bytes
+    #[pyo3(signature = (max_size = 65536))]
+    fn recv<'py>(&self, py: Python<'py>, max_size: usize) -> PyResult<Bound<'py, PyAny>> {
+        let reader = self.reader.clone();
+        future_into_py(py, async move {
+            let mut buf = vec![0u8; max_size];
+            let n = reader.lock().await.recv(&mut buf).await.map_err(os_error)?;
+            Ok(Python::with_gil(|py| PyBytes::new(py, &buf[..n]).unbind()))
+        })
+    }
+
+    /// Address of the peer as `host:port`
+    #[getter]
+    fn peer_addr(&self) -> String {
+        self.peer.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("KcpStream(peer={})", self.peer)
+    }
+}
+
+/// A listening KCP socket
+#[pyclass(name = "KcpListener")]
+struct PyKcpListener {
+    listener: Arc<Mutex<KcpListener>>,
+    local: SocketAddr,
+}
+
+#[pymethods]
+impl PyKcpListener {
+    /// Listen on `host:port`
+    #[staticmethod]
+    #[pyo3(signature = (addr, preset = None, stream_mode = false))]
+    fn bind<'py>(
+        py: Python<'py>,
+        addr: String,
+        preset: Option<&str>,
+        stream_mode: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let config = self::preset(preset, stream_mode)?;
+        future_into_py(py, async move {
+            let listener = KcpListener::bind(config, resolve(&addr)?)
+                .await
+                .map_err(os_error)?;
+            let local = listener.local_addr().map_err(os_error)?;
+            Ok(PyKcpListener {
+                listener: Arc::new(Mutex::new(listener)),
+                local,
+            })
+        })
+    }
+
+    /// Wait for a new connection, resolves to `(stream, "host:port")`
+    fn accept<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let listener = self.listener.clone();
+        future_into_py(py, async move {
+            let (stream, peer) = listener.lock().await.accept().await.map_err(os_error)?;
+            Ok((PyKcpStream::new(stream, peer), peer.to_string()))
+        })
+    }
+
+    /// Local address as `host:port`
+    #[getter]
+    fn local_addr(&self) -> String {
+        self.local.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("KcpListener(local={})", self.local)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "smol_kcp")]
+fn python_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyKcpStream>()?;
+    m.add_class::<PyKcpListener>()?;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Rate limiting of repetitive log messages and counting of misbehaving
+//! sources
+//!
+//! Error paths on the receive loop run at wire speed, a flood of corrupt or
+//! hostile packets would otherwise turn into a flood of log lines.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::logging::{log, Level};
+
+/// Messages logged per window before suppression starts
+const BURST: u64 = 5;
+/// Length of a rate limiting window
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// Limits one kind of log message to a few lines per window
+///
+/// Suppressed occurrences are counted and summarized as e.g. `1234
+/// malformed packets suppressed in the last 10s, last from 10.0.0.2:4000`
+/// with the next occurrence after the window ended.
+pub(crate) struct LogLimiter {
+    what: &'static str,
+    level: Level,
+    window_start: Option<Instant>,
+    logged: u64,
+    suppressed: u64,
+    last_peer: Option<SocketAddr>,
+}
+
+impl LogLimiter {
+    pub const fn new(what: &'static str, level: Level) -> Self {
+        Self {
+            what,
+            level,
+            window_start: None,
+            logged: 0,
+            suppressed: 0,
+            last_peer: None,
+        }
+    }
+
+    /// Account an occurrence, returns whether it should be logged
+    pub fn allow(&mut self, peer: SocketAddr) -> bool {
+        let now = Instant::now();
+        match self.window_start {
+            Some(start) if now.duration_since(start) < WINDOW => {}
+            _ => {
+                self.flush();
+                self.window_start = Some(now);
+                self.logged = 0;
+            }
+        }
+
+        if self.logged < BURST {
+            self.logged += 1;
+            return true;
+        }
+        self.suppressed += 1;
+        self.last_peer = Some(peer);
+        false
+    }
+
+    /// Log the summary of suppressed occurrences, if any
+    fn flush(&mut self) {
+        let Some(peer) = self.last_peer.take() else {
+            return;
+        };
+        let elapsed = self.window_start.map(|start| start.elapsed()).unwrap_or(WINDOW);
+        log!(
+            self.level,
+            "{} {} suppressed in the last {}s, last from {}",
+            self.suppressed,
+            self.what,
+            elapsed.as_secs(),
+            peer
+        );
+        self.suppressed = 0;
+    }
+}
+
+impl Drop for LogLimiter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Offenses of each source IP within a window, see
+/// [`KcpListener::set_auto_ban`](crate::KcpListener::set_auto_ban)
+#[derive(Default)]
+pub(crate) struct OffenseCounter {
+    /// Offenses and the start of their window
+    sources: HashMap<IpAddr, (u32, Instant)>,
+    /// Size at which entries of ended windows are pruned next
+    prune_at: usize,
+}
+
+impl OffenseCounter {
+    /// Account an offense of `ip`, returns the offenses in its current
+    /// window of length `window`
+    pub fn record(&mut self, ip: IpAddr, window: Duration) -> u32 {
+        let now = Instant::now();
+        if self.sources.len() >= self.prune_at {
+            self.sources.retain(|_, (_, start)| now.duration_since(*start) < window);
+            self.prune_at = (self.sources.len() * 2).max(64);
+        }
+        let (count, start) = self.sources.entry(ip).or_insert((0, now));
+        if now.duration_since(*start) >= window {
+            *count = 0;
+            *start = now;
+        }
+        *count += 1;
+        *count
+    }
+
+    /// Start over for `ip`, once it was banned
+    pub fn forget(&mut self, ip: IpAddr) {
+        self.sources.remove(&ip);
+    }
+}
@@ -0,0 +1,94 @@
+//! Stateless reset of routed sessions
+//!
+//! A listener given a reset key with [`KcpListener::set_reset_key`] appends
+//! a token to its echo of a client's session id offer (see
+//! [`crate::route`]):
+//!
+//! ```text
+//! | conv (4, LE) | cmd (1) | session id (8, LE) | reset token (8, LE) |
+//! ```
+//!
+//! The token is the SipHash-2-4 of the conv and session id under the key,
+//! so a listener that restarted with the same key can still compute it
+//! after losing every session. When it receives a routed datagram of a
+//! session id it does not know, it answers with a [`CMD_RESET`] packet of
+//! the same layout. A client whose token matches fails with
+//! [`Error::ConnectionReset`] at once instead of retransmitting until
+//! [`KcpConfig::session_expire`]; resets that do not match are ignored, so
+//! only whoever saw the echo can end a session. Datagrams are not
+//! encrypted, on the path that is anyone, as for close frames.
+//!
+//! [`KcpListener::set_reset_key`]: crate::KcpListener::set_reset_key
+//! [`CMD_RESET`]: crate::packet::CMD_RESET
+//! [`Error::ConnectionReset`]: crate::Error::ConnectionReset
+//! [`KcpConfig::session_expire`]: crate::KcpConfig::session_expire
+
+use crate::packet;
+
+/// Token of the session of `conv` routed by `id`
+pub(crate) fn token(key: &[u8; 16], conv: u32, id: u64) -> u64 {
+    let mut data = [0; 12];
+    data[..4].copy_from_slice(&conv.to_le_bytes());
+    data[4..].copy_from_slice(&id.to_le_bytes());
+    siphash24(key, &data)
+}
+
+/// Body of a session id echo or reset carrying `token`
+pub(crate) fn body(id: u64, token: u64) -> [u8; 16] {
+    let mut body = [0; 16];
+    body[..8].copy_from_slice(&id.to_le_bytes());
+    body[8..].copy_from_slice(&token.to_le_bytes());
+    body
+}
+
+/// Session id and token of a [`packet::CMD_RESET`] packet
+pub(crate) fn parse(datagram: &[u8]) -> Option<(u64, u64)> {
+    let body: [u8; 16] = datagram.get(packet::HEADER_LEN..)?.try_into().ok()?;
+    let id = u64::from_le_bytes(body[..8].try_into().ok()?);
+    let token = u64::from_le_bytes(body[8..].try_into().ok()?);
+    Some((id, token))
+}
+
+/// SipHash-2-4, the keyed hash std used for `HashMap` before 1.13
+fn siphash24(key: &[u8; 16], data: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    let rounds = |v: &mut [u64; 4], n: usize| {
+        for _ in 0..n {
+            v[0] = v[0].wrapping_add(v[1]);
+            v[1] = v[1].rotate_left(13) ^ v[0];
+            v[0] = v[0].rotate_left(32);
+            v[2] = v[2].wrapping_add(v[3]);
+            v[3] = v[3].rotate_left(16) ^ v[2];
+            v[0] = v[0].wrapping_add(v[3]);
+            v[3] = v[3].rotate_left(21) ^ v[0];
+            v[2] = v[2].wrapping_add(v[1]);
+            v[1] = v[1].rotate_left(17) ^ v[2];
+            v[2] = v[2].rotate_left(32);
+        }
+    };
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        rounds(&mut v, 2);
+        v[0] ^= m;
+    }
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    let m = u64::from_le_bytes(last) | (data.len() as u64) << 56;
+    v[3] ^= m;
+    rounds(&mut v, 2);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    rounds(&mut v, 4);
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
@@ -0,0 +1,136 @@
+//! Hand-off of received messages from the input side to `recv`
+//!
+//! The task feeding datagrams into a session (the listener's accept loop
+//! or a client stream waiting for input) holds the session lock anyway; it
+//! moves complete messages out of KCP into this ring. `recv` pops them
+//! without taking the lock, so a reader is not stuck behind input
+//! processing of a busy session.
+//!
+//! One producer and one consumer at a time: each side claims a flag, the
+//! slot accesses themselves are lock-free. A producer finding the flag
+//! taken backs off and the message stays in KCP, a consumer waits for it,
+//! so a reader racing another never takes a full ring for an empty one.
+
+use std::{
+    cell::UnsafeCell,
+    hint,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+};
+
+/// Messages a session buffers in its ring, the rest waits in KCP
+pub(crate) const RING_SLOTS: usize = 64;
+
+pub(crate) struct MessageRing {
+    slots: Box<[UnsafeCell<Option<Vec<u8>>>]>,
+    /// Next slot to pop, written by the consumer only
+    head: AtomicUsize,
+    /// Next slot to fill, written by the producer only
+    tail: AtomicUsize,
+    producing: AtomicBool,
+    consuming: AtomicBool,
+    /// Bytes of the messages in the ring
+    bytes: AtomicUsize,
+}
+
+// Slots are only touched by the side owning them, see `push` and `pop`
+unsafe impl Sync for MessageRing {}
+
+impl MessageRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity).map(|_| UnsafeCell::new(None)).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            producing: AtomicBool::new(false),
+            consuming: AtomicBool::new(false),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Append a message, handing it back if the ring is full or another
+    /// producer is active
+    pub fn push(&self, msg: Vec<u8>) -> Result<(), Vec<u8>> {
+        let Some(_claim) = Claim::new(&self.producing) else {
+            return Err(msg);
+        };
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == self.slots.len() {
+            return Err(msg);
+        }
+        self.bytes.fetch_add(msg.len(), Ordering::Relaxed);
+        // SAFETY: the slot is outside head..tail, the consumer does not
+        // read it until the store of `tail` below publishes it
+        unsafe { *self.slots[tail % self.slots.len()].get() = Some(msg) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Take the oldest message, `None` if empty
+    ///
+    /// Waits for another consumer to finish its pop first.
+    pub fn pop(&self) -> Option<Vec<u8>> {
+        let _claim = Claim::wait(&self.consuming);
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        // SAFETY: the slot is inside head..tail, published by the producer
+        // and not reused until the store of `head` below releases it
+        let msg = unsafe { (*self.slots[head % self.slots.len()].get()).take() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        if let Some(msg) = &msg {
+            self.bytes.fetch_sub(msg.len(), Ordering::Relaxed);
+        }
+        msg
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    pub fn is_full(&self) -> bool {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire)) == self.slots.len()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Exclusive role on the ring, released on drop
+struct Claim<'a>(&'a AtomicBool);
+
+impl<'a> Claim<'a> {
+    fn new(flag: &'a AtomicBool) -> Option<Self> {
+        flag.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| Self(flag))
+    }
+
+    /// Claim `flag` once its holder released it
+    fn wait(flag: &'a AtomicBool) -> Self {
+        let mut spins = 0;
+        loop {
+            if let Some(claim) = Self::new(flag) {
+                return claim;
+            }
+            // Held for a few instructions, unless its thread was preempted
+            if spins < 64 {
+                spins += 1;
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,175 @@
+//! Routing by 64-bit session id
+//!
+//! With [`KcpConfig::session_ids`] a client picks a random 64-bit id and
+//! offers it to the server with a [`CMD_SESSION_ID`] packet next to its
+//! first datagrams:
+//!
+//! ```text
+//! | conv (4, LE) | cmd (1) | session id (8, LE) |
+//! ```
+//!
+//! A listener that supports it echoes the offer, from then on the client
+//! wraps every datagram in a [`CMD_ROUTED`] header carrying the id:
+//!
+//! ```text
+//! | conv (4, LE) | cmd (1) | session id (8, LE) | datagram ... |
+//! ```
+//!
+//! The listener finds the session by the id instead of the address and
+//! conv, so a NAT rebinding that hands the client's old address to another
+//! client cannot mix the two up, and conv collisions do not matter. The
+//! move to a new address is still validated with a challenge. A listener
+//! with a reset key appends a token to the echo, see [`crate::reset`]. Servers
+//! that never echo (older versions, kcp-go) are given up on after a few
+//! offers and see the classic format only. The server's own output is
+//! never wrapped, the client has one session per socket.
+//!
+//! [`KcpConfig::session_ids`]: crate::KcpConfig::session_ids
+//! [`CMD_SESSION_ID`]: crate::packet::CMD_SESSION_ID
+//! [`CMD_ROUTED`]: crate::packet::CMD_ROUTED
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use async_lock::Mutex;
+
+use crate::{config::random_token, packet, socket::KcpSocket};
+
+/// Offers sent before a server that does not echo is given up on
+const MAX_OFFERS: u32 = 5;
+
+/// Interval between offers not echoed yet
+const OFFER_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Size of the [`packet::CMD_ROUTED`] header
+pub(crate) const ROUTED_HEADER_LEN: usize = packet::HEADER_LEN + 8;
+
+/// Client side of the negotiation
+pub(crate) struct SessionRoute {
+    id: u64,
+    /// The server echoed the offer, output is wrapped
+    confirmed: bool,
+    offers: u32,
+    last_offer: Option<Instant>,
+    /// Token the server echoed the offer with
+    reset_token: Option<u64>,
+}
+
+impl SessionRoute {
+    /// Route with a fresh random id, zero is skipped
+    pub fn new() -> Self {
+        let mut id = random_token();
+        while id == 0 {
+            id = random_token();
+        }
+        Self {
+            id,
+            confirmed: false,
+            offers: 0,
+            last_offer: None,
+            reset_token: None,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    /// Offer due now, if the server did not echo one yet
+    pub fn poll_offer(&mut self, conv: u32, now: Instant) -> Option<Vec<u8>> {
+        if self.confirmed
+            || self.offers >= MAX_OFFERS
+            || self.last_offer.is_some_and(|at| now - at < OFFER_INTERVAL)
+        {
+            return None;
+        }
+        self.offers += 1;
+        self.last_offer = Some(now);
+        Some(packet::encode(conv, packet::CMD_SESSION_ID, &self.id.to_le_bytes()))
+    }
+
+    /// Take the server's echo of an offer, returns whether it confirmed
+    /// the route just now
+    pub fn confirm(&mut self, body: &[u8]) -> bool {
+        let (id, token) = match body.len() {
+            8 => (body, None),
+            16 => (&body[..8], body[8..].try_into().ok().map(u64::from_le_bytes)),
+            _ => return false,
+        };
+        if self.confirmed || parse_id(id) != Some(self.id) {
+            return false;
+        }
+        self.confirmed = true;
+        self.reset_token = token;
+        true
+    }
+
+    /// Whether a reset of the session `id` carries the token the server
+    /// echoed the offer with
+    pub fn is_reset(&self, id: u64, token: u64) -> bool {
+        self.confirmed && id == self.id && self.reset_token == Some(token)
+    }
+
+    /// `datagram` in a [`packet::CMD_ROUTED`] header
+    pub fn wrap(&self, conv: u32, datagram: &[u8]) -> Vec<u8> {
+        let mut wrapped = Vec::with_capacity(ROUTED_HEADER_LEN + datagram.len());
+        wrapped.extend_from_slice(&conv.to_le_bytes());
+        wrapped.push(packet::CMD_ROUTED);
+        wrapped.extend_from_slice(&self.id.to_le_bytes());
+        wrapped.extend_from_slice(datagram);
+        wrapped
+    }
+}
+
+/// Session id of an offer or echo body
+pub(crate) fn parse_id(body: &[u8]) -> Option<u64> {
+    let id = u64::from_le_bytes(body.try_into().ok()?);
+    (id != 0).then_some(id)
+}
+
+/// Session id and inner datagram of a [`packet::CMD_ROUTED`] packet
+pub(crate) fn unwrap(datagram: &[u8]) -> Option<(u64, &[u8])> {
+    if datagram.len() < ROUTED_HEADER_LEN {
+        return None;
+    }
+    let id = parse_id(&datagram[packet::HEADER_LEN..ROUTED_HEADER_LEN])?;
+    Some((id, &datagram[ROUTED_HEADER_LEN..]))
+}
+
+/// Sessions of a listener by the id their clients route with
+///
+/// Entries of sessions gone from the listener and dropped by the
+/// application are pruned as the table grows.
+#[derive(Default)]
+pub(crate) struct RouteTable {
+    sessions: HashMap<u64, Weak<Mutex<KcpSocket>>>,
+    /// Size at which dead entries are pruned next
+    prune_at: usize,
+}
+
+impl RouteTable {
+    /// Route `id` to `session`, returns false if the id belongs to
+    /// another live session
+    pub fn insert(&mut self, id: u64, session: &Arc<Mutex<KcpSocket>>) -> bool {
+        if let Some(existing) = self.get(id) {
+            return Arc::ptr_eq(&existing, session);
+        }
+        if self.sessions.len() >= self.prune_at {
+            self.sessions.retain(|_, session| session.strong_count() > 0);
+            self.prune_at = (self.sessions.len() * 2).max(64);
+        }
+        self.sessions.insert(id, Arc::downgrade(session));
+        true
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Mutex<KcpSocket>>> {
+        self.sessions.get(&id)?.upgrade()
+    }
+}
@@ -0,0 +1,354 @@
+//! Thread-per-core listener
+//!
+//! [`KcpShardedListener`] runs one worker thread per core, each with its
+//! own [`KcpListener`] on the same UDP socket. Whichever worker the kernel
+//! wakes reads a datagram; sessions are owned by the worker their conv
+//! hashes to, so datagrams read by another worker are forwarded to the
+//! owner through a lock-free queue. Workers feed input to their own
+//! sessions only, so the KCP work of many sessions spreads over all
+//! cores instead of the single task polling [`KcpListener::accept`].
+//!
+//! Accepted streams are handed to the application through another
+//! lock-free queue and can be used from any thread.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use concurrent_queue::ConcurrentQueue;
+use event_listener::Event;
+use futures_lite::future;
+
+use crate::{
+    config::KcpConfig,
+    error::KcpResult,
+    listener::KcpListener,
+    logging::{debug, error, trace},
+    stream::KcpStream,
+};
+
+/// Datagrams waiting for their worker, more are dropped like on a full
+/// socket buffer
+const INBOX_CAPACITY: usize = 4096;
+
+/// Worker options of a [`KcpShardedListener`]
+#[derive(Debug, Clone, Copy)]
+pub struct ShardConfig {
+    /// Worker threads, 0 starts one per core available to the process
+    pub workers: usize,
+    /// Pin worker `i` to the `i`th core available to the process, Linux
+    /// only, ignored elsewhere
+    pub pin: bool,
+}
+
+impl Default for ShardConfig {
+    fn default() -> Self {
+        Self {
+            workers: 0,
+            pin: true,
+        }
+    }
+}
+
+/// Worker owning the sessions whose conv hashes to it
+///
+/// Spreads sequential convs from a counter as well as random ones.
+pub(crate) fn shard_of(conv: u32, count: usize) -> usize {
+    (conv.wrapping_mul(0x9e37_79b9) >> 16) as usize % count
+}
+
+/// Worker allocating a conv for a client that sent conv 0
+///
+/// Retransmissions before the client learns its conv reach the same
+/// worker, which then finds the session by address.
+fn shard_of_addr(addr: &SocketAddr, count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    addr.hash(&mut hasher);
+    hasher.finish() as usize % count
+}
+
+/// Datagrams forwarded to a worker by the others
+struct Inbox {
+    datagrams: ConcurrentQueue<(Vec<u8>, SocketAddr)>,
+    event: Event,
+}
+
+/// State shared by the workers and the [`KcpShardedListener`]
+struct Shared {
+    inboxes: Vec<Inbox>,
+    accepted: ConcurrentQueue<(KcpStream, SocketAddr)>,
+    accept_event: Event,
+    /// Workers still running
+    running: AtomicUsize,
+    stop: AtomicBool,
+}
+
+/// Listener running one demultiplexing and update worker per core
+///
+/// Sessions are spread over the workers by conv, a session is only ever
+/// touched by its own worker and by the application's stream. Accepted
+/// streams are driven by their worker, [`KcpShardedListener::accept`]
+/// does not need to be polled for them to make progress.
+///
+/// ```no_run
+/// use smol_kcp::{KcpConfig, KcpShardedListener, ShardConfig};
+///
+/// # futures_lite::future::block_on(async {
+/// let listener = KcpShardedListener::bind(
+///     KcpConfig::default(),
+///     "0.0.0.0:4000".parse().unwrap(),
+///     ShardConfig::default(),
+/// )?;
+/// loop {
+///     let (mut stream, peer) = listener.accept().await?;
+///     std::thread::spawn(move || futures_lite::future::block_on(async move {
+///         let mut buf = [0u8; 1500];
+///         while let Ok(n) = stream.recv(&mut buf).await {
+///             if n == 0 || stream.send(&buf[..n]).await.is_err() {
+///                 break;
+///             }
+///         }
+///     }));
+/// }
+/// # Ok::<(), smol_kcp::Error>(())
+/// # });
+/// ```
+///
+/// Dropping the listener stops the workers, streams accepted so far stop
+/// receiving input with them.
+pub struct KcpShardedListener {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+}
+
+impl KcpShardedListener {
+    /// Bind to an address and start the workers
+    pub fn bind(config: KcpConfig, addr: SocketAddr, shards: ShardConfig) -> KcpResult<Self> {
+        let udp = std::net::UdpSocket::bind(addr)?;
+        let local_addr = udp.local_addr()?;
+        let cpus = if shards.pin {
+            allowed_cpus()
+        } else {
+            Vec::new()
+        };
+        let count = match shards.workers {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+
+        let shared = Arc::new(Shared {
+            inboxes: (0..count)
+                .map(|_| Inbox {
+                    datagrams: ConcurrentQueue::bounded(INBOX_CAPACITY),
+                    event: Event::new(),
+                })
+                .collect(),
+            accepted: ConcurrentQueue::unbounded(),
+            accept_event: Event::new(),
+            running: AtomicUsize::new(count),
+            stop: AtomicBool::new(false),
+        });
+
+        for index in 0..count {
+            let mut listener = KcpListener::from_std(config, udp.try_clone()?)?;
+            listener.set_shard(index, count);
+            let cpu = (!cpus.is_empty()).then(|| cpus[index % cpus.len()]);
+            let worker = Worker {
+                index,
+                listener,
+                shared: shared.clone(),
+            };
+            let spawned = thread::Builder::new()
+                .name(format!("kcp-worker-{}", index))
+                .spawn(move || {
+                    if let Some(cpu) = cpu {
+                        pin_to_cpu(cpu);
+                    }
+                    async_io::block_on(worker.run());
+                });
+            if let Err(e) = spawned {
+                shared.stop();
+                return Err(e.into());
+            }
+        }
+        debug!(
+            "sharded listener on {} started {} workers",
+            local_addr, count
+        );
+
+        Ok(Self { shared, local_addr })
+    }
+
+    /// Accept a new connection from any worker
+    pub async fn accept(&self) -> KcpResult<(KcpStream, SocketAddr)> {
+        loop {
+            if let Ok(accepted) = self.shared.accepted.pop() {
+                return Ok(accepted);
+            }
+            let listener = self.shared.accept_event.listen();
+            if let Ok(accepted) = self.shared.accepted.pop() {
+                return Ok(accepted);
+            }
+            if self.shared.running.load(Ordering::Acquire) == 0 {
+                return Err(io::Error::other("all listener workers stopped").into());
+            }
+            listener.await;
+        }
+    }
+
+    /// Number of worker threads
+    pub fn workers(&self) -> usize {
+        self.shared.inboxes.len()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for KcpShardedListener {
+    fn drop(&mut self) {
+        self.shared.stop();
+    }
+}
+
+impl Shared {
+    /// Tell all workers to exit
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Release);
+        for inbox in &self.inboxes {
+            inbox.event.notify(usize::MAX);
+        }
+    }
+}
+
+struct Worker {
+    index: usize,
+    listener: KcpListener,
+    shared: Arc<Shared>,
+}
+
+impl Worker {
+    async fn run(mut self) {
+        if let Err(e) = self.serve().await {
+            error!("listener worker {} stopped: {}", self.index, e);
+        }
+        if self.shared.running.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.accept_event.notify(usize::MAX);
+        }
+    }
+
+    async fn serve(&mut self) -> KcpResult<()> {
+        let shared = self.shared.clone();
+        let count = shared.inboxes.len();
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let inbox = &shared.inboxes[self.index];
+            while let Ok((datagram, peer_addr)) = inbox.datagrams.pop() {
+                self.handle(&datagram, peer_addr).await?;
+            }
+            let forwarded = inbox.event.listen();
+            if shared.stop.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            if !inbox.datagrams.is_empty() {
+                continue;
+            }
+
+            let received = future::or(
+                async { Some(self.listener.recv_datagram(&mut buf).await) },
+                async {
+                    forwarded.await;
+                    None
+                },
+            )
+            .await;
+            let Some(received) = received else {
+                continue;
+            };
+            let (n, peer_addr) = received?;
+            let datagram = &buf[..n];
+            let owner = match self.listener.conv_of(datagram) {
+                // Too short for a conv, the local listener rejects it
+                None => self.index,
+                // Asks for a conv, the owner by address allocates one that
+                // maps back to it
+                Some(0) => shard_of_addr(&peer_addr, count),
+                Some(conv) => shard_of(conv, count),
+            };
+            if owner == self.index {
+                self.handle(datagram, peer_addr).await?;
+                continue;
+            }
+            let owner = &shared.inboxes[owner];
+            if owner.datagrams.push((datagram.to_vec(), peer_addr)).is_ok() {
+                owner.event.notify(1);
+            } else {
+                trace!("worker inbox full, dropped a datagram from {}", peer_addr);
+            }
+        }
+    }
+
+    async fn handle(&mut self, datagram: &[u8], peer_addr: SocketAddr) -> KcpResult<()> {
+        if let Some(accepted) = self.listener.handle_datagram(datagram, peer_addr).await? {
+            // Unbounded, only fails once closed, which it never is
+            let _ = self.shared.accepted.push(accepted);
+            self.shared.accept_event.notify(1);
+        }
+        Ok(())
+    }
+}
+
+/// Cores the process may run on
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> Vec<usize> {
+    // SAFETY: all-zero is an empty cpu_set_t, filled in by the call
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: set is a cpu_set_t of the given size
+    let ret =
+        unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if ret != 0 {
+        debug!(
+            "cannot read the CPU affinity: {}",
+            io::Error::last_os_error()
+        );
+        return Vec::new();
+    }
+    (0..libc::CPU_SETSIZE as usize)
+        // SAFETY: cpu is below CPU_SETSIZE
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cpus() -> Vec<usize> {
+    Vec::new()
+}
+
+/// Pin the calling thread to `cpu`
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) {
+    // SAFETY: all-zero is an empty cpu_set_t
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: cpu comes from sched_getaffinity, below CPU_SETSIZE
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    // SAFETY: set is a cpu_set_t of the given size, 0 is this thread
+    let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        debug!(
+            "cannot pin a worker to CPU {}: {}",
+            cpu,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_cpu: usize) {}
@@ -1,124 +1,2132 @@
 use std::{
-    io::{self, Write},
+    collections::VecDeque,
+    fmt,
+    io,
+    mem,
     net::SocketAddr,
-    sync::Arc,
-    time::{Instant, SystemTime, UNIX_EPOCH},
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
 };
 
 use async_io::Async;
-use kcp::{Kcp, KcpResult};
-use log::trace;
+use event_listener::{Event, EventListener};
+use smol_kcp_core::{serial, Connection, Error as EngineError};
 
-use crate::config::KcpConfig;
+use crate::{
+    checksum,
+    config::{random_token, FlushPolicy, HeaderFormat, KcpConfig, KcpInterop},
+    error::{Error, KcpResult},
+    events::{KcpEvent, KcpEventHandler, UserData},
+    hello,
+    histogram::{LatencyReport, LatencyTracker},
+    logging::{debug, trace},
+    message::{MessageLane, Reliability},
+    multipath::{MultipathMode, PathSet, PathStats, MAX_PATHS},
+    packet,
+    pcap::PcapWriter,
+    reset,
+    ring::{MessageRing, RING_SLOTS},
+    route::SessionRoute,
+    socks5::Socks5Association,
+    stats::{
+        BandwidthEstimator, DelayEstimator, KcpStats, OneWayDelay, RttEstimate, RttEstimator, StatsHistory, StatsSample,
+        TrafficCounters, WindowStatus,
+    },
+    telemetry::{SessionTrace, SocketMetrics},
+};
 
-/// KCP socket implementation
+/// KCP session state machine on a UDP socket
+///
+/// The layer below [`KcpStream`](crate::KcpStream) and
+/// [`KcpListener`](crate::KcpListener), for applications running their own
+/// UDP event loop: feed every datagram from the peer to
+/// [`KcpSocket::input`], call [`KcpSocket::flush`] every
+/// [`KcpSocket::interval`] and [`KcpSocket::send_output`] after anything
+/// that may produce output. Received data is taken with
+/// [`KcpSocket::recv`]. Nothing here waits: a full window or an empty
+/// receive queue is reported as an error.
+///
+/// The UDP socket is only written to, reading it is up to the
+/// application.
 pub struct KcpSocket {
-    kcp: Kcp<KcpOutput>,
+    kcp: Connection,
     udp: Arc<Async<std::net::UdpSocket>>,
     peer_addr: SocketAddr,
     last_update: Instant,
+    /// Last datagram from the peer
+    last_input: Instant,
+    /// Fail with [`Error::SessionExpired`] after this long without input
+    /// while data waits for acknowledgement
+    session_expire: Option<Duration>,
+    /// Fail with [`Error::HandshakeTimeout`] after this long without any
+    /// input while data waits for acknowledgement
+    handshake_timeout: Option<Duration>,
+    /// Anything arrived from the peer, the handshake is done
+    answered: bool,
+    /// The last word from the peer's host was an ICMP port unreachable
+    refused: bool,
+    /// Last send or receive by the application
+    last_used: Instant,
+    /// Time spent paused, subtracted from the clock KCP runs on
+    clock_offset: u32,
+    /// Pause start and the KCP clock at that moment
+    paused: Option<(Instant, u32)>,
+    /// Datagrams waiting to be sent, from KCP and the message lane
+    output: VecDeque<Vec<u8>>,
+    connected: bool,
+    /// The UDP socket is IPv6, IPv4 peers are sent to in their mapped form
+    ipv6_socket: bool,
+    stream: bool,
+    interval: u32,
+    rtt: RttEstimator,
+    bandwidth: BandwidthEstimator,
+    delay: DelayEstimator,
+    messages: MessageLane,
+    counters: TrafficCounters,
+    history: Option<StatsHistory>,
+    metrics: SocketMetrics,
+    trace: SessionTrace,
+    window_stalled: bool,
+    dead_link_reported: bool,
+    event_handler: Option<KcpEventHandler>,
+    user_data: Option<UserData>,
+    /// KCP's timeout retransmission counter at the last event check
+    last_xmit: u32,
+    latency: Option<LatencyTracker>,
+    /// Log every segment header in and out
+    log_segments: bool,
+    /// Capture sink and the local address written into captured packets
+    pcap: Option<(PcapWriter, SocketAddr)>,
+    /// Notified whenever input was processed
+    input_event: Event,
+    /// Datagrams go through the UDP relay of a SOCKS5 proxy
+    socks5: Option<Socks5Association>,
+    /// Extension packets are understood by the peer
+    extensions: bool,
+    /// Drop datagrams that are not plain well-formed KCP segments
+    strict: bool,
+    /// Byte order of the KCP header on the wire
+    header_format: HeaderFormat,
+    /// Datagrams end in a CRC32C trailer, see [`crate::checksum`]
+    checksum: bool,
+    /// Limit on the bytes buffered by this session
+    memory_limit: Option<usize>,
+    /// Deadline of messages queued by [`KcpSocket::send`], counted from
+    /// the send
+    message_ttl: Option<Duration>,
+    /// When sends flush
+    flush_policy: FlushPolicy,
+    /// Configuration the session runs with, see [`KcpSocket::reconfigure`]
+    config: KcpConfig,
+    /// Why the session was closed by the library, all buffers were
+    /// released and every further operation fails with this
+    closed: Option<CloseReason>,
+    /// The peer sent a close frame with this code, nothing more will
+    /// arrive
+    peer_closed: Option<CloseCode>,
+    /// Handed over for a hot restart, the peer must not be told to close
+    exported: bool,
+    /// Complete messages taken from KCP for `recv`, see [`MessageRing`]
+    ring: Arc<MessageRing>,
+    /// Message taken from the ring by [`KcpSocket::peek_size`] or partly
+    /// read by [`KcpSocket::recv`]
+    peeked: Option<Vec<u8>>,
+    /// Paths of a multipath session, `None` sends everything to
+    /// `peer_addr` through `udp`
+    paths: Option<PathSet>,
+    /// Datagrams for one specific path, multipath joins and their echoes
+    path_output: Vec<(usize, Vec<u8>)>,
+    /// Peers of paths removed unvalidated, until the listener forgot them
+    dropped_paths: Vec<SocketAddr>,
+    /// New address of the peer waiting for its challenge to be answered
+    migration: Option<Migration>,
+    /// Negotiation of a client's session id, see [`crate::route`]
+    route: Option<SessionRoute>,
+    /// 64-bit id the session is routed by, once negotiated
+    session_id: Option<u64>,
+    /// Encoded handshake wrapped around output until the server answers,
+    /// see [`crate::hello`]
+    hello: Option<Vec<u8>>,
+    /// Handshake payload the client sent
+    peer_hello: Option<Vec<u8>>,
+    /// Service the client asked for
+    peer_service: Option<String>,
+}
+
+/// Validation of a new peer address, see [`KcpSocket::migration_challenge`]
+struct Migration {
+    peer: SocketAddr,
+    token: [u8; 8],
+    sent: Instant,
 }
 
 impl KcpSocket {
+    /// Session `conv` with the peer at `peer_addr`, sending through `udp`
+    ///
+    /// Both ends must use the same conv and mode, `stream` overrides
+    /// [`KcpConfig::stream`]. A connected `udp` sends with `send`, an
+    /// unconnected one with `send_to` to `peer_addr`.
     pub fn new(
         config: &KcpConfig,
         conv: u32,
         udp: Arc<Async<std::net::UdpSocket>>,
         peer_addr: SocketAddr,
-        _stream: bool,
+        stream: bool,
     ) -> KcpResult<Self> {
-        let output = KcpOutput::new(udp.clone(), peer_addr);
-        let mut kcp = Kcp::new(conv, output);
-        
+        let mut kcp = if stream {
+            Connection::new_stream(conv)
+        } else {
+            Connection::new(conv)
+        };
         config.apply_config(&mut kcp);
-        // Note: set_stream method doesn't exist in kcp 0.5.3, stream mode is handled differently
+
+        // Connected client sockets use send(), the peer is fixed by the kernel
+        let connected = udp.get_ref().peer_addr().is_ok();
+        let ipv6_socket = is_ipv6(udp.get_ref());
+        let peer_addr = canonical_addr(peer_addr);
 
         Ok(Self {
             kcp,
-            udp,
             peer_addr,
             last_update: Instant::now(),
+            last_input: Instant::now(),
+            session_expire: config.session_expire,
+            handshake_timeout: config.handshake_timeout,
+            answered: false,
+            refused: false,
+            last_used: Instant::now(),
+            clock_offset: 0,
+            paused: None,
+            output: VecDeque::new(),
+            connected,
+            ipv6_socket,
+            stream,
+            interval: config.nodelay.interval.max(10) as u32,
+            rtt: RttEstimator::default(),
+            bandwidth: BandwidthEstimator::default(),
+            delay: DelayEstimator::default(),
+            messages: MessageLane::default(),
+            counters: TrafficCounters::default(),
+            history: config.stats_history.map(StatsHistory::new),
+            metrics: SocketMetrics::new(udp.get_ref().local_addr().ok()),
+            trace: SessionTrace::new(if connected { "client" } else { "server" }, conv, peer_addr),
+            window_stalled: false,
+            dead_link_reported: false,
+            event_handler: None,
+            user_data: None,
+            last_xmit: 0,
+            latency: config.latency_histograms.then(LatencyTracker::default),
+            log_segments: false,
+            pcap: None,
+            input_event: Event::new(),
+            socks5: None,
+            extensions: config.interop == KcpInterop::Native,
+            strict: config.interop == KcpInterop::Ikcp,
+            header_format: config.header_format,
+            checksum: config.checksum,
+            memory_limit: config.max_session_memory,
+            message_ttl: config.message_ttl.filter(|_| !stream),
+            flush_policy: config.flush_policy,
+            config: *config,
+            closed: None,
+            peer_closed: None,
+            exported: false,
+            ring: Arc::new(MessageRing::new(RING_SLOTS)),
+            peeked: None,
+            paths: None,
+            path_output: Vec::new(),
+            dropped_paths: Vec::new(),
+            migration: None,
+            route: None,
+            session_id: None,
+            hello: None,
+            peer_hello: None,
+            peer_service: None,
+            udp,
         })
     }
 
+    /// Address datagrams are sent to
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
     }
 
+    /// Identifier of this session for log messages
+    pub(crate) fn tag(&self) -> SessionTag {
+        SessionTag::new(self.kcp.conv(), self.peer_addr)
+    }
+
+    /// Process a datagram received from the peer
+    ///
+    /// ACKs it causes go out with the next [`KcpSocket::send_output`].
+    /// Malformed datagrams, those of another conversation and with
+    /// [`KcpConfig::checksum`] those failing it fail and change nothing.
     pub fn input(&mut self, data: &[u8]) -> KcpResult<bool> {
+        if !self.checksum {
+            return self.input_verified(data);
+        }
+        match checksum::strip(data) {
+            Some(data) => self.input_verified(data),
+            None => {
+                self.note_corrupted();
+                debug!("{} dropped a datagram failing its checksum", self.tag());
+                Err(io::Error::new(io::ErrorKind::InvalidData, "checksum mismatch").into())
+            }
+        }
+    }
+
+    /// Count a datagram of the peer dropped for failing its checksum
+    pub(crate) fn note_corrupted(&mut self) {
+        self.counters.corrupted += 1;
+    }
+
+    /// [`KcpSocket::input`] of a datagram whose checksum trailer was
+    /// checked and removed already
+    pub(crate) fn input_verified(&mut self, data: &[u8]) -> KcpResult<bool> {
         self.last_update = Instant::now();
+        self.last_input = self.last_update;
+        self.answered = true;
+        self.refused = false;
+        if let Some((pcap, local)) = &self.pcap {
+            pcap.write_datagram(self.peer_addr, *local, data);
+        }
+        let data = match packet::ext_cmd(data) {
+            Some(packet::CMD_HELLO) if self.extensions => {
+                let (hello, inner) = hello::unwrap(data).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "malformed handshake payload")
+                })?;
+                if self.peer_hello.is_none() && self.peer_service.is_none() {
+                    self.set_peer_hello(&hello);
+                }
+                inner
+            }
+            _ => data,
+        };
+        // Headers of forks with another byte order are read as ikcp's
+        let decoded = self.header_format.decode(data);
+        let data = &*decoded;
+        self.counters.on_received(data);
+        self.metrics.on_received(data.len());
+        if self.log_segments {
+            packet::log_datagram("<-", self.peer_addr, data);
+        }
+        if self.strict {
+            if let Err(reason) = packet::validate(data, Some(self.kcp.conv())) {
+                debug!("{} dropped a nonconforming datagram: {}", self.tag(), reason);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, reason).into());
+            }
+        }
+        // Anything of the server means it has the payload
+        if self.hello.is_some() && packet::conv(data) == Some(self.kcp.conv()) {
+            self.hello_answered()?;
+        }
         // Update KCP before input
-        let current = current_millis();
-        self.kcp.update(current)?;
-        match self.kcp.input(data) {
-            Ok(_) => Ok(true),
-            Err(e) => Err(e),
+        self.update()?;
+
+        if let Some(cmd) = packet::ext_cmd(data).filter(|_| self.extensions) {
+            let conv = self.kcp.conv();
+            if cmd == packet::CMD_CLOSE {
+                // A close frame of an earlier conversation must not end this one
+                if packet::conv(data) == Some(conv) {
+                    let code = CloseCode::decode(&data[packet::HEADER_LEN..]);
+                    self.peer_close(code);
+                }
+                self.input_event.notify(usize::MAX);
+                return Ok(true);
+            }
+            if cmd == packet::CMD_CHALLENGE {
+                // Answered from wherever this end sends from now
+                let token = &data[packet::HEADER_LEN..];
+                if packet::conv(data) == Some(conv) && token.len() == 8 {
+                    let response = packet::encode(conv, packet::CMD_RESPONSE, token);
+                    // A joined path is validated on the path itself
+                    match &self.paths {
+                        Some(paths) => self.path_output.push((paths.input_path(), response)),
+                        None => self.queue(response),
+                    }
+                }
+                return Ok(true);
+            }
+            if cmd == packet::CMD_RESPONSE {
+                // Migrations are completed by the listener, only the paths
+                // of a multipath session are validated here
+                let token = &data[packet::HEADER_LEN..];
+                let tag = self.tag();
+                if let Some(paths) = self.paths.as_mut().filter(|_| packet::conv(data) == Some(conv)) {
+                    if paths.validate(token) {
+                        debug!("{} path from {} validated", tag, paths.peer(paths.input_path()));
+                    }
+                }
+                return Ok(true);
+            }
+            if cmd == packet::CMD_SESSION_ID {
+                let body = &data[packet::HEADER_LEN..];
+                if let Some(route) = &mut self.route {
+                    if route.confirm(body) {
+                        let id = route.id();
+                        self.session_id = Some(id);
+                        debug!("{} routed by session id {:016x}", self.tag(), id);
+                    }
+                }
+                return Ok(true);
+            }
+            if cmd == packet::CMD_RESET {
+                let reset = reset::parse(data)
+                    .is_some_and(|(id, token)| self.route.as_ref().is_some_and(|route| route.is_reset(id, token)));
+                if reset && packet::conv(data) == Some(conv) {
+                    debug!("{} reset by the server, it lost the session", self.tag());
+                    self.close(CloseReason::ServerReset);
+                }
+                return Ok(true);
+            }
+            if cmd == packet::CMD_TIMESTAMP {
+                self.delay.on_echo(Instant::now(), &data[packet::HEADER_LEN..]);
+                return Ok(true);
+            }
+            if cmd == packet::CMD_ROUTED {
+                // Only clients route, and only to a listener
+                return Ok(true);
+            }
+            if cmd == packet::CMD_PATH {
+                let body = &data[packet::HEADER_LEN..];
+                if self.paths.as_mut().is_some_and(|paths| paths.confirm(body)) {
+                    debug!("{} path {:?} confirmed", self.tag(), body.first());
+                }
+                return Ok(true);
+            }
+            if let Some(ack) = self.messages.input(conv, cmd, &data[packet::HEADER_LEN..]) {
+                self.queue(ack);
+            }
+            self.fill_ring();
+            self.input_event.notify(usize::MAX);
+            return Ok(true);
+        }
+
+        let result = match self.kcp.handle_input(data, self.now()) {
+            Ok(_) => {
+                self.sample_rtt(data);
+                self.sample_delay(data);
+                self.fill_ring();
+                self.check_memory().map(|_| true)
+            }
+            Err(e) => Err(e.into()),
+        };
+        self.input_event.notify(usize::MAX);
+        result
+    }
+
+    /// Close the session if received data exceeds the memory limit
+    ///
+    /// Only what the peer pushed in counts here, data queued by sending is
+    /// limited by [`KcpSocket::can_send`].
+    fn check_memory(&mut self) -> KcpResult<()> {
+        let Some(limit) = self.memory_limit else {
+            return Ok(());
+        };
+        let received =
+            self.kcp.rcv_bytes() + self.messages.received_bytes() + self.ring.bytes();
+        if received <= limit {
+            return Ok(());
+        }
+
+        let buffered = self.buffered_bytes();
+        debug!(
+            "{} memory limit exceeded: {} bytes buffered, limit {}",
+            self.tag(),
+            buffered,
+            limit
+        );
+        self.trace.memory_exceeded(buffered, limit);
+        self.emit(KcpEvent::MemoryLimitExceeded { buffered, limit });
+
+        self.close(CloseReason::MemoryExceeded);
+        self.check_open()
+    }
+
+    /// Release all buffers and fail every further operation with the
+    /// error of `reason`
+    pub(crate) fn close(&mut self, reason: CloseReason) {
+        self.closed = Some(reason);
+        self.kcp = Connection::new(self.kcp.conv());
+        self.output = VecDeque::new();
+        self.messages = MessageLane::default();
+        while self.ring.pop().is_some() {}
+        self.peeked = None;
+        self.input_event.notify(usize::MAX);
+    }
+
+    /// Queue a close frame telling the peer the session is gone and why
+    ///
+    /// Best effort, it is sent once and never retransmitted. Peers without
+    /// the smol-kcp extensions are not told.
+    pub(crate) fn queue_close_frame(&mut self) {
+        if self.extensions && !self.exported {
+            let code = self.closed.map_or(CloseCode::Normal, CloseReason::code);
+            let frame = packet::encode(self.kcp.conv(), packet::CMD_CLOSE, &code.encode());
+            self.output.push_back(frame);
+        }
+    }
+
+    /// Close because the application dropped its last handle
+    ///
+    /// Queues the close frame for the next [`KcpSocket::send_output`] of
+    /// the session's owner. Exported sessions are left as they are.
+    pub(crate) fn close_dropped(&mut self) {
+        if self.closed.is_some() || self.exported {
+            return;
+        }
+        let notify = self.peer_closed.is_none();
+        self.close(CloseReason::Closed(CloseCode::Normal));
+        if notify {
+            self.queue_close_frame();
+        }
+    }
+
+    /// Send what is queued without waiting for the UDP socket
+    ///
+    /// For a stream whose last handle is dropped while it is the only user
+    /// of the session, there is nobody left to await
+    /// [`KcpSocket::send_output`]. The socket is non-blocking, datagrams
+    /// its buffer has no room for are lost.
+    pub(crate) fn send_output_now(&mut self) {
+        let conv = self.kcp.conv();
+        for datagram in mem::take(&mut self.output) {
+            let datagram = self.header_format.encode(&datagram).into_owned();
+            let datagram = self.wire(conv, &datagram).unwrap_or(datagram);
+            let datagram = match &self.socks5 {
+                Some(socks5) => socks5.encapsulate(&datagram),
+                None => datagram,
+            };
+            let udp = self.udp.get_ref();
+            let _ = if self.connected {
+                udp.send(&datagram)
+            } else {
+                udp.send_to(&datagram, send_addr(self.peer_addr, self.ipv6_socket))
+            };
+        }
+    }
+
+    fn peer_close(&mut self, code: CloseCode) {
+        if self.peer_closed.is_none() {
+            self.peer_closed = Some(code);
+            debug!("{} closed by the peer: {}", self.tag(), code);
+            self.emit(KcpEvent::PeerClosed);
+        }
+    }
+
+    /// The peer sent a close frame
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_closed.is_some()
+    }
+
+    /// Why the peer closed the session, `None` while it did not
+    pub fn peer_close_code(&self) -> Option<CloseCode> {
+        self.peer_closed
+    }
+
+    /// The error of operations that need the peer, once it closed the
+    /// session
+    ///
+    /// A normal close is [`Error::ConnectionClosed`], any other code is
+    /// reported as [`Error::ClosedByPeer`].
+    pub(crate) fn peer_close_error(&self) -> Option<Error> {
+        match self.peer_closed? {
+            CloseCode::Normal => Some(Error::ConnectionClosed),
+            code => Some(Error::ClosedByPeer(code)),
+        }
+    }
+
+    /// Serialize the session for a hot restart
+    ///
+    /// `unread` holds the messages already taken from KCP that the
+    /// application has not read, they are handed back on import. Afterwards dropping the
+    /// stream no longer tells the peer to close.
+    pub(crate) fn export_state(&mut self, unread: &[Vec<u8>]) -> KcpResult<Vec<u8>> {
+        self.check_open()?;
+        if self.socks5.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "sessions through a SOCKS5 proxy cannot be exported",
+            )
+            .into());
+        }
+        if self.paths.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "multipath sessions cannot be exported",
+            )
+            .into());
         }
+
+        let engine = self.kcp.export_state();
+        let unread_len = unread.iter().map(|msg| 4 + msg.len()).sum::<usize>();
+        let mut state = Vec::with_capacity(64 + unread_len + engine.len());
+        state.push(STATE_VERSION);
+        for addr in [self.peer_addr, self.udp.get_ref().local_addr()?] {
+            let addr = addr.to_string();
+            state.push(addr.len() as u8);
+            state.extend_from_slice(addr.as_bytes());
+        }
+        state.extend_from_slice(&self.messages.next_id().to_le_bytes());
+        state.extend_from_slice(&(unread.len() as u32).to_le_bytes());
+        for msg in unread {
+            state.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+            state.extend_from_slice(msg);
+        }
+        state.extend_from_slice(&engine);
+        self.exported = true;
+        Ok(state)
     }
 
+    /// Resume an exported session on `udp`
+    ///
+    /// `config` supplies everything that is not part of the KCP state, e.g.
+    /// the interop mode and the memory limit.
+    pub(crate) fn import_state(
+        config: &KcpConfig,
+        udp: Arc<Async<std::net::UdpSocket>>,
+        state: &ExportedState<'_>,
+    ) -> KcpResult<Self> {
+        let kcp = Connection::import_state(state.engine)?;
+        let mut socket = Self::new(config, kcp.conv(), udp, state.peer, kcp.is_stream())?;
+        socket.interval = kcp.interval();
+        // Carry on from the exporting process' clock, ts in flight and
+        // echoed back by the peer are on it
+        socket.clock_offset = current_millis().wrapping_sub(kcp.current());
+        socket.kcp = kcp;
+        socket.answered = true;
+        socket.kcp.set_ack_delay(config.ack_delay_ms());
+        socket.messages = MessageLane::with_next_id(state.next_msg_id);
+        // Unread messages are delivered before anything else
+        socket.ring = Arc::new(MessageRing::new(RING_SLOTS.max(state.unread.len())));
+        for msg in &state.unread {
+            let pushed = socket.ring.push(msg.to_vec());
+            debug_assert!(pushed.is_ok(), "ring refused a message");
+        }
+        Ok(socket)
+    }
+
+    /// Fails once the session was closed by [`KcpSocket::close`]
+    pub(crate) fn check_open(&self) -> KcpResult<()> {
+        match self.closed {
+            Some(reason) => Err(reason.error()),
+            None => Ok(()),
+        }
+    }
+
+    /// Conversation id
+    pub fn conv(&self) -> u32 {
+        self.kcp.conv()
+    }
+
+    /// Time since the last datagram from the peer
+    pub fn since_input(&self) -> Duration {
+        self.last_input.elapsed()
+    }
+
+    /// Time since the application last sent or received on the session
+    pub(crate) fn idle_time(&self) -> Duration {
+        self.last_used.elapsed()
+    }
+
+    /// Approximate bytes buffered by this session
+    pub fn buffered_bytes(&self) -> usize {
+        let output = self.output.iter().map(Vec::len).sum::<usize>();
+        self.kcp.snd_bytes()
+            + self.kcp.rcv_bytes()
+            + output
+            + self.messages.pending_bytes()
+            + self.messages.received_bytes()
+            + self.ring.bytes()
+            + self.peeked.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Whether `len` bytes can be queued without exceeding the memory limit
+    ///
+    /// In stream mode any room is enough, [`KcpSocket::send`] queues what
+    /// fits. A message larger than the whole limit never fits. Fails once
+    /// the peer closed the session.
+    pub(crate) fn can_send(&self, len: usize) -> KcpResult<bool> {
+        if let Some(e) = self.peer_close_error() {
+            return Err(e);
+        }
+        let Some(limit) = self.memory_limit else {
+            return Ok(true);
+        };
+        if !self.stream && len > limit {
+            return Err(Error::Protocol(EngineError::UserBufTooBig));
+        }
+        let room = limit.saturating_sub(self.buffered_bytes());
+        Ok(if self.stream { room > 0 } else { len <= room })
+    }
+
+    /// Wait for the next call to [`KcpSocket::input`]
+    ///
+    /// Register before releasing the lock to not miss input arriving in
+    /// between.
+    pub(crate) fn listen_input(&self) -> EventListener {
+        self.input_event.listen()
+    }
+
+    /// KCP update interval
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval as u64)
+    }
+
+    /// How much of a `len` byte write to queue now, 0 to wait for ACKs
+    ///
+    /// Stays within the send window and the memory limit. A message is
+    /// queued whole or not at all, into an empty window even if it is
+    /// larger; stream data is queued piecewise. Queued messages of lower
+    /// than `priority` do not count, the message goes ahead of them.
+    pub(crate) fn send_room(&self, len: usize, priority: u8) -> KcpResult<usize> {
+        self.check_open()?;
+        if !self.can_send(len)? {
+            return Ok(0);
+        }
+
+        let wnd = self.kcp.snd_wnd().min(self.kcp.rmt_wnd()) as usize;
+        let waiting = self.kcp.wait_snd();
+        let mss = self.kcp.mss();
+        if self.stream {
+            let mut room = (wnd.saturating_sub(waiting) * mss).min(self.kcp.max_send());
+            if let Some(limit) = self.memory_limit {
+                room = room.min(limit.saturating_sub(self.buffered_bytes()));
+            }
+            Ok(room.min(len))
+        } else {
+            let count = len.div_ceil(mss).max(1);
+            let waiting = self.kcp.wait_snd_before(priority);
+            Ok(if waiting == 0 || waiting + count <= wnd { len } else { 0 })
+        }
+    }
+
+    /// Queue data on KCP
+    ///
+    /// With a memory limit, stream mode queues only what fits and message
+    /// mode fails with [`Error::WindowExhausted`] unless the whole message fits.
+    /// Messages expire after the configured TTL.
     pub fn send(&mut self, data: &[u8]) -> KcpResult<usize> {
+        self.send_with(data, None, 0)
+    }
+
+    /// Queue a message ahead of queued ones of lower priority, dropped if
+    /// still queued at `deadline` or, without one, after the configured TTL
+    pub(crate) fn send_with(
+        &mut self,
+        mut data: &[u8],
+        deadline: Option<Instant>,
+        priority: u8,
+    ) -> KcpResult<usize> {
         self.last_update = Instant::now();
+        self.last_used = self.last_update;
         // Update KCP before sending
-        let current = current_millis();
-        self.kcp.update(current)?;
-        self.kcp.send(data)
+        self.update()?;
+
+        if !self.can_send(data.len())? {
+            return Err(Error::WindowExhausted);
+        }
+        if let (Some(limit), true) = (self.memory_limit, self.stream) {
+            let room = limit.saturating_sub(self.buffered_bytes());
+            data = &data[..data.len().min(room)];
+        }
+
+        let queued = self.kcp.wait_snd();
+        let deadline = deadline
+            .or_else(|| self.message_ttl.map(|ttl| self.last_update + ttl))
+            .map(|deadline| {
+                // Far deadlines are capped well inside the clock's range
+                let left = deadline
+                    .saturating_duration_since(self.last_update)
+                    .min(Duration::from_secs(86_400));
+                self.now().wrapping_add(left.as_millis() as u32)
+            });
+        let result = self.kcp.send_message(data, priority, deadline)?;
+        if let Some(latency) = &mut self.latency {
+            latency.on_send(self.kcp.wait_snd() - queued, self.last_update);
+        }
+        Ok(result)
+    }
+
+    /// Send a message outside the KCP queue with the given reliability
+    pub(crate) fn send_msg(&mut self, data: &[u8], reliability: Reliability) -> KcpResult<usize> {
+        if self.stream {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "per-message reliability requires message mode",
+            )
+            .into());
+        }
+        if !self.extensions {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "per-message reliability is a smol-kcp extension",
+            )
+            .into());
+        }
+        if data.len() > MessageLane::max_payload(self.kcp.mtu()) {
+            return Err(Error::Protocol(EngineError::UserBufTooBig));
+        }
+
+        self.last_update = Instant::now();
+        self.last_used = self.last_update;
+        self.update()?;
+        if !self.can_send(data.len())? {
+            return Err(Error::WindowExhausted);
+        }
+        let packet = self
+            .messages
+            .send(self.kcp.conv(), data, reliability, self.last_update);
+        self.queue(packet);
+        Ok(data.len())
+    }
+
+    /// Send a single byte out of band, ahead of everything queued
+    ///
+    /// Retransmitted until acknowledged, fails with
+    /// [`Error::WindowExhausted`] while too many urgent bytes wait for
+    /// their ack.
+    pub fn send_urgent(&mut self, byte: u8) -> KcpResult<()> {
+        if !self.extensions {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "urgent data is a smol-kcp extension",
+            )
+            .into());
+        }
+        if let Some(e) = self.peer_close_error() {
+            return Err(e);
+        }
+
+        self.last_update = Instant::now();
+        self.last_used = self.last_update;
+        self.update()?;
+        let packet = self
+            .messages
+            .send_urgent(self.kcp.conv(), byte, self.last_update)
+            .ok_or(Error::WindowExhausted)?;
+        self.queue(packet);
+        Ok(())
+    }
+
+    /// Next urgent byte received from the peer
+    pub fn take_urgent(&mut self) -> KcpResult<Option<u8>> {
+        self.check_open()?;
+        self.last_used = Instant::now();
+        Ok(self.messages.pop_urgent())
     }
 
+    /// Size of the next received message, in stream mode of the received
+    /// data up to the next chunk boundary
+    ///
+    /// Fails with [`EngineError::RecvQueueEmpty`] while nothing arrived.
+    pub fn peek_size(&mut self) -> KcpResult<usize> {
+        self.peeked()?
+            .map(|msg| msg.len())
+            .ok_or(Error::Protocol(EngineError::RecvQueueEmpty))
+    }
+
+    /// Receive the next message into `buf`
+    ///
+    /// A message larger than `buf` fails with
+    /// [`EngineError::UserBufTooSmall`] and stays queued, see
+    /// [`KcpSocket::peek_size`]. In stream mode as much as fits is
+    /// returned and the rest kept for the next call. Fails with
+    /// [`EngineError::RecvQueueEmpty`] while nothing arrived.
     pub fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
-        // Update KCP before receiving
-        let current = current_millis();
-        self.kcp.update(current)?;
-        self.kcp.recv(buf)
+        let stream = self.stream;
+        let Some(msg) = self.peeked()? else {
+            return Err(Error::Protocol(EngineError::RecvQueueEmpty));
+        };
+        if msg.len() > buf.len() && !stream {
+            return Err(Error::Protocol(EngineError::UserBufTooSmall));
+        }
+        let n = msg.len().min(buf.len());
+        buf[..n].copy_from_slice(&msg[..n]);
+        msg.drain(..n);
+        if msg.is_empty() {
+            self.peeked = None;
+        }
+        Ok(n)
+    }
+
+    /// Next received message, taken from the ring if needed
+    fn peeked(&mut self) -> KcpResult<Option<&mut Vec<u8>>> {
+        if self.peeked.is_none() {
+            self.peeked = match self.ring.pop() {
+                Some(msg) => Some(msg),
+                None if self.take_received()? => self.ring.pop(),
+                None => None,
+            };
+        }
+        Ok(self.peeked.as_mut())
+    }
+
+    /// Ring the stream's `recv` pops received messages from
+    pub(crate) fn ring(&self) -> &Arc<MessageRing> {
+        &self.ring
+    }
+
+    /// Move complete messages from KCP and those that arrived outside it
+    /// into the ring
+    ///
+    /// Called after input and by `recv` once the ring ran empty, so
+    /// messages left in KCP while the ring was full are not stranded.
+    /// Returns whether the ring holds anything.
+    pub(crate) fn take_received(&mut self) -> KcpResult<bool> {
+        self.update()?;
+        self.last_used = Instant::now();
+        self.fill_ring();
+        Ok(!self.ring.is_empty())
+    }
+
+    fn fill_ring(&mut self) {
+        // Only the holder of the session lock pushes, so the ring cannot
+        // fill up or refuse between the check and the push
+        while !self.ring.is_full() {
+            if let Some(msg) = self.messages.pop_received() {
+                let pushed = self.ring.push(msg);
+                debug_assert!(pushed.is_ok(), "ring refused a message");
+                continue;
+            }
+            let Ok(size) = self.kcp.peeksize() else {
+                break;
+            };
+            let mut msg = vec![0; size];
+            if self.kcp.recv(&mut msg).is_err() {
+                break;
+            }
+            // Empty segments carry nothing, they are not the end of the
+            // stream
+            if !msg.is_empty() {
+                let pushed = self.ring.push(msg);
+                debug_assert!(pushed.is_ok(), "ring refused a message");
+            }
+        }
+    }
+
+    /// Session tracing span and events
+    pub(crate) fn trace(&self) -> &SessionTrace {
+        &self.trace
+    }
+
+    /// Drive KCP timers and retransmit pending partially reliable messages
+    fn update(&mut self) -> KcpResult<()> {
+        self.check_open()?;
+        if self.paused.is_some() {
+            return Ok(());
+        }
+        self.kcp.update(self.now());
+        self.check_window();
+        self.check_expired()?;
+        let now = Instant::now();
+        let rto = self.rtt.rto(self.interval);
+        for packet in self.messages.poll_retransmit(now, rto) {
+            self.queue(packet);
+        }
+        let internals = self.kcp.internals();
+        let period = self.rtt.estimate().srtt.max(self.interval());
+        self.bandwidth
+            .sample(now, internals.delivered, period, internals.snd_queue_len == 0);
+
+        if let Some(mut history) = self.history.take() {
+            history.maybe_sample(now, || self.stats());
+            self.history = Some(history);
+        }
+        Ok(())
+    }
+
+    /// Report transitions into and out of a window-limited state
+    fn check_window(&mut self) {
+        let waiting = self.kcp.wait_snd();
+        let snd_wnd = self.kcp.snd_wnd();
+        let rmt_wnd = self.kcp.rmt_wnd();
+        let stalled = waiting > snd_wnd.min(rmt_wnd) as usize;
+
+        if stalled != self.window_stalled {
+            self.window_stalled = stalled;
+            if stalled {
+                debug!(
+                    "{} send window stalled: {} segments waiting, snd_wnd {}, rmt_wnd {}",
+                    self.tag(),
+                    waiting,
+                    snd_wnd,
+                    rmt_wnd
+                );
+                self.trace.window_stalled(waiting, snd_wnd, rmt_wnd);
+                self.emit(KcpEvent::WindowStalled {
+                    waiting,
+                    snd_wnd,
+                    rmt_wnd,
+                });
+            } else {
+                self.trace.window_resumed();
+                self.emit(KcpEvent::WindowResumed);
+            }
+        }
+
+        if self.kcp.is_dead_link() && !self.dead_link_reported {
+            self.dead_link_reported = true;
+            debug!("{} dead link", self.tag());
+            self.trace.dead_link();
+            self.emit(KcpEvent::DeadLink);
+        }
+    }
+
+    /// Close the session once the peer was silent for `session_expire`
+    /// with data in flight, or for `handshake_timeout` if it never
+    /// answered; idle sessions do not expire
+    fn check_expired(&mut self) -> KcpResult<()> {
+        if self.kcp.wait_snd() == 0 || self.peer_closed.is_some() {
+            return Ok(());
+        }
+        let silent = self.last_input.elapsed();
+        let handshake = !self.answered && self.handshake_timeout.is_some_and(|timeout| silent >= timeout);
+        let expired = self.session_expire.is_some_and(|expire| silent >= expire);
+        if !handshake && !expired {
+            return Ok(());
+        }
+        debug!("{} expired, no answer for {:?}", self.tag(), silent);
+        self.close(if self.refused {
+            CloseReason::Refused
+        } else if handshake {
+            CloseReason::HandshakeTimeout
+        } else {
+            CloseReason::Expired
+        });
+        self.check_open()
+    }
+
+    /// An ICMP error reported that nothing listens on the peer's port
+    ///
+    /// KCP keeps retransmitting, the peer may still come up. Only if it
+    /// never does the session expires as refused.
+    pub(crate) fn note_refused(&mut self) {
+        self.refused = true;
+    }
+
+    /// Feed the ts of the peer's data segments to the one-way delay
+    /// estimate and echo the latest one when due
+    fn sample_delay(&mut self, data: &[u8]) {
+        let latest = packet::segments(data)
+            .filter(|segment| segment.cmd == packet::KCP_CMD_PUSH)
+            .map(|segment| segment.ts)
+            .reduce(|latest, ts| if serial::after(ts, latest) { ts } else { latest });
+        let Some(ts) = latest else {
+            return;
+        };
+        let now = Instant::now();
+        self.delay.on_data(now, self.now(), ts);
+        if !self.extensions {
+            return;
+        }
+        if let Some(body) = self.delay.poll_echo(now) {
+            let echo = packet::encode(self.kcp.conv(), packet::CMD_TIMESTAMP, &body);
+            self.queue(echo);
+        }
+    }
+
+    fn sample_rtt(&mut self, data: &[u8]) {
+        let now = self.now();
+        let mut una = None;
+        for segment in packet::segments(data) {
+            una = Some(segment.una);
+            if segment.cmd != packet::KCP_CMD_ACK {
+                continue;
+            }
+            let rtt = serial::diff(now, segment.ts);
+            if rtt >= 0 {
+                self.rtt.update(rtt as u32);
+                if let Some(paths) = &mut self.paths {
+                    paths.on_ack(segment.sn, rtt as u32);
+                }
+                let rtt = Duration::from_millis(rtt as u64);
+                self.metrics.on_rtt(rtt);
+                if let Some(latency) = &mut self.latency {
+                    latency.on_rtt(rtt);
+                }
+            }
+        }
+
+        if let (Some(paths), Some(una)) = (&mut self.paths, una) {
+            paths.on_una(una);
+        }
+        if let (Some(latency), Some(una)) = (&mut self.latency, una) {
+            latency.on_una(una, Instant::now());
+        }
     }
 
-    pub fn peek_size(&self) -> Option<usize> {
-        self.kcp.peeksize().ok()
+    /// Flush after a send if the flush policy asks for it
+    pub(crate) fn flush_sent(&mut self) -> KcpResult<()> {
+        let due = match self.flush_policy {
+            FlushPolicy::PerSend => true,
+            FlushPolicy::OnInterval => false,
+            FlushPolicy::OnThreshold { bytes } => self.kcp.unsent_bytes() >= bytes,
+        };
+        if due {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = policy;
+        self.config.flush_policy = policy;
     }
 
+    /// Change the configuration of the running session
+    ///
+    /// `f` edits the configuration the session runs with. Changes to
+    /// `nodelay`, `wnd_size`, `ack_delay`, `session_expire`,
+    /// `max_session_memory`, `message_ttl` and `flush_policy` take effect
+    /// at once; the other fields are fixed when the session opens and
+    /// changes to them are ignored.
+    pub fn reconfigure(&mut self, f: impl FnOnce(&mut KcpConfig)) {
+        let mut config = self.config;
+        f(&mut config);
+        let nodelay = config.nodelay;
+        self.kcp.set_nodelay(nodelay.nodelay, nodelay.interval, nodelay.resend, nodelay.nc);
+        self.interval = nodelay.interval.max(10) as u32;
+        self.kcp.set_wndsize(config.wnd_size.0, config.wnd_size.1);
+        self.kcp.set_ack_delay(config.ack_delay_ms());
+        self.session_expire = config.session_expire;
+        self.memory_limit = config.max_session_memory;
+        self.message_ttl = config.message_ttl.filter(|_| !self.stream);
+        self.flush_policy = config.flush_policy;
+        self.config = KcpConfig {
+            nodelay,
+            wnd_size: config.wnd_size,
+            ack_delay: config.ack_delay,
+            session_expire: config.session_expire,
+            max_session_memory: config.max_session_memory,
+            message_ttl: config.message_ttl,
+            flush_policy: config.flush_policy,
+            ..self.config
+        };
+        debug!("{} reconfigured", self.tag());
+    }
+
+    /// Hold new data back until [`KcpSocket::uncork`]
+    pub fn cork(&mut self) {
+        self.kcp.set_corked(true);
+    }
+
+    /// Send the data held back by [`KcpSocket::cork`]
+    pub fn uncork(&mut self) -> KcpResult<()> {
+        self.kcp.set_corked(false);
+        self.flush()
+    }
+
+    /// Run KCP's timers and send what is due: ACKs, queued data and
+    /// retransmissions
     pub fn flush(&mut self) -> KcpResult<()> {
         // Update before flush
-        let current = current_millis();
-        self.kcp.update(current)?;
-        self.kcp.flush()
+        self.update()?;
+        if self.paused.is_some() {
+            return Ok(());
+        }
+        self.kcp.flush().map_err(Error::from)
     }
 
-    pub fn udp_socket(&self) -> &Arc<Async<std::net::UdpSocket>> {
+    /// Probe the peer's window so an idle session sees traffic both ways
+    pub(crate) fn keepalive(&mut self) -> KcpResult<()> {
+        self.kcp.probe_window();
+        self.flush()
+    }
+
+    /// Clock KCP runs on, in ms; stands still while paused
+    fn now(&self) -> u32 {
+        match self.paused {
+            Some((_, frozen)) => frozen,
+            None => current_millis().wrapping_sub(self.clock_offset),
+        }
+    }
+
+    /// Stop KCP's clock: nothing is sent or retransmitted and no timeout
+    /// runs until [`KcpSocket::resume`]
+    ///
+    /// Input is still processed, sends are queued.
+    pub fn pause(&mut self) {
+        if self.paused.is_none() {
+            self.paused = Some((Instant::now(), self.now()));
+            debug!("{} paused", self.tag());
+        }
+    }
+
+    /// Restart KCP's clock where it stopped
+    ///
+    /// The time spent paused is cut out, so segments in flight keep their
+    /// RTO and RTT samples taken across the pause stay short.
+    pub fn resume(&mut self) {
+        if let Some((since, _)) = self.paused.take() {
+            let paused = since.elapsed();
+            self.clock_offset = self.clock_offset.wrapping_add(paused.as_millis() as u32);
+            // The peer could not answer while the clock was frozen
+            self.last_input += paused;
+            debug!("{} resumed after {:?}", self.tag(), paused);
+        }
+    }
+
+    /// Whether the session runs in stream mode
+    pub fn is_stream(&self) -> bool {
+        self.stream
+    }
+
+    /// Whether [`KcpSocket::pause`] stopped the clock
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    pub(crate) fn udp_socket(&self) -> &Arc<Async<std::net::UdpSocket>> {
         &self.udp
     }
+
+    /// Replace the UDP socket used for output, keeping all KCP state
+    pub(crate) fn set_udp_socket(&mut self, udp: Arc<Async<std::net::UdpSocket>>) {
+        self.connected = udp.get_ref().peer_addr().is_ok();
+        self.ipv6_socket = is_ipv6(udp.get_ref());
+        self.udp = udp;
+    }
+
+    /// Send and receive through a SOCKS5 UDP relay, the UDP socket must be
+    /// connected to the relay
+    pub(crate) fn set_socks5(&mut self, association: Socks5Association) {
+        self.socks5 = Some(association);
+    }
+
+    pub(crate) fn is_proxied(&self) -> bool {
+        self.socks5.is_some()
+    }
+
+    /// KCP data of a datagram read from the UDP socket, `None` if the
+    /// proxy sent something that is not for us
+    pub(crate) fn unwrap_datagram<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        match &self.socks5 {
+            Some(socks5) => socks5.decapsulate(datagram),
+            None => Some(datagram),
+        }
+    }
+
+    /// Queue a datagram of the message lane behind KCP's output so far
+    fn queue(&mut self, datagram: Vec<u8>) {
+        self.output.extend(self.kcp.drain_transmit());
+        self.output.push_back(datagram);
+    }
+
+    /// Send datagrams queued by KCP to the peer
+    ///
+    /// KCP produces output synchronously from `update`/`flush`/`input`, so
+    /// every call that may flush must be followed by this to put the
+    /// datagrams on the wire without blocking the executor thread.
+    pub async fn send_output(&mut self) -> io::Result<()> {
+        let mut retransmitted = 0;
+        self.output.extend(self.kcp.drain_transmit());
+        let conv = self.kcp.conv();
+        let sealed = self.checksum;
+        if let Some(paths) = &mut self.paths {
+            let now = Instant::now();
+            self.path_output.extend(paths.poll_joins(conv, now));
+            self.path_output.extend(paths.poll_challenges(conv, now));
+            for (index, mut datagram) in mem::take(&mut self.path_output) {
+                if sealed {
+                    checksum::append(&mut datagram);
+                }
+                Self::send_on_path(paths, index, &datagram, &datagram, self.ipv6_socket).await;
+            }
+            for peer in paths.expire_challenges(now) {
+                debug!(
+                    "{} path from {} removed, its challenges went unanswered",
+                    SessionTag::new(conv, self.peer_addr),
+                    peer
+                );
+                self.dropped_paths.push(peer);
+            }
+        }
+        if let Some(offer) = self.route.as_mut().and_then(|route| route.poll_offer(conv, Instant::now())) {
+            self.output.push_back(offer);
+        }
+        for datagram in mem::take(&mut self.output) {
+            let handler = self.event_handler.as_deref();
+            let retransmissions = self.counters.on_sent(&datagram, |sn| {
+                if let Some(handler) = handler {
+                    handler(&KcpEvent::Retransmitted { sn });
+                }
+            });
+            let encoded = self.header_format.encode(&datagram);
+            let wire = self.wire(conv, &encoded);
+            let wire = wire.as_deref().unwrap_or(&encoded);
+            let n = if let Some(paths) = &mut self.paths {
+                let mut n = 0;
+                for index in paths.targets(&datagram, retransmissions > 0) {
+                    n = Self::send_on_path(paths, index, &datagram, wire, self.ipv6_socket).await;
+                }
+                n
+            } else if let Some(socks5) = &self.socks5 {
+                self.udp.send(&socks5.encapsulate(wire)).await?
+            } else if self.connected {
+                self.udp.send(wire).await?
+            } else {
+                self.udp.send_to(wire, send_addr(self.peer_addr, self.ipv6_socket)).await?
+            };
+            self.metrics.on_sent(wire.len(), retransmissions);
+            retransmitted += retransmissions;
+            if self.log_segments {
+                packet::log_datagram("->", self.peer_addr, &datagram);
+            }
+            if let Some((pcap, local)) = &self.pcap {
+                pcap.write_datagram(*local, self.peer_addr, &encoded);
+            }
+            trace!("{} UDP sent {} bytes", self.tag(), n);
+        }
+
+        if retransmitted > 0 {
+            self.trace.retransmitted(retransmitted);
+            self.classify_retransmissions(retransmitted);
+        }
+        Ok(())
+    }
+
+    /// `datagram` behind the handshake payload until the server answered,
+    /// behind the session id once the server accepted it and with the
+    /// checksum trailer, `None` if it goes out as it is
+    fn wire(&self, conv: u32, datagram: &[u8]) -> Option<Vec<u8>> {
+        let route = self.route.as_ref().filter(|route| route.is_confirmed());
+        if route.is_none() && self.hello.is_none() && !self.checksum {
+            return None;
+        }
+        let mut wire = match (&self.hello, route) {
+            (Some(hello), _) => hello::wrap(conv, hello, datagram),
+            (None, Some(route)) => route.wrap(conv, datagram),
+            (None, None) => datagram.to_vec(),
+        };
+        if self.checksum {
+            checksum::append(&mut wire);
+        }
+        Some(wire)
+    }
+
+    /// Add the checksum trailer if enabled, for datagrams sent around
+    /// [`KcpSocket::send_output`]
+    fn seal(&self, mut datagram: Vec<u8>) -> Vec<u8> {
+        if self.checksum {
+            checksum::append(&mut datagram);
+        }
+        datagram
+    }
+
+    /// Send `service` and `payload` with every datagram until the server
+    /// answers, see [`crate::hello`]
+    ///
+    /// Client sessions only, before anything was sent.
+    pub(crate) fn send_hello(&mut self, service: &str, payload: &[u8]) -> KcpResult<()> {
+        let body = hello::encode(service, payload).filter(|_| self.extensions);
+        let Some(body) = body else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "handshakes need KcpInterop::Native, service names of at most {} bytes and payloads of at most {}",
+                    hello::MAX_SERVICE_LEN,
+                    hello::MAX_HELLO_LEN
+                ),
+            )
+            .into());
+        };
+        let mtu = self.kcp.mtu().saturating_sub(hello::overhead(&body));
+        self.kcp.set_mtu(mtu)?;
+        self.hello = Some(body);
+        Ok(())
+    }
+
+    /// Stop wrapping output in the handshake payload and give its room
+    /// back to KCP
+    fn hello_answered(&mut self) -> KcpResult<()> {
+        if let Some(hello) = self.hello.take() {
+            let mtu = self.kcp.mtu() + hello::overhead(&hello);
+            self.kcp.set_mtu(mtu)?;
+            trace!("{} handshake payload delivered", self.tag());
+        }
+        Ok(())
+    }
+
+    /// Handshake payload the client connected with, see
+    /// [`KcpStream::connect_with_hello`](crate::KcpStream::connect_with_hello)
+    pub fn peer_hello(&self) -> Option<&[u8]> {
+        self.peer_hello.as_deref()
+    }
+
+    /// Service the client asked for, see
+    /// [`KcpStream::connect_to_service`](crate::KcpStream::connect_to_service)
+    pub fn peer_service(&self) -> Option<&str> {
+        self.peer_service.as_deref()
+    }
+
+    pub(crate) fn set_peer_hello(&mut self, hello: &hello::Hello<'_>) {
+        self.peer_hello = hello.payload.map(<[u8]>::to_vec);
+        self.peer_service = hello.service.map(str::to_owned);
+    }
+
+    /// Offer the server a 64-bit session id to route by, see
+    /// [`crate::route`]
+    ///
+    /// Client sessions only. Multipath sessions and peers without the
+    /// smol-kcp extensions keep the classic format.
+    pub(crate) fn offer_session_id(&mut self) {
+        if self.extensions && self.paths.is_none() {
+            self.route = Some(SessionRoute::new());
+        }
+    }
+
+    /// Take the session id a client offered and queue the echo, with the
+    /// reset token derived from `reset_key` if there is one
+    pub(crate) fn accept_session_id(&mut self, id: u64, reset_key: Option<&[u8; 16]>) {
+        if self.session_id.replace(id).is_none() {
+            debug!("{} routed by session id {:016x}", self.tag(), id);
+        }
+        let conv = self.kcp.conv();
+        let echo = match reset_key {
+            Some(key) => packet::encode(conv, packet::CMD_SESSION_ID, &reset::body(id, reset::token(key, conv, id))),
+            None => packet::encode(conv, packet::CMD_SESSION_ID, &id.to_le_bytes()),
+        };
+        self.queue(echo);
+    }
+
+    /// 64-bit id the session is routed by, `None` until client and server
+    /// agreed on one
+    pub(crate) fn session_id(&self) -> Option<u64> {
+        self.session_id
+    }
+
+    /// Send a datagram on one path of a multipath session as `wire`,
+    /// returns the bytes sent
+    ///
+    /// A failing path does not fail the session, KCP retransmits on the
+    /// others.
+    async fn send_on_path(
+        paths: &mut PathSet,
+        index: usize,
+        datagram: &[u8],
+        wire: &[u8],
+        ipv6_socket: bool,
+    ) -> usize {
+        let udp = paths.udp(index);
+        let result = if paths.is_connected(index) {
+            udp.send(wire).await
+        } else {
+            udp.send_to(wire, send_addr(paths.peer(index), ipv6_socket)).await
+        };
+        match result {
+            Ok(n) => {
+                paths.on_sent(index, datagram);
+                n
+            }
+            Err(e) => {
+                trace!("path to {} failed to send: {}", paths.peer(index), e);
+                0
+            }
+        }
+    }
+
+    /// Spread output over several paths, see [`crate::multipath`]
+    ///
+    /// Striping segments over paths of different RTT reorders them, so the
+    /// fast resend threshold of `config` is doubled.
+    pub(crate) fn enable_multipath(&mut self, config: &KcpConfig, mode: MultipathMode) -> KcpResult<()> {
+        if !self.extensions {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "multipath is a smol-kcp extension",
+            )
+            .into());
+        }
+        if self.paths.is_none() {
+            if mode == MultipathMode::Stripe && config.nodelay.resend > 0 {
+                self.kcp.set_fast_resend(config.nodelay.resend as u32 * 2);
+            }
+            self.paths = Some(PathSet::new(mode, self.udp.clone(), self.peer_addr));
+            // Joins and path changes go by conv and address
+            if self.route.take().is_some() {
+                self.session_id = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a client path on `udp`, connected to the peer
+    pub(crate) fn add_path(&mut self, udp: Arc<Async<std::net::UdpSocket>>) -> KcpResult<()> {
+        let peer = self.peer_addr;
+        let added = self.paths.as_mut().and_then(|paths| paths.add(udp, peer));
+        match added {
+            Some(id) => {
+                trace!("{} path {} added", self.tag(), id);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("multipath sessions have at most {} paths", MAX_PATHS),
+            )
+            .into()),
+        }
+    }
+
+    /// Take `peer` as a path of this session after it sent a join, and
+    /// queue the echo
+    ///
+    /// Returns false for sessions without the smol-kcp extensions, a
+    /// malformed join or a session with all paths taken.
+    pub(crate) fn join_path(&mut self, config: &KcpConfig, peer: SocketAddr, body: &[u8]) -> bool {
+        if self.enable_multipath(config, MultipathMode::Stripe).is_err() {
+            return false;
+        }
+        let tag = self.tag();
+        let Some(paths) = &mut self.paths else {
+            return false;
+        };
+        let known = paths.position(peer).is_some();
+        let Some((index, echo)) = paths.join(&self.udp, peer, self.kcp.conv(), body) else {
+            return false;
+        };
+        if !known {
+            debug!("{} path joined from {} ({:?})", tag, peer, paths.mode());
+        }
+        self.path_output.push((index, echo));
+        true
+    }
+
+    /// Challenge to send to `peer`, a new address the session's packets
+    /// arrive from, `None` while one sent there is still fresh
+    ///
+    /// The session only moves once the peer echoes the token from there,
+    /// see [`KcpSocket::complete_migration`], so a spoofed source address
+    /// cannot redirect it. Peers without the smol-kcp extensions cannot
+    /// answer and multipath sessions add paths instead, neither migrates.
+    pub(crate) fn migration_challenge(&mut self, peer: SocketAddr) -> Option<Vec<u8>> {
+        if !self.extensions || self.paths.is_some() || peer == self.peer_addr {
+            return None;
+        }
+        let retry = self.rtt.rto(self.interval);
+        if let Some(migration) = &self.migration {
+            if migration.peer == peer && migration.sent.elapsed() < retry {
+                return None;
+            }
+        }
+        // The latest new address replaces any earlier one
+        let token = random_token().to_le_bytes();
+        self.migration = Some(Migration {
+            peer,
+            token,
+            sent: Instant::now(),
+        });
+        trace!("{} challenging new address {}", self.tag(), peer);
+        Some(self.seal(packet::encode(self.kcp.conv(), packet::CMD_CHALLENGE, &token)))
+    }
+
+    /// Move the session to `peer` if `token` answers its challenge,
+    /// returns the address it moved from
+    pub(crate) fn complete_migration(&mut self, peer: SocketAddr, token: &[u8]) -> Option<SocketAddr> {
+        match &self.migration {
+            Some(migration) if migration.peer == peer && migration.token == token => {}
+            _ => return None,
+        }
+        self.migration = None;
+        let from = mem::replace(&mut self.peer_addr, peer);
+        debug!("{} migrated from {}", self.tag(), from);
+        self.emit(KcpEvent::PeerMigrated { from, to: peer });
+        Some(from)
+    }
+
+    /// The next input arrived from `peer`, one of the paths
+    pub(crate) fn note_input_from(&mut self, peer: SocketAddr) {
+        if let Some(paths) = &mut self.paths {
+            if let Some(index) = paths.position(peer) {
+                paths.note_input(index);
+            }
+        }
+    }
+
+    /// The next input arrived on client path `index`
+    pub(crate) fn note_input_on(&mut self, index: usize) {
+        if let Some(paths) = &mut self.paths {
+            paths.note_input(index);
+        }
+    }
+
+    /// Peer addresses of all paths, empty unless multipath
+    ///
+    /// Includes the paths removed since the last
+    /// [`KcpSocket::take_dropped_paths`], the listener may still route
+    /// their addresses here.
+    pub(crate) fn path_peers(&self) -> Vec<SocketAddr> {
+        self.paths
+            .as_ref()
+            .map(|paths| paths.peers().chain(self.dropped_paths.iter().copied()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Peer addresses of the paths removed since the last call
+    pub(crate) fn take_dropped_paths(&mut self) -> Vec<SocketAddr> {
+        mem::take(&mut self.dropped_paths)
+    }
+
+    /// Statistics of every path, empty unless multipath
+    pub(crate) fn path_stats(&self) -> Vec<PathStats> {
+        self.paths.as_ref().map(PathSet::stats).unwrap_or_default()
+    }
+
+    /// Decode and log every segment header sent and received
+    ///
+    /// Lines are logged at debug level with the `smol_kcp::wire` target.
+    pub fn set_segment_logging(&mut self, enabled: bool) {
+        self.log_segments = enabled;
+    }
+
+    /// Write sent and received datagrams to a pcap capture
+    pub(crate) fn set_pcap(&mut self, pcap: Option<PcapWriter>) {
+        let local = self
+            .udp
+            .get_ref()
+            .local_addr()
+            .unwrap_or_else(|_| SocketAddr::new(std::net::Ipv4Addr::UNSPECIFIED.into(), 0));
+        self.pcap = pcap.map(|pcap| (pcap, local));
+    }
+
+    /// Register a callback for connection events, replacing any previous one
+    pub fn set_event_handler(&mut self, handler: Option<KcpEventHandler>) {
+        self.last_xmit = self.kcp.internals().xmit;
+        self.event_handler = handler;
+    }
+
+    /// Attach application data to the session, replacing any previous
+    pub fn set_user_data(&mut self, data: Option<UserData>) {
+        self.user_data = data;
+    }
+
+    /// Data attached with [`KcpSocket::set_user_data`]
+    pub fn user_data(&self) -> Option<&UserData> {
+        self.user_data.as_ref()
+    }
+
+    fn emit(&self, event: KcpEvent) {
+        if let Some(handler) = &self.event_handler {
+            handler(&event);
+        }
+    }
+
+    /// Split retransmissions into timer-driven and fast resends
+    ///
+    /// KCP only counts timeout retransmissions, everything else it resent
+    /// was triggered by duplicate ACKs.
+    fn classify_retransmissions(&mut self, retransmitted: u64) {
+        if self.event_handler.is_none() {
+            return;
+        }
+
+        let internals = self.kcp.internals();
+        let timeouts = internals.xmit.wrapping_sub(self.last_xmit);
+        self.last_xmit = internals.xmit;
+
+        let fast = (retransmitted as u32).saturating_sub(timeouts);
+        if timeouts > 0 {
+            self.emit(KcpEvent::RtoBackoff {
+                segments: timeouts,
+                rto: Duration::from_millis(internals.rx_rto as u64),
+            });
+        }
+        if fast > 0 {
+            self.emit(KcpEvent::FastResend { segments: fast });
+        }
+    }
+
+    /// Payload bytes per second acknowledged by the peer, see
+    /// [`KcpStats::goodput`]
+    pub fn goodput(&self) -> u64 {
+        self.bandwidth.goodput()
+    }
+
+    /// Bandwidth of the path in bytes per second, see
+    /// [`KcpStats::estimated_bandwidth`]
+    pub fn estimated_bandwidth(&self) -> u64 {
+        self.bandwidth.bandwidth()
+    }
+
+    /// Delay of each direction, see [`OneWayDelay`]
+    pub fn one_way_delay(&self) -> OneWayDelay {
+        self.delay.estimate()
+    }
+
+    /// Current RTT and jitter estimate
+    pub fn rtt(&self) -> RttEstimate {
+        self.rtt.estimate()
+    }
+
+    /// Latency percentiles, `None` unless enabled in the config
+    pub(crate) fn latency_report(&self) -> Option<LatencyReport> {
+        self.latency.as_ref().map(LatencyTracker::report)
+    }
+
+    /// Clear the latency histograms
+    pub(crate) fn reset_latency(&mut self) {
+        if let Some(latency) = &mut self.latency {
+            latency.reset();
+        }
+    }
+
+    /// Periodic statistics samples, empty unless enabled in the config
+    pub(crate) fn stats_history(&self) -> Vec<StatsSample> {
+        self.history
+            .as_ref()
+            .map(StatsHistory::samples)
+            .unwrap_or_default()
+    }
+
+    /// Current windows and what limits sending
+    pub fn window_status(&self) -> WindowStatus {
+        let internals = self.kcp.internals();
+        WindowStatus::new(&internals, self.kcp.snd_wnd(), self.kcp.rmt_wnd())
+    }
+
+    /// Who the session is with and how it is doing, for listing sessions
+    pub fn info(&self) -> SessionInfo {
+        let now = Instant::now();
+        let state = if self.closed.is_some() {
+            SessionState::Closed
+        } else if self.peer_closed.is_some() {
+            SessionState::PeerClosed
+        } else {
+            SessionState::Open
+        };
+        SessionInfo {
+            peer: self.peer_addr,
+            conv: self.kcp.conv(),
+            state,
+            idle: self.since_input(),
+            bytes_sent: self.counters.bytes_sent,
+            bytes_received: self.counters.bytes_received,
+            send_rate: self.counters.send_rate.rate(now),
+            receive_rate: self.counters.receive_rate.rate(now),
+            user_data: self.user_data.clone(),
+        }
+    }
+
+    /// Snapshot of the connection statistics
+    pub fn stats(&self) -> KcpStats {
+        let internals = self.kcp.internals();
+        let counters = &self.counters;
+        let timeout_retransmissions = internals.xmit as u64;
+        let now = Instant::now();
+
+        KcpStats {
+            srtt: Duration::from_millis(internals.rx_srtt as u64),
+            rttvar: Duration::from_millis(internals.rx_rttval as u64),
+            rto: Duration::from_millis(internals.rx_rto as u64),
+            cwnd: internals.cwnd,
+            ssthresh: internals.ssthresh,
+            snd_wnd: self.kcp.snd_wnd(),
+            rcv_wnd: self.kcp.rcv_wnd(),
+            rmt_wnd: self.kcp.rmt_wnd(),
+            snd_queue_len: internals.snd_queue_len,
+            snd_buf_len: internals.snd_buf_len,
+            rcv_queue_len: internals.rcv_queue_len,
+            rcv_buf_len: internals.rcv_buf_len,
+            bytes_sent: counters.bytes_sent,
+            bytes_received: counters.bytes_received,
+            packets_sent: counters.packets_sent,
+            packets_received: counters.packets_received,
+            segments_sent: counters.segments_sent,
+            segments_received: counters.segments_received,
+            retransmissions: counters.retransmissions,
+            timeout_retransmissions,
+            fast_retransmissions: counters.retransmissions.saturating_sub(timeout_retransmissions),
+            expired_messages: internals.expired as u64,
+            conditions: counters.conditions,
+            corrupted_datagrams: counters.corrupted,
+            goodput: self.bandwidth.goodput(),
+            estimated_bandwidth: self.bandwidth.bandwidth(),
+            one_way_delay: self.delay.estimate(),
+            send_rate: counters.send_rate.rate(now),
+            receive_rate: counters.receive_rate.rate(now),
+        }
+    }
 }
 
-/// KCP output implementation
-struct KcpOutput {
-    udp: Arc<Async<std::net::UdpSocket>>,
-    peer_addr: SocketAddr,
+/// Why the library closed a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The peer opened a new conversation from the same address
+    PeerRestarted,
+    /// Buffered data exceeded a memory limit
+    MemoryExceeded,
+    /// The peer stopped acknowledging for `session_expire`
+    Expired,
+    /// Expired while ICMP reported the peer's port closed
+    Refused,
+    /// The peer never answered within `handshake_timeout`
+    HandshakeTimeout,
+    /// The application closed or dropped the session
+    Closed(CloseCode),
+    /// The server lost the session and said so with its reset token, see
+    /// [`crate::reset`]
+    ServerReset,
 }
 
-impl KcpOutput {
-    fn new(udp: Arc<Async<std::net::UdpSocket>>, peer_addr: SocketAddr) -> Self {
-        Self { udp, peer_addr }
+impl CloseReason {
+    fn error(self) -> Error {
+        match self {
+            CloseReason::PeerRestarted | CloseReason::ServerReset => Error::ConnectionReset,
+            CloseReason::MemoryExceeded => Error::MemoryLimitExceeded,
+            CloseReason::Expired => Error::SessionExpired,
+            CloseReason::Refused => Error::ConnectionRefused,
+            CloseReason::HandshakeTimeout => Error::HandshakeTimeout,
+            CloseReason::Closed(_) => Error::ConnectionClosed,
+        }
+    }
+
+    /// Code the close frame tells the peer
+    fn code(self) -> CloseCode {
+        match self {
+            CloseReason::PeerRestarted | CloseReason::MemoryExceeded | CloseReason::ServerReset => {
+                CloseCode::ProtocolError
+            }
+            CloseReason::Expired | CloseReason::Refused | CloseReason::HandshakeTimeout => {
+                CloseCode::IdleTimeout
+            }
+            CloseReason::Closed(code) => code,
+        }
     }
 }
 
-impl Write for KcpOutput {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // Use blocking send_to for simplicity in this minimal implementation
-        match self.udp.get_ref().send_to(buf, self.peer_addr) {
-            Ok(n) => {
-                trace!("UDP sent {} bytes to {}", n, self.peer_addr);
-                Ok(n)
+/// Summary of a session, see [`KcpSocket::info`] and
+/// [`KcpListener::sessions`](crate::KcpListener::sessions)
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Address the session sends to
+    pub peer: SocketAddr,
+    pub conv: u32,
+    pub state: SessionState,
+    /// Time since the last datagram from the peer
+    pub idle: Duration,
+    /// UDP payload bytes sent, headers and retransmissions included
+    pub bytes_sent: u64,
+    /// UDP payload bytes received
+    pub bytes_received: u64,
+    /// Bytes per second sent, smoothed over a few seconds
+    pub send_rate: u64,
+    /// Bytes per second received, smoothed over a few seconds
+    pub receive_rate: u64,
+    /// Data the application attached, see [`UserData`]
+    pub user_data: Option<UserData>,
+}
+
+/// Lifecycle state of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionState {
+    Open,
+    /// The peer sent a close frame, data received before it can still be
+    /// read
+    PeerClosed,
+    /// Closed on this side: by the application, on expiry or for
+    /// exceeding a memory limit
+    Closed,
+}
+
+/// Why a session was closed, carried in the close frame to the peer
+///
+/// The peer sees it through [`KcpSocket::peer_close_code`] and
+/// [`Error::ClosedByPeer`]. Close frames of older versions carry no code
+/// and read as [`CloseCode::Normal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CloseCode {
+    /// The application closed or dropped the session
+    Normal,
+    /// The session was idle or the peer stopped answering for too long
+    IdleTimeout,
+    /// The server is going away
+    ServerShutdown,
+    /// The session broke the rules, e.g. exceeded a memory limit
+    ProtocolError,
+    /// The server is at its connection limit, see
+    /// [`KcpListener::set_serve_limit`](crate::KcpListener::set_serve_limit)
+    Overloaded,
+    /// An operator ended the session, see
+    /// [`KcpListener::disconnect`](crate::KcpListener::disconnect)
+    Disconnected,
+    /// The application aborted the session with its own error code, see
+    /// [`KcpStream::reset`](crate::KcpStream::reset)
+    Application(u32),
+}
+
+impl CloseCode {
+    /// Close frame body: one byte, followed by the 32-bit little-endian
+    /// code for [`CloseCode::Application`]
+    pub(crate) fn encode(self) -> Vec<u8> {
+        match self {
+            CloseCode::Normal => vec![0],
+            CloseCode::IdleTimeout => vec![1],
+            CloseCode::ServerShutdown => vec![2],
+            CloseCode::ProtocolError => vec![3],
+            CloseCode::Overloaded => vec![5],
+            CloseCode::Disconnected => vec![6],
+            CloseCode::Application(code) => {
+                let mut body = vec![4];
+                body.extend_from_slice(&code.to_le_bytes());
+                body
             }
-            Err(e) => Err(e),
         }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    /// Code of a close frame body, unknown codes of newer versions read
+    /// as a normal close
+    fn decode(body: &[u8]) -> Self {
+        match *body {
+            [1, ..] => CloseCode::IdleTimeout,
+            [2, ..] => CloseCode::ServerShutdown,
+            [3, ..] => CloseCode::ProtocolError,
+            [4, a, b, c, d, ..] => CloseCode::Application(u32::from_le_bytes([a, b, c, d])),
+            [5, ..] => CloseCode::Overloaded,
+            [6, ..] => CloseCode::Disconnected,
+            _ => CloseCode::Normal,
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseCode::Normal => f.write_str("normal close"),
+            CloseCode::IdleTimeout => f.write_str("idle timeout"),
+            CloseCode::ServerShutdown => f.write_str("server shutdown"),
+            CloseCode::ProtocolError => f.write_str("protocol error"),
+            CloseCode::Overloaded => f.write_str("server overloaded"),
+            CloseCode::Disconnected => f.write_str("disconnected by the server"),
+            CloseCode::Application(code) => write!(f, "application error {}", code),
+        }
     }
 }
 
+/// Version of the [`KcpSocket::export_state`] format
+///
+/// Version 1 stored the unread data as one blob, version 2 as a list of
+/// messages. Both are read.
+const STATE_VERSION: u8 = 2;
+
+/// A parsed [`KcpSocket::export_state`]
+pub(crate) struct ExportedState<'a> {
+    pub peer: SocketAddr,
+    pub local: SocketAddr,
+    /// Messages taken from KCP but not read by the application yet
+    pub unread: Vec<&'a [u8]>,
+    next_msg_id: u32,
+    engine: &'a [u8],
+}
+
+impl<'a> ExportedState<'a> {
+    pub fn parse(state: &'a [u8]) -> KcpResult<Self> {
+        fn invalid() -> Error {
+            io::Error::new(io::ErrorKind::InvalidData, "invalid exported session state").into()
+        }
+        fn take<'a>(rest: &mut &'a [u8], len: usize) -> KcpResult<&'a [u8]> {
+            if rest.len() < len {
+                return Err(invalid());
+            }
+            let (head, tail) = rest.split_at(len);
+            *rest = tail;
+            Ok(head)
+        }
+        fn len(rest: &mut &[u8]) -> KcpResult<usize> {
+            let len = take(rest, 4)?;
+            Ok(u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize)
+        }
+        fn addr(rest: &mut &[u8]) -> KcpResult<SocketAddr> {
+            let len = take(rest, 1)?[0] as usize;
+            std::str::from_utf8(take(rest, len)?)
+                .ok()
+                .and_then(|addr| addr.parse().ok())
+                .ok_or_else(invalid)
+        }
+
+        let mut rest = state;
+        let version = take(&mut rest, 1)?[0];
+        if !(1..=STATE_VERSION).contains(&version) {
+            return Err(invalid());
+        }
+        let peer = addr(&mut rest)?;
+        let local = addr(&mut rest)?;
+        let id = take(&mut rest, 4)?;
+        let next_msg_id = u32::from_le_bytes([id[0], id[1], id[2], id[3]]);
+        let unread = if version == 1 {
+            let n = len(&mut rest)?;
+            let blob = take(&mut rest, n)?;
+            if blob.is_empty() {
+                Vec::new()
+            } else {
+                vec![blob]
+            }
+        } else {
+            let count = len(&mut rest)?;
+            let mut unread = Vec::new();
+            for _ in 0..count {
+                let n = len(&mut rest)?;
+                unread.push(take(&mut rest, n)?);
+            }
+            unread
+        };
+        Ok(Self {
+            peer,
+            local,
+            unread,
+            next_msg_id,
+            engine: rest,
+        })
+    }
+}
+
+/// Session identifier prefixed to log messages
+///
+/// Lets the logs of many concurrent sessions on one listener be told
+/// apart, formats as `[conv=1234 peer=10.0.0.2:4000]`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SessionTag {
+    conv: u32,
+    peer: SocketAddr,
+}
+
+impl SessionTag {
+    pub fn new(conv: u32, peer: SocketAddr) -> Self {
+        Self { conv, peer }
+    }
+}
+
+impl fmt::Display for SessionTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[conv={} peer={}]", self.conv, self.peer)
+    }
+}
+
+/// `::ffff:a.b.c.d` as `a.b.c.d`, so a client reaching a dual-stack
+/// socket over IPv4 is one peer however its address is written
+pub(crate) fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// `addr` as a socket bound to IPv6 sends to it, IPv4 addresses in their
+/// mapped form
+pub(crate) fn send_addr(addr: SocketAddr, ipv6_socket: bool) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if ipv6_socket => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        addr => addr,
+    }
+}
+
+pub(crate) fn is_ipv6(udp: &std::net::UdpSocket) -> bool {
+    udp.local_addr().is_ok_and(|addr| addr.is_ipv6())
+}
+
+/// Bind an IPv4 UDP port other sockets of the host bind too, such as the
+/// mDNS port of other responders or a multicast group several receivers
+/// join
+#[cfg(all(unix, any(feature = "discovery", feature = "multicast")))]
+pub(crate) fn bind_shared(port: u16) -> io::Result<std::net::UdpSocket> {
+    use std::{
+        mem,
+        os::fd::{FromRawFd, OwnedFd},
+    };
+
+    // SAFETY: plain socket call, the descriptor is owned right away
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a fresh descriptor
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let enable: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        // SAFETY: the option value is a live c_int of the given size
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // SAFETY: all-zero is a valid sockaddr_in
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = port.to_be();
+    // SAFETY: addr is a sockaddr_in of the given size
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(std::net::UdpSocket::from(socket))
+}
+
+#[cfg(all(not(unix), any(feature = "discovery", feature = "multicast")))]
+pub(crate) fn bind_shared(port: u16) -> io::Result<std::net::UdpSocket> {
+    std::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, port))
+}
+
+/// Apply platform specific options to a freshly created UDP socket
+pub(crate) fn configure_udp(udp: &std::net::UdpSocket) -> io::Result<()> {
+    #[cfg(windows)]
+    disable_udp_connreset(udp)?;
+    #[cfg(not(windows))]
+    let _ = udp;
+    Ok(())
+}
+
+/// Stop Windows from failing `recv_from` with `WSAECONNRESET` whenever an
+/// ICMP port-unreachable arrives for an earlier datagram, which would
+/// otherwise break the listener loop every time a client goes away.
+#[cfg(windows)]
+fn disable_udp_connreset(udp: &std::net::UdpSocket) -> io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+    use windows_sys::Win32::Networking::WinSock::{WSAIoctl, SIO_UDP_CONNRESET};
+
+    let enable: u32 = 0;
+    let mut returned: u32 = 0;
+    // SAFETY: the socket handle is valid for the duration of the call and the
+    // input buffer points to a live u32 of the advertised size.
+    let ret = unsafe {
+        WSAIoctl(
+            udp.as_raw_socket() as usize,
+            SIO_UDP_CONNRESET,
+            &enable as *const u32 as *const _,
+            mem::size_of::<u32>() as u32,
+            std::ptr::null_mut(),
+            0,
+            &mut returned,
+            std::ptr::null_mut(),
+            None,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Start of KCP's clock, shared by every session of the process
+static CLOCK_START: OnceLock<Instant> = OnceLock::new();
+
+/// Milliseconds on a monotonic clock, wrapping after about 49.7 days
+///
+/// Steps of the system clock must not move RTOs and flush deadlines.
 fn current_millis() -> u32 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u32
-}
\ No newline at end of file
+    CLOCK_START.get_or_init(Instant::now).elapsed().as_millis() as u32
+}
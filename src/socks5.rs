@@ -0,0 +1,168 @@
+//! SOCKS5 UDP ASSOCIATE (RFC 1928) for client connections
+//!
+//! The proxy relays datagrams between its UDP relay port and the target,
+//! each datagram in either direction carries a header naming the remote
+//! end. The association lasts as long as the TCP control connection.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+};
+
+use async_io::Async;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// An open UDP association with a SOCKS5 proxy
+pub(crate) struct Socks5Association {
+    /// Closing it ends the association
+    _control: Async<TcpStream>,
+    /// Header prefixed to every datagram for the target
+    header: Vec<u8>,
+}
+
+impl Socks5Association {
+    /// Ask `proxy` to relay datagrams sent from `local` to `target`
+    ///
+    /// Returns the association and the relay address to send to.
+    pub async fn open(
+        proxy: SocketAddr,
+        local: SocketAddr,
+        target: SocketAddr,
+    ) -> io::Result<(Self, SocketAddr)> {
+        let mut control = Async::<TcpStream>::connect(proxy).await?;
+
+        control.write_all(&[VERSION, 1, NO_AUTH]).await?;
+        let mut choice = [0u8; 2];
+        control.read_exact(&mut choice).await?;
+        if choice[0] != VERSION {
+            return Err(protocol_error("proxy does not speak SOCKS5"));
+        }
+        if choice[1] != NO_AUTH {
+            return Err(protocol_error("proxy requires authentication"));
+        }
+
+        // Only the port is known, the address the proxy sees may be NATed
+        let local = SocketAddr::new(unspecified(local.ip()), local.port());
+        let mut request = vec![VERSION, CMD_UDP_ASSOCIATE, 0];
+        write_addr(&mut request, local);
+        control.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        control.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(protocol_error("proxy does not speak SOCKS5"));
+        }
+        if reply[1] != 0 {
+            return Err(protocol_error(&format!(
+                "UDP ASSOCIATE refused: {}",
+                reply_message(reply[1])
+            )));
+        }
+        let mut relay = read_addr(&mut control, reply[3]).await?;
+        // An unspecified address means the address of the proxy
+        if relay.ip().is_unspecified() {
+            relay.set_ip(proxy.ip());
+        }
+
+        let mut header = vec![0, 0, 0];
+        write_addr(&mut header, target);
+        Ok((
+            Self {
+                _control: control,
+                header,
+            },
+            relay,
+        ))
+    }
+
+    /// Wrap a datagram for the relay
+    pub fn encapsulate(&self, datagram: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(self.header.len() + datagram.len());
+        packet.extend_from_slice(&self.header);
+        packet.extend_from_slice(datagram);
+        packet
+    }
+
+    /// Payload of a datagram from the relay, `None` for malformed and
+    /// fragmented ones
+    pub fn decapsulate<'a>(&self, packet: &'a [u8]) -> Option<&'a [u8]> {
+        // RSV(2) FRAG(1) ATYP(1)
+        let (&[0, 0, 0, atyp], rest) = packet.split_first_chunk::<4>()? else {
+            return None;
+        };
+        let addr_len = match atyp {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => 1 + *rest.first()? as usize,
+            _ => return None,
+        };
+        rest.get(addr_len + 2..)
+    }
+}
+
+fn unspecified(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Append ATYP, address and port
+fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Read the address and port of a reply
+async fn read_addr(control: &mut Async<TcpStream>, atyp: u8) -> io::Result<SocketAddr> {
+    let ip: IpAddr = match atyp {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            control.read_exact(&mut octets).await?;
+            octets.into()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            control.read_exact(&mut octets).await?;
+            octets.into()
+        }
+        _ => return Err(protocol_error("proxy replied with an unsupported address type")),
+    };
+    let mut port = [0u8; 2];
+    control.read_exact(&mut port).await?;
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -0,0 +1,804 @@
+//! Per-connection statistics
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime},
+};
+
+use smol_kcp_core::{serial, Internals};
+
+use crate::packet::{self, KCP_CMD_PUSH};
+
+/// Snapshot of a connection's state and counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KcpStats {
+    /// Smoothed round trip time
+    pub srtt: Duration,
+    /// Round trip time variance
+    pub rttvar: Duration,
+    /// Current retransmission timeout
+    pub rto: Duration,
+    /// Congestion window in segments
+    pub cwnd: u16,
+    /// Slow start threshold in segments
+    pub ssthresh: u16,
+    /// Local send window in segments
+    pub snd_wnd: u16,
+    /// Local receive window in segments
+    pub rcv_wnd: u16,
+    /// Receive window last advertised by the peer
+    pub rmt_wnd: u16,
+    /// Segments queued but not yet sent
+    pub snd_queue_len: usize,
+    /// Segments sent and waiting for acknowledgement
+    pub snd_buf_len: usize,
+    /// Segments received in order, waiting for the application
+    pub rcv_queue_len: usize,
+    /// Segments received out of order
+    pub rcv_buf_len: usize,
+    /// UDP payload bytes sent
+    pub bytes_sent: u64,
+    /// UDP payload bytes received
+    pub bytes_received: u64,
+    /// Datagrams sent
+    pub packets_sent: u64,
+    /// Datagrams received
+    pub packets_received: u64,
+    /// KCP segments sent, including retransmissions and ACKs
+    pub segments_sent: u64,
+    /// KCP segments received
+    pub segments_received: u64,
+    /// Data segments sent more than once
+    pub retransmissions: u64,
+    /// Retransmissions triggered by the retransmission timer
+    pub timeout_retransmissions: u64,
+    /// Retransmissions triggered by duplicate ACKs
+    pub fast_retransmissions: u64,
+    /// Messages dropped unsent because their deadline passed
+    pub expired_messages: u64,
+    /// Loss, reordering and duplication seen on the path
+    pub conditions: NetworkConditions,
+    /// Datagrams dropped for failing their checksum, see
+    /// [`KcpConfig::checksum`](crate::KcpConfig::checksum)
+    pub corrupted_datagrams: u64,
+    /// Payload bytes per second acknowledged by the peer, smoothed over a
+    /// few RTTs
+    pub goodput: u64,
+    /// Highest rate payload was acknowledged at over the last ten RTTs
+    /// the application kept the window busy, in bytes per second
+    pub estimated_bandwidth: u64,
+    /// Delay of each direction and its trend
+    pub one_way_delay: OneWayDelay,
+    /// Bytes per second sent, like `bytes_sent` and smoothed over a few
+    /// seconds
+    pub send_rate: u64,
+    /// Bytes per second received, like `send_rate`
+    pub receive_rate: u64,
+}
+
+/// Quality of the path to the peer, estimated from sequence numbers
+///
+/// Rates are between 0 and 1 and smoothed over roughly the last
+/// hundred segments, so they follow changes of the path. Loss is measured
+/// on the data this end sends, reordering and duplication on the data it
+/// receives.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Share of data transmissions that had to be repeated, spurious
+    /// retransmissions included
+    pub loss_rate: f64,
+    /// Share of data segments from the peer that arrived after one with a
+    /// higher sn sent later; retransmissions filling a gap do not count
+    pub reordering_rate: f64,
+    /// Share of data segments from the peer that arrived more than once
+    /// from the same transmission; retransmissions do not count
+    pub duplication_rate: f64,
+}
+
+/// Round trip time estimate of a connection
+///
+/// Updated from every ACK received, independently of the snapshot in
+/// [`KcpStats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RttEstimate {
+    /// Most recent RTT sample
+    pub latest: Duration,
+    /// Smoothed RTT
+    pub srtt: Duration,
+    /// RTT variance (mean deviation)
+    pub rttvar: Duration,
+    /// Interarrival jitter as in RFC 3550: smoothed difference between
+    /// consecutive RTT samples
+    pub jitter: Duration,
+}
+
+/// Smoothed RTT estimate using the same filter as KCP, plus jitter
+#[derive(Default)]
+pub(crate) struct RttEstimator {
+    srtt: u32,
+    rttvar: u32,
+    latest: Option<u32>,
+    /// Jitter in milliseconds
+    jitter: f64,
+}
+
+impl RttEstimator {
+    pub fn update(&mut self, rtt: u32) {
+        let rtt = rtt.min(60_000);
+        if self.srtt == 0 {
+            self.srtt = rtt.max(1);
+            self.rttvar = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.srtt);
+            self.rttvar = (3 * self.rttvar + delta) / 4;
+            self.srtt = ((7 * self.srtt + rtt) / 8).max(1);
+        }
+
+        if let Some(latest) = self.latest {
+            let d = rtt.abs_diff(latest) as f64;
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.latest = Some(rtt);
+    }
+
+    /// Retransmission timeout, 200ms until the first sample arrives
+    pub fn rto(&self, interval: u32) -> Duration {
+        if self.srtt == 0 {
+            return Duration::from_millis(200);
+        }
+        let rto = self.srtt + interval.max(4 * self.rttvar);
+        Duration::from_millis(rto.clamp(30, 60_000) as u64)
+    }
+
+    pub fn estimate(&self) -> RttEstimate {
+        RttEstimate {
+            latest: Duration::from_millis(self.latest.unwrap_or_default() as u64),
+            srtt: Duration::from_millis(self.srtt as u64),
+            rttvar: Duration::from_millis(self.rttvar as u64),
+            jitter: Duration::from_secs_f64(self.jitter / 1000.0),
+        }
+    }
+}
+
+/// What currently limits the sending rate of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendLimit {
+    /// Nothing to send and nothing in flight
+    Idle,
+    /// The application does not supply data fast enough to fill the window
+    Application,
+    /// Data is waiting for the congestion window to open
+    Congestion,
+    /// Data is waiting for the receive window advertised by the peer
+    RemoteWindow,
+    /// Data is waiting for the local send window
+    SendWindow,
+}
+
+/// Send window state of a connection
+#[derive(Debug, Clone, Copy)]
+pub struct WindowStatus {
+    /// Receive window last advertised by the peer in segments
+    pub rmt_wnd: u16,
+    /// Local send window in segments
+    pub snd_wnd: u16,
+    /// Congestion window in segments
+    pub cwnd: u16,
+    /// Whether congestion control is enabled
+    pub congestion_control: bool,
+    /// Segments KCP may have in flight, the smallest of the windows above
+    pub effective_wnd: u16,
+    /// Segments sent and not yet acknowledged
+    pub in_flight: usize,
+    /// Segments queued behind the window
+    pub queued: usize,
+    /// Current bottleneck
+    pub limit: SendLimit,
+}
+
+impl WindowStatus {
+    pub(crate) fn new(internals: &Internals, snd_wnd: u16, rmt_wnd: u16) -> Self {
+        let congestion_control = !internals.nocwnd;
+        let mut effective_wnd = snd_wnd.min(rmt_wnd);
+        if congestion_control {
+            effective_wnd = effective_wnd.min(internals.cwnd);
+        }
+
+        let in_flight = internals.snd_buf_len;
+        let queued = internals.snd_queue_len;
+        let limit = if queued == 0 {
+            if in_flight == 0 {
+                SendLimit::Idle
+            } else {
+                SendLimit::Application
+            }
+        } else if congestion_control && internals.cwnd < snd_wnd.min(rmt_wnd) {
+            SendLimit::Congestion
+        } else if rmt_wnd < snd_wnd {
+            SendLimit::RemoteWindow
+        } else {
+            SendLimit::SendWindow
+        };
+
+        Self {
+            rmt_wnd,
+            snd_wnd,
+            cwnd: internals.cwnd,
+            congestion_control,
+            effective_wnd,
+            in_flight,
+            queued,
+            limit,
+        }
+    }
+}
+
+/// Configuration of the periodic statistics history
+#[derive(Debug, Clone, Copy)]
+pub struct StatsHistoryConfig {
+    /// Time between two samples
+    pub interval: Duration,
+    /// Number of samples kept, older ones are discarded
+    pub depth: usize,
+}
+
+impl Default for StatsHistoryConfig {
+    /// One sample every 5 seconds for the last 5 minutes
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            depth: 60,
+        }
+    }
+}
+
+/// A statistics snapshot taken at a point in time
+#[derive(Debug, Clone, Copy)]
+pub struct StatsSample {
+    /// Wall clock time of the sample
+    pub timestamp: SystemTime,
+    /// Statistics at that time
+    pub stats: KcpStats,
+}
+
+/// Ring buffer of periodic [`StatsSample`]s
+///
+/// Samples are taken opportunistically whenever the connection is driven,
+/// so an idle connection records no samples until traffic resumes.
+pub(crate) struct StatsHistory {
+    config: StatsHistoryConfig,
+    last_sample: Option<Instant>,
+    samples: VecDeque<StatsSample>,
+}
+
+impl StatsHistory {
+    pub fn new(config: StatsHistoryConfig) -> Self {
+        Self {
+            config,
+            last_sample: None,
+            samples: VecDeque::with_capacity(config.depth),
+        }
+    }
+
+    /// Record a sample if the interval has elapsed
+    pub fn maybe_sample(&mut self, now: Instant, stats: impl FnOnce() -> KcpStats) {
+        if self.config.depth == 0 {
+            return;
+        }
+        if let Some(last) = self.last_sample {
+            if now.duration_since(last) < self.config.interval {
+                return;
+            }
+        }
+
+        self.last_sample = Some(now);
+        if self.samples.len() == self.config.depth {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(StatsSample {
+            timestamp: SystemTime::now(),
+            stats: stats(),
+        });
+    }
+
+    /// Samples from oldest to newest
+    pub fn samples(&self) -> Vec<StatsSample> {
+        self.samples.iter().copied().collect()
+    }
+}
+
+/// Weight of a new sample in the smoothed goodput
+const GOODPUT_WEIGHT: f64 = 0.25;
+
+/// Delivery rate samples, one per RTT, the bandwidth estimate is the
+/// highest of
+const BANDWIDTH_SAMPLES: usize = 10;
+
+/// Delivery rate of a connection, sampled once per RTT
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BandwidthEstimator {
+    /// Start of the current sample and the bytes delivered by then
+    start: Option<(Instant, u64)>,
+    goodput: f64,
+    samples: VecDeque<f64>,
+}
+
+impl BandwidthEstimator {
+    /// Take a sample once `period` has passed since the last one
+    ///
+    /// `delivered` counts the payload bytes acknowledged so far. A sample
+    /// of a period the application left data unsent in (`app_limited`)
+    /// says little about the path, it only counts for the bandwidth if it
+    /// is a new high.
+    pub fn sample(&mut self, now: Instant, delivered: u64, period: Duration, app_limited: bool) {
+        let Some((start, before)) = self.start else {
+            self.start = Some((now, delivered));
+            return;
+        };
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed < period || elapsed.is_zero() {
+            return;
+        }
+        self.start = Some((now, delivered));
+
+        let rate = delivered.saturating_sub(before) as f64 / elapsed.as_secs_f64();
+        self.goodput += (rate - self.goodput) * GOODPUT_WEIGHT;
+        if app_limited && rate <= self.max_rate() {
+            return;
+        }
+        if self.samples.len() == BANDWIDTH_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rate);
+    }
+
+    fn max_rate(&self) -> f64 {
+        self.samples.iter().copied().fold(0.0, f64::max)
+    }
+
+    /// Smoothed delivery rate in bytes per second
+    pub fn goodput(&self) -> u64 {
+        self.goodput as u64
+    }
+
+    /// Highest recent delivery rate in bytes per second
+    pub fn bandwidth(&self) -> u64 {
+        self.max_rate() as u64
+    }
+}
+
+/// Period traffic rates are sampled over
+const RATE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Weight of a period in a smoothed traffic rate
+const RATE_WEIGHT: f64 = 0.5;
+
+/// Smoothed rate of the bytes passing in one direction
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ByteRate {
+    /// Start of the current period and the bytes counted in it
+    period: Option<(Instant, u64)>,
+    rate: f64,
+}
+
+impl ByteRate {
+    pub fn add(&mut self, now: Instant, bytes: usize) {
+        let (start, counted) = self.period.get_or_insert((now, 0));
+        let elapsed = now.saturating_duration_since(*start);
+        if elapsed >= RATE_PERIOD {
+            self.rate = Self::smoothed(self.rate, *counted, elapsed);
+            *start = now;
+            *counted = 0;
+        }
+        *counted += bytes as u64;
+    }
+
+    /// Rate in bytes per second, decaying while nothing passes
+    pub fn rate(&self, now: Instant) -> u64 {
+        let Some((start, counted)) = self.period else {
+            return 0;
+        };
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed < RATE_PERIOD {
+            return self.rate as u64;
+        }
+        Self::smoothed(self.rate, counted, elapsed) as u64
+    }
+
+    /// `rate` updated with `counted` bytes over `elapsed`, weighted by the
+    /// periods it spans so an idle gap decays the rate as much as as many
+    /// empty periods would
+    fn smoothed(rate: f64, counted: u64, elapsed: Duration) -> f64 {
+        let sample = counted as f64 / elapsed.as_secs_f64();
+        let periods = elapsed.as_secs_f64() / RATE_PERIOD.as_secs_f64();
+        sample + (rate - sample) * (1.0 - RATE_WEIGHT).powf(periods)
+    }
+}
+
+/// Delay of each direction of a connection
+///
+/// The clocks of the two ends are not synchronized, so only the sum of
+/// the lowest delays of both directions is known; `forward` and `reverse`
+/// split it evenly and add the queuing seen on each direction. The
+/// queuing delays and trends are exact, e.g. on a DSL link whose uplink
+/// is saturated `forward_queuing` grows while `reverse_queuing` stays
+/// near zero. The forward direction is measured by the peer and echoed,
+/// it needs [`KcpInterop::Native`](crate::KcpInterop::Native).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OneWayDelay {
+    /// Estimated delay towards the peer
+    pub forward: Duration,
+    /// Estimated delay from the peer
+    pub reverse: Duration,
+    /// Delay towards the peer above the lowest of the last minute
+    pub forward_queuing: Duration,
+    /// Delay from the peer above the lowest of the last minute
+    pub reverse_queuing: Duration,
+    /// Change of the delay towards the peer over the last two seconds, in
+    /// ms per second; positive while a queue builds up
+    pub forward_trend: f64,
+    /// Change of the delay from the peer, like `forward_trend`
+    pub reverse_trend: f64,
+    /// Rate the peer's clock runs at relative to the local one in parts
+    /// per million, positive if it is faster
+    ///
+    /// Estimated from the lowest delays of both directions over the last
+    /// ten minutes and taken out of the other fields, so devices without
+    /// NTP do not show a drift as a growing queue. RTTs are measured on
+    /// the local clock alone and need no correction.
+    pub clock_skew_ppm: f64,
+}
+
+/// Span of the delay history the lowest delay is taken from, in buckets
+const BASE_DELAY_BUCKETS: usize = 6;
+
+/// Length of one bucket of the delay history
+const BASE_DELAY_BUCKET: Duration = Duration::from_secs(10);
+
+/// Samples the delay trend is fitted over
+const DELAY_TREND_WINDOW: Duration = Duration::from_secs(2);
+
+/// Minimum spacing of the samples kept for the trend
+const DELAY_TREND_SPACING: Duration = Duration::from_millis(10);
+
+/// Minimum interval between timestamp echoes sent to the peer
+const DELAY_ECHO_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Delay history kept to estimate the clock skew, in buckets
+const SKEW_BUCKETS: usize = 60;
+
+/// Buckets of history needed before the skew is estimated
+const SKEW_MIN_BUCKETS: usize = 3;
+
+/// Largest skew believed, in ms per second; a steeper slope of the lowest
+/// delays is a route change rather than a clock
+const MAX_SKEW: f64 = 1.0;
+
+/// Lowest delay of one bucket of the history
+#[derive(Debug, Clone, Copy)]
+struct DelayBucket {
+    start: Instant,
+    /// When the lowest delay was seen
+    at: Instant,
+    lowest: i32,
+}
+
+/// Least squares slope of `points` in units of `y` per second
+fn slope(points: impl Iterator<Item = (Instant, f64)> + Clone) -> Option<f64> {
+    let first = points.clone().next()?.0;
+    let points = points.map(|(at, y)| (at.saturating_duration_since(first).as_secs_f64(), y));
+    let n = points.clone().count() as f64;
+    let mean_t = points.clone().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.clone().map(|p| p.1).sum::<f64>() / n;
+    let (mut cov, mut var) = (0.0, 0.0);
+    for (t, y) in points {
+        cov += (t - mean_t) * (y - mean_y);
+        var += (t - mean_t) * (t - mean_t);
+    }
+    (var > 0.0).then(|| cov / var)
+}
+
+/// Relative delay of one direction: the receiver's clock minus the
+/// sender's, the clock offset included
+///
+/// A skew between the clocks makes the offset drift, by the same amount
+/// in both directions with opposite signs. The drift of a direction is
+/// passed in to be taken out.
+#[derive(Debug, Clone, Default)]
+struct DelayTrack {
+    /// Lowest delay of every bucket of the history, newest last
+    buckets: VecDeque<DelayBucket>,
+    smoothed: Option<f64>,
+    last: Option<Instant>,
+    recent: VecDeque<(Instant, i32)>,
+}
+
+impl DelayTrack {
+    fn sample(&mut self, now: Instant, delay: i32) {
+        match self.buckets.back_mut() {
+            Some(bucket) if now.saturating_duration_since(bucket.start) < BASE_DELAY_BUCKET => {
+                if delay < bucket.lowest {
+                    bucket.lowest = delay;
+                    bucket.at = now;
+                }
+            }
+            _ => {
+                if self.buckets.len() == SKEW_BUCKETS {
+                    self.buckets.pop_front();
+                }
+                self.buckets.push_back(DelayBucket {
+                    start: now,
+                    at: now,
+                    lowest: delay,
+                });
+            }
+        }
+        let smoothed = self.smoothed.get_or_insert(delay as f64);
+        *smoothed += (delay as f64 - *smoothed) / 8.0;
+        self.last = Some(now);
+
+        if self
+            .recent
+            .back()
+            .is_some_and(|&(at, _)| now.saturating_duration_since(at) < DELAY_TREND_SPACING)
+        {
+            return;
+        }
+        while self
+            .recent
+            .front()
+            .is_some_and(|&(at, _)| now.saturating_duration_since(at) > DELAY_TREND_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((now, delay));
+    }
+
+    /// Slope of the lowest delays over the history in ms per second,
+    /// `None` while it is too short
+    fn drift(&self) -> Option<f64> {
+        if self.buckets.len() < SKEW_MIN_BUCKETS {
+            return None;
+        }
+        slope(self.buckets.iter().map(|bucket| (bucket.at, bucket.lowest as f64)))
+    }
+
+    /// Lowest delay of the recent buckets, each moved forward to the
+    /// latest sample by `drift`
+    fn base(&self, drift: f64) -> Option<f64> {
+        let last = self.last?;
+        self.buckets
+            .iter()
+            .rev()
+            .take(BASE_DELAY_BUCKETS)
+            .map(|bucket| {
+                let elapsed = last.saturating_duration_since(bucket.at).as_secs_f64();
+                bucket.lowest as f64 + drift * elapsed
+            })
+            .reduce(f64::min)
+    }
+
+    fn queuing(&self, drift: f64) -> Duration {
+        match (self.smoothed, self.base(drift)) {
+            (Some(smoothed), Some(base)) => {
+                Duration::from_secs_f64((smoothed - base).max(0.0) / 1000.0)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Slope of the recent samples in ms per second, `drift` taken out
+    fn trend(&self, drift: f64) -> f64 {
+        let points = self.recent.iter().map(|&(at, delay)| (at, delay as f64));
+        slope(points).map_or(0.0, |slope| slope - drift)
+    }
+}
+
+/// One-way delays from the timestamps of data segments and the peer's
+/// echoes of them
+///
+/// Data segments carry the sender's clock, their arrival gives the delay
+/// from the peer. The latest of them is echoed back with the time it
+/// arrived, which gives the peer the delay towards it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DelayEstimator {
+    forward: DelayTrack,
+    reverse: DelayTrack,
+    /// ts of the latest data segment of the peer and when it arrived, on
+    /// the local clock, not echoed yet
+    pending_echo: Option<(u32, u32)>,
+    last_echo: Option<Instant>,
+}
+
+impl DelayEstimator {
+    /// A data segment sent at the peer's `ts` arrived at `local`
+    pub fn on_data(&mut self, now: Instant, local: u32, ts: u32) {
+        self.reverse.sample(now, serial::diff(local, ts));
+        self.pending_echo = Some((ts, local));
+    }
+
+    /// Body of a timestamp echo due now: the peer's ts and its arrival
+    pub fn poll_echo(&mut self, now: Instant) -> Option<[u8; 8]> {
+        if self.last_echo.is_some_and(|at| now.saturating_duration_since(at) < DELAY_ECHO_INTERVAL) {
+            return None;
+        }
+        let (ts, local) = self.pending_echo.take()?;
+        self.last_echo = Some(now);
+        let mut body = [0; 8];
+        body[..4].copy_from_slice(&ts.to_le_bytes());
+        body[4..].copy_from_slice(&local.to_le_bytes());
+        Some(body)
+    }
+
+    /// Take an echo of the peer: the local ts of a data segment and when
+    /// it arrived on the peer's clock
+    pub fn on_echo(&mut self, now: Instant, body: &[u8]) {
+        let [a, b, c, d, e, f, g, h] = *body else {
+            return;
+        };
+        let ts = u32::from_le_bytes([a, b, c, d]);
+        let arrived = u32::from_le_bytes([e, f, g, h]);
+        self.forward.sample(now, serial::diff(arrived, ts));
+    }
+
+    /// Rate the peer's clock runs at relative to the local one, in ms
+    /// per second, positive if it is faster
+    ///
+    /// Zero until a few buckets of history were collected.
+    fn skew(&self) -> f64 {
+        let skew = match (self.forward.drift(), self.reverse.drift()) {
+            (Some(forward), Some(reverse)) => (forward - reverse) / 2.0,
+            (Some(forward), None) => forward,
+            (None, Some(reverse)) => -reverse,
+            (None, None) => 0.0,
+        };
+        skew.clamp(-MAX_SKEW, MAX_SKEW)
+    }
+
+    pub fn estimate(&self) -> OneWayDelay {
+        let skew = self.skew();
+        let forward_queuing = self.forward.queuing(skew);
+        let reverse_queuing = self.reverse.queuing(-skew);
+        // The clock offset cancels out of the sum
+        let base = match (self.forward.base(skew), self.reverse.base(-skew)) {
+            (Some(forward), Some(reverse)) => {
+                Duration::from_secs_f64((forward + reverse).max(0.0) / 2000.0)
+            }
+            _ => Duration::ZERO,
+        };
+        OneWayDelay {
+            forward: base + forward_queuing,
+            reverse: base + reverse_queuing,
+            forward_queuing,
+            reverse_queuing,
+            forward_trend: self.forward.trend(skew),
+            reverse_trend: self.reverse.trend(-skew),
+            clock_skew_ppm: skew * 1000.0,
+        }
+    }
+}
+
+/// Weight of a new sample in the smoothed rates of [`NetworkConditions`]
+const CONDITIONS_WEIGHT: f64 = 1.0 / 64.0;
+
+/// Received sns below the highest one remembered to classify late arrivals
+const CONDITIONS_WINDOW: usize = 1024;
+
+fn smooth(rate: &mut f64, sample: bool) {
+    *rate += (if sample { 1.0 } else { 0.0 } - *rate) * CONDITIONS_WEIGHT;
+}
+
+/// Traffic counters maintained from the datagrams passing the socket
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TrafficCounters {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub segments_sent: u64,
+    pub segments_received: u64,
+    pub retransmissions: u64,
+    pub conditions: NetworkConditions,
+    /// Datagrams dropped for failing their checksum
+    pub corrupted: u64,
+    pub send_rate: ByteRate,
+    pub receive_rate: ByteRate,
+    /// Next data sequence number that has never been sent
+    snd_nxt: u32,
+    /// Highest data sn received and the ts of its transmission
+    rcv_max: Option<(u32, u32)>,
+    /// ts of the first copy received of the sns below `rcv_max`, the
+    /// front is `rcv_max` itself; `None` for those not received yet
+    rcv_seen: VecDeque<Option<u32>>,
+}
+
+impl TrafficCounters {
+    /// Account a sent datagram, returns the number of retransmitted segments
+    ///
+    /// `on_retransmit` is called with the sequence number of every data
+    /// segment that was sent before.
+    pub fn on_sent(&mut self, datagram: &[u8], mut on_retransmit: impl FnMut(u32)) -> u64 {
+        self.bytes_sent += datagram.len() as u64;
+        self.packets_sent += 1;
+        self.send_rate.add(Instant::now(), datagram.len());
+        if packet::ext_cmd(datagram).is_some() {
+            return 0;
+        }
+
+        let mut retransmissions = 0;
+        for segment in packet::segments(datagram) {
+            self.segments_sent += 1;
+            if segment.cmd != KCP_CMD_PUSH {
+                continue;
+            }
+            let retransmitted = serial::before(segment.sn, self.snd_nxt);
+            if retransmitted {
+                retransmissions += 1;
+                on_retransmit(segment.sn);
+            } else {
+                self.snd_nxt = serial::add(segment.sn, 1);
+            }
+            smooth(&mut self.conditions.loss_rate, retransmitted);
+        }
+        self.retransmissions += retransmissions;
+        retransmissions
+    }
+
+    pub fn on_received(&mut self, datagram: &[u8]) {
+        self.bytes_received += datagram.len() as u64;
+        self.packets_received += 1;
+        self.receive_rate.add(Instant::now(), datagram.len());
+        if packet::ext_cmd(datagram).is_some() {
+            return;
+        }
+        for segment in packet::segments(datagram) {
+            self.segments_received += 1;
+            if segment.cmd == KCP_CMD_PUSH {
+                self.on_data_received(segment.sn, segment.ts);
+            }
+        }
+    }
+
+    /// Classify a received data segment for [`NetworkConditions`]
+    ///
+    /// A copy of an sn received before is a duplicate if it carries the
+    /// same ts, a retransmission otherwise. A missing sn arriving with an
+    /// older ts than the highest one was overtaken, with a newer ts it is
+    /// a retransmission of a lost segment.
+    fn on_data_received(&mut self, sn: u32, ts: u32) {
+        let conditions = &mut self.conditions;
+        let Some((max_sn, max_ts)) = self.rcv_max else {
+            self.rcv_max = Some((sn, ts));
+            self.rcv_seen.push_front(Some(ts));
+            return;
+        };
+        let ahead = serial::diff(sn, max_sn);
+        if ahead > 0 {
+            if ahead as usize > CONDITIONS_WINDOW {
+                self.rcv_seen.clear();
+            } else {
+                for _ in 1..ahead {
+                    self.rcv_seen.push_front(None);
+                }
+            }
+            self.rcv_seen.push_front(Some(ts));
+            self.rcv_seen.truncate(CONDITIONS_WINDOW);
+            self.rcv_max = Some((sn, ts));
+            smooth(&mut conditions.reordering_rate, false);
+            smooth(&mut conditions.duplication_rate, false);
+            return;
+        }
+        // Too old to tell
+        let Some(seen) = self.rcv_seen.get_mut((-ahead) as usize) else {
+            return;
+        };
+        match *seen {
+            Some(first) => smooth(&mut conditions.duplication_rate, first == ts),
+            None => {
+                *seen = Some(ts);
+                smooth(&mut conditions.duplication_rate, false);
+                smooth(&mut conditions.reordering_rate, !serial::after(ts, max_ts));
+            }
+        }
+    }
+}
@@ -1,30 +1,177 @@
 use std::{
-    io,
+    any::Any,
+    future::Future,
+    io::{self, IoSliceMut},
+    mem,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
     pin::Pin,
+    time::{Duration, Instant},
 };
 
-use async_io::Async;
+use async_io::{Async, Timer};
 use async_lock::Mutex;
-use futures_lite::io::{AsyncRead, AsyncWrite};
-use kcp::{Error as KcpError, KcpResult};
-use log::trace;
+use event_listener::EventListener;
+use futures_lite::{
+    future,
+    io::{AsyncRead, AsyncWrite},
+    ready,
+};
+
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
 
-use crate::{config::KcpConfig, socket::KcpSocket};
+use crate::{
+    config::{FlushPolicy, KcpConfig},
+    connector::Demux,
+    error::{Error, KcpResult},
+    events::{KcpEvent, KcpEventHandler, UserData},
+    histogram::LatencyReport,
+    listener::Abandoned,
+    logging::trace,
+    message::{Reliability, SendOptions},
+    multipath::{MultipathMode, PathStats},
+    pcap::PcapWriter,
+    ring::MessageRing,
+    socket::{configure_udp, CloseCode, CloseReason, ExportedState, KcpSocket, SessionTag},
+    socks5::Socks5Association,
+    stats::{KcpStats, OneWayDelay, RttEstimate, StatsSample, WindowStatus},
+};
 
 /// KCP stream for client connections
 pub struct KcpStream {
     pub(crate) socket: Arc<Mutex<KcpSocket>>,
+    pub(crate) udp: Arc<Async<std::net::UdpSocket>>,
     pub(crate) recv_buffer: Vec<u8>,
     pub(crate) recv_buffer_pos: usize,
     pub(crate) recv_buffer_cap: usize,
+    /// Received messages, popped without the session lock
+    ring: Arc<MessageRing>,
+    pub(crate) tag: SessionTag,
+    /// Datagram buffer of client streams reading their own UDP socket
+    pub(crate) input_buffer: Vec<u8>,
+    /// Number of handles of the connection, the last one dropped closes it
+    handles: Arc<AtomicUsize>,
+    /// Sockets of the paths after the first of a multipath stream
+    paths: Vec<Arc<Async<std::net::UdpSocket>>>,
+    /// Shared socket of a [`KcpConnector`](crate::KcpConnector) stream
+    pub(crate) demux: Option<Arc<Demux>>,
+    /// Listener that closes the session of an accepted stream once all
+    /// its handles are dropped
+    pub(crate) owner: Option<Arc<Abandoned>>,
+    /// Receive of the `AsyncRead` impl waiting for input
+    read_op: Option<PendingOp<KcpResult<Option<Vec<u8>>>>>,
+    /// Step of the `AsyncWrite` impl in progress
+    write_op: Option<(WriteStep, PendingOp<KcpResult<()>>)>,
+}
+
+/// Operation of the `AsyncRead`/`AsyncWrite` impls that returned
+/// `Pending`, run on a handle of its own so it outlives the poll call
+///
+/// Resolves to the datagram buffer it borrowed from the stream and its
+/// result.
+struct PendingOp<T>(Pin<Box<dyn Future<Output = (Vec<u8>, T)> + Send>>);
+
+// SAFETY: the future is only reached through `&mut`, sharing a
+// `&KcpStream` between threads cannot touch it
+unsafe impl<T> Sync for PendingOp<T> {}
+
+impl<T> PendingOp<T> {
+    fn new(future: impl Future<Output = (Vec<u8>, T)> + Send + 'static) -> Self {
+        Self(Box::pin(future))
+    }
+}
+
+/// What a pending operation of the `AsyncWrite` impl is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriteStep {
+    /// Waiting for the session lock
+    Lock,
+    /// Waiting for ACKs to free room in the send window
+    Room,
+    /// Sending the datagrams of a write
+    Output,
+    Flush,
+    Close,
 }
 
 impl KcpStream {
     /// Connect to a KCP server
     pub async fn connect(config: &KcpConfig, addr: SocketAddr) -> KcpResult<Self> {
+        Self::connect_conv(config, addr, None)
+    }
+
+    /// Connect to a KCP server with the given conversation id
+    ///
+    /// For peers that agree on the conv out of band instead of taking a
+    /// random one from [`KcpConfig::conv_generator`].
+    pub async fn connect_with_conv(
+        config: &KcpConfig,
+        addr: SocketAddr,
+        conv: u32,
+    ) -> KcpResult<Self> {
+        Self::connect_conv(config, addr, Some(conv))
+    }
+
+    /// Connect to a KCP server, attaching `hello` to the handshake
+    ///
+    /// The server gets the payload, e.g. an auth token or the client
+    /// version, with the accepted stream from
+    /// [`KcpListener::accept_with_info`](crate::KcpListener::accept_with_info),
+    /// without a round trip of its own. It rides along with every datagram
+    /// until the server answers, see [`crate::hello`]; at most 512 bytes,
+    /// and the server must be a smol-kcp listener with
+    /// [`KcpInterop::Native`](crate::KcpInterop::Native).
+    pub async fn connect_with_hello(config: &KcpConfig, addr: SocketAddr, hello: &[u8]) -> KcpResult<Self> {
+        let stream = Self::connect_conv(config, addr, None)?;
+        stream.socket.lock().await.send_hello("", hello)?;
+        Ok(stream)
+    }
+
+    /// Connect to the service named `service` of a KCP server
+    ///
+    /// For servers running several services on one port, see
+    /// [`KcpListener::register`](crate::KcpListener::register). The name,
+    /// at most 255 bytes, travels with the handshake like the payload of
+    /// [`KcpStream::connect_with_hello`], `hello` may be empty. A server
+    /// serving without such a service hands the stream to its default
+    /// handler.
+    pub async fn connect_to_service(
+        config: &KcpConfig,
+        addr: SocketAddr,
+        service: &str,
+        hello: &[u8],
+    ) -> KcpResult<Self> {
+        let stream = Self::connect_conv(config, addr, None)?;
+        stream.socket.lock().await.send_hello(service, hello)?;
+        Ok(stream)
+    }
+
+    /// Connect to a KCP server and send `data` with the first datagram
+    ///
+    /// KCP has no handshake of its own, the session opens with the first
+    /// segment the server receives; this queues `data` before anything
+    /// else and flushes it at once, whatever the [`FlushPolicy`], so a
+    /// request is answered one round trip after connecting. What does not
+    /// fit the first flight (one segment while KCP's congestion window
+    /// opens, unless `nodelay.nc`) follows as acknowledgements arrive.
+    /// Datagrams are not encrypted or authenticated, so no anti-replay
+    /// limits apply: a replayed first datagram reopens the session on a
+    /// server that dropped it, as it would for any KCP client.
+    pub async fn connect_with_early_data(config: &KcpConfig, addr: SocketAddr, data: &[u8]) -> KcpResult<Self> {
+        let mut stream = Self::connect_conv(config, addr, None)?;
+        stream.send(data).await?;
+        stream.flush().await?;
+        Ok(stream)
+    }
+
+    fn connect_conv(config: &KcpConfig, addr: SocketAddr, conv: Option<u32>) -> KcpResult<Self> {
         let udp_addr = match addr.ip() {
             IpAddr::V4(_) => "0.0.0.0:0",
             IpAddr::V6(_) => "[::]:0",
@@ -32,97 +179,990 @@ impl KcpStream {
 
         let udp = std::net::UdpSocket::bind(udp_addr)?;
         udp.connect(addr)?;
+        let socket = Self::client_socket(config, udp, addr, conv)?;
+        Ok(Self::client(socket))
+    }
+
+    /// Connect to a KCP server through the UDP relay of a SOCKS5 proxy
+    ///
+    /// Performs a UDP ASSOCIATE (RFC 1928) with `proxy`, which must accept
+    /// clients without authentication, and keeps its TCP control
+    /// connection open for the lifetime of the stream. Datagrams carry the
+    /// SOCKS5 UDP header, the proxy forwards them to `target`.
+    pub async fn connect_via_socks5(
+        config: &KcpConfig,
+        proxy: SocketAddr,
+        target: SocketAddr,
+    ) -> KcpResult<Self> {
+        let udp_addr = match proxy.ip() {
+            IpAddr::V4(_) => "0.0.0.0:0",
+            IpAddr::V6(_) => "[::]:0",
+        };
+
+        let udp = std::net::UdpSocket::bind(udp_addr)?;
+        let (association, relay) =
+            Socks5Association::open(proxy, udp.local_addr()?, target).await?;
+        trace!("SOCKS5 proxy {} relays via {}", proxy, relay);
+        udp.connect(relay)?;
+
+        let mut socket = Self::client_socket(config, udp, target, None)?;
+        socket.set_socks5(association);
+        Ok(Self::client(socket))
+    }
+
+    /// Connect to a KCP server over several paths (experimental)
+    ///
+    /// Each socket is bound to a different interface (e.g. DSL and LTE),
+    /// they are connected to `addr` here. The first one opens the session,
+    /// the others join it and carry output once the server confirmed them.
+    /// See [`MultipathMode`] for how output is spread and
+    /// [`KcpStream::path_stats`] for the state of each path. The server
+    /// must be a smol-kcp listener with [`KcpInterop::Native`].
+    ///
+    /// [`KcpInterop::Native`]: crate::KcpInterop::Native
+    pub async fn connect_multipath(
+        config: &KcpConfig,
+        addr: SocketAddr,
+        sockets: Vec<std::net::UdpSocket>,
+        mode: MultipathMode,
+    ) -> KcpResult<Self> {
+        let mut sockets = sockets.into_iter();
+        let primary = sockets.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "multipath needs at least one socket")
+        })?;
+        primary.connect(addr)?;
+        let mut socket = Self::client_socket(config, primary, addr, None)?;
+        socket.enable_multipath(config, mode)?;
+
+        let mut paths = Vec::new();
+        for udp in sockets {
+            udp.connect(addr)?;
+            configure_udp(&udp)?;
+            let udp = Arc::new(Async::new(udp)?);
+            socket.add_path(udp.clone())?;
+            paths.push(udp);
+        }
+
+        let mut stream = Self::client(socket);
+        stream.paths = paths;
+        Ok(stream)
+    }
+
+    /// Connect to the first instance of `service` (e.g. `_mykcp._udp`)
+    /// found on the LAN with mDNS
+    ///
+    /// Waits up to three seconds for an answer and connects to the first
+    /// address of the instance.
+    #[cfg(feature = "discovery")]
+    pub async fn discover_and_connect(config: &KcpConfig, service: &str) -> KcpResult<Self> {
+        let instance = crate::discovery::discover(service, crate::discovery::DEFAULT_TIMEOUT)
+            .await?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no instance of {} found", service),
+                )
+            })?;
+        let addr = *instance.addrs.first().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "discovered instance has no address")
+        })?;
+        trace!("discovered {} at {}", instance.name, addr);
+        Self::connect(config, addr).await
+    }
+
+    /// KCP socket on a connected UDP socket, with a fresh conversation
+    /// unless `conv` is given
+    fn client_socket(
+        config: &KcpConfig,
+        udp: std::net::UdpSocket,
+        peer: SocketAddr,
+        conv: Option<u32>,
+    ) -> KcpResult<KcpSocket> {
+        configure_udp(&udp)?;
         let udp = Arc::new(Async::new(udp)?);
 
-        let mut conv = rand::random::<u32>();
-        while conv == 0 {
-            conv = rand::random();
+        let conv = conv.unwrap_or_else(|| config.new_conv());
+        let mut socket = KcpSocket::new(config, conv, udp, peer, config.stream)?;
+        if config.session_ids {
+            socket.offer_session_id();
         }
+        Ok(socket)
+    }
 
-        let socket = KcpSocket::new(config, conv, udp, addr, config.stream)?;
-        
-        Ok(Self {
-            socket: Arc::new(Mutex::new(socket)),
-            recv_buffer: Vec::new(),
-            recv_buffer_pos: 0,
-            recv_buffer_cap: 0,
-        })
+    /// Stream reading its own UDP socket
+    fn client(socket: KcpSocket) -> Self {
+        let udp = socket.udp_socket().clone();
+        let tag = socket.tag();
+        let ring = socket.ring().clone();
+        Self::from_socket(Arc::new(Mutex::new(socket)), ring, udp, tag)
     }
 
     /// Create a stream from an existing socket (used by listener)
-    pub(crate) fn from_socket(socket: Arc<Mutex<KcpSocket>>) -> Self {
+    pub(crate) fn from_socket(
+        socket: Arc<Mutex<KcpSocket>>,
+        ring: Arc<MessageRing>,
+        udp: Arc<Async<std::net::UdpSocket>>,
+        tag: SessionTag,
+    ) -> Self {
         Self {
             socket,
+            udp,
             recv_buffer: Vec::new(),
             recv_buffer_pos: 0,
             recv_buffer_cap: 0,
+            ring,
+            tag,
+            input_buffer: Vec::new(),
+            handles: Arc::new(AtomicUsize::new(1)),
+            paths: Vec::new(),
+            demux: None,
+            owner: None,
+            read_op: None,
+            write_op: None,
+        }
+    }
+
+    /// Create another handle to the same connection
+    ///
+    /// Lets one task send while another receives. The new handle has its
+    /// own receive buffer, so only one handle should be used for receiving.
+    pub fn clone_handle(&self) -> Self {
+        self.handles.fetch_add(1, Ordering::Relaxed);
+        let mut handle = Self::from_socket(
+            self.socket.clone(),
+            self.ring.clone(),
+            self.udp.clone(),
+            self.tag,
+        );
+        handle.handles = self.handles.clone();
+        handle.paths = self.paths.clone();
+        handle.demux = self.demux.clone();
+        handle.owner = self.owner.clone();
+        handle
+    }
+
+    /// Save the session for a hot restart
+    ///
+    /// Covers sequence numbers, windows, RTT and congestion state and all
+    /// unacknowledged and unread data. Call it once the application stopped
+    /// using the stream, then exit without closing it; the new process
+    /// resumes with [`KcpStream::import_state`] (connected streams) or
+    /// [`KcpListener::import_session`](crate::KcpListener::import_session)
+    /// (accepted streams) and the peer only sees a stall.
+    pub async fn export_state(&self) -> KcpResult<Vec<u8>> {
+        let mut socket = self.socket.lock().await;
+        // The rest of a partly read message comes first
+        let partial = &self.recv_buffer[self.recv_buffer_pos..self.recv_buffer_cap];
+        let mut unread: Vec<Vec<u8>> = Vec::new();
+        if !partial.is_empty() {
+            unread.push(partial.to_vec());
         }
+        unread.extend(std::iter::from_fn(|| self.ring.pop()));
+        socket.export_state(&unread)
+    }
+
+    /// Resume a stream created by [`KcpStream::connect`] in an earlier
+    /// process
+    ///
+    /// Binds the same local address, the server tells sessions apart by it.
+    /// `config` supplies the options outside the KCP state, such as the
+    /// interop mode; use the one the stream was connected with.
+    pub async fn import_state(config: &KcpConfig, state: &[u8]) -> KcpResult<Self> {
+        let state = ExportedState::parse(state)?;
+        let udp = std::net::UdpSocket::bind(state.local)?;
+        udp.connect(state.peer)?;
+        configure_udp(&udp)?;
+        let udp = Arc::new(Async::new(udp)?);
+
+        let socket = KcpSocket::import_state(config, udp, &state)?;
+        Ok(Self::client(socket))
+    }
+
+    /// Freeze the session's timers, e.g. before the device sleeps
+    ///
+    /// While paused nothing is sent or retransmitted and no RTO backs off,
+    /// so a long sleep does not reach the dead link limit. Received data
+    /// can still be read and sends are queued until [`KcpStream::resume`].
+    pub async fn pause(&self) {
+        self.socket.lock().await.pause();
+    }
+
+    /// Continue a paused session after wake-up
+    ///
+    /// Timers continue where they stopped, the time asleep is left out of
+    /// RTT and RTO. Queued data and ACKs go out right away.
+    pub async fn resume(&self) -> KcpResult<()> {
+        let mut socket = self.socket.lock().await;
+        socket.resume();
+        socket.flush()?;
+        socket.send_output().await?;
+        Ok(())
+    }
+
+    /// Whether the session is paused
+    pub async fn is_paused(&self) -> bool {
+        self.socket.lock().await.is_paused()
+    }
+
+    /// Close the session, telling the peer why
+    ///
+    /// Dropping the stream closes it with [`CloseCode::Normal`]. Every
+    /// handle fails with [`Error::ConnectionClosed`] afterwards, the peer
+    /// reads the data it received and then its close error. Unsent data
+    /// is discarded.
+    pub async fn close_with(&self, code: CloseCode) -> KcpResult<()> {
+        let mut socket = self.socket.lock().await;
+        socket.check_open()?;
+        socket.close(CloseReason::Closed(code));
+        if !socket.is_peer_closed() {
+            socket.queue_close_frame();
+        }
+        socket.send_output().await?;
+        Ok(())
+    }
+
+    /// Abort the session with an application-defined error code
+    ///
+    /// Like [`KcpStream::close_with`] with [`CloseCode::Application`]:
+    /// unsent data is discarded and the peer fails with
+    /// [`Error::ClosedByPeer`] carrying `code`, instead of waiting for the
+    /// session to expire.
+    pub async fn reset(&self, code: u32) -> KcpResult<()> {
+        self.close_with(CloseCode::Application(code)).await
+    }
+
+    /// Handshake payload the client connected with, see
+    /// [`KcpStream::connect_with_hello`]
+    pub async fn peer_hello(&self) -> Option<Vec<u8>> {
+        self.socket.lock().await.peer_hello().map(<[u8]>::to_vec)
+    }
+
+    /// Service the client connected to, see
+    /// [`KcpStream::connect_to_service`]
+    pub async fn peer_service(&self) -> Option<String> {
+        self.socket.lock().await.peer_service().map(str::to_owned)
+    }
+
+    /// 64-bit id the session is routed by, `None` until the server
+    /// accepted the one offered with [`KcpConfig::session_ids`]
+    pub async fn session_id(&self) -> Option<u64> {
+        self.socket.lock().await.session_id()
+    }
+
+    /// Why the peer closed the session, `None` while it did not
+    pub async fn peer_close_code(&self) -> Option<CloseCode> {
+        self.socket.lock().await.peer_close_code()
     }
 
     /// Send data
+    ///
+    /// Queues as much as the send window and
+    /// [`KcpConfig::max_session_memory`] admit and waits for ACKs to free
+    /// space before continuing, so writes of any size go out completely
+    /// without filling the queue. A message is queued as a whole once
+    /// there is room for it. [`KcpConfig::flush_policy`] decides whether
+    /// it goes out right away.
     pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        self.send_until(buf, None, 0).await
+    }
+
+    /// Send a message that is dropped instead of sent if it is still
+    /// queued at `deadline`
+    ///
+    /// For realtime state where a late update is worthless: during a link
+    /// stall the queue does not fill up with obsolete messages, the fresh
+    /// ones go out once it recovers. A message already partly sent is
+    /// always completed. Fails with [`Error::WindowExhausted`] if the
+    /// deadline passes before there is room to queue it. Message mode
+    /// only, [`KcpConfig::message_ttl`] sets a deadline for every send.
+    ///
+    /// [`Error::WindowExhausted`]: crate::Error::WindowExhausted
+    pub async fn send_with_deadline(&mut self, buf: &[u8], deadline: Instant) -> KcpResult<usize> {
+        if self.socket.lock().await.is_stream() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "send deadlines require message mode",
+            )
+            .into());
+        }
+        self.send_until(buf, Some(deadline), 0).await
+    }
+
+    async fn send_until(
+        &mut self,
+        buf: &[u8],
+        deadline: Option<Instant>,
+        priority: u8,
+    ) -> KcpResult<usize> {
+        let mut sent = 0;
+        loop {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(Error::WindowExhausted);
+            }
+            let mut socket = self.socket.lock().await;
+            let room = socket.send_room(buf.len() - sent, priority)?;
+            if room > 0 || buf.is_empty() {
+                sent += socket.send_with(&buf[sent..sent + room], deadline, priority)?;
+                socket.flush_sent()?;
+                socket.send_output().await?;
+                if sent == buf.len() {
+                    return Ok(sent);
+                }
+            }
+
+            let input = socket.listen_input();
+            let interval = socket.interval();
+            drop(socket);
+            self.wait_input(input, interval).await?;
+        }
+    }
+
+    /// Send everything queued now, whatever the flush policy
+    pub async fn flush(&mut self) -> KcpResult<()> {
         let mut socket = self.socket.lock().await;
-        let result = socket.send(buf)?;
         socket.flush()?;
+        socket.send_output().await?;
+        Ok(())
+    }
+
+    /// Change when sends flush, see [`FlushPolicy`]
+    pub async fn set_flush_policy(&self, policy: FlushPolicy) {
+        self.socket.lock().await.set_flush_policy(policy);
+    }
+
+    /// Hold sent data back until [`KcpStream::uncork`]
+    ///
+    /// Like `TCP_CORK`: while an application assembles a message from
+    /// several writes nothing is flushed, so no partly filled segments go
+    /// out. ACKs and retransmissions continue. Data beyond a full send
+    /// window is not held back, a large write does not stall.
+    pub async fn cork(&self) {
+        self.socket.lock().await.cork();
+    }
+
+    /// Send everything held back since [`KcpStream::cork`] in as few
+    /// datagrams as possible
+    pub async fn uncork(&mut self) -> KcpResult<()> {
+        let mut socket = self.socket.lock().await;
+        socket.uncork()?;
+        socket.send_output().await?;
+        Ok(())
+    }
+
+    /// Send a message with per-message reliability
+    ///
+    /// Only available in message mode. Unreliable and partially reliable
+    /// messages must fit a single datagram and are delivered unordered with
+    /// respect to reliable data, see [`SendOptions`]. Reliable messages
+    /// with a priority overtake queued ones of lower priority.
+    pub async fn send_msg_with(&mut self, buf: &[u8], opts: SendOptions) -> KcpResult<usize> {
+        if opts.reliability == Reliability::Reliable {
+            if opts.priority > 0 && self.socket.lock().await.is_stream() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "send priorities require message mode",
+                )
+                .into());
+            }
+            return self.send_until(buf, None, opts.priority).await;
+        }
+
+        let mut socket = self.socket.lock().await;
+        let result = socket.send_msg(buf, opts.reliability)?;
+        socket.send_output().await?;
         Ok(result)
     }
 
+    /// Send a single urgent byte out of band
+    ///
+    /// It bypasses the ordered queue and is retransmitted on its own until
+    /// acknowledged, so a signal like "abort transfer" reaches the peer
+    /// even while the stream is blocked behind lost segments. The peer
+    /// reads it with [`KcpStream::recv_urgent`]. Works in both modes,
+    /// requires [`KcpInterop::Native`]. Fails with
+    /// [`Error::WindowExhausted`] while too many urgent bytes are
+    /// unacknowledged.
+    ///
+    /// [`KcpInterop::Native`]: crate::KcpInterop::Native
+    pub async fn send_urgent(&mut self, byte: u8) -> KcpResult<()> {
+        let mut socket = self.socket.lock().await;
+        socket.send_urgent(byte)?;
+        socket.send_output().await?;
+        Ok(())
+    }
+
+    /// Receive the next urgent byte sent with [`KcpStream::send_urgent`]
+    ///
+    /// Independent of [`KcpStream::recv`], call it from a task of its own
+    /// on a [`KcpStream::clone_handle`]. Fails with the peer's close error
+    /// once it closed the session and every urgent byte was read, see
+    /// [`KcpStream::recv`].
+    pub async fn recv_urgent(&mut self) -> KcpResult<u8> {
+        loop {
+            let mut socket = self.socket.lock().await;
+            if let Some(byte) = socket.take_urgent()? {
+                return Ok(byte);
+            }
+            if let Some(e) = socket.peer_close_error() {
+                return Err(e);
+            }
+            let input = socket.listen_input();
+            let interval = socket.interval();
+            drop(socket);
+            self.wait_input(input, interval).await?;
+        }
+    }
+
     /// Receive data
+    ///
+    /// In stream mode this returns a prefix of the byte stream, however
+    /// small `buf` is, and keeps the rest for the next call. In message
+    /// mode it returns one message; a KCP message larger than `buf` is
+    /// split across calls, one sent with [`KcpStream::send_msg_with`] is
+    /// truncated. `Ok(0)` means the peer closed the session normally, or
+    /// `buf` is empty; any other close fails with
+    /// [`Error::ClosedByPeer`] after the data received before it.
     pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
-        loop {
-            // First, try to consume from internal buffer
-            if self.recv_buffer_pos < self.recv_buffer_cap {
-                let remaining = self.recv_buffer_cap - self.recv_buffer_pos;
-                let copy_length = remaining.min(buf.len());
+        self.recv_vectored(&mut [IoSliceMut::new(buf)]).await
+    }
 
-                buf[..copy_length].copy_from_slice(
-                    &self.recv_buffer[self.recv_buffer_pos..self.recv_buffer_pos + copy_length]
-                );
-                self.recv_buffer_pos += copy_length;
-                return Ok(copy_length);
+    /// Receive data into several buffers, filled in order
+    ///
+    /// Returns what [`KcpStream::recv`] would return for one buffer as
+    /// large as all of them together, e.g. one message split into a header
+    /// and a body without copying it twice.
+    pub async fn recv_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> KcpResult<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if len == 0 {
+            return Ok(0);
+        }
+        // Data left over from an earlier call comes first
+        if let Some(n) = self.read_buffered(bufs) {
+            return Ok(n);
+        }
+        // A receive the AsyncRead impl left waiting is finished first
+        let msg = if self.read_op.is_some() {
+            future::poll_fn(|cx| self.poll_message(cx)).await?
+        } else {
+            self.next_message().await?
+        };
+        Ok(msg.map_or(0, |msg| self.deliver(msg, bufs)))
+    }
+
+    /// Copy what is left of a partly read message into `bufs`
+    fn read_buffered(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Option<usize> {
+        if self.recv_buffer_pos == self.recv_buffer_cap {
+            return None;
+        }
+        let buffered = &self.recv_buffer[self.recv_buffer_pos..self.recv_buffer_cap];
+        let n = scatter(buffered, bufs);
+        self.recv_buffer_pos += n;
+        Some(n)
+    }
+
+    /// Copy a received message into `bufs`, keeping what does not fit
+    fn deliver(&mut self, msg: Vec<u8>, bufs: &mut [IoSliceMut<'_>]) -> usize {
+        let n = scatter(&msg, bufs);
+        if n < msg.len() {
+            trace!("{} recv buffered {} bytes", self.tag, msg.len() - n);
+            self.recv_buffer = msg;
+            self.recv_buffer_pos = n;
+            self.recv_buffer_cap = self.recv_buffer.len();
+        }
+        n
+    }
+
+    /// Next received message, `None` once the peer closed the session
+    /// normally
+    async fn next_message(&mut self) -> KcpResult<Option<Vec<u8>>> {
+        loop {
+            // Complete messages are handed over by whoever feeds input to
+            // the session, no need to wait for the lock
+            if let Some(msg) = self.ring.pop() {
+                return Ok(Some(msg));
             }
 
-            // Try to receive from KCP
             let mut socket = self.socket.lock().await;
-            
-            // Check if we can read directly into user buffer
-            let peek_size = socket.peek_size().unwrap_or(0);
-            
-            if peek_size > 0 && peek_size <= buf.len() {
-                match socket.recv(buf) {
-                    Ok(n) => {
-                        trace!("recv directly {} bytes", n);
-                        return Ok(n);
-                    }
-                    Err(KcpError::UserBufTooSmall) => {}
-                    Err(err) => return Err(err),
+            socket.check_open()?;
+            if socket.take_received()? {
+                // The window update is retried with the next flush, the
+                // data taken from KCP must not be lost to a send error
+                if let Err(e) = socket.send_output().await {
+                    trace!("{} window update not sent: {}", self.tag, e);
                 }
+                continue;
+            }
+            match socket.peer_close_error() {
+                Some(Error::ConnectionClosed) => return Ok(None),
+                Some(e) => return Err(e),
+                None => {}
             }
+            // No data available, need to wait for input
+            let input = socket.listen_input();
+            let interval = socket.interval();
+            drop(socket);
+            self.wait_input(input, interval).await?;
+        }
+    }
+
+    /// [`KcpStream::next_message`] for the `AsyncRead` impl
+    fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Option<Vec<u8>>>> {
+        if self.read_op.is_none() {
+            if let Some(msg) = self.ring.pop() {
+                return Poll::Ready(Ok(Some(msg)));
+            }
+            let mut handle = self.io_handle();
+            self.read_op = Some(PendingOp::new(async move {
+                let result = handle.next_message().await;
+                (mem::take(&mut handle.input_buffer), result)
+            }));
+        }
+        let op = self.read_op.as_mut().expect("read operation just started");
+        let (buffer, result) = ready!(op.0.as_mut().poll(cx));
+        self.read_op = None;
+        self.input_buffer = buffer;
+        Poll::Ready(result)
+    }
+
+    /// Handle for a pending operation, lending it the datagram buffer
+    fn io_handle(&mut self) -> KcpStream {
+        let mut handle = self.clone_handle();
+        handle.input_buffer = mem::take(&mut self.input_buffer);
+        handle
+    }
 
-            // Need to use internal buffer
-            if peek_size > 0 {
-                if self.recv_buffer.len() < peek_size {
-                    self.recv_buffer.resize(peek_size, 0);
+    /// [`KcpStream::send`] for the `AsyncWrite` impl
+    ///
+    /// Queues what fits the window right away and leaves sending it to a
+    /// pending operation the next write, flush or close finishes, so it
+    /// never waits for the datagrams of the write it reports. A full window
+    /// is [`Poll::Pending`] until input or the update interval makes room,
+    /// never [`Error::WindowExhausted`].
+    fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            ready!(self.poll_write_op(cx))?;
+            let Some(mut socket) = self.socket.try_lock_arc() else {
+                let socket = self.socket.clone();
+                self.start_write(WriteStep::Lock, async move {
+                    drop(socket.lock_arc().await);
+                    (Vec::new(), Ok(()))
+                });
+                continue;
+            };
+            let room = socket.send_room(buf.len(), 0)?;
+            let sent = match room {
+                0 => Err(Error::WindowExhausted),
+                room => socket.send_with(&buf[..room], None, 0),
+            };
+            let n = match sent {
+                Ok(n) => n,
+                Err(Error::WindowExhausted) => {
+                    // The op wakes this task once ACKs or an update free room
+                    let input = socket.listen_input();
+                    let interval = socket.interval();
+                    drop(socket);
+                    let mut handle = self.io_handle();
+                    self.start_write(WriteStep::Room, async move {
+                        let result = handle.wait_input(input, interval).await;
+                        (mem::take(&mut handle.input_buffer), result)
+                    });
+                    continue;
                 }
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            socket.flush_sent()?;
+            drop(socket);
+
+            let socket = self.socket.clone();
+            self.start_write(WriteStep::Output, async move {
+                let result = socket.lock().await.send_output().await;
+                (Vec::new(), result.map_err(Error::from))
+            });
+            if let Poll::Ready(Err(e)) = self.poll_write_op(cx) {
+                return Poll::Ready(Err(e));
+            }
+            return Poll::Ready(Ok(n));
+        }
+    }
+
+    /// Finish the pending write operation, then run `step`
+    fn poll_write_step(&mut self, cx: &mut Context<'_>, step: WriteStep) -> Poll<KcpResult<()>> {
+        loop {
+            match ready!(self.poll_write_op(cx))? {
+                Some(done) if done == step => return Poll::Ready(Ok(())),
+                Some(_) => continue,
+                None => {}
+            }
+            let mut handle = self.io_handle();
+            self.start_write(step, async move {
+                let result = match step {
+                    WriteStep::Close => handle.shutdown().await,
+                    // Nothing is left to flush once the application closed
+                    _ => match handle.flush().await {
+                        Err(Error::ConnectionClosed) => Ok(()),
+                        result => result,
+                    },
+                };
+                (mem::take(&mut handle.input_buffer), result)
+            });
+        }
+    }
+
+    fn start_write(
+        &mut self,
+        step: WriteStep,
+        op: impl Future<Output = (Vec<u8>, KcpResult<()>)> + Send + 'static,
+    ) {
+        self.write_op = Some((step, PendingOp::new(op)));
+    }
+
+    /// Drive the pending write operation, returns its step once done
+    fn poll_write_op(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Option<WriteStep>>> {
+        let Some((step, op)) = &mut self.write_op else {
+            return Poll::Ready(Ok(None));
+        };
+        let step = *step;
+        let (buffer, result) = ready!(op.0.as_mut().poll(cx));
+        self.write_op = None;
+        if !buffer.is_empty() {
+            self.input_buffer = buffer;
+        }
+        Poll::Ready(result.map(|()| Some(step)))
+    }
 
-                match socket.recv(&mut self.recv_buffer) {
-                    Ok(0) => return Ok(0),
-                    Ok(n) => {
-                        trace!("recv buffered {} bytes", n);
-                        self.recv_buffer_pos = 0;
-                        self.recv_buffer_cap = n;
-                        continue;
+    /// Flush, wait until the peer acknowledged everything sent and close
+    /// the session
+    async fn shutdown(&mut self) -> KcpResult<()> {
+        loop {
+            let mut socket = self.socket.lock().await;
+            match socket.check_open() {
+                Err(Error::ConnectionClosed) => return Ok(()),
+                result => result?,
+            }
+            socket.flush()?;
+            socket.send_output().await?;
+            let status = socket.window_status();
+            if status.in_flight + status.queued == 0 || socket.is_peer_closed() {
+                break;
+            }
+            let input = socket.listen_input();
+            let interval = socket.interval();
+            drop(socket);
+            self.wait_input(input, interval).await?;
+        }
+        self.close_with(CloseCode::Normal).await
+    }
+
+    /// Wait until new input was fed to KCP or the update interval elapsed
+    ///
+    /// Streams created by [`KcpStream::connect`] own their UDP socket and
+    /// read it here, those of a connector read the shared socket for all
+    /// its streams. Accepted streams are fed by the listener's accept loop.
+    async fn wait_input(&mut self, input: EventListener, interval: Duration) -> KcpResult<()> {
+        if let Some(demux) = self.demux.clone() {
+            let mut buf = mem::take(&mut self.input_buffer);
+            buf.resize(65536, 0);
+            let received = future::or(
+                async {
+                    input.await;
+                    Ok(true)
+                },
+                future::or(async { demux.receive(&mut buf).await.map(|()| true) }, async {
+                    Timer::after(interval).await;
+                    Ok(false)
+                }),
+            )
+            .await;
+            self.input_buffer = buf;
+            if !received? {
+                self.drive().await?;
+            }
+            return Ok(());
+        }
+        if self.udp.get_ref().peer_addr().is_err() {
+            let received = future::or(async { input.await; true }, async {
+                Timer::after(interval).await;
+                false
+            })
+            .await;
+            if !received {
+                self.drive().await?;
+            }
+            return Ok(());
+        }
+
+        let mut buf = mem::take(&mut self.input_buffer);
+        buf.resize(65536, 0);
+        let udp = self.udp.clone();
+        let paths = self.paths.clone();
+        let received = future::or(async { Some(recv_any(&udp, &paths, &mut buf).await) }, async {
+            Timer::after(interval).await;
+            None
+        })
+        .await;
+
+        let result = match received {
+            Some(Ok((n, path))) => {
+                let mut socket = self.socket.lock().await;
+                socket.note_input_on(path);
+                let result = match socket.unwrap_datagram(&buf[..n]) {
+                    Some(data) => socket.input(data),
+                    None => {
+                        trace!("{} dropped a malformed relayed datagram", self.tag);
+                        Ok(false)
                     }
-                    Err(err) => return Err(err),
+                };
+                socket.send_output().await?;
+                if let Err(e) = result {
+                    // Bad datagrams are dropped, only a closed session fails
+                    socket.check_open()?;
+                    trace!("{} dropped a datagram: {}", self.tag, e);
                 }
+                Ok(())
             }
+            // ICMP errors for earlier datagrams, KCP retransmits anyway
+            Some(Err(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+                ) =>
+            {
+                self.socket.lock().await.note_refused();
+                Ok(())
+            }
+            Some(Err(e)) => Err(e.into()),
+            None => self.drive().await,
+        };
+        self.input_buffer = buf;
+        result
+    }
 
-            // No data available, need to wait for input
-            drop(socket);
-            
-            // Simple polling approach with async delay
-            futures_lite::future::yield_now().await;
+    /// Run KCP timers so retransmissions and ACKs go out without traffic
+    async fn drive(&self) -> KcpResult<()> {
+        let mut socket = self.socket.lock().await;
+        socket.flush()?;
+        socket.send_output().await?;
+        Ok(())
+    }
+
+    /// Whether the session is open, this is its only handle and everything
+    /// received was read, so a pool can hand it out for another request
+    pub(crate) async fn is_reusable(&self) -> bool {
+        if self.recv_buffer_pos < self.recv_buffer_cap || self.handles.load(Ordering::Acquire) != 1 {
+            return false;
+        }
+        let mut socket = self.socket.lock().await;
+        socket.check_open().is_ok()
+            && socket.peer_close_error().is_none()
+            && !socket.take_received().unwrap_or(true)
+    }
+
+    /// Probe the peer of an idle session, returns the time since it was
+    /// last heard from
+    pub(crate) async fn keepalive(&self) -> KcpResult<Duration> {
+        let mut socket = self.socket.lock().await;
+        socket.check_open()?;
+        socket.keepalive()?;
+        socket.send_output().await?;
+        Ok(socket.since_input())
+    }
+
+    /// Move the connection onto a new local UDP socket
+    ///
+    /// Keeps all KCP state (sequence numbers, unacknowledged data, windows)
+    /// so a mobile client can survive a WiFi/cellular switch by binding a
+    /// socket on the new interface and handing it over. Pending data is
+    /// flushed through the new socket right away so the server learns the
+    /// new address; a smol-kcp listener moves the session there once the
+    /// client answered its challenge from it. Only supported on streams created by
+    /// [`KcpStream::connect`]; accepted streams share the listener socket.
+    pub async fn rebind(&mut self, udp: std::net::UdpSocket) -> KcpResult<()> {
+        if self.demux.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot rebind a stream sharing the socket of a connector",
+            )
+            .into());
         }
+        if self.udp.get_ref().peer_addr().is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot rebind a listener-accepted stream",
+            )
+            .into());
+        }
+
+        if !self.paths.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot rebind a multipath stream",
+            )
+            .into());
+        }
+
+        let mut socket = self.socket.lock().await;
+        if socket.is_proxied() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot rebind a stream relayed by a SOCKS5 proxy",
+            )
+            .into());
+        }
+        udp.connect(socket.peer_addr())?;
+        configure_udp(&udp)?;
+        let udp = Arc::new(Async::new(udp)?);
+
+        socket.set_udp_socket(udp.clone());
+        self.udp = udp;
+        trace!("{} rebound to {}", self.tag, self.udp.get_ref().local_addr()?);
+
+        socket.flush()?;
+        socket.send_output().await?;
+        Ok(())
+    }
+
+    /// Snapshot of the connection statistics
+    pub async fn stats(&self) -> KcpStats {
+        self.socket.lock().await.stats()
+    }
+
+    /// Address, RTT and traffic of every path, empty unless multipath
+    ///
+    /// Accepted streams list the paths their client joined.
+    pub async fn path_stats(&self) -> Vec<PathStats> {
+        self.socket.lock().await.path_stats()
+    }
+
+    /// Windows of the connection and whether sending is currently limited
+    /// by the application, the congestion window or the peer's receive
+    /// window
+    pub async fn window_status(&self) -> WindowStatus {
+        self.socket.lock().await.window_status()
+    }
+
+    /// Receive window last advertised by the peer, in segments
+    pub async fn remote_window(&self) -> u16 {
+        self.window_status().await.rmt_wnd
+    }
+
+    /// Current congestion window, in segments
+    pub async fn congestion_window(&self) -> u16 {
+        self.window_status().await.cwnd
+    }
+
+    /// Payload bytes per second the peer acknowledged recently, e.g. for
+    /// the progress rate of a transfer
+    pub async fn goodput(&self) -> u64 {
+        self.socket.lock().await.goodput()
+    }
+
+    /// Estimated bandwidth of the path in bytes per second: the highest
+    /// rate data was acknowledged at over the last ten RTTs, zero before
+    /// the first ACKs
+    pub async fn estimated_bandwidth(&self) -> u64 {
+        self.socket.lock().await.estimated_bandwidth()
+    }
+
+    /// Delay towards and from the peer with its queuing part and trend,
+    /// see [`OneWayDelay`]
+    pub async fn one_way_delay(&self) -> OneWayDelay {
+        self.socket.lock().await.one_way_delay()
+    }
+
+    /// Full RTT estimate (latest sample, smoothed RTT, variance, jitter)
+    pub async fn rtt(&self) -> RttEstimate {
+        self.socket.lock().await.rtt()
+    }
+
+    /// Smoothed round trip time, zero until the first ACK arrives
+    pub async fn srtt(&self) -> Duration {
+        self.rtt().await.srtt
+    }
+
+    /// Round trip time variance
+    pub async fn rttvar(&self) -> Duration {
+        self.rtt().await.rttvar
+    }
+
+    /// Jitter estimate, e.g. for sizing a playout buffer
+    pub async fn jitter(&self) -> Duration {
+        self.rtt().await.jitter
+    }
+
+    /// Decode and log the header of every KCP segment sent and received
+    ///
+    /// Each segment is logged as e.g. `-> 10.0.0.2:4000 PUSH conv=1 frg=0
+    /// wnd=128 ts=52310 sn=7 una=3 len=1376` at debug level with the
+    /// `smol_kcp::wire` target, which helps debugging interop with other
+    /// KCP implementations.
+    pub async fn set_segment_logging(&self, enabled: bool) {
+        self.socket.lock().await.set_segment_logging(enabled);
+    }
+
+    /// Capture the datagrams of this connection, `None` stops capturing
+    pub async fn set_pcap(&self, pcap: Option<PcapWriter>) {
+        self.socket.lock().await.set_pcap(pcap);
+    }
+
+    /// RTT and send-to-ack latency percentiles
+    ///
+    /// `None` unless [`KcpConfig::latency_histograms`] is enabled.
+    pub async fn latency_report(&self) -> Option<LatencyReport> {
+        self.socket.lock().await.latency_report()
+    }
+
+    /// Clear the latency histograms, e.g. after exporting a report
+    pub async fn reset_latency_histograms(&self) {
+        self.socket.lock().await.reset_latency();
+    }
+
+    /// Periodic statistics samples, oldest first
+    ///
+    /// Empty unless [`KcpConfig::stats_history`] is set.
+    pub async fn stats_history(&self) -> Vec<StatsSample> {
+        self.socket.lock().await.stats_history()
+    }
+
+    /// Register a callback for connection events
+    ///
+    /// See [`KcpEvent`] for what is reported and [`KcpEventHandler`] for
+    /// the constraints on the callback.
+    pub async fn set_event_handler<F>(&self, handler: F)
+    where
+        F: Fn(&KcpEvent) + Send + Sync + 'static,
+    {
+        let handler: KcpEventHandler = Arc::new(handler);
+        self.socket.lock().await.set_event_handler(Some(handler));
+    }
+
+    /// Remove the event callback
+    pub async fn clear_event_handler(&self) {
+        self.socket.lock().await.set_event_handler(None);
+    }
+
+    /// Attach application data to the session, e.g. the identity the
+    /// peer authenticated as, replacing any previous
+    ///
+    /// It is listed with the session by
+    /// [`KcpListener::sessions`](crate::KcpListener::sessions).
+    pub async fn set_user_data<T: Any + Send + Sync>(&self, data: T) {
+        let data: UserData = Arc::new(data);
+        self.socket.lock().await.set_user_data(Some(data));
+    }
+
+    /// Data attached with [`KcpStream::set_user_data`], `None` without
+    /// any or if it is not a `T`
+    pub async fn user_data<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        let data = self.socket.lock().await.user_data().cloned()?;
+        data.downcast().ok()
+    }
+
+    /// Remove the attached data
+    pub async fn clear_user_data(&self) {
+        self.socket.lock().await.set_user_data(None);
     }
 
     /// Get local address
@@ -131,6 +1171,14 @@ impl KcpStream {
         socket.udp_socket().get_ref().local_addr()
     }
 
+    /// Get the underlying UDP socket
+    ///
+    /// For listener-accepted streams this is the listener's shared socket,
+    /// for those of a [`KcpConnector`](crate::KcpConnector) the connector's.
+    pub fn udp_socket(&self) -> &std::net::UdpSocket {
+        self.udp.get_ref()
+    }
+
     /// Get peer address
     pub async fn peer_addr(&self) -> SocketAddr {
         let socket = self.socket.lock().await;
@@ -138,40 +1186,136 @@ impl KcpStream {
     }
 }
 
+/// Receive on the first of a stream's sockets with a datagram, returns its
+/// length and the index of the path it arrived on
+async fn recv_any(
+    udp: &Async<std::net::UdpSocket>,
+    paths: &[Arc<Async<std::net::UdpSocket>>],
+    buf: &mut [u8],
+) -> io::Result<(usize, usize)> {
+    if paths.is_empty() {
+        return udp.recv(buf).await.map(|n| (n, 0));
+    }
+    loop {
+        let ready = future::poll_fn(|cx| {
+            let sockets = std::iter::once(udp).chain(paths.iter().map(|udp| &**udp));
+            for (index, udp) in sockets.enumerate() {
+                if udp.poll_readable(cx).is_ready() {
+                    return Poll::Ready(index);
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        let socket = if ready == 0 { udp } else { &*paths[ready - 1] };
+        match socket.get_ref().recv(buf) {
+            Ok(n) => return Ok((n, ready)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 impl AsyncRead for KcpStream {
-    fn poll_read(
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.poll_read_vectored(cx, &mut [IoSliceMut::new(buf)])
+    }
+
+    fn poll_read_vectored(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
-        buf: &mut [u8],
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
     ) -> Poll<io::Result<usize>> {
-        // Simple implementation - in production you'd want proper async polling
-        match futures_lite::future::block_on(self.recv(buf)) {
-            Ok(n) => Poll::Ready(Ok(n)),
-            Err(KcpError::IoError(err)) => Poll::Ready(Err(err)),
-            Err(err) => Poll::Ready(Err(io::Error::other(err))),
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Poll::Ready(Ok(0));
+        }
+        if let Some(n) = self.read_buffered(bufs) {
+            return Poll::Ready(Ok(n));
         }
+        let msg = ready!(self.poll_message(cx))?;
+        Poll::Ready(Ok(msg.map_or(0, |msg| self.deliver(msg, bufs))))
     }
 }
 
+/// Copy the start of `data` into `bufs` in order, returns the bytes copied
+fn scatter(data: &[u8], bufs: &mut [IoSliceMut<'_>]) -> usize {
+    let mut copied = 0;
+    for buf in bufs {
+        let n = buf.len().min(data.len() - copied);
+        buf[..n].copy_from_slice(&data[copied..copied + n]);
+        copied += n;
+        if copied == data.len() {
+            break;
+        }
+    }
+    copied
+}
+
 impl AsyncWrite for KcpStream {
-    fn poll_write(
-        mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<io::Result<usize>> {
-        match futures_lite::future::block_on(self.send(buf)) {
-            Ok(n) => Poll::Ready(Ok(n)),
-            Err(KcpError::IoError(err)) => Poll::Ready(Err(err)),
-            Err(err) => Poll::Ready(Err(io::Error::other(err))),
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.poll_send(cx, buf).map_err(io::Error::from)
+    }
+
+    /// Succeeds without sending anything once the session was closed
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_write_step(cx, WriteStep::Flush).map_err(io::Error::from)
+    }
+
+    /// Flushes, waits until the peer acknowledged everything and closes the
+    /// session with [`CloseCode::Normal`], for every handle
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_write_step(cx, WriteStep::Close).map_err(io::Error::from)
+    }
+}
+
+/// Dropping the last handle closes the session and tells the peer with a
+/// best-effort close frame, unless it was exported for a hot restart
+///
+/// The session of an accepted stream is handed to the listener, whose
+/// accept loop closes it. A stream on a socket of its own is the last
+/// user of its session and sends the frame right away.
+impl Drop for KcpStream {
+    fn drop(&mut self) {
+        if self.handles.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+        if let Some(owner) = &self.owner {
+            owner.push(self.socket.clone());
+        } else if let Some(demux) = &self.demux {
+            demux.close_dropped(&self.socket);
+        } else if let Some(mut socket) = self.socket.try_lock() {
+            // Nothing else holds the session of a stream reading its own
+            // socket once its handles are gone
+            socket.close_dropped();
+            socket.send_output_now();
         }
     }
+}
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        // UDP doesn't need explicit flushing
-        Poll::Ready(Ok(()))
+#[cfg(unix)]
+impl AsRawFd for KcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.udp.as_raw_fd()
     }
+}
+
+#[cfg(unix)]
+impl AsFd for KcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.udp.get_ref().as_fd()
+    }
+}
 
-    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Poll::Ready(Ok(()))
+#[cfg(windows)]
+impl AsRawSocket for KcpStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.udp.as_raw_socket()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(windows)]
+impl AsSocket for KcpStream {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.udp.get_ref().as_socket()
+    }
+}
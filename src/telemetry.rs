@@ -0,0 +1,213 @@
+//! Metrics export through the `metrics` facade and `tracing` instrumentation
+//!
+//! With the `metrics` feature enabled every session records into counters,
+//! gauges and histograms labelled with the local address of its socket, so
+//! all sessions of one listener aggregate into the same series. Install
+//! any `metrics` recorder (e.g. `metrics-exporter-prometheus`) to collect
+//! them.
+//!
+//! With the `tracing` feature enabled every session owns a `kcp_session`
+//! span carrying its conv and peer address, and lifecycle, retransmission
+//! and window-stall events are emitted inside it.
+//!
+//! Without the features these types compile to no-ops.
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use std::{net::SocketAddr, time::Duration};
+
+    use metrics::{
+        counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Counter,
+        Gauge, Histogram, Unit,
+    };
+
+    /// Register descriptions for all metrics, safe to call repeatedly
+    pub fn describe() {
+        describe_gauge!("kcp_sessions", "Active KCP sessions");
+        describe_counter!("kcp_sessions_accepted_total", "KCP sessions accepted");
+        describe_counter!("kcp_bytes_sent_total", Unit::Bytes, "UDP payload bytes sent");
+        describe_counter!("kcp_bytes_received_total", Unit::Bytes, "UDP payload bytes received");
+        describe_counter!("kcp_packets_sent_total", "Datagrams sent");
+        describe_counter!("kcp_packets_received_total", "Datagrams received");
+        describe_counter!("kcp_retransmissions_total", "Data segments sent more than once");
+        describe_histogram!("kcp_rtt_seconds", Unit::Seconds, "Round trip time samples");
+    }
+
+    /// Listener level metric handles
+    pub struct ListenerMetrics {
+        sessions: Gauge,
+        accepted: Counter,
+    }
+
+    impl ListenerMetrics {
+        pub fn new(local: Option<SocketAddr>) -> Self {
+            describe();
+            let local = label(local);
+            Self {
+                sessions: gauge!("kcp_sessions", "local" => local.clone()),
+                accepted: counter!("kcp_sessions_accepted_total", "local" => local),
+            }
+        }
+
+        pub fn session_opened(&self) {
+            self.sessions.increment(1.0);
+            self.accepted.increment(1);
+        }
+
+        pub fn session_closed(&self) {
+            self.sessions.decrement(1.0);
+        }
+    }
+
+    /// Session level metric handles
+    pub struct SocketMetrics {
+        bytes_sent: Counter,
+        bytes_received: Counter,
+        packets_sent: Counter,
+        packets_received: Counter,
+        retransmissions: Counter,
+        rtt: Histogram,
+    }
+
+    impl SocketMetrics {
+        pub fn new(local: Option<SocketAddr>) -> Self {
+            let local = label(local);
+            Self {
+                bytes_sent: counter!("kcp_bytes_sent_total", "local" => local.clone()),
+                bytes_received: counter!("kcp_bytes_received_total", "local" => local.clone()),
+                packets_sent: counter!("kcp_packets_sent_total", "local" => local.clone()),
+                packets_received: counter!("kcp_packets_received_total", "local" => local.clone()),
+                retransmissions: counter!("kcp_retransmissions_total", "local" => local.clone()),
+                rtt: histogram!("kcp_rtt_seconds", "local" => local),
+            }
+        }
+
+        pub fn on_sent(&self, bytes: usize, retransmissions: u64) {
+            self.bytes_sent.increment(bytes as u64);
+            self.packets_sent.increment(1);
+            self.retransmissions.increment(retransmissions);
+        }
+
+        pub fn on_received(&self, bytes: usize) {
+            self.bytes_received.increment(bytes as u64);
+            self.packets_received.increment(1);
+        }
+
+        pub fn on_rtt(&self, rtt: Duration) {
+            self.rtt.record(rtt.as_secs_f64());
+        }
+    }
+
+    fn label(local: Option<SocketAddr>) -> String {
+        local.map(|addr| addr.to_string()).unwrap_or_default()
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    use std::{net::SocketAddr, time::Duration};
+
+    pub struct ListenerMetrics;
+
+    impl ListenerMetrics {
+        pub fn new(_local: Option<SocketAddr>) -> Self {
+            Self
+        }
+
+        pub fn session_opened(&self) {}
+
+        pub fn session_closed(&self) {}
+    }
+
+    pub struct SocketMetrics;
+
+    impl SocketMetrics {
+        pub fn new(_local: Option<SocketAddr>) -> Self {
+            Self
+        }
+
+        pub fn on_sent(&self, _bytes: usize, _retransmissions: u64) {}
+
+        pub fn on_received(&self, _bytes: usize) {}
+
+        pub fn on_rtt(&self, _rtt: Duration) {}
+    }
+}
+
+#[cfg(feature = "tracing")]
+mod trace_imp {
+    use std::net::SocketAddr;
+
+    use tracing::{debug, info, info_span, warn, Span};
+
+    /// Span and events of one session
+    pub struct SessionTrace {
+        span: Span,
+    }
+
+    impl SessionTrace {
+        pub fn new(role: &'static str, conv: u32, peer: SocketAddr) -> Self {
+            let span = info_span!("kcp_session", role, conv, %peer);
+            info!(parent: &span, "session opened");
+            Self { span }
+        }
+
+        pub fn conv_allocated(&self) {
+            debug!(parent: &self.span, "conv allocated by server");
+        }
+
+        pub fn retransmitted(&self, segments: u64) {
+            debug!(parent: &self.span, segments, "segments retransmitted");
+        }
+
+        pub fn window_stalled(&self, waiting: usize, snd_wnd: u16, rmt_wnd: u16) {
+            warn!(parent: &self.span, waiting, snd_wnd, rmt_wnd, "send window stalled");
+        }
+
+        pub fn window_resumed(&self) {
+            debug!(parent: &self.span, "send window resumed");
+        }
+
+        pub fn dead_link(&self) {
+            warn!(parent: &self.span, "retransmission limit reached, link is dead");
+        }
+
+        pub fn memory_exceeded(&self, buffered: usize, limit: usize) {
+            warn!(parent: &self.span, buffered, limit, "session memory limit exceeded");
+        }
+    }
+
+    impl Drop for SessionTrace {
+        fn drop(&mut self) {
+            info!(parent: &self.span, "session closed");
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod trace_imp {
+    use std::net::SocketAddr;
+
+    pub struct SessionTrace;
+
+    impl SessionTrace {
+        pub fn new(_role: &'static str, _conv: u32, _peer: SocketAddr) -> Self {
+            Self
+        }
+
+        pub fn conv_allocated(&self) {}
+
+        pub fn retransmitted(&self, _segments: u64) {}
+
+        pub fn window_stalled(&self, _waiting: usize, _snd_wnd: u16, _rmt_wnd: u16) {}
+
+        pub fn window_resumed(&self) {}
+
+        pub fn dead_link(&self) {}
+
+        pub fn memory_exceeded(&self, _buffered: usize, _limit: usize) {}
+    }
+}
+
+pub(crate) use imp::{ListenerMetrics, SocketMetrics};
+pub(crate) use trace_imp::SessionTrace;
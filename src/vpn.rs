@@ -0,0 +1,188 @@
+//! Layer 3 VPN over KCP (Linux)
+//!
+//! A [`TunDevice`] hands IP packets to and from the kernel. Each packet is
+//! carried as one KCP message, so the stream has to use message mode
+//! (`KcpConfig::stream == false`):
+//!
+//! ```ignore
+//! let mut tun = TunDevice::create("kcp%d")?;
+//! tun.set_ipv4(Ipv4Addr::new(10, 8, 0, 2), 24)?;
+//! tun.set_mtu(1376)?;
+//! tun.up()?;
+//! let stream = KcpStream::connect(&KcpConfig::wan(), server).await?;
+//! tun.forward(stream).await?;
+//! ```
+//!
+//! Creating and configuring the interface needs `CAP_NET_ADMIN`.
+
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    mem,
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use async_io::Async;
+use futures_lite::future;
+use libc::{c_char, c_int, c_short, c_ulong};
+use crate::{error::KcpResult, logging::debug, stream::KcpStream};
+
+/// `_IOW('T', 202, int)` from `linux/if_tun.h`
+const TUNSETIFF: c_ulong = 0x4004_54ca;
+
+/// Largest IP packet
+const MAX_PACKET: usize = 65535;
+
+/// A TUN interface
+pub struct TunDevice {
+    io: Async<File>,
+    name: String,
+}
+
+impl TunDevice {
+    /// Create the interface `name`, or open it if it exists
+    ///
+    /// A `%d` in the name is replaced by the kernel with the first free
+    /// number, [`TunDevice::name`] tells the result.
+    pub fn create(name: &str) -> io::Result<Self> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("interface name {:?} is too long", name),
+            ));
+        }
+
+        let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+        let mut req = ifreq(name);
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as c_short;
+        // SAFETY: TUNSETIFF reads and writes an ifreq
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: the kernel NUL terminates the name
+        let name = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        Ok(Self {
+            io: Async::new(file)?,
+            name,
+        })
+    }
+
+    /// Name of the interface
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set the MTU of the interface
+    ///
+    /// Packets up to the KCP MSS (`mtu` of the config minus 24 bytes of
+    /// header) travel as a single segment.
+    pub fn set_mtu(&self, mtu: u32) -> io::Result<()> {
+        let mut req = ifreq(&self.name);
+        req.ifr_ifru.ifru_mtu = mtu as c_int;
+        control(libc::SIOCSIFMTU, &mut req)
+    }
+
+    /// Assign an IPv4 address, `prefix` is the length of the netmask
+    pub fn set_ipv4(&self, addr: Ipv4Addr, prefix: u8) -> io::Result<()> {
+        if prefix > 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "prefix is longer than 32"));
+        }
+        let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+
+        let mut req = ifreq(&self.name);
+        req.ifr_ifru.ifru_addr = sockaddr(addr);
+        control(libc::SIOCSIFADDR, &mut req)?;
+        req.ifr_ifru.ifru_netmask = sockaddr(Ipv4Addr::from(mask));
+        control(libc::SIOCSIFNETMASK, &mut req)
+    }
+
+    /// Bring the interface up
+    pub fn up(&self) -> io::Result<()> {
+        let mut req = ifreq(&self.name);
+        control(libc::SIOCGIFFLAGS, &mut req)?;
+        // SAFETY: SIOCGIFFLAGS filled in the flags
+        unsafe { req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as c_short };
+        control(libc::SIOCSIFFLAGS, &mut req)
+    }
+
+    /// Read the next packet the kernel routed into the interface
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read_with(|mut file| file.read(buf)).await
+    }
+
+    /// Hand a packet to the kernel
+    pub async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.io.write_with(|mut file| file.write(packet)).await
+    }
+
+    /// Carry packets between the interface and a KCP stream
+    ///
+    /// Runs until the interface or the stream fails. Packets the kernel
+    /// rejects, e.g. malformed ones, are dropped.
+    pub async fn forward(&self, stream: KcpStream) -> KcpResult<()> {
+        let mut upstream = stream.clone_handle();
+        let mut downstream = stream;
+
+        let outbound = async {
+            let mut buf = vec![0u8; MAX_PACKET];
+            loop {
+                let n = self.recv(&mut buf).await?;
+                upstream.send(&buf[..n]).await?;
+            }
+        };
+        let inbound = async {
+            let mut buf = vec![0u8; MAX_PACKET];
+            loop {
+                let n = downstream.recv(&mut buf).await?;
+                if let Err(e) = self.send(&buf[..n]).await {
+                    debug!("{} dropped a packet of {} bytes: {}", self.name, n, e);
+                }
+            }
+        };
+        future::or(outbound, inbound).await
+    }
+}
+
+/// Request for the interface `name`
+fn ifreq(name: &str) -> libc::ifreq {
+    // SAFETY: all-zero is a valid ifreq
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes().take(libc::IFNAMSIZ - 1)) {
+        *dst = src as c_char;
+    }
+    req
+}
+
+fn sockaddr(addr: Ipv4Addr) -> libc::sockaddr {
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: sockaddr_in and sockaddr have the same size
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(sin) }
+}
+
+/// Interface ioctl through a throwaway socket
+fn control(request: c_ulong, req: &mut libc::ifreq) -> io::Result<()> {
+    // SAFETY: plain socket call, the descriptor is owned right away
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a fresh descriptor
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: interface requests read and write an ifreq
+    if unsafe { libc::ioctl(socket.as_raw_fd(), request as _, req) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
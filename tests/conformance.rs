@@ -0,0 +1,10 @@
+//! The local KCP engine against the golden ikcp vectors, so changes to
+//! the core cannot silently break wire compatibility
+
+use smol_kcp::conformance;
+
+#[test]
+fn engine_matches_golden_vectors() {
+    let report = conformance::verify_engine();
+    assert!(report.passed(), "{}", report);
+}
@@ -0,0 +1,91 @@
+//! Read a stream-mode connection through tiny and odd-sized buffers
+//!
+//! The client writes a known byte pattern in writes of varying size,
+//! empty ones included. The server reads it back with buffers of 1 byte
+//! and other sizes around the segment size, every byte has to arrive
+//! once and in order.
+
+use std::net::SocketAddr;
+
+use futures_lite::future;
+use smol_kcp::{KcpConfig, KcpListener, KcpStream};
+
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+/// Send `total` pattern bytes in writes cycling through `write_sizes`
+/// and read them with buffers cycling through `read_sizes`
+fn transfer(total: usize, write_sizes: &[usize], read_sizes: &[usize]) {
+    let config = KcpConfig {
+        stream: true,
+        ..Default::default()
+    };
+
+    future::block_on(async {
+        let mut listener = KcpListener::bind(config, "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+
+        let client = async {
+            let mut stream = KcpStream::connect(&config, addr).await.unwrap();
+            let data: Vec<u8> = (0..total).map(pattern).collect();
+            let mut sent = 0;
+            for size in write_sizes.iter().cycle() {
+                if sent == total {
+                    break;
+                }
+                let end = (sent + size).min(total);
+                sent += stream.send(&data[sent..end]).await.unwrap();
+            }
+            // Stay connected until the server has read everything
+            let mut done = [0u8; 1];
+            stream.recv(&mut done).await.unwrap();
+        };
+
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let read_all = async {
+                let mut buf = vec![0u8; *read_sizes.iter().max().unwrap()];
+                let mut received = 0;
+                for size in read_sizes.iter().cycle() {
+                    if received == total {
+                        break;
+                    }
+                    let n = stream.recv(&mut buf[..*size]).await.unwrap();
+                    assert!(n > 0 && n <= *size, "read {} bytes into {}", n, size);
+                    for (i, &byte) in buf[..n].iter().enumerate() {
+                        assert_eq!(byte, pattern(received + i), "byte {}", received + i);
+                    }
+                    received += n;
+                }
+                assert_eq!(received, total);
+                stream.send(b"k").await.unwrap();
+            };
+            // Accepted streams are fed by the accept loop
+            future::or(read_all, async {
+                loop {
+                    let _ = listener.accept().await;
+                }
+            })
+            .await;
+        };
+
+        future::zip(client, server).await;
+    });
+}
+
+#[test]
+fn one_byte_reads() {
+    transfer(20_000, &[1, 1377, 4000], &[1]);
+}
+
+#[test]
+fn mixed_reads_around_the_segment_size() {
+    transfer(
+        200_000,
+        &[0, 1, 7, 1375, 1376, 1377, 4000],
+        &[1, 1, 2, 3, 1375, 1376, 1377, 9000],
+    );
+}